@@ -0,0 +1,98 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Session script switching on what the ulb.a11y= kernel argument asks for, so one image serves
+// both the plain live entry and the accessible ones
+const SCRIPT_PATH: &str = "/usr/local/bin/ulb-a11y";
+const SCRIPT: &str = r#"#!/bin/sh
+for arg in $(cat /proc/cmdline); do
+    case "$arg" in ulb.a11y=*) A11Y="${arg#ulb.a11y=}" ;; esac
+done
+case ",$A11Y," in *,screen-reader,*)
+    gsettings set org.gnome.desktop.a11y.applications screen-reader-enabled true
+    # GNOME starts Orca from the setting, other desktops need it started
+    case "$XDG_CURRENT_DESKTOP" in *GNOME*) ;; *) orca --replace & ;; esac
+    ;;
+esac
+case ",$A11Y," in *,high-contrast,*)
+    gsettings set org.gnome.desktop.a11y.interface high-contrast true
+    gsettings set org.gnome.desktop.interface gtk-theme HighContrast
+    ;;
+esac
+case ",$A11Y," in *,magnifier,*)
+    gsettings set org.gnome.desktop.a11y.applications screen-magnifier-enabled true
+    ;;
+esac
+exit 0
+"#;
+
+// Optional [accessibility] section: live boot entries starting the session with assistive tools
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AccessibilityConfig {
+    #[serde(default)]
+    pub screen_reader: bool, // Orca with speech-dispatcher and eSpeak NG
+    #[serde(default)]
+    pub high_contrast: bool, // The HighContrast GTK theme
+    #[serde(default)]
+    pub magnifier: bool, // The desktop's screen magnifier
+}
+
+/// The live boot entries for [accessibility], as title suffix and the kernel argument the
+/// session script looks for.
+pub fn boot_entries(profile: &Profile) -> Vec<(&'static str, &'static str)> {
+    let config = profile.accessibility.clone().unwrap_or_default();
+    [
+        (config.screen_reader, " (screen reader)", "ulb.a11y=screen-reader"),
+        (config.high_contrast, " (high contrast)", "ulb.a11y=high-contrast"),
+        (config.magnifier, " (magnifier)", "ulb.a11y=magnifier"),
+    ]
+    .into_iter()
+    .filter(|(enabled, _, _)| *enabled)
+    .map(|(_, title, arg)| (title, arg))
+    .collect()
+}
+
+/// Installs the assistive tools [accessibility] asks for and the autostart entry that turns them
+/// on when the live session starts from one of its boot entries.
+pub fn install(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(config) = profile.accessibility.as_ref() else {
+        return Ok(());
+    };
+    if boot_entries(profile).is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Installing accessibility tools...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut packages: Vec<&str> = Vec::new();
+    if config.screen_reader {
+        packages.extend(match package_manager {
+            PackageManager::Portage => ["app-accessibility/orca", "app-accessibility/speech-dispatcher", "app-accessibility/espeak-ng"],
+            _ => ["orca", "speech-dispatcher", "espeak-ng"],
+        });
+    }
+    if config.high_contrast {
+        packages.push(match package_manager {
+            PackageManager::Portage => "x11-themes/gnome-themes-standard",
+            _ => "gnome-themes-extra",
+        });
+    }
+    // The script talks to the desktop through gsettings, which debootstrap leaves out
+    if package_manager == PackageManager::Apt {
+        packages.push("libglib2.0-bin");
+    }
+
+    let mut lines = vec!["set -e".to_string()];
+    lines.push(package_manager.install(&packages.iter().map(|p| p.to_string()).collect::<Vec<_>>()));
+    lines.push(format!("mkdir -p $(dirname {0}) && cat > {0} <<'EOF'\n{1}EOF\nchmod +x {0}", SCRIPT_PATH, SCRIPT));
+    lines.push(format!(
+        "mkdir -p /etc/xdg/autostart && printf '%s\\n' '[Desktop Entry]' 'Type=Application' 'Name=Accessibility' \
+         'Exec={}' 'NoDisplay=true' > /etc/xdg/autostart/ulb-a11y.desktop",
+        SCRIPT_PATH
+    ));
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Accessibility setup")
+}
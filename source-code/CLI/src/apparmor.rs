@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::{boot, PackageManager, Profile};
+
+const PROFILES_DIR: &str = "etc/apparmor.d";
+
+// Optional [apparmor] section: AppArmor enabled with the distro's and the profile's own profiles
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AppArmorConfig {
+    #[serde(default)]
+    pub profiles: Vec<String>, // AppArmor profiles in files/, installed to /etc/apparmor.d
+    #[serde(default)]
+    pub extra_profiles: bool, // Also the apparmor-profiles and apparmor-profiles-extra collections
+    #[serde(default)]
+    pub complain: Vec<String>, // Profiles (file names in /etc/apparmor.d) that only log instead of enforcing
+}
+
+/// The [apparmor] settings, once checked. Debian and Ubuntu kernels enable AppArmor unless the
+/// command line turns it off or picks another LSM, which would leave the profiles unloaded.
+pub fn config(profile: &Profile) -> Result<Option<AppArmorConfig>> {
+    let Some(config) = profile.apparmor.clone() else {
+        return Ok(None);
+    };
+    if crate::package_manager(profile)? != PackageManager::Apt {
+        return Err(anyhow::anyhow!("[apparmor] needs the debian or ubuntu base"));
+    }
+    if profile.selinux.is_some() {
+        return Err(anyhow::anyhow!("[apparmor] and selinux can't both be the major LSM"));
+    }
+    for arg in boot::kernel_cmdline(profile)?.unwrap_or_default().split_whitespace() {
+        let disables = match arg.split_once('=') {
+            Some(("apparmor", value)) => value == "0",
+            Some(("security", value)) => value != "apparmor",
+            Some(("lsm", value)) => !value.split(',').any(|lsm| lsm == "apparmor"),
+            _ => false,
+        };
+        if disables {
+            return Err(anyhow::anyhow!("kernel_cmdline {} turns AppArmor off, which [apparmor] needs", arg));
+        }
+    }
+    let names = config.profiles.iter().filter_map(|p| Path::new(p).file_name()?.to_str()).chain(config.complain.iter().map(String::as_str));
+    if let Some(name) = names.into_iter().find(|name| name.is_empty() || name.starts_with('.') || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))) {
+        return Err(anyhow::anyhow!("Invalid [apparmor] profile name: {:?}", name));
+    }
+    Ok(Some(config))
+}
+
+/// Installs AppArmor and the utilities, copies the [apparmor] profiles, checks every one of
+/// them with apparmor_parser (without loading it into the build host's kernel), puts the
+/// complain ones in complain mode and enables the service loading them at boot.
+pub fn configure(profile: &Profile, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    let Some(config) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Configuring AppArmor...".yellow());
+
+    let mut names = Vec::new();
+    for file in &config.profiles {
+        let source = files_dir.join(file.trim_start_matches('/'));
+        let name = source.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+        let target = rootfs.join(PROFILES_DIR).join(&name);
+        fs::create_dir_all(rootfs.join(PROFILES_DIR)).context("Failed to create /etc/apparmor.d")?;
+        fs::copy(&source, &target).context(format!("Failed to install AppArmor profile {}", source.display()))?;
+        names.push(name);
+    }
+
+    let mut packages = vec!["apparmor".to_string(), "apparmor-utils".to_string()];
+    if config.extra_profiles {
+        packages.extend(["apparmor-profiles".to_string(), "apparmor-profiles-extra".to_string()]);
+    }
+    let mut lines = vec!["set -e".to_string(), crate::package_manager(profile)?.install(&packages)];
+    lines.extend(names.iter().map(|name| format!("apparmor_parser --skip-kernel-load --skip-cache /{}/{}", PROFILES_DIR, name)));
+    lines.extend(config.complain.iter().map(|name| format!("aa-complain --no-reload /{}/{}", PROFILES_DIR, name)));
+    lines.push(match profile.init_system.as_str() {
+        "openrc" => "rc-update add apparmor boot".to_string(),
+        _ => "systemctl enable apparmor".to_string(),
+    });
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "AppArmor configuration")
+}
@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::Profile;
+
+// One [[appimages]] entry of the profile
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AppImage {
+    pub url: String,
+    pub sha256: String,
+    pub path: Option<String>,         // Install path in the image, defaults to /opt/appimages/<file>
+    pub name: Option<String>,         // Menu name; generates a .desktop entry when set
+    pub desktop_file: Option<String>, // .desktop file under files/ to install instead
+}
+
+const FLATHUB_REPO: &str = "https://dl.flathub.org/repo/flathub.flatpakrepo";
+
+/// Name and .flatpakrepo URL of the profile's flatpak remote, Flathub by default.
+pub fn flatpak_remote(profile: &Profile) -> Result<(&str, &str)> {
+    let remote_url = profile.flatpak_remote.as_deref().unwrap_or(FLATHUB_REPO);
+    // Remotes are named after their .flatpakrepo file, e.g. flathub
+    let remote_name = remote_url
+        .rsplit('/')
+        .next()
+        .and_then(|file| file.strip_suffix(".flatpakrepo"))
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("flatpak_remote must point to a .flatpakrepo file: {}", remote_url))?;
+    Ok((remote_name, remote_url))
+}
+
+/// Installs flatpak, adds the configured remote (Flathub by default) and preinstalls the
+/// profile's flatpaks system-wide, so they are in the image rather than fetched on first boot.
+pub fn install_flatpaks(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.flatpaks.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Installing flatpaks...".yellow());
+
+    let (remote_name, remote_url) = flatpak_remote(profile)?;
+    let install_flatpak = crate::package_manager(profile)?.install(&["flatpak".to_string()]);
+    // flatpak needs /proc for its bubblewrap sandbox even when only deploying
+    let flatpak_cmd = format!(
+        "{} && mount -t proc proc /proc && flatpak remote-add --system --if-not-exists {} {} && flatpak install --system -y --noninteractive {} {}; status=$?; umount /proc; exit $status",
+        install_flatpak,
+        remote_name,
+        remote_url,
+        remote_name,
+        profile.flatpaks.join(" ")
+    );
+    crate::run_in_chroot(profile, rootfs, &flatpak_cmd, "Flatpak installation")
+}
+
+/// Seeds the profile's snaps into /var/lib/snapd/seed so snapd installs them on first boot.
+/// `snap install` needs a running snapd, which a chroot doesn't have, so the snaps, their
+/// bases and the generic-classic model assertions are downloaded in the builder container
+/// and described in a generated seed.yaml instead.
+pub fn seed_snaps(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.snaps.is_empty() {
+        return Ok(());
+    }
+    if profile.base != "ubuntu" {
+        return Err(anyhow::anyhow!("snaps can only be seeded on the ubuntu base"));
+    }
+    if profile.packages_to_remove.iter().any(|p| p == "snapd") {
+        return Err(anyhow::anyhow!("snaps are listed but snapd is in packages_to_remove"));
+    }
+    println!("{}", "Seeding snaps...".yellow());
+
+    crate::run_in_chroot(profile, rootfs, "apt-get install -y snapd", "snapd installation")?;
+
+    // "name" or "name=channel", like pinned packages
+    let snaps: Vec<(&str, &str)> = profile
+        .snaps
+        .iter()
+        .map(|snap| snap.split_once('=').unwrap_or((snap, "stable")))
+        .collect();
+    let downloads: Vec<String> = snaps
+        .iter()
+        .map(|(name, channel)| format!("snap download --channel={} {}", channel, name))
+        .collect();
+    let channels: Vec<String> = snaps
+        .iter()
+        .map(|(name, channel)| format!("    {}) channel={} ;;", name, channel))
+        .collect();
+
+    let seed_script = format!(
+        r#"set -e
+apt-get update && apt-get install -y snapd squashfs-tools
+SEED=/rootfs/var/lib/snapd/seed
+rm -rf $SEED && mkdir -p $SEED/snaps $SEED/assertions && cd $SEED/snaps
+snap download snapd
+{downloads}
+# Pull in the base of every snap until nothing is missing
+while true; do
+  missing=""
+  for f in *.snap; do
+    type=$(unsquashfs -cat "$f" meta/snap.yaml | sed -n 's/^type: *//p')
+    case "$type" in base|snapd|os) continue ;; esac
+    base=$(unsquashfs -cat "$f" meta/snap.yaml | sed -n 's/^base: *//p')
+    base=${{base:-core}}
+    ls "${{base}}"_*.snap >/dev/null 2>&1 || missing="$missing $base"
+  done
+  [ -z "$missing" ] && break
+  for base in $(echo $missing | tr ' ' '\n' | sort -u); do snap download "$base"; done
+done
+mv *.assert ../assertions/
+cd ../assertions
+snap known --remote model series=16 model=generic-classic brand-id=generic > model
+snap known --remote account-key public-key-sha3-384=$(sed -n 's/^sign-key-sha3-384: //p' model) > account-key
+snap known --remote account account-id=generic > account
+cd ../snaps
+echo "snaps:" > ../seed.yaml
+for f in *.snap; do
+  case "${{f%%_*}}" in
+{channels}
+    *) channel=stable ;;
+  esac
+  echo "  - name: ${{f%%_*}}" >> ../seed.yaml
+  echo "    channel: $channel" >> ../seed.yaml
+  echo "    file: $f" >> ../seed.yaml
+  if unsquashfs -cat "$f" meta/snap.yaml | grep -q '^confinement: *classic'; then
+    echo "    classic: true" >> ../seed.yaml
+  fi
+done
+"#,
+        downloads = downloads.join("\n"),
+        channels = channels.join("\n")
+    );
+    crate::run_in_container(&crate::base_image(profile)?, rootfs, &seed_script, "Snap seeding")
+}
+
+/// Downloads the profile's AppImages, verifies their checksums and installs them into the
+/// rootfs, together with a .desktop entry for the application menu.
+pub fn install_appimages(profile: &Profile, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    if profile.appimages.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Installing AppImages...".yellow());
+
+    let download_dir = Path::new("/tmp/.ulb/appimages");
+    fs::create_dir_all(download_dir).context("Failed to create AppImage download directory")?;
+
+    for appimage in &profile.appimages {
+        let file_name = appimage
+            .url
+            .rsplit('/')
+            .next()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("AppImage URL has no file name: {}", appimage.url))?;
+        let download = download_dir.join(file_name);
+
+        let output = Command::new("curl")
+            .args(["-fsSL", "-o"])
+            .arg(&download)
+            .arg(&appimage.url)
+            .output()
+            .context("Failed to run curl")?;
+        if !output.status.success() {
+            error!("AppImage download failed: {}", String::from_utf8_lossy(&output.stderr));
+            return Err(anyhow::anyhow!("Failed to download {}", appimage.url));
+        }
+
+        let sha256 = crate::sha256sum(&download)?;
+        if !sha256.eq_ignore_ascii_case(&appimage.sha256) {
+            return Err(anyhow::anyhow!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                file_name,
+                appimage.sha256,
+                sha256
+            ));
+        }
+
+        let install_path = appimage.path.clone().unwrap_or_else(|| format!("/opt/appimages/{}", file_name));
+        let target = rootfs.join(install_path.trim_start_matches('/'));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+        fs::copy(&download, &target).context(format!("Failed to install {}", target.display()))?;
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o755))
+            .context(format!("Failed to make {} executable", target.display()))?;
+
+        let stem = file_name.trim_end_matches(".AppImage");
+        let desktop_target = rootfs.join(format!("usr/share/applications/{}.desktop", stem));
+        let desktop = if let Some(desktop_file) = &appimage.desktop_file {
+            Some(
+                fs::read_to_string(files_dir.join(desktop_file.trim_start_matches('/')))
+                    .context(format!("Failed to read desktop file {}", desktop_file))?,
+            )
+        } else {
+            appimage.name.as_ref().map(|name| {
+                format!(
+                    "[Desktop Entry]\nType=Application\nName={}\nExec={} %U\nTerminal=false\nCategories=Utility;\n",
+                    name, install_path
+                )
+            })
+        };
+        if let Some(desktop) = desktop {
+            fs::create_dir_all(desktop_target.parent().unwrap_or(rootfs))
+                .context("Failed to create applications directory")?;
+            fs::write(&desktop_target, desktop).context(format!("Failed to write {}", desktop_target.display()))?;
+        }
+    }
+    Ok(())
+}
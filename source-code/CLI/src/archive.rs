@@ -0,0 +1,151 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{PackageManager, Profile};
+
+// Optional [tar] section for the tar format
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TarConfig {
+    pub compression: Option<String>, // "gzip", "xz" (default), "zstd" or "none"
+}
+
+// Optional [wsl] section for the wsl format
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct WslConfig {
+    pub default_user: Option<String>, // User wsl.exe logs in as; must exist in the image
+    pub icon: Option<String>,         // .ico path inside the image for the Start menu shortcut
+    #[serde(default)]
+    pub bundle: bool, // Also emit a .wsl file that installs with a double click
+}
+
+/// Packs the rootfs into a tarball. Entries are sorted, owned by root and clamped to
+/// SOURCE_DATE_EPOCH (0 when unset), so the same rootfs always gives the same archive.
+pub fn build_tarball(profile: &Profile, rootfs: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Packing rootfs tarball...".yellow());
+
+    let config = profile.tar.clone().unwrap_or_default();
+    let stem = format!("{}-{}", profile.distro_name, profile.version);
+    pack(profile, rootfs, build_dir, &stem, config.compression.as_deref().unwrap_or("xz"))
+}
+
+/// Builds a WSL distribution: the rootfs with wsl.conf and wsl-distribution.conf added, as a
+/// gzipped tarball for `wsl --import` and optionally as a .wsl bundle.
+pub fn build_wsl(profile: &Profile, rootfs: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Packing WSL distribution...".yellow());
+
+    let config = profile.wsl.clone().unwrap_or_default();
+    let mut wsl_conf = String::new();
+    if profile.init_system == "systemd" {
+        wsl_conf.push_str("[boot]\nsystemd=true\n");
+    }
+    if let Some(user) = &config.default_user {
+        wsl_conf.push_str(&format!("[user]\ndefault={}\n", user));
+    }
+    let mut distribution_conf = format!("[oobe]\ndefaultName = {}\n", profile.distro_name);
+    if let Some(icon) = &config.icon {
+        distribution_conf.push_str(&format!("\n[shortcut]\nicon = {}\n", icon));
+    }
+    // Files from files/ were copied earlier and take precedence
+    for (path, content) in [("etc/wsl.conf", wsl_conf), ("etc/wsl-distribution.conf", distribution_conf)] {
+        let target = rootfs.join(path);
+        if !content.is_empty() && !target.exists() {
+            fs::write(&target, content).context(format!("Failed to write {}", target.display()))?;
+        }
+    }
+
+    // WSL imports gzip and the .wsl format is a renamed .tar.gz
+    let tarball = pack(profile, rootfs, build_dir, &format!("{}-{}-wsl", profile.distro_name, profile.version), "gzip")?;
+    if config.bundle {
+        let bundle = build_dir.join(format!("{}-{}.wsl", profile.distro_name, profile.version));
+        fs::copy(&tarball, &bundle).context("Failed to write .wsl bundle")?;
+        info!("WSL bundle built at {}", bundle.display());
+    }
+    Ok(tarball)
+}
+
+/// Builds an image `lxc image import` and `incus image import` take: a metadata tarball with
+/// metadata.yaml and a hostname template, next to the rootfs tarball.
+pub fn build_lxc(profile: &Profile, rootfs: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Packing LXC image...".yellow());
+
+    let staging = PathBuf::from("/tmp/.ulb/lxc");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).context("Failed to clear LXC staging directory")?;
+    }
+    fs::create_dir_all(staging.join("templates")).context("Failed to create LXC staging directory")?;
+
+    let creation_date = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch,
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System clock is before the Unix epoch")?
+            .as_secs()
+            .to_string(),
+    };
+    let metadata = format!(
+        "architecture: {arch}\ncreation_date: {date}\nproperties:\n  description: {name} {version}\n  os: {name}\n  release: \"{version}\"\n\
+         templates:\n  /etc/hostname:\n    when:\n      - create\n      - copy\n    template: hostname.tpl\n",
+        arch = crate::target_arch(profile)?,
+        date = creation_date,
+        name = profile.distro_name,
+        version = profile.version
+    );
+    fs::write(staging.join("metadata.yaml"), metadata).context("Failed to write metadata.yaml")?;
+    fs::write(staging.join("templates/hostname.tpl"), "{{ container.name }}\n")
+        .context("Failed to write hostname template")?;
+
+    let stem = format!("{}-{}", profile.distro_name, profile.version);
+    pack(profile, &staging, build_dir, &format!("{}-lxc-meta", stem), "xz")?;
+    pack(profile, rootfs, build_dir, &format!("{}-lxc-rootfs", stem), "xz")
+}
+
+// Packs `source` into <stem>.tar[.ext] in the build directory
+fn pack(profile: &Profile, source: &Path, build_dir: &Path, stem: &str, compression: &str) -> Result<PathBuf> {
+    let (extension, compressor, tool) = match compression {
+        "gzip" => (".gz", "gzip -n -9", None),
+        "xz" => (".xz", "xz -9", None),
+        "zstd" => (".zst", "zstd -19 -T0", Some("zstd")),
+        "none" => ("", "cat", None),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Unsupported tar compression: {}. Supported: gzip, xz, zstd, none",
+                compression
+            ))
+        }
+    };
+    let tarball_name = format!("{}.tar{}", stem, extension);
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| "0".to_string());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut tools = vec![tar_package(package_manager)];
+    tools.extend(tool);
+    let tar_cmd = format!(
+        "set -o pipefail && {} && \
+         tar --create --sort=name --mtime=@{} --clamp-mtime --owner=0 --group=0 --numeric-owner \
+         --xattrs --acls --pax-option=exthdr.name=%d/PaxHeaders/%f,delete=atime,delete=ctime \
+         -C /rootfs . | {} > /out/{}",
+        package_manager.refresh_and_install(&tools),
+        epoch,
+        compressor,
+        tarball_name
+    );
+    let volumes = vec![crate::rootfs_volume(source), format!("{}:/out:z", build_dir.display())];
+    crate::run_in_builder(profile, volumes, &tar_cmd, "Tarball packing")?;
+
+    let tarball_path = build_dir.join(&tarball_name);
+    info!("Tarball built at {}", tarball_path.display());
+    Ok(tarball_path)
+}
+
+// GNU tar, as the builder images may ship a different or no tar
+fn tar_package(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Portage => "app-arch/tar",
+        _ => "tar",
+    }
+}
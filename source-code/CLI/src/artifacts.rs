@@ -0,0 +1,512 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{apparmor, archive, atomic, board, boot, branding, cloud, desktop, disk, firewall, firmware, flash, ignition, initramfs, installer, kernel, kiosk, live, locale, minimize, netboot, network, nixos, oem, overlayroot, repos, secureboot, selinux, swap, sysext, timers, udev, unattended, zfs, Profile, SUPPORTED_FORMATS};
+
+// Live root filesystem shared by every format that boots from a squashfs
+const SQUASHFS: &str = "/tmp/.ulb/filesystem.squashfs";
+// Live UKI, built inside the rootfs after the squashfs and removed once it's on the ISO
+const UKI_PATH: &str = "/boot/ulb-live.efi";
+
+// Optional [iso] section for the iso format
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct IsoConfig {
+    #[serde(default)]
+    pub arches: Vec<String>, // Two or more architectures for one multi-architecture ISO
+    #[serde(default)]
+    pub checksums: Vec<String>, // "md5" implanted with implantisomd5, "sha256" as a sha256sum.txt manifest
+    #[serde(default)]
+    pub media_check: bool, // Boot entry verifying the media before starting the live session
+}
+
+// Optional [squashfs] section for the live squashfs of the iso and pxe formats
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SquashfsConfig {
+    pub compressor: Option<String>, // "xz" (default), "zstd", "lz4", "gzip" or "lzo"
+    pub level: Option<u32>, // Compression level for zstd (1-22), gzip and lzo (1-9)
+    pub block_size: Option<String>, // mksquashfs -b, e.g. "1M" (default 128K)
+    #[serde(default)]
+    pub no_duplicates: bool, // Skip duplicate file detection
+    #[serde(default)]
+    pub no_recovery: bool, // Don't write a recovery file
+}
+
+// mksquashfs options from the [squashfs] section
+fn mksquashfs_options(profile: &Profile) -> Result<String> {
+    let config = profile.squashfs.clone().unwrap_or_default();
+    let compressor = config.compressor.as_deref().unwrap_or("xz");
+    if !matches!(compressor, "xz" | "zstd" | "lz4" | "gzip" | "lzo") {
+        return Err(anyhow::anyhow!("Unsupported squashfs compressor: {}. Supported: xz, zstd, lz4, gzip, lzo", compressor));
+    }
+    let mut options = vec![format!("-comp {}", compressor)];
+    match (compressor, config.level) {
+        (_, None) => {}
+        ("zstd", Some(level @ 1..=22)) | ("gzip" | "lzo", Some(level @ 1..=9)) => {
+            options.push(format!("-Xcompression-level {}", level));
+        }
+        ("xz" | "lz4", Some(_)) => return Err(anyhow::anyhow!("squashfs level isn't supported with {}", compressor)),
+        (_, Some(level)) => return Err(anyhow::anyhow!("squashfs level {} is out of range for {}", level, compressor)),
+    }
+    if let Some(block_size) = &config.block_size {
+        // mksquashfs takes bytes or a K/M suffix, in powers of two from 4K to 1M
+        let (number, unit) = match block_size.strip_suffix(['K', 'k']) {
+            Some(number) => (number, 1024),
+            None => block_size.strip_suffix(['M', 'm']).map_or((block_size.as_str(), 1), |number| (number, 1024 * 1024)),
+        };
+        let bytes = number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit));
+        if !bytes.is_some_and(|bytes| bytes.is_power_of_two() && (4096..=1024 * 1024).contains(&bytes)) {
+            return Err(anyhow::anyhow!("Invalid squashfs block_size: {:?}, expected a power of two from 4K to 1M, e.g. \"1M\"", block_size));
+        }
+        options.push(format!("-b {}", block_size));
+    }
+    if config.no_duplicates {
+        options.push("-no-duplicates".to_string());
+    }
+    if config.no_recovery {
+        options.push("-no-recovery".to_string());
+    }
+    Ok(options.join(" "))
+}
+
+/// Filesystem of the live root image shared by the iso and pxe formats: "squashfs" (the
+/// default) or "erofs", which needs a dracut-based live initramfs as live-boot can't mount it.
+pub fn live_fs(profile: &Profile) -> Result<&'static str> {
+    match profile.live_fs.as_deref() {
+        None | Some("squashfs") => Ok("squashfs"),
+        Some("erofs") if crate::package_manager(profile)? == crate::PackageManager::Apt => {
+            Err(anyhow::anyhow!("live_fs = \"erofs\" needs a dracut-based base; live-boot only mounts squashfs"))
+        }
+        Some("erofs") => Ok("erofs"),
+        Some(live_fs) => Err(anyhow::anyhow!("Unsupported live_fs: {}. Supported: squashfs, erofs", live_fs)),
+    }
+}
+
+/// The [iso] media checksums: "md5" implants one into the ISO for checkisomd5 and dracut's
+/// rd.live.check, "sha256" adds a sha256sum.txt that live-boot and GRUB's sha256sum can check.
+/// media_check adds the one its initramfs verifies.
+pub fn iso_checksums(profile: &Profile) -> Result<Vec<String>> {
+    let mut checksums = profile.iso.clone().unwrap_or_default().checksums;
+    if let Some(checksum) = boot::media_check_checksum(profile)? {
+        if !checksums.iter().any(|c| c == checksum) {
+            checksums.push(checksum.to_string());
+        }
+    }
+    for checksum in &checksums {
+        match checksum.as_str() {
+            "md5" => match crate::package_manager(profile)? {
+                crate::PackageManager::Apt | crate::PackageManager::Dnf => {}
+                _ => return Err(anyhow::anyhow!("[iso] checksums = [\"md5\"] needs isomd5sum, which {} doesn't package", profile.base)),
+            },
+            "sha256" => {}
+            _ => return Err(anyhow::anyhow!("Unsupported ISO checksum: {}. Supported: md5, sha256", checksum)),
+        }
+    }
+    Ok(checksums)
+}
+
+// Shell lines writing /tmp/sha256sum.txt for the (file, path on the ISO) pairs, in the
+// "hash  ./path" form of live-build's manifests
+fn sha256_manifest_command(files: &[(&str, &str)]) -> String {
+    files
+        .iter()
+        .map(|(file, path)| format!("echo \"$(sha256sum < {} | cut -d' ' -f1)  ./{}\" >> /tmp/sha256sum.txt", file, path))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Checks the requested formats before anything is built.
+pub fn validate_formats(profile: &Profile) -> Result<()> {
+    if profile.format.is_empty() {
+        return Err(anyhow::anyhow!("No output format given. Supported: {}", SUPPORTED_FORMATS));
+    }
+    if let Some(format) = profile.format.iter().find(|f| !SUPPORTED_FORMATS.split(", ").any(|s| s == f.as_str())) {
+        return Err(anyhow::anyhow!("Unsupported format: {}. Supported: {}", format, SUPPORTED_FORMATS));
+    }
+    nixos::check(profile)?;
+    atomic::check(profile)?;
+    live_fs(profile)?;
+    mksquashfs_options(profile)?;
+    iso_checksums(profile)?;
+    board::uboot(profile)?;
+    board::devicetree(profile)?;
+    secureboot::keys(profile)?;
+    boot::memtest(profile)?;
+    flash::persistence_label(profile)?;
+    boot::check_toram(profile)?;
+    boot::boot_menu(profile)?;
+    boot::boot_entries(profile)?;
+    boot::languages(profile)?;
+    disk::esp(profile)?;
+    boot::kernel_cmdline(profile)?;
+    kernel::package(profile)?;
+    kernel::custom(profile)?;
+    initramfs::config(profile)?;
+    firmware::config(profile)?;
+    zfs::check(profile)?;
+    live::config(profile)?;
+    branding::config(profile)?;
+    desktop::check(profile)?;
+    installer::check(profile)?;
+    cloud::cloud_init(profile)?;
+    ignition::butane(profile)?;
+    oem::check(profile)?;
+    kiosk::config(profile)?;
+    unattended::config(profile)?;
+    locale::check(profile)?;
+    network::config(profile)?;
+    firewall::config(profile)?;
+    crate::check_services(profile)?;
+    crate::check_sysctl(profile)?;
+    crate::check_debconf(profile)?;
+    crate::check_alternatives(profile)?;
+    swap::config(profile)?;
+    selinux::mode(profile)?;
+    apparmor::config(profile)?;
+    minimize::check(profile)?;
+    overlayroot::config(profile)?;
+    udev::check(profile)?;
+    timers::check(profile)?;
+    boot::plymouth_theme(profile)?;
+    if crate::target_arch(profile)? != "aarch64" && profile.format.iter().any(|f| f == "rpi") {
+        return Err(anyhow::anyhow!("format = \"rpi\" needs arch = \"aarch64\""));
+    }
+    let extensions = profile.format.iter().filter(|f| sysext::ExtensionKind::from_format(f).is_some()).count();
+    if extensions > 0 && profile.format.len() > 1 {
+        return Err(anyhow::anyhow!("sysext and confext can't be combined with other formats"));
+    }
+    Ok(())
+}
+
+// Formats that add to the rootfs (agents, users, config) and so are built from their own copy
+// of it, keeping those additions out of the images that don't ask for them
+fn modifies_rootfs(format: &str) -> bool {
+    cloud::is_cloud_format(format) || matches!(format, "vagrant" | "wsl" | "pxe")
+}
+
+/// Builds every requested format from the one prepared rootfs. The squashfs and the plain raw
+/// disk image are made once and shared by all the formats built from them; formats adding to
+/// the rootfs work on a copy of it, removed once they are packed.
+pub fn build_artifacts(profile: &Profile, rootfs: &Path, files_dir: &Path, build_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut seen = HashSet::new();
+    let formats: Vec<String> = profile.format.iter().filter(|f| seen.insert(f.as_str())).cloned().collect();
+
+    let mut squashfs: Option<PathBuf> = None;
+    let mut raw: Option<PathBuf> = None;
+    let mut artifacts = Vec::new();
+    for format in &formats {
+        let copy = if modifies_rootfs(format) { Some(copy_rootfs(profile, rootfs, format)?) } else { None };
+        let rootfs = copy.as_deref().unwrap_or(rootfs);
+        let artifact = match format.as_str() {
+            "iso" => {
+                let squashfs = shared_squashfs(profile, rootfs, &mut squashfs)?;
+                build_iso(profile, rootfs, &squashfs, build_dir)?
+            }
+            "pxe" => {
+                let squashfs = shared_squashfs(profile, rootfs, &mut squashfs)?;
+                netboot::build_pxe(profile, rootfs, &squashfs, build_dir)?
+            }
+            format if disk::is_vm_format(format) => {
+                let raw = match &raw {
+                    Some(raw) => raw.clone(),
+                    None => raw.insert(build_vm_raw(profile, rootfs, files_dir, build_dir)?).clone(),
+                };
+                if format == "raw" {
+                    raw
+                } else {
+                    disk::convert_image(profile, &raw, format, build_dir)?
+                }
+            }
+            format if cloud::is_cloud_format(format) || format == "vagrant" => {
+                disk::build_provider_image(profile, format, rootfs, files_dir, build_dir)?
+            }
+            "tar" => archive::build_tarball(profile, rootfs, build_dir)?,
+            "wsl" => archive::build_wsl(profile, rootfs, build_dir)?,
+            "lxc" => archive::build_lxc(profile, rootfs, build_dir)?,
+            "rpi" => board::build_rpi_image(profile, rootfs, build_dir)?,
+            _ => return Err(anyhow::anyhow!("Unsupported format: {}. Supported: {}", format, SUPPORTED_FORMATS)),
+        };
+        if let Some(copy) = copy {
+            fs::remove_dir_all(&copy).context(format!("Failed to remove {}", copy.display()))?;
+        }
+        artifacts.push(artifact);
+    }
+
+    // A seed ISO goes next to the disk images it configures
+    artifacts.extend(cloud::build_seed_iso(profile, build_dir)?);
+
+    // The raw image was only an intermediate for the VM formats
+    if let Some(raw) = raw {
+        if !formats.iter().any(|f| f == "raw") {
+            fs::remove_file(&raw).context(format!("Failed to remove {}", raw.display()))?;
+        }
+    }
+    Ok(artifacts)
+}
+
+// Copies the rootfs, ownership and attributes included, for `format` to add to
+fn copy_rootfs(profile: &Profile, rootfs: &Path, format: &str) -> Result<PathBuf> {
+    let copy = rootfs.with_file_name(format!("rootfs-{}", format));
+    if copy.exists() {
+        fs::remove_dir_all(&copy).context(format!("Failed to remove {}", copy.display()))?;
+    }
+    fs::create_dir_all(&copy).context(format!("Failed to create {}", copy.display()))?;
+    let volumes = vec![crate::rootfs_volume(rootfs), format!("{}:/copy:z", copy.display())];
+    crate::run_in_builder(profile, volumes, "cp -a /rootfs/. /copy/", &format!("Rootfs copy for {}", format))?;
+    Ok(copy)
+}
+
+// The raw image the VM formats are converted from. [cloud_init] adds cloud-init and its seed,
+// to a copy of the rootfs so the other formats don't get them, with the [[repositories]]
+// credentials back only while cloud-init installs.
+fn build_vm_raw(profile: &Profile, rootfs: &Path, files_dir: &Path, build_dir: &Path) -> Result<PathBuf> {
+    if profile.cloud_init.is_none() {
+        return disk::build_raw_image(profile, "raw", rootfs, build_dir);
+    }
+    let copy = copy_rootfs(profile, rootfs, "raw")?;
+    repos::with_repositories(profile, files_dir, &copy, || cloud::configure_cloud_init(profile, &copy))?;
+    let raw = disk::build_raw_image(profile, "raw", &copy, build_dir)?;
+    fs::remove_dir_all(&copy).context(format!("Failed to remove {}", copy.display()))?;
+    Ok(raw)
+}
+
+fn shared_squashfs(profile: &Profile, rootfs: &Path, squashfs: &mut Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(squashfs) = squashfs {
+        return Ok(squashfs.clone());
+    }
+    let out_dir = Path::new(SQUASHFS).parent().unwrap_or(Path::new("/tmp"));
+    Ok(squashfs.insert(build_live_image(profile, rootfs, out_dir)?).clone())
+}
+
+/// Packs the rootfs into the live image (squashfs or EROFS, per live_fs) as
+/// `out_dir`/filesystem.squashfs.
+pub fn build_live_image(profile: &Profile, rootfs: &Path, out_dir: &Path) -> Result<PathBuf> {
+    let live_fs = live_fs(profile)?;
+    println!("{}", format!("Building {}...", live_fs).yellow());
+
+    // The file keeps its squashfs name either way; dracut probes the filesystem type itself
+    let package_manager = crate::package_manager(profile)?;
+    let (tool, pack) = match live_fs {
+        "erofs" => ("erofs-utils", "mkfs.erofs -zlz4hc /out/filesystem.squashfs /rootfs".to_string()),
+        _ => (
+            "squashfs-tools",
+            format!("mksquashfs /rootfs /out/filesystem.squashfs {} -noappend", mksquashfs_options(profile)?),
+        ),
+    };
+    let tool = match package_manager {
+        crate::PackageManager::Portage => format!("sys-fs/{}", tool),
+        _ => tool.to_string(),
+    };
+    let squashfs_cmd = format!("{} && {}", package_manager.refresh_and_install(&[tool.as_str()]), pack);
+    fs::create_dir_all(out_dir).context(format!("Failed to create {}", out_dir.display()))?;
+    let volumes = vec![crate::rootfs_volume(rootfs), format!("{}:/out:z", out_dir.display())];
+    crate::run_in_builder(profile, volumes, &squashfs_cmd, "Squashfs build")?;
+
+    Ok(out_dir.join("filesystem.squashfs"))
+}
+
+fn build_iso(profile: &Profile, rootfs: &Path, squashfs: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Building ISO...".yellow());
+
+    let iso_name = format!("{}-{}.iso", profile.distro_name, profile.version);
+    let volume_id = profile.distro_name.to_uppercase();
+    // isolinux only exists for x86, other architectures boot the ISO through EFI alone. The
+    // isohybrid MBR and GPT make the same image bootable when written to a USB stick with dd.
+    // Only the live image and what boots it are grafted, the rootfs itself stays off the ISO.
+    let arch = crate::target_arch(profile)?;
+    let package_manager = crate::package_manager(profile)?;
+    let mut tools = vec!["xorriso"];
+    let checksums = iso_checksums(profile)?;
+    if checksums.iter().any(|c| c == "md5") {
+        tools.push("isomd5sum");
+    }
+    let sha256 = checksums.iter().any(|c| c == "sha256");
+    let manifest_graft = if sha256 { " /sha256sum.txt=/tmp/sha256sum.txt" } else { "" };
+    let keys = secureboot::keys(profile)?;
+    let mut sign = String::new();
+    if let Some(keys) = &keys {
+        tools.extend(secureboot::tools(profile, keys)?);
+        sign = secureboot::sign_esp_command(profile, keys, "/tmp/esp")?;
+    }
+    let (bios_boot, isolinux, isolinux_graft) = if profile.bios_support && arch == "x86_64" {
+        tools.extend(boot::syslinux_packages(package_manager));
+        (
+            "-isohybrid-mbr /tmp/isohdpfx.bin -b isolinux/isolinux.bin -c isolinux/boot.cat -no-emul-boot -boot-load-size 4 -boot-info-table -eltorito-alt-boot ",
+            boot::isolinux_command(profile, &volume_id)?,
+            " /isolinux=/tmp/isolinux",
+        )
+    } else {
+        ("", String::new(), "")
+    };
+
+    // The ESP image and the live image, which is what live-boot's verify-checksums reads
+    let esp_manifest = if sha256 {
+        sha256_manifest_command(&[("/tmp/efiboot.img", "EFI/efiboot.img"), ("/filesystem.squashfs", boot::live_squashfs_path(profile)?)])
+    } else {
+        String::new()
+    };
+
+    let build_cmd = if profile.uki {
+        // The UKI carries the live command line, so the ESP image only needs the one binary
+        // (plus shim with Secure Boot)
+        let uki_cmd = boot::uki_command(UKI_PATH, &boot::live_cmdline(profile, &volume_id, "")?);
+        crate::run_in_chroot(profile, rootfs, &format!("set -e\n{}", uki_cmd), "UKI build")?;
+
+        tools.extend(["dosfstools", "mtools"]);
+        format!(
+            r#"set -e
+{tools}
+{isolinux}
+mkdir -p /tmp/esp/EFI/BOOT && mv /rootfs{uki} /tmp/esp/EFI/BOOT/{efi_binary}
+{sign}
+{esp_image}
+{manifest}
+xorriso -as mkisofs -o /out/{iso} {bios_boot}-e EFI/efiboot.img -no-emul-boot -isohybrid-gpt-basdat -V '{volid}' -graft-points /{squashfs}=/filesystem.squashfs /EFI/efiboot.img=/tmp/efiboot.img{isolinux_graft}{manifest_graft}
+"#,
+            esp_image = disk::esp_image_command(profile, "/tmp/esp", "/tmp/efiboot.img")?,
+            manifest = esp_manifest,
+            manifest_graft = manifest_graft,
+            tools = package_manager.refresh_and_install(&tools),
+            isolinux = isolinux,
+            isolinux_graft = isolinux_graft,
+            sign = sign,
+            uki = UKI_PATH,
+            efi_binary = boot::efi_fallback_binary(arch),
+            bios_boot = bios_boot,
+            iso = iso_name,
+            volid = volume_id,
+            squashfs = boot::live_squashfs_path(profile)?,
+        )
+    } else if profile.bootloader == "refind" {
+        if !profile.uefi_support {
+            return Err(anyhow::anyhow!("bootloader = \"refind\" needs uefi_support = true"));
+        }
+        // rEFInd can't read ISO 9660, so the live kernel and initramfs sit next to it on the EFI image
+        tools.extend(["dosfstools", "mtools", boot::refind_package(package_manager)?]);
+        let menu: Vec<(String, String)> = boot::live_entries(profile, &volume_id, "")?
+            .into_iter()
+            .map(|(title, cmdline)| (format!("{} {}{}", profile.distro_name, profile.version, title), cmdline))
+            .collect();
+        format!(
+            r#"set -e
+{tools}
+{isolinux}
+KVER=$(ls /rootfs/lib/modules | sort -V | tail -n1)
+PKGBASE=$(cat /rootfs/lib/modules/$KVER/pkgbase 2>/dev/null)
+for KERNEL in /rootfs/boot/vmlinuz-$KVER /rootfs/lib/modules/$KVER/vmlinuz /rootfs/boot/vmlinuz-$PKGBASE; do [ -f $KERNEL ] && break; done
+for INITRD in /rootfs/boot/initrd.img-$KVER /rootfs/boot/initramfs-$KVER.img /rootfs/boot/initramfs-$PKGBASE.img /rootfs/boot/initramfs.img; do [ -f $INITRD ] && break; done
+mkdir -p /tmp/esp && cp $KERNEL /tmp/esp/vmlinuz && cp $INITRD /tmp/esp/initrd.img
+{refind}
+{sign}
+{esp_image}
+{manifest}
+xorriso -as mkisofs -o /out/{iso} {bios_boot}-e EFI/efiboot.img -no-emul-boot -isohybrid-gpt-basdat -V '{volid}' -graft-points /{squashfs}=/filesystem.squashfs /EFI/efiboot.img=/tmp/efiboot.img{isolinux_graft}{manifest_graft}
+"#,
+            esp_image = disk::esp_image_command(profile, "/tmp/esp", "/tmp/efiboot.img")?,
+            manifest = esp_manifest,
+            manifest_graft = manifest_graft,
+            tools = package_manager.refresh_and_install(&tools),
+            isolinux = isolinux,
+            refind = boot::refind_command(profile, "/tmp/esp", &menu)?,
+            sign = sign,
+            bios_boot = bios_boot,
+            iso = iso_name,
+            volid = volume_id,
+            squashfs = boot::live_squashfs_path(profile)?,
+            isolinux_graft = isolinux_graft,
+        )
+    } else if keys.is_some() {
+        return Err(anyhow::anyhow!("[secure_boot] ISOs need uki = true or bootloader = \"refind\""));
+    } else {
+        // GRUB boots EFI from its own menu on the ISO, found by the volume label
+        let squashfs = boot::live_squashfs_path(profile)?;
+        let (efi, efi_boot, efi_graft) = if profile.uefi_support {
+            tools.extend(["dosfstools", "mtools"]);
+            tools.extend(boot::grub_efi_packages(package_manager, arch));
+            (
+                format!(
+                    r#"{memtest}{theme}
+cat > /tmp/grub/grub.cfg <<'EOF'
+{config}
+EOF
+cat > /tmp/embed.cfg <<'EOF'
+search --no-floppy --set=root --label {volid}
+set prefix=($root)/boot/grub
+configfile /boot/grub/grub.cfg
+EOF
+mkdir -p /tmp/esp/EFI/BOOT
+MKSTANDALONE=grub-mkstandalone; command -v grub2-mkstandalone >/dev/null && MKSTANDALONE=grub2-mkstandalone
+$MKSTANDALONE -O {target} -o /tmp/esp/EFI/BOOT/{efi_binary} "boot/grub/grub.cfg=/tmp/embed.cfg"
+{esp_image}"#,
+                    memtest = if boot::memtest(profile)? { format!("\n{}\ncp $MEMTEST_EFI /tmp/live/memtest.efi", boot::memtest_locate()) } else { String::new() },
+                    theme = boot::grub_theme_copy(profile, "/tmp/grub")?,
+                    config = boot::grub_live_config(profile, &volume_id)?,
+                    volid = volume_id,
+                    target = crate::grub_efi_target(arch),
+                    efi_binary = boot::efi_fallback_binary(arch),
+                    esp_image = disk::esp_image_command(profile, "/tmp/esp", "/tmp/efiboot.img")?,
+                ),
+                "-e EFI/efiboot.img -no-emul-boot -isohybrid-gpt-basdat ",
+                " /EFI/efiboot.img=/tmp/efiboot.img /boot/live=/tmp/live /boot/grub=/tmp/grub",
+            )
+        } else {
+            (String::new(), "", "")
+        };
+        let manifest = if sha256 {
+            let mut files = vec![("/filesystem.squashfs", squashfs)];
+            if profile.uefi_support {
+                files.push(("/tmp/efiboot.img", "EFI/efiboot.img"));
+            }
+            sha256_manifest_command(&files)
+        } else {
+            String::new()
+        };
+        format!(
+            r#"set -e
+{tools}
+{isolinux}
+KVER=$(ls /rootfs/lib/modules | sort -V | tail -n1)
+PKGBASE=$(cat /rootfs/lib/modules/$KVER/pkgbase 2>/dev/null)
+for KERNEL in /rootfs/boot/vmlinuz-$KVER /rootfs/lib/modules/$KVER/vmlinuz /rootfs/boot/vmlinuz-$PKGBASE; do [ -f $KERNEL ] && break; done
+for INITRD in /rootfs/boot/initrd.img-$KVER /rootfs/boot/initramfs-$KVER.img /rootfs/boot/initramfs-$PKGBASE.img /rootfs/boot/initramfs.img; do [ -f $INITRD ] && break; done
+mkdir -p /tmp/live /tmp/grub && cp $KERNEL /tmp/live/vmlinuz && cp $INITRD /tmp/live/initrd.img{efi}
+{manifest}
+xorriso -as mkisofs -o /out/{iso} {bios_boot}{efi_boot}-V '{volid}' -graft-points /{squashfs}=/filesystem.squashfs{efi_graft}{isolinux_graft}{manifest_graft}
+"#,
+            tools = package_manager.refresh_and_install(&tools),
+            isolinux = isolinux,
+            efi = efi,
+            manifest = manifest,
+            iso = iso_name,
+            bios_boot = bios_boot,
+            efi_boot = efi_boot,
+            volid = volume_id,
+            squashfs = squashfs,
+            efi_graft = efi_graft,
+            isolinux_graft = isolinux_graft,
+            manifest_graft = manifest_graft,
+        )
+    };
+    // Implanted last, the md5 covers the whole image as written
+    let build_cmd = if checksums.iter().any(|c| c == "md5") {
+        format!("{}\nimplantisomd5 /out/{}", build_cmd.trim_end(), iso_name)
+    } else {
+        build_cmd
+    };
+
+    let mut volumes = vec![
+        crate::rootfs_volume(rootfs),
+        format!("{}:/filesystem.squashfs:z,ro", squashfs.display()),
+        format!("{}:/out:z", build_dir.display()),
+    ];
+    volumes.extend(keys.iter().flat_map(|keys| keys.volumes()));
+    crate::run_in_builder(profile, volumes, &build_cmd, "ISO build")?;
+
+    let iso_path = build_dir.join(&iso_name);
+    info!("ISO built at {}", iso_path.display());
+    Ok(iso_path)
+}
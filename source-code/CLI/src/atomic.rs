@@ -0,0 +1,355 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::{boot, ignition, netinstall, Profile};
+
+/// Directory under build/iso/ holding the OSTree repository atomic builds commit to. It's an
+/// archive repo, so it can be served over HTTP as the update stream as it is.
+pub const REPO_DIR: &str = "ostree-repo";
+// Kept between builds, so recomposing only downloads what changed
+const CACHE_DIR: &str = "/tmp/.ulb/atomic-cache";
+// Where the installer finds the ISO it booted from, and the repo on it
+const ISO_REPO_URL: &str = "file:///run/install/repo/ostree/repo";
+
+// What every commit needs to boot and update itself, before the profile's packages
+const BASE_PACKAGES: &[&str] = &[
+    "fedora-release",
+    "kernel",
+    "systemd",
+    "dracut",
+    "dracut-config-generic",
+    "rpm-ostree",
+    "ostree-grub2",
+    "nss-altfiles",
+    "selinux-policy-targeted",
+    "NetworkManager",
+    "passwd",
+    "shadow-utils",
+    "sudo",
+    "efibootmgr",
+];
+
+// Optional [ostree] section: the ref atomic builds commit to and where installs update from
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct OstreeConfig {
+    #[serde(rename = "ref")]
+    pub ostree_ref: Option<String>, // Branch of the commits, defaults to <distro>/<version>/<arch>
+    pub remote_url: Option<String>, // Served ostree-repo installed systems pull updates from; without it they keep none
+}
+
+/// Checks that the profile can be composed: atomic builds are fedora only, produce an installer
+/// ISO, nothing else, and take no settings the treefile can't carry.
+pub fn check(profile: &Profile) -> Result<()> {
+    if !profile.atomic {
+        if profile.ostree.is_some() {
+            return Err(anyhow::anyhow!("[ostree] needs atomic = true"));
+        }
+        return Ok(());
+    }
+    if profile.base != "fedora" {
+        return Err(anyhow::anyhow!("atomic = true is only supported on the fedora base"));
+    }
+    if profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("atomic = true needs init_system = \"systemd\""));
+    }
+    if profile.format.iter().any(|format| format != "iso") {
+        return Err(anyhow::anyhow!("atomic = true only produces an installer iso"));
+    }
+    if profile.variant.as_deref().is_some_and(|variant| variant != "live") || profile.uki || profile.bootloader != "grub" {
+        return Err(anyhow::anyhow!("atomic = true builds its own Anaconda ISO, without variant, uki or a bootloader other than grub"));
+    }
+    if !matches!(crate::target_arch(profile)?, "x86_64" | "aarch64") {
+        return Err(anyhow::anyhow!("atomic = true is only supported on x86_64 and aarch64"));
+    }
+    let ostree_ref = ostree_ref(profile)?;
+    if ostree_ref.is_empty() || ostree_ref.starts_with('/') || !ostree_ref.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/')) {
+        return Err(anyhow::anyhow!("Invalid [ostree] ref: {:?}", ostree_ref));
+    }
+    if let Some(url) = profile.ostree.as_ref().and_then(|config| config.remote_url.as_ref()) {
+        if !(url.starts_with("http://") || url.starts_with("https://")) || url.contains(char::is_whitespace) {
+            return Err(anyhow::anyhow!("[ostree] remote_url must be an http(s) URL: {}", url));
+        }
+    }
+    if let Some(repo) = profile.repositories.iter().find(|repo| repo.credentials.is_some()) {
+        return Err(anyhow::anyhow!("Repository {} needs credentials, which atomic composes don't support", repo.name));
+    }
+    // Settings the treefile and kickstart have no equivalent for, which would be left out silently
+    let unsupported = [
+        ("hostname", profile.hostname.is_some()),
+        ("locales", !profile.locales.is_empty()),
+        ("timezone", profile.timezone.is_some()),
+        ("keymap", profile.keymap.is_some() || profile.x11_layout.is_some()),
+        ("[kernel]", profile.kernel.is_some()),
+        ("[initramfs]", profile.initramfs.is_some()),
+        ("[drivers]", profile.drivers.is_some()),
+        ("[firmware]", profile.firmware.is_some()),
+        ("dkms_modules", !profile.dkms_modules.is_empty()),
+        ("zfs", profile.zfs),
+        ("[boot_menu]", profile.boot_menu.is_some()),
+        ("[[boot_entries]]", !profile.boot_entries.is_empty()),
+        ("[[languages]]", !profile.languages.is_empty()),
+        ("plymouth_theme", profile.plymouth_theme.is_some()),
+        ("services_preset_all", profile.services_preset_all),
+        ("[sysctl]", !profile.sysctl.is_empty()),
+        ("selinux", profile.selinux.is_some()),
+        ("[apparmor]", profile.apparmor.is_some()),
+        ("[swap]", profile.swap.is_some()),
+        ("[minimize]", profile.minimize.is_some()),
+        ("[overlay_root]", profile.overlay_root.is_some()),
+        ("[[udev_rules]]", !profile.udev_rules.is_empty()),
+        ("[[timers]] and [[cron]]", !profile.timers.is_empty() || !profile.cron.is_empty()),
+        ("[network]", profile.network.is_some()),
+        ("[firewall]", profile.firewall.is_some()),
+        ("[live]", profile.live.is_some()),
+        ("[accessibility]", profile.accessibility.is_some()),
+        ("[branding]", profile.branding.is_some()),
+        ("[dconf] and [plasma]", !profile.dconf.is_empty() || !profile.plasma.is_empty()),
+        ("toram", profile.toram),
+        ("include_memtest", profile.include_memtest),
+        ("live_fs", profile.live_fs.is_some()),
+        ("[iso]", profile.iso.is_some()),
+        ("[persistence]", profile.persistence.is_some()),
+        ("[squashfs]", profile.squashfs.is_some()),
+        ("[unattended]", profile.unattended.is_some()),
+        ("installer", profile.installer.is_some()),
+        ("oem_setup", profile.oem_setup),
+        ("preset", profile.preset.is_some() || profile.kiosk.is_some()),
+        ("[secure_boot]", profile.secure_boot.is_some()),
+        ("packages_hold", !profile.packages_hold.is_empty()),
+        ("flatpaks", !profile.flatpaks.is_empty()),
+        ("[[appimages]]", !profile.appimages.is_empty()),
+        ("[alternatives]", !profile.alternatives.is_empty()),
+        ("ca_certificates", !profile.ca_certificates.is_empty()),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(anyhow::anyhow!("{} isn't supported with atomic = true; set it up with files/etc or scripts/ instead", name));
+    }
+    Ok(())
+}
+
+/// Name of the OS the commits are deployed as, and of the remote installs update from.
+pub fn osname(profile: &Profile) -> String {
+    profile.distro_name.to_lowercase().chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-').collect()
+}
+
+/// The ref atomic builds commit to: [ostree] ref, or <distro>/<version>/<arch>.
+pub fn ostree_ref(profile: &Profile) -> Result<String> {
+    match profile.ostree.as_ref().and_then(|config| config.ostree_ref.clone()) {
+        Some(ostree_ref) => Ok(ostree_ref),
+        None => Ok(format!("{}/{}/{}", osname(profile), profile.version, crate::target_arch(profile)?)),
+    }
+}
+
+/// Builds an atomic fedora from the profile. Instead of assembling a rootfs, the packages,
+/// [[repositories]], services, files/ and scripts/ are translated into an rpm-ostree treefile,
+/// composed into a commit of build/iso/ostree-repo, and that commit is put on an Anaconda ISO
+/// which deploys it with ostreesetup.
+pub fn build(profile: &Profile, files_dir: &Path, scripts_dir: &Path, build_dir: &Path) -> Result<PathBuf> {
+    check(profile)?;
+    let work_dir = PathBuf::from("/tmp/.ulb/atomic");
+    if work_dir.exists() {
+        fs::remove_dir_all(&work_dir).context("Failed to clear atomic work directory")?;
+    }
+    fs::create_dir_all(&work_dir).context("Failed to create atomic work directory")?;
+    fs::create_dir_all(CACHE_DIR).context("Failed to create the rpm-ostree cache")?;
+    let repo_dir = build_dir.join(REPO_DIR);
+    fs::create_dir_all(&repo_dir).context("Failed to create the OSTree repository directory")?;
+
+    let user_config = ignition::compile(profile, files_dir, &work_dir)?;
+
+    println!("{}", "Generating rpm-ostree treefile...".yellow());
+    for repo in profile.repositories.iter().filter(|repo| repo.enabled) {
+        fs::write(work_dir.join(format!("{}.repo", repo.name)), repo_file(repo)).context(format!("Failed to write repository {}", repo.name))?;
+    }
+    let mut add_files = Vec::new();
+    if files_dir.exists() {
+        crate::copy_files(files_dir, &work_dir.join("files"))?;
+        add_files = collect_files(files_dir)?;
+    }
+    if user_config.is_some() {
+        add_files.push(("user.ign".to_string(), ignition::USER_CONFIG.to_string()));
+    }
+    let postprocess = postprocess_script(profile, scripts_dir)?;
+    if let Some(script) = &postprocess {
+        fs::write(work_dir.join("postprocess.sh"), script).context("Failed to write the postprocess script")?;
+    }
+    fs::write(work_dir.join("treefile.json"), treefile(profile, &add_files, postprocess.is_some())?).context("Failed to write treefile.json")?;
+    fs::write(work_dir.join("ks.cfg"), kickstart(profile)?).context("Failed to write ks.cfg")?;
+
+    println!("{}", "Composing OSTree commit...".yellow());
+    let ostree_ref = ostree_ref(profile)?;
+    let iso_name = format!("{}-{}.iso", profile.distro_name, profile.version);
+    let volume_id = profile.distro_name.to_uppercase();
+    let tools: &[&str] = match crate::target_arch(profile)? {
+        "aarch64" => &["rpm-ostree", "ostree", "curl", "grub2-tools-extra", "grub2-efi-aa64-modules", "mtools", "xorriso"],
+        _ => &["rpm-ostree", "ostree", "curl", "grub2-tools-extra", "grub2-pc-modules", "grub2-efi-x64-modules", "mtools", "xorriso"],
+    };
+    let compose_cmd = format!(
+        r#"set -e
+{tools}
+RELEASEVER={releasever}
+sed -i "s/@RELEASEVER@/$RELEASEVER/" /work/treefile.json
+cp /etc/yum.repos.d/fedora.repo /etc/yum.repos.d/fedora-updates.repo /work/
+[ -f /repo/config ] || ostree init --repo=/repo --mode=archive
+rpm-ostree compose tree --unified-core --repo=/repo --cachedir=/cache /work/treefile.json
+ostree summary --repo=/repo --update
+mkdir -p /iso/boot/grub /iso/images /iso/ostree
+curl -fsSL -o /iso/boot/vmlinuz {tree}/images/pxeboot/vmlinuz
+curl -fsSL -o /iso/boot/initrd.img {tree}/images/pxeboot/initrd.img
+curl -fsSL -o /iso/images/install.img {tree}/images/install.img
+ostree init --repo=/iso/ostree/repo --mode=archive
+ostree pull-local --repo=/iso/ostree/repo /repo {ostree_ref}
+cp /work/ks.cfg /iso/ks.cfg
+{grub}
+MKRESCUE=grub-mkrescue; command -v grub2-mkrescue >/dev/null && MKRESCUE=grub2-mkrescue
+$MKRESCUE -o /out/{iso} /iso -- -volid '{volid}'
+"#,
+        tools = crate::package_manager(profile)?.refresh_and_install(tools),
+        releasever = crate::dnf_releasever(profile),
+        tree = netinstall::anaconda_tree(profile)?,
+        ostree_ref = ostree_ref,
+        grub = netinstall::grub_cfg(profile, &format!("inst.stage2=hd:LABEL={volid} inst.ks=hd:LABEL={volid}:/ks.cfg quiet", volid = volume_id)),
+        iso = iso_name,
+        volid = volume_id,
+    );
+    let volumes = vec![
+        format!("{}:/work:z", work_dir.display()),
+        format!("{}:/repo:z", repo_dir.display()),
+        format!("{}:/cache:z", CACHE_DIR),
+        format!("{}:/out:z", build_dir.display()),
+    ];
+    crate::run_in_builder(profile, volumes, &compose_cmd, "Atomic compose")?;
+
+    let iso_path = build_dir.join(&iso_name);
+    info!("Committed {} to {}", ostree_ref, repo_dir.display());
+    info!("Installer ISO built at {}", iso_path.display());
+    Ok(iso_path)
+}
+
+fn list(values: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", values.into_iter().map(|value| crate::quoted(&value)).collect::<Vec<_>>().join(", "))
+}
+
+fn treefile(profile: &Profile, add_files: &[(String, String)], postprocess: bool) -> Result<String> {
+    let bootloader: &[&str] = match crate::target_arch(profile)? {
+        "aarch64" => &["grub2-efi-aa64", "shim-aa64"],
+        _ => &["grub2-pc", "grub2-efi-x64", "shim-x64"],
+    };
+    let mut packages: Vec<String> = BASE_PACKAGES.iter().chain(bootloader).map(|p| p.to_string()).collect();
+    packages.extend(profile.packages.iter().map(|p| p.replacen('=', "-", 1)));
+    packages.extend(ignition::packages(profile)?.iter().map(|p| p.to_string()));
+    let repos = ["fedora".to_string(), "updates".to_string()].into_iter().chain(profile.repositories.iter().filter(|r| r.enabled).map(|r| r.name.clone()));
+
+    let mut fields = vec![
+        format!("\"ref\": {}", crate::quoted(&ostree_ref(profile)?)),
+        "\"releasever\": \"@RELEASEVER@\"".to_string(),
+        format!("\"repos\": {}", list(repos)),
+        "\"selinux\": true".to_string(),
+        "\"boot-location\": \"modules\"".to_string(),
+        "\"tmp-is-dir\": true".to_string(),
+        "\"etc-group-members\": [\"wheel\"]".to_string(),
+        format!("\"packages\": {}", list(packages)),
+    ];
+    if !profile.packages_to_remove.is_empty() {
+        fields.push(format!("\"exclude-packages\": {}", list(profile.packages_to_remove.iter().cloned())));
+    }
+    if !profile.services_enable.is_empty() {
+        fields.push(format!("\"units\": {}", list(profile.services_enable.iter().cloned())));
+    }
+    let initramfs_args = ignition::initramfs_args(profile)?;
+    if !initramfs_args.is_empty() {
+        fields.push(format!("\"initramfs-args\": {}", list(initramfs_args.iter().map(|arg| arg.to_string()))));
+    }
+    if !add_files.is_empty() {
+        let pairs: Vec<String> = add_files.iter().map(|(source, target)| format!("[{}, {}]", crate::quoted(source), crate::quoted(target))).collect();
+        fields.push(format!("\"add-files\": [{}]", pairs.join(", ")));
+    }
+    if postprocess {
+        fields.push("\"postprocess-script\": \"postprocess.sh\"".to_string());
+    }
+    Ok(format!("{{\n  {}\n}}\n", fields.join(",\n  ")))
+}
+
+// Compose repos are only read while composing, so a key given as a URL is all dnf can check
+fn repo_file(repo: &crate::repos::Repository) -> String {
+    let gpg = match repo.gpg_key.as_ref().or(repo.key_url.as_ref()).filter(|key| key.starts_with("http")) {
+        Some(key) => format!("gpgcheck=1\ngpgkey={}\n", key),
+        None => "gpgcheck=0\n".to_string(),
+    };
+    format!("[{name}]\nname={name}\nbaseurl={}\nenabled=1\n{}", repo.url, gpg, name = repo.name)
+}
+
+// files/ entries as add-files pairs; rpm-ostree only takes them under /usr and /etc
+fn collect_files(files_dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(files_dir) {
+        let entry = entry.context("Failed to walk dir")?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(files_dir).context("Failed to strip prefix")?.to_string_lossy().to_string();
+        if relative.starts_with("usr/") || relative.starts_with("etc/") {
+            files.push((format!("files/{}", relative), format!("/{}", relative)));
+        } else {
+            warn!("Skipping {}: only files under files/usr and files/etc go into an atomic commit", relative);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// scripts/*.sh run in order inside the composed tree, followed by the disabled and masked services
+fn postprocess_script(profile: &Profile, scripts_dir: &Path) -> Result<Option<String>> {
+    let mut lines = Vec::new();
+    if scripts_dir.exists() {
+        let mut scripts: Vec<_> = fs::read_dir(scripts_dir)
+            .context("Failed to read scripts dir")?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sh"))
+            .collect();
+        scripts.sort();
+        for (index, script) in scripts.iter().enumerate() {
+            let content = fs::read_to_string(script).context(format!("Failed to read {}", script.display()))?;
+            lines.push(format!("bash <<'ULB_SCRIPT_{index}'\n{}\nULB_SCRIPT_{index}", content.trim_end()));
+        }
+    }
+    if !profile.services_disable.is_empty() {
+        lines.push(format!("systemctl disable {}", profile.services_disable.join(" ")));
+    }
+    if !profile.services_mask.is_empty() {
+        lines.push(format!("systemctl mask {}", profile.services_mask.join(" ")));
+    }
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!("#!/bin/bash\nset -euo pipefail\n{}\n", lines.join("\n"))))
+}
+
+// Deploys the commit from the ISO; everything else is asked by Anaconda. Installs then follow
+// [ostree] remote_url, or keep no remote when there is none.
+fn kickstart(profile: &Profile) -> Result<String> {
+    let osname = osname(profile);
+    let mut ks = vec![
+        format!("# {} {}, installed from an atomic ulb build", profile.distro_name, profile.version),
+        format!("ostreesetup --osname={os} --remote={os} --url={} --ref={} --nogpg", ISO_REPO_URL, ostree_ref(profile)?, os = osname),
+    ];
+    let args: Vec<&str> = [ignition::kernel_args(profile)?].into_iter().chain(boot::kernel_cmdline(profile)?).filter(|args| !args.is_empty()).collect();
+    if !args.is_empty() {
+        ks.push(format!("bootloader --append=\"{}\"", args.join(" ")));
+    }
+    ks.push(String::new());
+    ks.push("%post --erroronfail".to_string());
+    ks.push(format!("ostree remote delete {}", osname));
+    if let Some(url) = profile.ostree.as_ref().and_then(|config| config.remote_url.as_ref()) {
+        ks.push(format!("ostree remote add --set=gpg-verify=false {} {}", osname, url));
+    }
+    ks.extend(ignition::firstboot_command(profile)?);
+    ks.push("%end".to_string());
+    Ok(ks.join("\n") + "\n")
+}
@@ -0,0 +1,264 @@
+use anyhow::Result;
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::{disk, overlayroot, PackageManager, Profile};
+
+const RPI_FIRMWARE_REPO: &str = "https://github.com/raspberrypi/firmware.git";
+// The Pi firmware only reads FAT boot partitions from an MBR disk reliably
+const RPI_BOOT_SIZE: &str = "256MiB";
+
+// Optional [rpi] section for the rpi format
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RpiConfig {
+    pub firmware_ref: Option<String>, // Branch or tag of raspberrypi/firmware, defaults to "stable"
+    #[serde(default)]
+    pub config: Vec<String>, // Extra config.txt lines, e.g. "dtoverlay=vc4-kms-v3d"
+    pub cmdline: Option<String>, // Extra kernel arguments appended to cmdline.txt
+}
+
+/// Builds a flashable Raspberry Pi SD card image: an MBR disk with a FAT boot partition holding
+/// the firmware, kernel, device trees, config.txt and cmdline.txt, and an ext4 root partition
+/// with the rootfs plus the matching kernel modules. The kernel comes from the Foundation's
+/// firmware repository, so any base works with arch = "aarch64".
+pub fn build_rpi_image(profile: &Profile, rootfs: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Building Raspberry Pi image...".yellow());
+
+    let config = profile.rpi.clone().unwrap_or_default();
+    let firmware_ref = config.firmware_ref.as_deref().unwrap_or("stable");
+    let image_name = format!("{}-{}-rpi.img", profile.distro_name, profile.version);
+
+    let mut config_txt = vec![
+        "arm_64bit=1".to_string(),
+        "kernel=kernel8.img".to_string(),
+        "enable_uart=1".to_string(),
+    ];
+    if let Some(devicetree) = devicetree(profile)? {
+        config_txt.extend(rpi_devicetree(&devicetree));
+    }
+    config_txt.extend(config.config.iter().cloned());
+    let mut cmdline = "console=serial0,115200 console=tty1 root=PARTUUID=$PARTUUID-02 rootfstype=ext4 rootwait".to_string();
+    let volatile = overlayroot::kernel_args(profile)?;
+    if !volatile.is_empty() {
+        cmdline.push(' ');
+        cmdline.push_str(volatile);
+    }
+    if let Some(extra) = &config.cmdline {
+        cmdline.push(' ');
+        cmdline.push_str(extra);
+    }
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut tools = disk::disk_tools(package_manager).to_vec();
+    tools.push("git");
+    let rpi_cmd = format!(
+        r#"set -e
+{tools}
+git clone --depth 1 --branch {firmware_ref} --filter=blob:none --sparse {repo} /tmp/firmware
+git -C /tmp/firmware sparse-checkout set boot modules
+IMG=/out/{image}
+rm -f $IMG && truncate -s {size} $IMG
+sfdisk $IMG <<EOF
+label: dos
+size={boot_size}, type=c, bootable
+type=83
+EOF
+LOOP=$(losetup --find --show --partscan $IMG)
+trap 'umount -R /mnt/image 2>/dev/null; losetup -d $LOOP' EXIT
+mkfs.vfat -F 32 -n BOOT ${{LOOP}}p1
+mkfs.ext4 -q -L root ${{LOOP}}p2
+mkdir -p /mnt/image && mount ${{LOOP}}p2 /mnt/image
+cp -a /rootfs/. /mnt/image/
+mkdir -p /mnt/image/boot/firmware && mount ${{LOOP}}p1 /mnt/image/boot/firmware
+cp -r /tmp/firmware/boot/. /mnt/image/boot/firmware/
+mkdir -p /mnt/image/lib/modules && cp -a /tmp/firmware/modules/*-v8* /mnt/image/lib/modules/
+PARTUUID=$(sfdisk --disk-id $IMG | sed 's/^0x//')
+printf '%s\n' {config_txt} > /mnt/image/boot/firmware/config.txt
+echo "{cmdline}" > /mnt/image/boot/firmware/cmdline.txt
+printf 'PARTUUID=%s-02 / ext4 defaults,noatime 0 1\nPARTUUID=%s-01 /boot/firmware vfat defaults 0 2\n' $PARTUUID $PARTUUID > /mnt/image/etc/fstab
+"#,
+        tools = package_manager.refresh_and_install(&tools),
+        firmware_ref = firmware_ref,
+        repo = RPI_FIRMWARE_REPO,
+        image = image_name,
+        size = disk::image_size(profile)?,
+        boot_size = RPI_BOOT_SIZE,
+        config_txt = config_txt.iter().map(|line| format!("'{}'", line)).collect::<Vec<_>>().join(" "),
+        cmdline = cmdline,
+    );
+    let volumes = vec![crate::rootfs_volume(rootfs), format!("{}:/out:z", build_dir.display())];
+    crate::run_in_builder(profile, volumes, &rpi_cmd, "Raspberry Pi image build")?;
+
+    let image_path = build_dir.join(&image_name);
+    info!("Raspberry Pi image built at {}", image_path.display());
+    Ok(image_path)
+}
+
+// Optional [board] section: U-Boot for single-board computers booting the disk image formats
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BoardConfig {
+    pub name: String, // U-Boot board name, e.g. "rock64-rk3328" or "orangepi_zero2"
+    pub package: Option<String>, // Builder package shipping U-Boot for the board, e.g. "u-boot-rockchip"
+    pub binary: Option<String>, // Host directory with prebuilt U-Boot images, instead of a package
+    #[serde(default)]
+    pub writes: Vec<UbootWrite>, // [[board.writes]] images written ahead of the first partition
+}
+
+// [[board.writes]] entry: a U-Boot image and where on the disk the boot ROM expects it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UbootWrite {
+    pub file: String, // e.g. "u-boot-sunxi-with-spl.bin" or "idbloader.img"
+    pub offset: u64, // In 512-byte sectors, e.g. 16 (8 KiB) for Allwinner, 64 for Rockchip
+}
+
+// Usable sectors after the protective MBR and the GPT header
+const GPT_ENTRIES_START: u64 = 2;
+// The default 128-entry GPT partition table ends here
+const GPT_ENTRIES_END: u64 = 34;
+
+/// The [board] settings, once checked. U-Boot's distro boot finds the system through
+/// extlinux.conf (or boot.scr), so boards need bootloader = "extlinux".
+pub fn uboot(profile: &Profile) -> Result<Option<BoardConfig>> {
+    let Some(config) = profile.board.clone() else {
+        return Ok(None);
+    };
+    if profile.bootloader != "extlinux" {
+        return Err(anyhow::anyhow!("[board] needs bootloader = \"extlinux\""));
+    }
+    if crate::target_arch(profile)? == "x86_64" {
+        return Err(anyhow::anyhow!("[board] needs arch = \"aarch64\" or \"riscv64\""));
+    }
+    if config.name.is_empty() {
+        return Err(anyhow::anyhow!("[board] needs the U-Boot board name"));
+    }
+    if config.package.is_some() == config.binary.is_some() {
+        return Err(anyhow::anyhow!("[board] needs exactly one of package or binary"));
+    }
+    if config.writes.is_empty() {
+        return Err(anyhow::anyhow!("[board] needs at least one [[board.writes]] entry"));
+    }
+    if let Some(write) = config.writes.iter().find(|w| w.offset < GPT_ENTRIES_START) {
+        return Err(anyhow::anyhow!("{} can't be written over the partition table (offset {})", write.file, write.offset));
+    }
+    Ok(Some(config))
+}
+
+/// sfdisk header lines keeping the GPT clear of the U-Boot images: the partitions start after
+/// the last image ($FIRST_LBA, from `uboot_locate`), and the partition table is shortened
+/// when an image sits where its entries normally go.
+pub fn uboot_label(config: &BoardConfig) -> String {
+    let mut label = "first-lba: $FIRST_LBA".to_string();
+    let lowest = config.writes.iter().map(|w| w.offset).min().unwrap_or(GPT_ENTRIES_END);
+    if lowest < GPT_ENTRIES_END {
+        // Four 128-byte entries fit in each sector
+        label.push_str(&format!("\ntable-length: {}", (lowest - GPT_ENTRIES_START) * 4));
+    }
+    label
+}
+
+/// Shell snippet run before partitioning: finds the board's U-Boot images in /uboot (the
+/// binary directory) or where the package installed them, and sets $UBOOT and $FIRST_LBA.
+pub fn uboot_locate(config: &BoardConfig) -> String {
+    let writes: Vec<String> = config.writes.iter().map(|w| format!("'{}:{}'", w.file, w.offset)).collect();
+    format!(
+        r#"UBOOT=
+for DIR in /uboot /usr/lib/u-boot/{name} /usr/share/uboot/{name}; do if [ -d $DIR ]; then UBOOT=$DIR; break; fi; done
+[ -n "$UBOOT" ] || {{ echo "No U-Boot found for board {name}" >&2; exit 1; }}
+FIRST_LBA=2048
+for WRITE in {writes}; do
+  FILE=$UBOOT/${{WRITE%:*}}; OFFSET=${{WRITE##*:}}
+  [ -f $FILE ] || {{ echo "$FILE not found" >&2; exit 1; }}
+  END=$(( OFFSET + ($(stat -c %s $FILE) + 511) / 512 ))
+  if [ $END -gt $FIRST_LBA ]; then FIRST_LBA=$(( (END + 2047) / 2048 * 2048 )); fi
+done"#,
+        name = config.name,
+        writes = writes.join(" "),
+    )
+}
+
+/// Shell snippet writing the U-Boot images to the raw sectors of $LOOP.
+pub fn uboot_write(config: &BoardConfig) -> String {
+    config
+        .writes
+        .iter()
+        .map(|w| format!("dd if=$UBOOT/{} of=$LOOP bs=512 seek={} conv=notrunc,fsync status=none", w.file, w.offset))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Package providing mkimage, which turns boot.cmd into the boot.scr U-Boot runs.
+pub fn uboot_tools_package(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Apt | PackageManager::Xbps => "u-boot-tools",
+        PackageManager::Portage => "dev-embedded/u-boot-tools",
+        _ => "uboot-tools",
+    }
+}
+
+/// mkimage's name for the architecture of `arch`.
+pub fn mkimage_arch(arch: &str) -> &'static str {
+    match arch {
+        "riscv64" => "riscv",
+        _ => "arm64",
+    }
+}
+
+// Optional [devicetree] section for aarch64/riscv64 boards that don't describe themselves
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct DevicetreeConfig {
+    pub dtb: Option<String>, // Kernel device tree, e.g. "rockchip/rk3328-rock64.dtb"
+    #[serde(default)]
+    pub overlays: Vec<String>, // .dtbo overlays applied on top, e.g. "rockchip/overlay/rk3328-uart1.dtbo"
+}
+
+/// Directory under /boot (or the ESP) the selected device trees are copied to.
+pub const DEVICETREE_DIR: &str = "devicetree";
+
+/// The [devicetree] settings, once checked. Names are relative to the kernel's dtbs
+/// directory, the way the kernel installs them.
+pub fn devicetree(profile: &Profile) -> Result<Option<DevicetreeConfig>> {
+    let Some(config) = profile.devicetree.clone() else {
+        return Ok(None);
+    };
+    if crate::target_arch(profile)? == "x86_64" {
+        return Err(anyhow::anyhow!("[devicetree] needs arch = \"aarch64\" or \"riscv64\""));
+    }
+    if profile.uki {
+        return Err(anyhow::anyhow!("[devicetree] can't be combined with uki"));
+    }
+    let files = config.dtb.iter().map(|dtb| (dtb, ".dtb")).chain(config.overlays.iter().map(|o| (o, ".dtbo")));
+    for (file, extension) in files {
+        if !file.ends_with(extension) || file.starts_with('/') || file.split('/').any(|part| part == "..") {
+            return Err(anyhow::anyhow!("Device tree {} must be a relative {} path", file, extension));
+        }
+    }
+    Ok(Some(config))
+}
+
+/// Shell snippet copying the selected device trees of the newest kernel in the image mounted
+/// at /mnt/image into `dest`. Debian, Fedora and Arch each keep them somewhere else.
+pub fn install_devicetree_command(config: &DevicetreeConfig, dest: &str) -> String {
+    let files: Vec<&str> = config.dtb.iter().chain(config.overlays.iter()).map(|f| f.as_str()).collect();
+    format!(
+        r#"KVER=$(ls /mnt/image/lib/modules | sort -V | tail -n1)
+for DTBS in /mnt/image/usr/lib/linux-image-$KVER /mnt/image/boot/dtb-$KVER /mnt/image/boot/dtbs /mnt/image/lib/modules/$KVER/dtb; do if [ -d $DTBS ]; then break; fi; done
+[ -d $DTBS ] || {{ echo "No device trees found for kernel $KVER" >&2; exit 1; }}
+for DTB in {files}; do
+  [ -f $DTBS/$DTB ] || {{ echo "$DTB not found in $DTBS" >&2; exit 1; }}
+  mkdir -p {dest}/$(dirname $DTB) && cp $DTBS/$DTB {dest}/$DTB
+done"#,
+        files = files.join(" "),
+        dest = dest,
+    )
+}
+
+// config.txt lines selecting the device tree: the Pi firmware ships its own, so only the base
+// names matter
+fn rpi_devicetree(config: &DevicetreeConfig) -> Vec<String> {
+    let base = |file: &str| file.rsplit('/').next().unwrap_or(file).to_string();
+    let mut lines: Vec<String> = config.dtb.iter().map(|dtb| format!("device_tree={}", base(dtb))).collect();
+    lines.extend(config.overlays.iter().map(|o| format!("dtoverlay={}", base(o).trim_end_matches(".dtbo"))));
+    lines
+}
@@ -0,0 +1,710 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::{artifacts, disk, flash, overlayroot, PackageManager, Profile};
+
+// Optional [boot_menu] section: branding of the ISO and disk image boot menus. Paths are in
+// the rootfs, so images and themes usually come from files/.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BootMenuConfig {
+    pub title: Option<String>, // Menu title, defaults to "<distro_name> <version>"
+    pub timeout: Option<u32>, // Seconds before the default entry boots, defaults to 5
+    pub default: Option<usize>, // Index of the default entry, counting from 0
+    pub background: Option<String>, // PNG shown behind the menu, e.g. "/usr/share/ulb/splash.png"
+    pub theme: Option<String>, // GRUB theme directory holding theme.txt, e.g. "/boot/grub/themes/mydistro"
+}
+
+// [[boot_entries]] entry: one more boot menu entry starting the same system with extra kernel
+// arguments, e.g. title = "Safe graphics" and cmdline = "nomodeset"
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BootEntry {
+    pub title: String, // Shown after the system name, e.g. "Recovery"
+    pub cmdline: String, // Appended to the regular kernel arguments, e.g. "single"
+}
+
+/// The [[boot_entries]], once checked.
+pub fn boot_entries(profile: &Profile) -> Result<&[BootEntry]> {
+    if !profile.boot_entries.is_empty() && profile.uki {
+        return Err(anyhow::anyhow!("[[boot_entries]] can't be combined with uki, its command line is built in"));
+    }
+    for entry in &profile.boot_entries {
+        check_menu_text("[[boot_entries]] title", &entry.title)?;
+        check_menu_text("[[boot_entries]] cmdline", &entry.cmdline)?;
+    }
+    Ok(&profile.boot_entries)
+}
+
+// [[languages]] entry: one choice in the live menu's language and keyboard submenu
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Language {
+    pub locale: String, // e.g. "de_DE.UTF-8"
+    pub keymap: Option<String>, // Console and X keyboard layout, e.g. "de"
+    pub title: Option<String>, // Shown in the submenu, defaults to the locale, e.g. "Deutsch"
+}
+
+/// The [[languages]], once checked.
+pub fn languages(profile: &Profile) -> Result<&[Language]> {
+    if !profile.languages.is_empty() && (profile.uki || profile.bootloader == "refind") {
+        return Err(anyhow::anyhow!("[[languages]] are offered by the GRUB and isolinux live menus, not with uki or rEFInd"));
+    }
+    for language in &profile.languages {
+        let valid = |value: &str| !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '@' | '-'));
+        if !valid(&language.locale) || language.keymap.as_deref().is_some_and(|keymap| !valid(keymap)) {
+            return Err(anyhow::anyhow!("Invalid [[languages]] locale or keymap: {} {:?}", language.locale, language.keymap));
+        }
+        if let Some(title) = &language.title {
+            check_menu_text("[[languages]] title", title)?;
+        }
+    }
+    Ok(&profile.languages)
+}
+
+/// The live language submenu, as title and kernel arguments: the live system with each of the
+/// [[languages]], set through live-config on the apt bases and dracut and systemd on the others.
+pub fn language_entries(profile: &Profile, volume_id: &str, dir: &str) -> Result<Vec<(String, String)>> {
+    let cmdline = live_cmdline(profile, volume_id, dir)?;
+    let apt = crate::package_manager(profile)? == PackageManager::Apt;
+    Ok(languages(profile)?
+        .iter()
+        .map(|language| {
+            let mut args = if apt {
+                format!("locales={}", language.locale)
+            } else {
+                format!("rd.locale.LANG={0} locale.LANG={0}", language.locale)
+            };
+            if let Some(keymap) = &language.keymap {
+                args.push_str(&if apt {
+                    format!(" keyboard-layouts={}", keymap)
+                } else {
+                    format!(" rd.vconsole.keymap={0} vconsole.keymap={0}", keymap)
+                });
+            }
+            let title = match (&language.title, &language.keymap) {
+                (Some(title), _) => title.clone(),
+                (None, Some(keymap)) => format!("{} ({} keyboard)", language.locale, keymap),
+                (None, None) => language.locale.clone(),
+            };
+            (title, format!("{} {}", cmdline, args))
+        })
+        .collect())
+}
+
+// Menu text ends up in single-quoted shell strings and printf formats
+fn check_menu_text(what: &str, text: &str) -> Result<()> {
+    if text.trim().is_empty() || text.contains(['\'', '"', '%', '\\', '\n']) {
+        return Err(anyhow::anyhow!("{} can't be empty or contain quotes, %, \\ or newlines", what));
+    }
+    Ok(())
+}
+
+/// The [boot_menu] settings, once checked.
+pub fn boot_menu(profile: &Profile) -> Result<BootMenuConfig> {
+    let config = profile.boot_menu.clone().unwrap_or_default();
+    for path in config.background.iter().chain(config.theme.iter()) {
+        if !path.starts_with('/') || path.contains(['\'', ' ']) {
+            return Err(anyhow::anyhow!("[boot_menu] paths must be absolute paths in the rootfs without spaces, got {}", path));
+        }
+    }
+    if let Some(title) = &config.title {
+        check_menu_text("[boot_menu] title", title)?;
+    }
+    if config.background.as_ref().is_some_and(|background| !background.ends_with(".png")) {
+        return Err(anyhow::anyhow!("[boot_menu] background must be a PNG image"));
+    }
+    Ok(config)
+}
+
+/// Title shown above the boot menu.
+pub fn menu_title(profile: &Profile) -> Result<String> {
+    Ok(boot_menu(profile)?.title.unwrap_or_else(|| format!("{} {}", profile.distro_name, profile.version)))
+}
+
+/// Seconds before the default entry boots.
+pub fn menu_timeout(profile: &Profile) -> Result<u32> {
+    Ok(boot_menu(profile)?.timeout.unwrap_or(5))
+}
+
+/// The entry of `entries` to boot by default, by the [boot_menu] index.
+pub fn menu_default<'a, T>(profile: &Profile, entries: &'a [T]) -> Result<&'a T> {
+    let index = boot_menu(profile)?.default.unwrap_or(0);
+    entries
+        .get(index)
+        .ok_or_else(|| anyhow::anyhow!("[boot_menu] default = {} but the menu has {} entries", index, entries.len()))
+}
+
+/// Shell snippet copying the [boot_menu] background and GRUB theme from the rootfs at /rootfs
+/// into `grub_dir`, where `grub_menu_header` points GRUB at them.
+pub fn grub_theme_copy(profile: &Profile, grub_dir: &str) -> Result<String> {
+    let config = boot_menu(profile)?;
+    let mut cmd = String::new();
+    if let Some(theme) = &config.theme {
+        cmd.push_str(&format!("\nrm -rf {grub_dir}/themes/ulb && mkdir -p {grub_dir}/themes && cp -r /rootfs{theme} {grub_dir}/themes/ulb"));
+    }
+    if let Some(background) = &config.background {
+        cmd.push_str(&format!("\ncp /rootfs{background} {grub_dir}/background.png"));
+    }
+    Ok(cmd)
+}
+
+/// grub.cfg lines setting the timeout, the default entry and the graphical theme or background
+/// copied by `grub_theme_copy`, with `grub_dir` as GRUB sees it.
+pub fn grub_menu_header(profile: &Profile, grub_dir: &str, entries: usize) -> Result<String> {
+    let config = boot_menu(profile)?;
+    let mut lines = vec![
+        format!("set timeout={}", menu_timeout(profile)?),
+        format!("set default={}", menu_default(profile, &(0..entries).collect::<Vec<_>>())?),
+    ];
+    if config.theme.is_some() || config.background.is_some() {
+        lines.extend(["insmod all_video", "insmod gfxterm", "insmod png", "terminal_output gfxterm"].map(String::from));
+    }
+    if config.theme.is_some() {
+        lines.push("insmod regexp".to_string());
+        lines.push(format!("for font in {grub_dir}/themes/ulb/*.pf2; do loadfont $font; done"));
+        lines.push(format!("set theme={grub_dir}/themes/ulb/theme.txt"));
+    }
+    if config.background.is_some() {
+        lines.push(format!("background_image {grub_dir}/background.png"));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Installs ukify and the systemd EFI stub into the rootfs, so every artifact can build its
+/// own unified kernel image with the command line it needs.
+pub fn install_uki_tools(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if !profile.uki {
+        return Ok(());
+    }
+    println!("{}", "Installing UKI tools...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let packages: &[&str] = match package_manager {
+        PackageManager::Apt => &["systemd-ukify", "systemd-boot-efi"],
+        PackageManager::Dnf => &["systemd-ukify", "systemd-boot-unsigned"],
+        PackageManager::Pacman => &["systemd-ukify"],
+        _ => return Err(anyhow::anyhow!("uki is not supported on the {} base", profile.base)),
+    };
+    let packages: Vec<String> = packages.iter().map(|p| p.to_string()).collect();
+    crate::run_in_chroot(profile, rootfs, &package_manager.install(&packages), "UKI tools installation")
+}
+
+/// Rebuilds the initramfs with dracut and its systemd-veritysetup module, which maps
+/// /dev/mapper/root from the roothash= and systemd.verity_root_* arguments of a verity image.
+pub fn install_verity_tools(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if !disk::is_verity(profile)? {
+        return Ok(());
+    }
+    println!("{}", "Setting up dm-verity initramfs...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    // Same initramfs names the bootloader setup picks up for each base
+    let initramfs = match package_manager {
+        PackageManager::Apt => "/boot/initrd.img-$KVER",
+        PackageManager::Dnf => "/boot/initramfs.img",
+        PackageManager::Pacman => "/boot/initramfs-$(cat /lib/modules/$KVER/pkgbase).img",
+        _ => return Err(anyhow::anyhow!("verity is not supported on the {} base", profile.base)),
+    };
+    let packages = vec!["dracut".to_string(), disk::veritysetup_package(package_manager).to_string()];
+    let verity_cmd = format!(
+        "set -e\n{}\n\
+         mkdir -p /etc/dracut.conf.d\n\
+         echo 'add_dracutmodules+=\" systemd-veritysetup \"' > /etc/dracut.conf.d/ulb-verity.conf\n\
+         KVER=$(ls /lib/modules | sort -V | tail -n1)\n\
+         dracut -f {} $KVER",
+        package_manager.install(&packages),
+        initramfs
+    );
+    crate::run_in_chroot(profile, rootfs, &verity_cmd, "dm-verity initramfs setup")
+}
+
+/// Installs cryptsetup and sets the initramfs generator up to unlock a LUKS root. The image
+/// build regenerates the initramfs once /etc/crypttab names the encrypted partition.
+pub fn install_encryption_tools(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if disk::encryption(profile)?.is_none() {
+        return Ok(());
+    }
+    println!("{}", "Installing disk encryption tools...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let (packages, setup): (&[&str], &str) = match package_manager {
+        PackageManager::Apt => (&["cryptsetup", "cryptsetup-initramfs"], "true"),
+        PackageManager::Dnf => (
+            &["cryptsetup"],
+            "mkdir -p /etc/dracut.conf.d && echo 'add_dracutmodules+=\" crypt \"' > /etc/dracut.conf.d/ulb-crypt.conf",
+        ),
+        // sd-encrypt reads the same rd.luks.* arguments as dracut, but needs the systemd hook
+        PackageManager::Pacman => (
+            &["cryptsetup"],
+            "sed -i '/^HOOKS=/{s/\\budev\\b/systemd/;s/\\bencrypt\\b//;s/\\bfilesystems\\b/sd-encrypt filesystems/}' /etc/mkinitcpio.conf",
+        ),
+        _ => return Err(anyhow::anyhow!("Disk encryption is not supported on the {} base", profile.base)),
+    };
+    let packages: Vec<String> = packages.iter().map(|p| p.to_string()).collect();
+    let encryption_cmd = format!("{} && {}", package_manager.install(&packages), setup);
+    crate::run_in_chroot(profile, rootfs, &encryption_cmd, "Disk encryption tools installation")
+}
+
+/// Makes sure the kernel can mount an EROFS live image and rebuilds the initramfs with the
+/// erofs module in it.
+pub fn install_live_fs_support(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if artifacts::live_fs(profile)? != "erofs" {
+        return Ok(());
+    }
+    println!("{}", "Checking EROFS support...".yellow());
+
+    let live_fs_cmd = format!(
+        r#"set -e
+KVER=$(ls /lib/modules | sort -V | tail -n1)
+if ! {{ [ -d /lib/modules/$KVER/kernel/fs/erofs ] || grep -qs '^CONFIG_EROFS_FS=y' /boot/config-$KVER /lib/modules/$KVER/build/.config; }}; then
+  echo "Kernel $KVER has no EROFS support" >&2; exit 1
+fi
+mkdir -p /etc/dracut.conf.d /etc/mkinitcpio.conf.d
+echo 'add_drivers+=" erofs "' > /etc/dracut.conf.d/ulb-erofs.conf
+echo 'MODULES+=(erofs)' > /etc/mkinitcpio.conf.d/ulb-erofs.conf
+{}"#,
+        disk::initramfs_command(crate::package_manager(profile)?)
+    );
+    crate::run_in_chroot(profile, rootfs, &live_fs_cmd, "EROFS support check")
+}
+
+/// The kernel_cmdline arguments, or None when the defaults are kept.
+pub fn kernel_cmdline(profile: &Profile) -> Result<Option<&str>> {
+    let Some(args) = profile.kernel_cmdline.as_deref() else {
+        return Ok(None);
+    };
+    check_menu_text("kernel_cmdline", args)?;
+    Ok(Some(args))
+}
+
+/// Adds kernel_cmdline to the system's GRUB defaults, so the entries grub-mkconfig writes on
+/// the installed system keep it.
+pub fn persist_kernel_cmdline(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(args) = kernel_cmdline(profile)? else {
+        return Ok(());
+    };
+    if profile.bootloader != "grub" {
+        return Ok(());
+    }
+    let path = rootfs.join("etc/default/grub");
+    std::fs::create_dir_all(rootfs.join("etc/default")).context("Failed to create /etc/default")?;
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .context(format!("Failed to open {}", path.display()))?;
+    // The file is sourced as a shell script, so this extends whatever the distribution sets
+    let setting = format!("\n# Added by ULB\nGRUB_CMDLINE_LINUX_DEFAULT=\"$GRUB_CMDLINE_LINUX_DEFAULT {}\"\n", args);
+    file.write_all(setting.as_bytes()).context("Failed to write GRUB defaults")?;
+    Ok(())
+}
+
+/// Kernel arguments showing the Plymouth splash, or nothing without plymouth_theme.
+pub fn splash_args(profile: &Profile) -> &'static str {
+    if profile.plymouth_theme.is_some() {
+        "quiet splash"
+    } else {
+        ""
+    }
+}
+
+/// The Plymouth theme name, or None when the boot stays text-only.
+pub fn plymouth_theme(profile: &Profile) -> Result<Option<&str>> {
+    let Some(theme) = profile.plymouth_theme.as_deref() else {
+        return Ok(None);
+    };
+    if theme.is_empty() || !theme.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow::anyhow!("plymouth_theme must be a theme name, got {:?}", theme));
+    }
+    Ok(Some(theme))
+}
+
+/// Installs Plymouth with plymouth_theme as the default theme and rebuilds the initramfs, which
+/// is where the splash is drawn from. A theme copied from files/ to /usr/share/plymouth/themes
+/// is used as is, any other comes from the distribution's plymouth-theme-<name> package.
+pub fn install_plymouth(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(theme) = plymouth_theme(profile)? else {
+        return Ok(());
+    };
+    println!("{}", format!("Installing Plymouth theme {}...", theme).yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let plymouth = match package_manager {
+        PackageManager::Portage => "sys-boot/plymouth",
+        _ => "plymouth",
+    };
+    // Arch, Void and Gentoo ship their themes in the plymouth package itself
+    let theme_install = match package_manager {
+        PackageManager::Apt | PackageManager::Dnf => format!(
+            "\n[ -d /usr/share/plymouth/themes/{theme} ] || {}",
+            package_manager.install(&[format!("plymouth-theme-{}", theme)])
+        ),
+        _ => String::new(),
+    };
+    let plymouth_cmd = format!(
+        r#"set -e
+{install}{theme_install}
+[ -d /usr/share/plymouth/themes/{theme} ] || {{ echo "Plymouth theme {theme} not found, add it to files/usr/share/plymouth/themes" >&2; exit 1; }}
+plymouth-set-default-theme {theme}
+if [ -f /etc/mkinitcpio.conf ] && ! grep -q '^HOOKS=.*plymouth' /etc/mkinitcpio.conf; then
+  sed -i 's/^\(HOOKS=(.*\budev\)/\1 plymouth/' /etc/mkinitcpio.conf
+fi
+{initramfs}"#,
+        install = package_manager.install(&[plymouth.to_string()]),
+        initramfs = disk::initramfs_command(package_manager),
+    );
+    crate::run_in_chroot(profile, rootfs, &plymouth_cmd, "Plymouth installation")
+}
+
+/// Shell snippet building a UKI from the newest kernel and initramfs, run inside the system
+/// (a chroot) so ukify and the stub come from the image itself. `cmdline` may reference
+/// shell variables set by the caller.
+pub fn uki_command(output: &str, cmdline: &str) -> String {
+    format!(
+        r#"KVER=$(ls /lib/modules | sort -V | tail -n1)
+PKGBASE=$(cat /lib/modules/$KVER/pkgbase 2>/dev/null)
+for KERNEL in /boot/vmlinuz-$KVER /lib/modules/$KVER/vmlinuz /boot/vmlinuz-$PKGBASE; do [ -f $KERNEL ] && break; done
+for INITRD in /boot/initrd.img-$KVER /boot/initramfs-$KVER.img /boot/initramfs-$PKGBASE.img /boot/initramfs.img; do [ -f $INITRD ] && break; done
+mkdir -p $(dirname {output})
+ukify build --linux=$KERNEL --initrd=$INITRD --cmdline="{cmdline}" --os-release=@/etc/os-release --output={output}"#
+    )
+}
+
+/// Whether to add a memtest86+ boot entry. memtest86+ only runs on x86, and a UKI ISO boots
+/// straight into the system without a menu to add it to.
+pub fn memtest(profile: &Profile) -> Result<bool> {
+    if !profile.include_memtest {
+        return Ok(false);
+    }
+    if crate::target_arch(profile)? != "x86_64" {
+        return Err(anyhow::anyhow!("include_memtest needs arch = \"x86_64\""));
+    }
+    if profile.bootloader == "extlinux" {
+        return Err(anyhow::anyhow!("include_memtest needs bootloader = \"grub\", \"systemd-boot\" or \"refind\""));
+    }
+    if profile.uki && profile.format.iter().any(|f| f == "iso") {
+        return Err(anyhow::anyhow!("include_memtest needs a boot menu, which uki ISOs don't have"));
+    }
+    Ok(true)
+}
+
+/// Checks toram, which adds a second entry to the ISO boot menus.
+pub fn check_toram(profile: &Profile) -> Result<()> {
+    if profile.toram && profile.uki && profile.format.iter().any(|f| f == "iso") {
+        return Err(anyhow::anyhow!("toram needs a boot menu, which uki ISOs don't have"));
+    }
+    Ok(())
+}
+
+/// Installs memtest86+ (BIOS and EFI builds) into the rootfs, where the boot menus of every
+/// artifact take it from.
+pub fn install_memtest(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if !memtest(profile)? {
+        return Ok(());
+    }
+    println!("{}", "Installing memtest86+...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let packages: &[&str] = match package_manager {
+        PackageManager::Pacman => &["memtest86+", "memtest86+-efi"],
+        PackageManager::Portage => &["sys-apps/memtest86+"],
+        _ => &["memtest86+"],
+    };
+    let packages: Vec<String> = packages.iter().map(|p| p.to_string()).collect();
+    crate::run_in_chroot(profile, rootfs, &package_manager.install(&packages), "memtest86+ installation")
+}
+
+/// Shell snippet setting $MEMTEST_BIN and $MEMTEST_EFI to the memtest86+ builds in the rootfs
+/// mounted at /rootfs. Each distribution names and places them differently.
+pub fn memtest_locate() -> String {
+    r#"MEMTEST_BIN=$(find /rootfs/boot /rootfs/usr/lib/memtest86+ -name 'memtest*.bin' 2>/dev/null | sort | head -n1)
+MEMTEST_EFI=$(find /rootfs/boot /rootfs/usr/lib/memtest86+ -name 'memtest*.efi' 2>/dev/null | sort | head -n1)
+[ -n "$MEMTEST_BIN" ] && [ -n "$MEMTEST_EFI" ] || { echo "memtest86+ not found in the rootfs" >&2; exit 1; }"#
+        .to_string()
+}
+
+/// Packages providing isolinux.bin and the BIOS syslinux modules in the builder container.
+pub fn syslinux_packages(package_manager: PackageManager) -> &'static [&'static str] {
+    match package_manager {
+        PackageManager::Apt => &["isolinux", "syslinux-common"],
+        PackageManager::Portage => &["sys-boot/syslinux"],
+        _ => &["syslinux"],
+    }
+}
+
+/// Shell snippet staging the isolinux BIOS loader in /tmp/isolinux for the ISO, run in the
+/// builder with the rootfs at /rootfs: the syslinux files, the newest kernel and initramfs,
+/// and an isolinux.cfg booting them with the live command line. The isohybrid MBR goes to
+/// /tmp/isohdpfx.bin.
+pub fn isolinux_command(profile: &Profile, volume_id: &str) -> Result<String> {
+    let (memtest_copy, memtest_entry) = if memtest(profile)? {
+        (
+            format!("\n{}\ncp $MEMTEST_BIN /tmp/isolinux/memtest.bin", memtest_locate()),
+            "\nLABEL memtest\n  MENU LABEL Memory test (memtest86+)\n  LINUX /isolinux/memtest.bin",
+        )
+    } else {
+        (String::new(), "")
+    };
+    let live = live_entries(profile, volume_id, "")?;
+    let mut labels: Vec<String> = (0..live.len()).map(|index| format!("live{}", index)).collect();
+    let entries = live
+        .iter()
+        .zip(&labels)
+        .map(|((title, cmdline), label)| {
+            format!(
+                "LABEL {}\n  MENU LABEL {} {}{}\n  KERNEL /isolinux/vmlinuz\n  APPEND initrd=/isolinux/initrd.img {}",
+                label, profile.distro_name, profile.version, title, cmdline
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    // Submenu entries aren't candidates for [boot_menu] default, so they don't get labels
+    let languages: String = language_entries(profile, volume_id, "")?
+        .iter()
+        .enumerate()
+        .map(|(index, (title, cmdline))| {
+            format!(
+                "\n  LABEL lang{}\n    MENU LABEL {}\n    KERNEL /isolinux/vmlinuz\n    APPEND initrd=/isolinux/initrd.img {}",
+                index, title, cmdline
+            )
+        })
+        .collect();
+    let languages = if languages.is_empty() {
+        String::new()
+    } else {
+        format!("\nMENU BEGIN languages\n  MENU TITLE Language and keyboard{}\n  MENU SEPARATOR\n  LABEL back\n    MENU LABEL Back\n    MENU EXIT\nMENU END", languages)
+    };
+    if !memtest_entry.is_empty() {
+        labels.push("memtest".to_string());
+    }
+    // vesamenu draws the background, the plain text menu.c32 can't
+    let menu = boot_menu(profile)?;
+    let (ui, background) = match &menu.background {
+        Some(background) => ("vesamenu.c32", format!("\ncp /rootfs{} /tmp/isolinux/splash.png", background)),
+        None => ("menu.c32", String::new()),
+    };
+    let background_line = if menu.background.is_some() { "\nMENU BACKGROUND splash.png" } else { "" };
+    Ok(format!(
+        r#"mkdir -p /tmp/isolinux
+for DIR in /usr/lib/ISOLINUX /usr/share/syslinux /usr/lib/syslinux/bios; do if [ -f $DIR/isolinux.bin ]; then cp $DIR/isolinux.bin /tmp/isolinux/ && cp $DIR/isohdpfx.bin /tmp/; break; fi; done
+for DIR in /usr/lib/syslinux/modules/bios /usr/share/syslinux /usr/lib/syslinux/bios; do if [ -f $DIR/ldlinux.c32 ]; then cp $DIR/ldlinux.c32 $DIR/menu.c32 $DIR/vesamenu.c32 $DIR/libutil.c32 $DIR/libcom32.c32 /tmp/isolinux/; break; fi; done
+[ -f /tmp/isolinux/isolinux.bin ] && [ -f /tmp/isolinux/ldlinux.c32 ] || {{ echo "isolinux not found in the builder" >&2; exit 1; }}
+KVER=$(ls /rootfs/lib/modules | sort -V | tail -n1)
+PKGBASE=$(cat /rootfs/lib/modules/$KVER/pkgbase 2>/dev/null)
+for KERNEL in /rootfs/boot/vmlinuz-$KVER /rootfs/lib/modules/$KVER/vmlinuz /rootfs/boot/vmlinuz-$PKGBASE; do [ -f $KERNEL ] && break; done
+for INITRD in /rootfs/boot/initrd.img-$KVER /rootfs/boot/initramfs-$KVER.img /rootfs/boot/initramfs-$PKGBASE.img /rootfs/boot/initramfs.img; do [ -f $INITRD ] && break; done
+cp $KERNEL /tmp/isolinux/vmlinuz && cp $INITRD /tmp/isolinux/initrd.img{memtest_copy}{background}
+cat > /tmp/isolinux/isolinux.cfg <<'EOF'
+UI {ui}
+PROMPT 0
+TIMEOUT {timeout}
+DEFAULT {default}
+MENU TITLE {title}{background_line}
+{entries}{languages}{memtest_entry}
+EOF"#,
+        timeout = menu_timeout(profile)? * 10,
+        default = menu_default(profile, &labels)?,
+        title = menu_title(profile)?,
+        entries = entries,
+        languages = languages,
+        memtest_copy = memtest_copy,
+        memtest_entry = memtest_entry,
+    ))
+}
+
+/// grub-mkstandalone plus the EFI modules for `arch`, in the builder container of that arch.
+pub fn grub_efi_packages(package_manager: PackageManager, arch: &str) -> Vec<&'static str> {
+    match (package_manager, arch) {
+        (PackageManager::Apt, "aarch64") => vec!["grub-common", "grub-efi-arm64-bin"],
+        (PackageManager::Apt, "riscv64") => vec!["grub-common", "grub-efi-riscv64-bin"],
+        (PackageManager::Apt, _) => vec!["grub-common", "grub-efi-amd64-bin"],
+        (PackageManager::Dnf, "aarch64") => vec!["grub2-tools-extra", "grub2-efi-aa64-modules"],
+        (PackageManager::Dnf, _) => vec!["grub2-tools-extra", "grub2-efi-x64-modules"],
+        (PackageManager::Xbps, "aarch64") => vec!["grub-arm64-efi"],
+        (PackageManager::Xbps, _) => vec!["grub-x86_64-efi"],
+        (PackageManager::Portage, _) => vec!["sys-boot/grub"],
+        _ => vec!["grub"],
+    }
+}
+
+/// grub.cfg of a single-architecture live ISO: the live entries, the language submenu and
+/// memtest86+, booting the kernel and initramfs copied to /boot/live.
+pub fn grub_live_config(profile: &Profile, volume_id: &str) -> Result<String> {
+    let live = live_entries(profile, volume_id, "")?;
+    let languages = language_entries(profile, volume_id, "")?;
+    let memtest = memtest(profile)?;
+    let count = live.len() + usize::from(!languages.is_empty()) + usize::from(memtest);
+    let mut config = grub_menu_header(profile, "/boot/grub", count)?;
+    for (title, cmdline) in &live {
+        config.push_str(&format!(
+            "\nmenuentry '{} {}{}' {{\n  linux /boot/live/vmlinuz {}\n  initrd /boot/live/initrd.img\n}}",
+            profile.distro_name, profile.version, title, cmdline
+        ));
+    }
+    if !languages.is_empty() {
+        config.push_str("\nsubmenu 'Language and keyboard' {");
+        for (title, cmdline) in &languages {
+            config.push_str(&format!("\n  menuentry '{}' {{\n    linux /boot/live/vmlinuz {}\n    initrd /boot/live/initrd.img\n  }}", title, cmdline));
+        }
+        config.push_str("\n}");
+    }
+    if memtest {
+        config.push_str("\nmenuentry 'Memory test (memtest86+)' {\n  chainloader /boot/live/memtest.efi\n}");
+    }
+    Ok(config)
+}
+
+/// rEFInd package for the builder container, which supplies the EFI binary for ISOs and
+/// disk images alike.
+pub fn refind_package(package_manager: PackageManager) -> Result<&'static str> {
+    match package_manager {
+        PackageManager::Apt | PackageManager::Pacman | PackageManager::Xbps => Ok("refind"),
+        PackageManager::Portage => Ok("sys-boot/refind"),
+        PackageManager::Dnf => Err(anyhow::anyhow!("bootloader = \"refind\" is not packaged for the dnf bases")),
+    }
+}
+
+/// Shell snippet copying rEFInd from the builder into `efi_dir` as the removable-media loader,
+/// with a refind.conf listing `entries` (title, kernel arguments) for /vmlinuz and
+/// /initrd.img on the same filesystem, plus memtest86+ when included. Only manual entries are
+/// shown, so the menu holds exactly what the profile boots. Needs the rootfs at /rootfs.
+pub fn refind_command(profile: &Profile, efi_dir: &str, entries: &[(String, String)]) -> Result<String> {
+    let arch = crate::target_arch(profile)?;
+    let binary = match arch {
+        "x86_64" => "refind_x64.efi",
+        "aarch64" => "refind_aa64.efi",
+        _ => return Err(anyhow::anyhow!("bootloader = \"refind\" is not available for {}", arch)),
+    };
+    let mut menu: String = entries
+        .iter()
+        .map(|(title, args)| {
+            format!("\nmenuentry \"{title}\" {{\n    loader /vmlinuz\n    initrd /initrd.img\n    options \"{args}\"\n}}\n")
+        })
+        .collect();
+    let mut count = entries.len();
+    let mut copies = String::new();
+    if memtest(profile)? {
+        copies = format!("\n{}\ncp $MEMTEST_EFI {efi_dir}/memtest.efi", memtest_locate());
+        menu.push_str("\nmenuentry \"Memory test (memtest86+)\" {\n    loader /memtest.efi\n}\n");
+        count += 1;
+    }
+    let config = boot_menu(profile)?;
+    let mut banner = String::new();
+    if let Some(background) = &config.background {
+        copies.push_str(&format!("\ncp /rootfs{} {efi_dir}/EFI/BOOT/banner.png", background));
+        banner = "banner banner.png\nbanner_scale fillscreen\n".to_string();
+    }
+    // default_selection counts from 1
+    let default = *menu_default(profile, &(1..=count).collect::<Vec<_>>())?;
+    Ok(format!(
+        r#"for REFIND in /usr/share/refind/refind /usr/share/refind; do if [ -f $REFIND/{binary} ]; then break; fi; done
+[ -f $REFIND/{binary} ] || {{ echo "rEFInd not found in the builder" >&2; exit 1; }}
+mkdir -p {efi_dir}/EFI/BOOT
+cp $REFIND/{binary} {efi_dir}/EFI/BOOT/{fallback}{copies}
+cat > {efi_dir}/EFI/BOOT/refind.conf <<EOF
+timeout {timeout}
+scanfor manual
+default_selection {default}
+{banner}{menu}EOF"#,
+        fallback = efi_fallback_binary(arch),
+        timeout = menu_timeout(profile)?,
+    ))
+}
+
+/// Name of the removable-media EFI loader for `arch`, as firmware looks for it in EFI/BOOT.
+pub fn efi_fallback_binary(arch: &str) -> &'static str {
+    match arch {
+        "aarch64" => "BOOTAA64.EFI",
+        "riscv64" => "BOOTRISCV64.EFI",
+        _ => "BOOTX64.EFI",
+    }
+}
+
+/// Kernel arguments that boot the live squashfs from the ISO labelled `volume_id`: live-boot
+/// on Debian/Ubuntu, dracut's dmsquash-live everywhere else. `dir` is the ISO directory the
+/// live files sit under, empty for the top level.
+pub fn live_cmdline(profile: &Profile, volume_id: &str, dir: &str) -> Result<String> {
+    let package_manager = crate::package_manager(profile)?;
+    let mut cmdline = match (package_manager, dir) {
+        (PackageManager::Apt, "") => "boot=live components".to_string(),
+        (PackageManager::Apt, dir) => format!("boot=live components live-media-path=/{}/live", dir),
+        (_, "") => format!("root=live:CDLABEL={} rd.live.image", volume_id),
+        (_, dir) => format!("root=live:CDLABEL={} rd.live.image rd.live.dir=/{}/LiveOS", volume_id, dir),
+    };
+    if let Some(label) = flash::persistence_label(profile)? {
+        cmdline.push(' ');
+        cmdline.push_str(&flash::persistence_args(package_manager, &label));
+    }
+    let overlay = overlayroot::live_args(profile)?;
+    if !overlay.is_empty() {
+        cmdline.push(' ');
+        cmdline.push_str(overlay);
+    }
+    if profile.plymouth_theme.is_some() {
+        cmdline.push(' ');
+        cmdline.push_str(splash_args(profile));
+    }
+    if let Some(args) = kernel_cmdline(profile)? {
+        cmdline.push(' ');
+        cmdline.push_str(args);
+    }
+    Ok(cmdline)
+}
+
+/// Boot menu entries of live media, as title suffix and kernel arguments: the live system,
+/// the same after a media check with [iso] media_check, loaded entirely into RAM with toram
+/// so the medium can be removed, with each [accessibility] tool turned on, and one per
+/// [[boot_entries]].
+pub fn live_entries(profile: &Profile, volume_id: &str, dir: &str) -> Result<Vec<(String, String)>> {
+    let cmdline = live_cmdline(profile, volume_id, dir)?;
+    let mut entries = vec![(String::new(), cmdline.clone())];
+    // Second, like the "Test this media" entries of the Fedora and Ubuntu ISOs
+    if let Some(check) = media_check(profile)? {
+        entries.push((" (check media)".to_string(), format!("{} {}", cmdline, check)));
+    }
+    if profile.toram {
+        let toram = match crate::package_manager(profile)? {
+            PackageManager::Apt => "toram",
+            _ => "rd.live.ram=1",
+        };
+        entries.push((" (load to RAM)".to_string(), format!("{} {}", cmdline, toram)));
+    }
+    for (title, arg) in crate::accessibility::boot_entries(profile) {
+        entries.push((title.to_string(), format!("{} {}", cmdline, arg)));
+    }
+    for entry in boot_entries(profile)? {
+        entries.push((format!(" ({})", entry.title), format!("{} {}", cmdline, entry.cmdline)));
+    }
+    Ok(entries)
+}
+
+/// The kernel argument making the live initramfs verify the media before booting from it, for
+/// [iso] media_check: live-boot checks the sha256sum.txt manifest, dracut the implanted md5.
+pub fn media_check(profile: &Profile) -> Result<Option<&'static str>> {
+    Ok(media_check_checksum(profile)?.map(|checksum| match checksum {
+        "sha256" => "verify-checksums",
+        _ => "rd.live.check",
+    }))
+}
+
+/// The [iso] checksum the media check of [iso] media_check needs embedded in the ISO.
+pub fn media_check_checksum(profile: &Profile) -> Result<Option<&'static str>> {
+    if !profile.iso.as_ref().is_some_and(|iso| iso.media_check) {
+        return Ok(None);
+    }
+    match crate::package_manager(profile)? {
+        PackageManager::Apt => Ok(Some("sha256")),
+        PackageManager::Dnf => Ok(Some("md5")),
+        _ => Err(anyhow::anyhow!("[iso] media_check needs live-boot or Fedora's dracut, which {} doesn't boot with", profile.base)),
+    }
+}
+
+/// Where the live squashfs goes on the ISO for the initramfs to find it.
+pub fn live_squashfs_path(profile: &Profile) -> Result<&'static str> {
+    Ok(match crate::package_manager(profile)? {
+        PackageManager::Apt => "live/filesystem.squashfs",
+        _ => "LiveOS/squashfs.img",
+    })
+}
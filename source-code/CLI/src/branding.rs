@@ -0,0 +1,265 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::Profile;
+
+// Optional [branding] section: how the built system names itself
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BrandingConfig {
+    pub id: Option<String>, // os-release ID, defaults to distro_name in lowercase, e.g. "mydistro"
+    pub pretty_name: Option<String>, // Defaults to "<distro_name> <version>"
+    pub version_codename: Option<String>, // e.g. "aurora"
+    pub homepage: Option<String>, // HOME_URL, also shown in the motd
+    pub support_url: Option<String>,
+    pub bug_report_url: Option<String>,
+    pub logo: Option<String>, // Icon name from the icon theme, e.g. "mydistro-logo"
+    pub motd: Option<String>, // Message of the day, defaults to a welcome line
+    #[serde(default)]
+    pub assets: Option<AssetsConfig>, // [branding.assets] installed and set as the desktop defaults
+}
+
+// Optional [branding.assets] section: files from files/ and theme names set as defaults
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AssetsConfig {
+    pub wallpaper: Option<String>, // Image in files/, e.g. "branding/wallpaper.png"
+    pub logo: Option<String>, // Image in files/, installed under the [branding] logo name
+    pub icon_theme: Option<String>, // e.g. "Papirus"
+    pub gtk_theme: Option<String>, // e.g. "Adwaita-dark"
+    pub plasma_theme: Option<String>, // Plasma global theme, e.g. "org.kde.breezedark.desktop"
+}
+
+// GSettings defaults compiled into the schemas for GNOME, Cinnamon, MATE and Budgie; overrides of
+// schemas that aren't installed are ignored
+const GSCHEMA_OVERRIDE: &str = "/usr/share/glib-2.0/schemas/90_ulb-branding.gschema.override";
+
+/// The os-release ID: lowercase letters, digits and ".", "_", "-" only.
+pub fn id(profile: &Profile, config: &BrandingConfig) -> String {
+    config.id.clone().unwrap_or_else(|| {
+        profile
+            .distro_name
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '-' })
+            .collect()
+    })
+}
+
+pub fn pretty_name(profile: &Profile, config: &BrandingConfig) -> String {
+    config.pretty_name.clone().unwrap_or_else(|| format!("{} {}", profile.distro_name, profile.version))
+}
+
+/// Icon name the logo is installed as and os-release's LOGO points at.
+pub fn logo_name(profile: &Profile, config: &BrandingConfig) -> Option<String> {
+    let has_logo_file = config.assets.as_ref().is_some_and(|assets| assets.logo.is_some());
+    config.logo.clone().or_else(|| has_logo_file.then(|| format!("{}-logo", id(profile, config))))
+}
+
+/// The [branding] settings, once checked.
+pub fn config(profile: &Profile) -> Result<Option<BrandingConfig>> {
+    let Some(config) = profile.branding.clone() else {
+        return Ok(None);
+    };
+    let id = id(profile, &config);
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-')) {
+        return Err(anyhow::anyhow!("Invalid [branding] id: {:?}, use lowercase letters, digits, \".\", \"_\" and \"-\"", id));
+    }
+    // The values end up double-quoted in os-release and lsb-release, which are also sourced by shells
+    let values = [
+        ("distro_name", Some(&profile.distro_name)),
+        ("version", Some(&profile.version)),
+        ("pretty_name", config.pretty_name.as_ref()),
+        ("version_codename", config.version_codename.as_ref()),
+        ("homepage", config.homepage.as_ref()),
+        ("support_url", config.support_url.as_ref()),
+        ("bug_report_url", config.bug_report_url.as_ref()),
+        ("logo", config.logo.as_ref()),
+    ];
+    for (what, value) in values {
+        if value.is_some_and(|value| value.contains(['"', '\\', '$', '`', '\n'])) {
+            return Err(anyhow::anyhow!("[branding] {} can't contain quotes, backslashes, $, backticks or newlines", what));
+        }
+    }
+    if let Some(assets) = &config.assets {
+        for (what, file) in [("wallpaper", &assets.wallpaper), ("logo", &assets.logo)] {
+            if file.as_deref().is_some_and(|file| file.is_empty() || file.split('/').any(|part| part == "..") || file.contains(['\'', '\n'])) {
+                return Err(anyhow::anyhow!("[branding.assets] {} must be a file in files/", what));
+            }
+        }
+        let themes = [("icon_theme", &assets.icon_theme), ("gtk_theme", &assets.gtk_theme), ("plasma_theme", &assets.plasma_theme)];
+        for (what, theme) in themes {
+            if theme.as_deref().is_some_and(|theme| theme.is_empty() || !theme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+' | ' '))) {
+                return Err(anyhow::anyhow!("Invalid [branding.assets] {}: {:?}", what, theme));
+            }
+        }
+    }
+    if config.motd.as_deref().is_some_and(|motd| motd.lines().any(|line| line == "EOF")) {
+        return Err(anyhow::anyhow!("[branding] motd can't contain a line reading EOF"));
+    }
+    Ok(Some(config))
+}
+
+/// Rewrites os-release, lsb-release, issue and motd so the system names itself after the profile.
+/// The base's own ID moves to ID_LIKE, so tools that check for it still recognize the family.
+pub fn apply(profile: &Profile, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    let Some(config) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Applying branding...".yellow());
+
+    let id = id(profile, &config);
+    let pretty_name = pretty_name(profile, &config);
+    let mut os_release = vec![
+        format!("NAME=\"{}\"", profile.distro_name),
+        format!("PRETTY_NAME=\"{}\"", pretty_name),
+        format!("ID={}", id),
+        format!("VERSION=\"{}\"", profile.version),
+        format!("VERSION_ID=\"{}\"", profile.version),
+    ];
+    let optional = [
+        ("VERSION_CODENAME", &config.version_codename),
+        ("HOME_URL", &config.homepage),
+        ("SUPPORT_URL", &config.support_url),
+        ("BUG_REPORT_URL", &config.bug_report_url),
+        ("LOGO", &logo_name(profile, &config)),
+    ];
+    os_release.extend(optional.iter().filter_map(|(key, value)| value.as_ref().map(|value| format!("{}=\"{}\"", key, value))));
+
+    let lsb_release = [
+        format!("DISTRIB_ID=\"{}\"", profile.distro_name),
+        format!("DISTRIB_RELEASE=\"{}\"", profile.version),
+        format!("DISTRIB_CODENAME=\"{}\"", config.version_codename.as_deref().unwrap_or_default()),
+        format!("DISTRIB_DESCRIPTION=\"{}\"", pretty_name),
+    ];
+    let motd = config.motd.clone().unwrap_or_else(|| match &config.homepage {
+        Some(homepage) => format!("Welcome to {}!\n\n{}", pretty_name, homepage),
+        None => format!("Welcome to {}!", pretty_name),
+    });
+
+    // /etc/os-release is a link to /usr/lib/os-release on most bases; both end up pointing at
+    // the new file. Ubuntu's update-motd scripts would print their own banner at login.
+    let script = format!(
+        "set -e\n\
+         BASE_LIKE=$( (. /usr/lib/os-release 2>/dev/null || . /etc/os-release; echo $ID $ID_LIKE) | sed 's/\\b{id}\\b//g' | xargs)\n\
+         mkdir -p /usr/lib\n\
+         rm -f /etc/os-release /usr/lib/os-release\n\
+         cat > /usr/lib/os-release <<'EOF'\n{os_release}\nEOF\n\
+         [ -n \"$BASE_LIKE\" ] && echo \"ID_LIKE=\\\"$BASE_LIKE\\\"\" >> /usr/lib/os-release\n\
+         ln -s ../usr/lib/os-release /etc/os-release\n\
+         cat > /etc/lsb-release <<'EOF'\n{lsb_release}\nEOF\n\
+         cat > /etc/issue <<'EOF'\n{pretty_name} \\n \\l\n\nEOF\n\
+         cat > /etc/issue.net <<'EOF'\n{pretty_name}\nEOF\n\
+         cat > /etc/motd <<'EOF'\n{motd}\nEOF\n\
+         [ -d /etc/update-motd.d ] && chmod -x /etc/update-motd.d/*; true",
+        id = id,
+        os_release = os_release.join("\n"),
+        lsb_release = lsb_release.join("\n"),
+        pretty_name = pretty_name,
+        motd = motd.trim_end(),
+    );
+    crate::run_in_chroot(profile, rootfs, &script, "Branding")?;
+
+    if let Some(assets) = &config.assets {
+        install_assets(profile, &config, assets, files_dir, rootfs)?;
+    }
+    Ok(())
+}
+
+/// Copies a file from files/ to a path in the rootfs.
+fn install_file(files_dir: &Path, file: &str, rootfs: &Path, target: &str) -> Result<()> {
+    let source = files_dir.join(file.trim_start_matches('/'));
+    let target = rootfs.join(target.trim_start_matches('/'));
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    }
+    fs::copy(&source, &target).context(format!("Failed to install {}", source.display()))?;
+    Ok(())
+}
+
+fn extension(file: &str) -> &str {
+    Path::new(file).extension().and_then(|ext| ext.to_str()).unwrap_or("png")
+}
+
+/// Installs the wallpaper and logo where each desktop looks for them and makes them and the
+/// themes the defaults: GSettings overrides for the GNOME family, settings.ini for other GTK
+/// desktops and kdeglobals/plasmarc for Plasma. Users' own settings still win.
+fn install_assets(profile: &Profile, config: &BrandingConfig, assets: &AssetsConfig, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    println!("{}", "Installing branding assets...".yellow());
+    let id = id(profile, config);
+
+    let mut gsettings: Vec<String> = Vec::new();
+    let mut kdeglobals: Vec<String> = Vec::new();
+    let mut plasmarc: Vec<String> = Vec::new();
+    let mut gtk_settings: Vec<String> = Vec::new();
+
+    if let Some(wallpaper) = &assets.wallpaper {
+        let ext = extension(wallpaper);
+        let path = format!("/usr/share/backgrounds/{}/default.{}", id, ext);
+        install_file(files_dir, wallpaper, rootfs, &path)?;
+        // Plasma only offers wallpapers packaged with metadata, in per-resolution image names
+        install_file(files_dir, wallpaper, rootfs, &format!("/usr/share/wallpapers/{}/contents/images/1920x1080.{}", id, ext))?;
+        let metadata = format!("{{\"KPlugin\": {{\"Id\": \"{}\", \"Name\": \"{}\"}}}}\n", id, profile.distro_name);
+        fs::write(rootfs.join(format!("usr/share/wallpapers/{}/metadata.json", id)), metadata).context("Failed to write wallpaper metadata")?;
+
+        gsettings.push(format!(
+            "[org.gnome.desktop.background]\npicture-uri='file://{0}'\npicture-uri-dark='file://{0}'\n\
+             [org.gnome.desktop.screensaver]\npicture-uri='file://{0}'\n\
+             [org.cinnamon.desktop.background]\npicture-uri='file://{0}'\n\
+             [org.mate.background]\npicture-filename='{0}'",
+            path
+        ));
+        plasmarc.push(format!("[Wallpapers]\ndefaultWallpaperTheme={}\ndefaultFileSuffix=.{}\ndefaultWidth=1920\ndefaultHeight=1080", id, ext));
+    }
+    if let (Some(logo), Some(name)) = (&assets.logo, logo_name(profile, config)) {
+        let ext = extension(logo);
+        install_file(files_dir, logo, rootfs, &format!("/usr/share/pixmaps/{}.{}", name, ext))?;
+        let icon_dir = if ext == "svg" { "scalable" } else { "256x256" };
+        install_file(files_dir, logo, rootfs, &format!("/usr/share/icons/hicolor/{}/apps/{}.{}", icon_dir, name, ext))?;
+    }
+
+    let mut interface = Vec::new();
+    if let Some(icon_theme) = &assets.icon_theme {
+        interface.push(format!("icon-theme='{}'", icon_theme));
+        gtk_settings.push(format!("gtk-icon-theme-name={}", icon_theme));
+        kdeglobals.push(format!("[Icons]\nTheme={}", icon_theme));
+    }
+    if let Some(gtk_theme) = &assets.gtk_theme {
+        interface.push(format!("gtk-theme='{}'", gtk_theme));
+        gtk_settings.push(format!("gtk-theme-name={}", gtk_theme));
+    }
+    if !interface.is_empty() {
+        gsettings.push(format!("[org.gnome.desktop.interface]\n{0}\n[org.cinnamon.desktop.interface]\n{0}", interface.join("\n")));
+    }
+    if let Some(plasma_theme) = &assets.plasma_theme {
+        kdeglobals.push(format!("[KDE]\nLookAndFeelPackage={}", plasma_theme));
+    }
+
+    let mut lines = vec!["set -e".to_string()];
+    if !gsettings.is_empty() {
+        fs::create_dir_all(rootfs.join("usr/share/glib-2.0/schemas")).context("Failed to create the GSettings schema directory")?;
+        fs::write(rootfs.join(GSCHEMA_OVERRIDE.trim_start_matches('/')), gsettings.join("\n") + "\n").context("Failed to write the GSettings overrides")?;
+        lines.push("command -v glib-compile-schemas >/dev/null && glib-compile-schemas /usr/share/glib-2.0/schemas; true".to_string());
+    }
+    if !gtk_settings.is_empty() {
+        for gtk in ["etc/gtk-3.0", "etc/gtk-4.0"] {
+            fs::create_dir_all(rootfs.join(gtk)).context(format!("Failed to create /{}", gtk))?;
+            fs::write(rootfs.join(gtk).join("settings.ini"), format!("[Settings]\n{}\n", gtk_settings.join("\n")))
+                .context(format!("Failed to write /{}/settings.ini", gtk))?;
+        }
+    }
+    for (file, groups) in [("kdeglobals", &kdeglobals), ("plasmarc", &plasmarc)] {
+        if !groups.is_empty() {
+            fs::create_dir_all(rootfs.join("etc/xdg")).context("Failed to create /etc/xdg")?;
+            fs::write(rootfs.join("etc/xdg").join(file), groups.join("\n\n") + "\n").context(format!("Failed to write /etc/xdg/{}", file))?;
+        }
+    }
+    if assets.logo.is_some() {
+        lines.push("command -v gtk-update-icon-cache >/dev/null && gtk-update-icon-cache -f /usr/share/icons/hicolor; true".to_string());
+    }
+    if lines.len() == 1 {
+        return Ok(());
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Branding assets")
+}
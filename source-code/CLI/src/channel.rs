@@ -0,0 +1,176 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const METADATA: &str = "channel.toml";
+const SIGNATURE: &str = "channel.toml.asc";
+
+// channel.toml, the file clients poll for updates
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+struct ChannelMetadata {
+    channel: String,
+    latest: Option<String>, // Build ID of the newest published build
+    #[serde(default)]
+    builds: Vec<ChannelBuild>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct ChannelBuild {
+    id: String,
+    file: String,   // Path relative to the channel directory
+    sha256: String,
+    size: u64,
+    published: u64, // Unix timestamp
+    delta_from: Option<String>,   // Build ID the delta applies to
+    delta: Option<String>,        // xdelta3 patch relative to the channel directory
+    delta_sha256: Option<String>,
+}
+
+/// Publishes `artifact` as the newest build of `channel` under `channels_dir`, writing the
+/// checksums, a delta against the previous build (when xdelta3 is available) and the
+/// metadata clients poll. The metadata is detach-signed when `sign_key` is given.
+pub fn publish(channels_dir: &Path, channel: &str, artifact: &Path, sign_key: Option<&str>) -> Result<()> {
+    // The name becomes a directory under channels_dir, and must stay one
+    if channel.is_empty() || matches!(channel, "." | "..") || !channel.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+        return Err(anyhow::anyhow!("Invalid channel name: {:?}, use letters, digits, '.', '_' and '-'", channel));
+    }
+    println!("{}", format!("Publishing {} to channel '{}'...", artifact.display(), channel).yellow());
+
+    if !artifact.is_file() {
+        return Err(anyhow::anyhow!("Artifact not found: {}", artifact.display()));
+    }
+    let file_name = artifact
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Artifact has no valid file name")?
+        .to_string();
+
+    let channel_dir = channels_dir.join(channel);
+    fs::create_dir_all(&channel_dir).context("Failed to create channel directory")?;
+    let metadata_path = channel_dir.join(METADATA);
+    let mut metadata = if metadata_path.exists() {
+        let content = fs::read_to_string(&metadata_path).context("Failed to read channel metadata")?;
+        toml::from_str(&content).context("Failed to parse channel metadata")?
+    } else {
+        ChannelMetadata { channel: channel.to_string(), ..Default::default() }
+    };
+
+    let published = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System clock is before the Unix epoch")?
+        .as_secs();
+    let id = published.to_string();
+    if metadata.builds.iter().any(|b| b.id == id) {
+        return Err(anyhow::anyhow!("Build {} is already published", id));
+    }
+
+    let build_dir = channel_dir.join(&id);
+    fs::create_dir_all(&build_dir).context("Failed to create build directory in channel")?;
+    let target = build_dir.join(&file_name);
+    fs::copy(artifact, &target).context(format!("Failed to copy {}", artifact.display()))?;
+    let sha256 = crate::sha256sum(&target)?;
+    fs::write(build_dir.join("SHA256SUMS"), format!("{}  {}\n", sha256, file_name))
+        .context("Failed to write SHA256SUMS")?;
+
+    let mut build = ChannelBuild {
+        id: id.clone(),
+        file: format!("{}/{}", id, file_name),
+        sha256,
+        size: fs::metadata(&target).context("Failed to stat artifact")?.len(),
+        published,
+        delta_from: None,
+        delta: None,
+        delta_sha256: None,
+    };
+
+    let previous = metadata
+        .latest
+        .as_ref()
+        .and_then(|latest| metadata.builds.iter().find(|b| &b.id == latest));
+    if let Some(previous) = previous {
+        let delta = format!("deltas/{}-{}.xdelta", previous.id, id);
+        if make_delta(&channel_dir.join(&previous.file), &target, &channel_dir.join(&delta))? {
+            build.delta_sha256 = Some(crate::sha256sum(&channel_dir.join(&delta))?);
+            build.delta_from = Some(previous.id.clone());
+            build.delta = Some(delta);
+        }
+    }
+
+    metadata.builds.push(build);
+    metadata.latest = Some(id.clone());
+    let content = toml::to_string(&metadata).context("Failed to serialize channel metadata")?;
+    // A signature of the previous metadata would fail to verify rather than read as unsigned
+    let signature_path = channel_dir.join(SIGNATURE);
+    if signature_path.exists() {
+        fs::remove_file(&signature_path).context("Failed to remove the previous metadata signature")?;
+    }
+    fs::write(&metadata_path, content).context("Failed to write channel metadata")?;
+
+    if let Some(key) = sign_key {
+        sign(&metadata_path, &signature_path, key)?;
+    }
+
+    println!("{}", format!("Published build {} to channel '{}'", id, channel).green());
+    info!("Channel metadata written to {}", metadata_path.display());
+    Ok(())
+}
+
+/// Returns the most recently modified file in `dir`, the default artifact to publish.
+pub fn latest_artifact(dir: &Path) -> Result<PathBuf> {
+    fs::read_dir(dir)
+        .context(format!("Failed to read {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+        .ok_or_else(|| anyhow::anyhow!("No artifacts found in {}. Run 'ulb build' first.", dir.display()))
+}
+
+// Deltas are an optimisation, so a missing xdelta3 only skips them
+fn make_delta(old: &Path, new: &Path, delta: &Path) -> Result<bool> {
+    if Command::new("xdelta3").arg("-V").output().is_err() {
+        println!("{}", "xdelta3 not found, publishing without a delta.".yellow());
+        return Ok(false);
+    }
+    if let Some(parent) = delta.parent() {
+        fs::create_dir_all(parent).context("Failed to create deltas directory")?;
+    }
+    let output = Command::new("xdelta3")
+        .args(["-e", "-f", "-s"])
+        .arg(old)
+        .arg(new)
+        .arg(delta)
+        .output()
+        .context("Failed to run xdelta3")?;
+    if !output.status.success() {
+        error!("Delta generation failed: {}", String::from_utf8_lossy(&output.stderr));
+        return Err(anyhow::anyhow!("Delta generation failed"));
+    }
+    Ok(true)
+}
+
+// Signs into a temporary file first, so a failed signing leaves no signature at all
+fn sign(path: &Path, signature: &Path, key: &str) -> Result<()> {
+    let partial = signature.with_extension("asc.partial");
+    let output = Command::new("gpg")
+        .args(["--batch", "--yes", "--armor", "--detach-sign", "--local-user", key, "--output"])
+        .arg(&partial)
+        .arg(path)
+        .output()
+        .context("Failed to run gpg")?;
+    if !output.status.success() {
+        if partial.exists() {
+            fs::remove_file(&partial).context("Failed to remove the partial signature")?;
+        }
+        error!("Signing failed: {}", String::from_utf8_lossy(&output.stderr));
+        return Err(anyhow::anyhow!("Channel metadata signing failed"));
+    }
+    fs::rename(&partial, signature).context("Failed to write the metadata signature")?;
+    Ok(())
+}
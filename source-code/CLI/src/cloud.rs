@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{disk, PackageManager, Profile};
+
+// Where cloud-init's NoCloud datasource looks for a seed inside the image
+const NOCLOUD_SEED_DIR: &str = "var/lib/cloud/seed/nocloud";
+// Datasource list written for the image, overriding the packaged default
+const DATASOURCE_CFG: &str = "etc/cloud/cloud.cfg.d/90_ulb_datasources.cfg";
+
+// Optional [cloud_init] section: cloud-init for disk images, with a NoCloud seed
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CloudInitConfig {
+    pub user_data: Option<String>, // User-data, e.g. "#cloud-config\nusers: ..."
+    pub meta_data: Option<String>, // Defaults to an instance-id and the hostname
+    pub network_config: Option<String>, // Network config v1/v2, defaults to DHCP on the first NIC
+    pub seed: Option<String>, // "embedded" (default) in the image, or "iso" for a separate cidata seed ISO
+    #[serde(default)]
+    pub datasources: Vec<String>, // datasource_list, defaults to NoCloud plus the clouds in format, then None
+}
+
+/// The [cloud_init] settings, once checked, with the seed kind.
+pub fn cloud_init(profile: &Profile) -> Result<Option<(CloudInitConfig, &'static str)>> {
+    let Some(config) = profile.cloud_init.clone() else {
+        return Ok(None);
+    };
+    let seed = match config.seed.as_deref() {
+        None | Some("embedded") => "embedded",
+        Some("iso") => "iso",
+        Some(seed) => return Err(anyhow::anyhow!("Unsupported [cloud_init] seed: {}. Supported: embedded, iso", seed)),
+    };
+    if !profile.format.iter().any(|f| disk::is_vm_format(f) || is_cloud_format(f)) {
+        return Err(anyhow::anyhow!("[cloud_init] needs a disk image or cloud format"));
+    }
+    if !matches!(profile.init_system.as_str(), "systemd" | "openrc") {
+        return Err(anyhow::anyhow!("[cloud_init] needs init_system = \"systemd\" or \"openrc\""));
+    }
+    if config.user_data.as_deref().is_some_and(|user_data| !user_data.starts_with('#')) {
+        return Err(anyhow::anyhow!("[cloud_init] user_data must start with a header such as #cloud-config"));
+    }
+    if let Some(datasource) = config.datasources.iter().find(|d| d.is_empty() || !d.chars().all(|c| c.is_ascii_alphanumeric())) {
+        return Err(anyhow::anyhow!("Invalid [cloud_init] datasource: {:?}", datasource));
+    }
+    Ok(Some((config, seed)))
+}
+
+/// NoCloud seed files: user-data, meta-data and network-config.
+fn seed_files(profile: &Profile, config: &CloudInitConfig) -> Vec<(&'static str, String)> {
+    let hostname = profile.hostname.clone().unwrap_or_else(|| profile.distro_name.to_lowercase());
+    let mut files = vec![
+        ("user-data", config.user_data.clone().unwrap_or_else(|| "#cloud-config\n".to_string())),
+        (
+            "meta-data",
+            config
+                .meta_data
+                .clone()
+                .unwrap_or_else(|| format!("instance-id: {}-{}\nlocal-hostname: {}\n", profile.distro_name.to_lowercase(), profile.version, hostname)),
+        ),
+    ];
+    if let Some(network_config) = &config.network_config {
+        files.push(("network-config", network_config.clone()));
+    }
+    files
+}
+
+/// Installs cloud-init, limits it to the [cloud_init] datasources, enables its services and
+/// embeds the NoCloud seed unless it ships as a separate ISO.
+pub fn configure_cloud_init(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some((config, seed)) = cloud_init(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Configuring cloud-init...".yellow());
+
+    let datasources = if config.datasources.is_empty() {
+        let mut datasources = vec!["NoCloud".to_string()];
+        for format in &profile.format {
+            let datasource = match format.as_str() {
+                "aws" => "Ec2",
+                "gce" => "GCE",
+                "azure" => "Azure",
+                _ => continue,
+            };
+            datasources.push(datasource.to_string());
+        }
+        datasources.push("None".to_string());
+        datasources
+    } else {
+        config.datasources.clone()
+    };
+    let cfg_path = rootfs.join(DATASOURCE_CFG);
+    crate::write_file(&cfg_path, &format!("datasource_list: [ {} ]\n", datasources.join(", ")))?;
+
+    if seed == "embedded" {
+        let seed_dir = rootfs.join(NOCLOUD_SEED_DIR);
+        fs::create_dir_all(&seed_dir).context("Failed to create the NoCloud seed directory")?;
+        for (name, content) in seed_files(profile, &config) {
+            fs::write(seed_dir.join(name), content).context(format!("Failed to write the NoCloud {}", name))?;
+        }
+    }
+
+    let package_manager = crate::package_manager(profile)?;
+    let enable = match profile.init_system.as_str() {
+        "openrc" => "rc-update add cloud-init-local boot && rc-update add cloud-init default && \
+                     rc-update add cloud-config default && rc-update add cloud-final default",
+        // cloud-init 24.3 split cloud-init.service into cloud-init-main and cloud-init-network
+        _ => "systemctl enable cloud-init-local.service cloud-init.service cloud-config.service cloud-final.service 2>/dev/null || \
+              systemctl enable cloud-init-main.service cloud-init-local.service cloud-init-network.service cloud-config.service cloud-final.service",
+    };
+    let cloud_init_cmd = format!("{} && {}", package_manager.install(&[cloud_init_package(package_manager)]), enable);
+    crate::run_in_chroot(profile, rootfs, &cloud_init_cmd, "cloud-init configuration")
+}
+
+/// Builds the separate NoCloud seed ISO, labelled cidata, when [cloud_init] asks for one.
+pub fn build_seed_iso(profile: &Profile, build_dir: &Path) -> Result<Option<PathBuf>> {
+    let Some((config, "iso")) = cloud_init(profile)? else {
+        return Ok(None);
+    };
+    println!("{}", "Building cloud-init seed ISO...".yellow());
+
+    let seed_dir = Path::new("/tmp/.ulb/seed");
+    if seed_dir.exists() {
+        fs::remove_dir_all(seed_dir).context("Failed to clean the seed directory")?;
+    }
+    fs::create_dir_all(seed_dir).context("Failed to create the seed directory")?;
+    for (name, content) in seed_files(profile, &config) {
+        fs::write(seed_dir.join(name), content).context(format!("Failed to write the seed {}", name))?;
+    }
+    let iso_name = format!("{}-{}-seed.iso", profile.distro_name, profile.version);
+    let seed_cmd = format!(
+        "{} && xorriso -as mkisofs -output /out/{} -volid cidata -joliet -rock /seed",
+        crate::package_manager(profile)?.refresh_and_install(&["xorriso"]),
+        iso_name
+    );
+    let volumes = vec![format!("{}:/seed:z", seed_dir.display()), format!("{}:/out:z", build_dir.display())];
+    crate::run_in_builder(profile, volumes, &seed_cmd, "Seed ISO build")?;
+
+    let iso_path = build_dir.join(iso_name);
+    info!("cloud-init seed ISO built at {}", iso_path.display());
+    Ok(Some(iso_path))
+}
+
+fn cloud_init_package(package_manager: PackageManager) -> String {
+    match package_manager {
+        PackageManager::Portage => "app-emulation/cloud-init".to_string(),
+        _ => "cloud-init".to_string(),
+    }
+}
+
+/// Whether `format` targets a cloud provider's image import.
+pub fn is_cloud_format(format: &str) -> bool {
+    matches!(format, "aws" | "gce" | "azure")
+}
+
+/// Kernel arguments each cloud needs to show the serial console and find its disks.
+pub fn kernel_cmdline(format: &str) -> &'static str {
+    match format {
+        "aws" => "console=tty1 console=ttyS0,115200n8 nvme_core.io_timeout=4294967295",
+        "gce" => "console=ttyS0,38400n8",
+        // Azure attaches the OS disk late on some VM sizes
+        "azure" => "console=tty1 console=ttyS0,115200n8 earlyprintk=ttyS0 rootdelay=300",
+        _ => "",
+    }
+}
+
+/// Installs cloud-init, plus the provider's guest agent where the base packages it, so the
+/// image picks up its SSH keys, hostname and network from the cloud on first boot.
+pub fn install_agents(profile: &Profile, format: &str, rootfs: &Path) -> Result<()> {
+    println!("{}", "Installing cloud agents...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut packages = vec![cloud_init_package(package_manager)];
+    match (format, package_manager) {
+        ("azure", PackageManager::Apt) => packages.push("walinuxagent".to_string()),
+        ("azure", PackageManager::Dnf) => packages.push("WALinuxAgent".to_string()),
+        _ => {}
+    }
+    let mut agent_cmd = package_manager.install(&packages);
+    // cloud-init drives provisioning on Azure, the agent only reports readiness
+    if format == "azure" {
+        agent_cmd.push_str(
+            " && if [ -f /etc/waagent.conf ]; then sed -i 's/^Provisioning.Agent=.*/Provisioning.Agent=cloud-init/' /etc/waagent.conf; fi",
+        );
+    }
+    crate::run_in_chroot(profile, rootfs, &agent_cmd, "Cloud agent installation")
+}
@@ -0,0 +1,143 @@
+use anyhow::{Context, Result};
+use colored::*;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Group (or dconf path) -> key -> value
+pub type Settings = BTreeMap<String, BTreeMap<String, toml::Value>>;
+
+// System database the [dconf] keys are compiled into, read after each user's own
+const DCONF_KEYFILE: &str = "/etc/dconf/db/local.d/00-ulb";
+
+/// A [dconf] value as GVariant text. Strings, booleans, numbers and arrays of them map directly;
+/// { variant = "uint32 300" } passes typed values through as written.
+fn gvariant(value: &toml::Value) -> Result<String> {
+    Ok(match value {
+        toml::Value::String(text) => format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'")),
+        toml::Value::Integer(number) => number.to_string(),
+        toml::Value::Float(number) => number.to_string(),
+        toml::Value::Boolean(flag) => flag.to_string(),
+        toml::Value::Array(items) => format!("[{}]", items.iter().map(gvariant).collect::<Result<Vec<_>>>()?.join(", ")),
+        toml::Value::Table(table) => match (table.len(), table.get("variant")) {
+            (1, Some(toml::Value::String(variant))) => variant.clone(),
+            _ => return Err(anyhow::anyhow!("dconf values can only be tables of the form {{ variant = \"uint32 300\" }}")),
+        },
+        toml::Value::Datetime(_) => return Err(anyhow::anyhow!("dconf values can't be dates")),
+    })
+}
+
+/// A [plasma] value as KConfig text; lists are comma-separated.
+fn kconfig(value: &toml::Value) -> Result<String> {
+    Ok(match value {
+        toml::Value::String(text) => text.clone(),
+        toml::Value::Integer(number) => number.to_string(),
+        toml::Value::Float(number) => number.to_string(),
+        toml::Value::Boolean(flag) => flag.to_string(),
+        toml::Value::Array(items) => items.iter().map(kconfig).collect::<Result<Vec<_>>>()?.join(","),
+        _ => return Err(anyhow::anyhow!("KDE config values can't be tables or dates")),
+    })
+}
+
+/// Checks the [dconf] paths and keys and the [plasma] files, groups and keys, and that every
+/// value converts.
+pub fn check(profile: &Profile) -> Result<()> {
+    let name_chars = |name: &str, extra: &[char]| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') || extra.contains(&c));
+    for (path, keys) in &profile.dconf {
+        if path.starts_with('/') || path.ends_with('/') || !name_chars(path, &['/']) || path.split('/').any(str::is_empty) {
+            return Err(anyhow::anyhow!("Invalid [dconf] path: {:?}, expected e.g. \"org/gnome/desktop/interface\"", path));
+        }
+        for (key, value) in keys {
+            if !name_chars(key, &[]) {
+                return Err(anyhow::anyhow!("Invalid [dconf] key in {}: {:?}", path, key));
+            }
+            let text = gvariant(value).context(format!("[dconf] {}/{}", path, key))?;
+            if text.contains('\n') {
+                return Err(anyhow::anyhow!("[dconf] {}/{} can't contain newlines", path, key));
+            }
+        }
+    }
+    for (file, groups) in &profile.plasma {
+        if !name_chars(file, &[]) {
+            return Err(anyhow::anyhow!("Invalid [plasma] config file: {:?}, expected e.g. \"kdeglobals\"", file));
+        }
+        for (group, keys) in groups {
+            if group.is_empty() || group.contains([']', '[', '\n']) {
+                return Err(anyhow::anyhow!("Invalid [plasma] group in {}: {:?}", file, group));
+            }
+            for (key, value) in keys {
+                if key.is_empty() || key.contains(['=', '\n', '[']) {
+                    return Err(anyhow::anyhow!("Invalid [plasma] key in {}: {:?}", file, key));
+                }
+                if kconfig(value).context(format!("[plasma] {} {}", file, key))?.contains('\n') {
+                    return Err(anyhow::anyhow!("[plasma] {} {} can't contain newlines", file, key));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compiles the [dconf] keys into a system dconf database and appends the [plasma] groups to the
+/// system-wide KDE config in /etc/xdg. Both are defaults, users' own changes still win.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.dconf.is_empty() && profile.plasma.is_empty() {
+        return Ok(());
+    }
+    check(profile)?;
+    println!("{}", "Writing default desktop settings...".yellow());
+
+    for (file, groups) in &profile.plasma {
+        let mut content = String::new();
+        for (group, keys) in groups {
+            // Nested groups are written as "Parent/Child", KConfig wants [Parent][Child]
+            content.push_str(&format!("\n[{}]\n", group.split('/').collect::<Vec<_>>().join("][")));
+            for (key, value) in keys {
+                content.push_str(&format!("{}={}\n", key, kconfig(value)?));
+            }
+        }
+        let path = rootfs.join("etc/xdg").join(file);
+        fs::create_dir_all(rootfs.join("etc/xdg")).context("Failed to create /etc/xdg")?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut config| config.write_all(content.as_bytes()))
+            .context(format!("Failed to write /etc/xdg/{}", file))?;
+    }
+
+    if profile.dconf.is_empty() {
+        return Ok(());
+    }
+    let mut keyfile = String::new();
+    for (path, keys) in &profile.dconf {
+        keyfile.push_str(&format!("[{}]\n", path));
+        for (key, value) in keys {
+            keyfile.push_str(&format!("{}={}\n", key, gvariant(value)?));
+        }
+        keyfile.push('\n');
+    }
+    let keyfile_path = rootfs.join(DCONF_KEYFILE.trim_start_matches('/'));
+    crate::write_file(&keyfile_path, &keyfile)?;
+
+    let package_manager = crate::package_manager(profile)?;
+    let dconf = match package_manager {
+        PackageManager::Apt => "dconf-cli",
+        PackageManager::Portage => "gnome-base/dconf",
+        _ => "dconf",
+    };
+    // The user profile lists the databases dconf reads; GNOME-based bases may ship one already
+    let script = format!(
+        "set -e\n\
+         command -v dconf >/dev/null || {}\n\
+         mkdir -p /etc/dconf/profile\n\
+         [ -f /etc/dconf/profile/user ] || echo 'user-db:user' > /etc/dconf/profile/user\n\
+         grep -qx 'system-db:local' /etc/dconf/profile/user || echo 'system-db:local' >> /etc/dconf/profile/user\n\
+         dconf update",
+        package_manager.install(&[dconf.to_string()])
+    );
+    crate::run_in_chroot(profile, rootfs, &script, "dconf defaults")
+}
@@ -0,0 +1,919 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{board, boot, cloud, overlayroot, repos, secureboot, vagrant, PackageManager, Profile};
+
+// Default size of the EFI system partition, in MiB
+pub const ESP_SIZE: u64 = 512;
+// Size of the separate /boot partition next to an encrypted root
+const BOOT_SIZE: &str = "1GiB";
+// GPT type GUID of the BIOS boot partition GRUB embeds its core image into
+const BIOS_BOOT_TYPE: &str = "21686148-6449-6E6F-744E-656564454649";
+// Discoverable Partitions type GUIDs of the root verity hash partition, per architecture
+const ROOT_VERITY_TYPE_X86_64: &str = "2C7357ED-EBD2-46D9-AEC1-23D51FCCC16C";
+const ROOT_VERITY_TYPE_AARCH64: &str = "DF3300CE-D69F-4C92-978C-9BFB0F38D820";
+
+// Optional [esp] section: the EFI system partition of disk images and the EFI image of ISOs
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct EspConfig {
+    pub size: Option<String>, // e.g. "1GiB"; disk images default to 512MiB, ISOs to their contents plus 8MiB
+    pub label: Option<String>, // FAT volume label, up to 11 characters; disk images default to "ESP"
+    #[serde(default)]
+    pub files: std::collections::BTreeMap<String, String>, // ESP path = rootfs path, e.g. "/EFI/tools/shellx64.efi" = "/usr/share/edk2-shell/x64/Shell.efi"
+}
+
+/// The [esp] settings, once checked, with the size in MiB.
+pub fn esp(profile: &Profile) -> Result<(EspConfig, Option<u64>)> {
+    let config = profile.esp.clone().unwrap_or_default();
+    let size = match &config.size {
+        Some(size) => {
+            let (number, unit) = size.split_at(size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len()));
+            let mib = match (number.parse::<u64>(), unit) {
+                (Ok(number), "M" | "MiB") => number,
+                (Ok(number), "G" | "GiB") => number.checked_mul(1024).ok_or_else(|| anyhow::anyhow!("[esp] size is too large: {}", size))?,
+                _ => return Err(anyhow::anyhow!("Invalid [esp] size: {}, expected e.g. \"512MiB\" or \"2GiB\"", size)),
+            };
+            // FAT32 needs at least 65525 clusters, which is about 33MiB
+            if mib < 34 {
+                return Err(anyhow::anyhow!("[esp] size must be at least 34MiB, got {}", size));
+            }
+            Some(mib)
+        }
+        None => None,
+    };
+    if let Some(label) = &config.label {
+        if label.is_empty() || label.len() > 11 || !label.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+            return Err(anyhow::anyhow!("[esp] label must be 1-11 uppercase letters, digits, _ or -, got {}", label));
+        }
+    }
+    for (target, source) in &config.files {
+        if !target.starts_with('/') || !source.starts_with('/') || target.contains(['\'', ' ']) || source.contains(['\'', ' ']) {
+            return Err(anyhow::anyhow!("[esp] files must map absolute paths without spaces, got {} = {}", target, source));
+        }
+    }
+    Ok((config, size))
+}
+
+/// Shell lines copying the [esp] files from the rootfs at /rootfs into the ESP at `esp_dir`.
+pub fn esp_files_command(profile: &Profile, esp_dir: &str) -> Result<String> {
+    let (config, _) = esp(profile)?;
+    Ok(config
+        .files
+        .iter()
+        .map(|(target, source)| format!("mkdir -p $(dirname {esp_dir}{target}) && cp -r /rootfs{source} {esp_dir}{target}"))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Shell lines packing the ESP tree at `esp_dir` into the FAT image `image` an ISO boots EFI
+/// from, sized by [esp] or to fit, with the [esp] label and files.
+pub fn esp_image_command(profile: &Profile, esp_dir: &str, image: &str) -> Result<String> {
+    let (config, size) = esp(profile)?;
+    let size = match size {
+        Some(size) => format!("{}M", size),
+        None => format!("$(( $(du -sm {} | cut -f1) + 8 ))M", esp_dir),
+    };
+    let label = config.label.map(|label| format!(" -n {}", label)).unwrap_or_default();
+    Ok(format!(
+        "{files}\ntruncate -s {size} {image}\nmkfs.vfat{label} {image}\nmcopy -s -i {image} {esp_dir}/* ::/",
+        files = esp_files_command(profile, esp_dir)?,
+    ))
+}
+
+// Optional [disk] section for disk image formats
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct DiskConfig {
+    pub size: Option<String>, // Image size, e.g. "8G"; defaults to the rootfs size plus headroom
+    pub layout: Option<String>, // "single" (default) or "ab" for two root slots and a data partition
+    pub slot_size: Option<String>, // Size of each root slot with layout = "ab", e.g. "4G"
+    pub data_size: Option<String>, // Minimum size of the data partition with layout = "ab", defaults to "1G"
+    #[serde(default)]
+    pub verity: bool, // Read-only root verified by dm-verity (systemd-boot only)
+    pub encryption: Option<EncryptionConfig>, // LUKS-encrypted root partition
+    pub filesystem: Option<String>, // Root filesystem, "ext4" (default) or "btrfs"
+    #[serde(default)]
+    pub subvolumes: Vec<Subvolume>, // Btrfs [[disk.subvolumes]], defaults to @, @home and @snapshots
+    pub compression: Option<String>, // Btrfs compress= mount option, defaults to "zstd:1"
+}
+
+// [[disk.subvolumes]] entry: a Btrfs subvolume and where it is mounted
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Subvolume {
+    pub name: String, // e.g. "@home"
+    pub path: String, // e.g. "/home"; exactly one subvolume is mounted at "/"
+}
+
+// encryption = { type = "luks2", passphrase_env = "..." } in the [disk] section
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct EncryptionConfig {
+    #[serde(rename = "type")]
+    pub kind: Option<String>, // Only "luks2" for now, the default
+    pub passphrase_env: String, // Host environment variable holding the passphrase
+}
+
+/// The root encryption settings, once checked. The passphrase itself stays in the host
+/// environment and only reaches the build container by variable name.
+pub fn encryption(profile: &Profile) -> Result<Option<EncryptionConfig>> {
+    let Some(config) = profile.disk.as_ref().and_then(|disk| disk.encryption.clone()) else {
+        return Ok(None);
+    };
+    if let Some(kind) = config.kind.as_deref().filter(|kind| *kind != "luks2") {
+        return Err(anyhow::anyhow!("Unsupported encryption type: {}. Supported: luks2", kind));
+    }
+    if is_verity(profile)? || is_ab_layout(profile)? {
+        return Err(anyhow::anyhow!("encryption can't be combined with verity or the ab disk layout"));
+    }
+    let name = &config.passphrase_env;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(anyhow::anyhow!("passphrase_env must name an environment variable, got {:?}", name));
+    }
+    if std::env::var(name).map_or(true, |passphrase| passphrase.is_empty()) {
+        return Err(anyhow::anyhow!("Set the disk encryption passphrase in ${}", name));
+    }
+    Ok(Some(config))
+}
+
+/// The Btrfs subvolumes of the root, parents first, or None for an ext4 root. The "/"
+/// subvolume is made the default, so the system boots without rootflags=subvol= and
+/// snapshot tools can roll back by changing the default.
+pub fn btrfs_subvolumes(profile: &Profile) -> Result<Option<Vec<Subvolume>>> {
+    let Some(config) = profile.disk.as_ref() else {
+        return Ok(None);
+    };
+    match config.filesystem.as_deref() {
+        None | Some("ext4") => return Ok(None),
+        Some("btrfs") => {}
+        Some(filesystem) => return Err(anyhow::anyhow!("Unsupported filesystem: {}. Supported: ext4, btrfs", filesystem)),
+    }
+    if config.verity || is_ab_layout(profile)? {
+        return Err(anyhow::anyhow!("btrfs can't be combined with verity or the ab disk layout"));
+    }
+    let mut subvolumes = if config.subvolumes.is_empty() {
+        [("@", "/"), ("@home", "/home"), ("@snapshots", "/.snapshots")]
+            .iter()
+            .map(|(name, path)| Subvolume { name: name.to_string(), path: path.to_string() })
+            .collect()
+    } else {
+        config.subvolumes.clone()
+    };
+    if let Some(subvolume) = subvolumes.iter().find(|s| !s.path.starts_with('/') || s.name.is_empty() || s.name.contains('/')) {
+        return Err(anyhow::anyhow!("Invalid Btrfs subvolume: {} at {}", subvolume.name, subvolume.path));
+    }
+    if subvolumes.iter().filter(|s| s.path == "/").count() != 1 {
+        return Err(anyhow::anyhow!("Exactly one Btrfs subvolume must be mounted at /"));
+    }
+    subvolumes.sort_by_key(|s| if s.path == "/" { 0 } else { s.path.matches('/').count() });
+    Ok(Some(subvolumes))
+}
+
+/// Installs the userspace tools for the root filesystem into the rootfs, so the booted system
+/// can check, snapshot and resize it.
+pub fn install_filesystem_tools(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if btrfs_subvolumes(profile)?.is_none() {
+        return Ok(());
+    }
+    println!("{}", "Installing Btrfs tools...".yellow());
+    let package_manager = crate::package_manager(profile)?;
+    let packages = vec![btrfs_package(package_manager).to_string()];
+    crate::run_in_chroot(profile, rootfs, &package_manager.install(&packages), "Btrfs tools installation")
+}
+
+fn btrfs_package(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Portage => "sys-fs/btrfs-progs",
+        _ => "btrfs-progs",
+    }
+}
+
+/// Whether the disk images use the A/B layout: two root slots, A booted by default and B
+/// holding the same system, plus a data partition mounted at /data that survives updates.
+pub fn is_ab_layout(profile: &Profile) -> Result<bool> {
+    match profile.disk.as_ref().and_then(|disk| disk.layout.as_deref()) {
+        None | Some("single") => Ok(false),
+        Some("ab") if profile.uki => Err(anyhow::anyhow!("uki can't be combined with the ab disk layout")),
+        Some("ab") => Ok(true),
+        Some(layout) => Err(anyhow::anyhow!("Unsupported disk layout: {}. Supported: single, ab", layout)),
+    }
+}
+
+/// Whether the root partition is sealed with a dm-verity hash tree. The root hash ends up on the
+/// kernel command line, which only stays off the verified filesystem with systemd-boot.
+pub fn is_verity(profile: &Profile) -> Result<bool> {
+    if !profile.disk.as_ref().is_some_and(|disk| disk.verity) {
+        return Ok(false);
+    }
+    if profile.bootloader != "systemd-boot" {
+        return Err(anyhow::anyhow!("verity needs bootloader = \"systemd-boot\""));
+    }
+    if profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("verity needs init_system = \"systemd\""));
+    }
+    if is_ab_layout(profile)? {
+        return Err(anyhow::anyhow!("verity can't be combined with the ab disk layout"));
+    }
+    Ok(true)
+}
+
+/// Whether `format` is the plain raw image or a virtual machine disk converted from it.
+pub fn is_vm_format(format: &str) -> bool {
+    matches!(format, "raw" | "qcow2" | "vmdk" | "vdi" | "vhdx")
+}
+
+/// Builds a disk image set up for a cloud or Vagrant: the provider's agents or user are added
+/// to the rootfs, with the [[repositories]] credentials back while they install, then a raw
+/// image is built and packaged the way the provider imports it.
+pub fn build_provider_image(profile: &Profile, format: &str, rootfs: &Path, files_dir: &Path, build_dir: &Path) -> Result<PathBuf> {
+    repos::with_repositories(profile, files_dir, rootfs, || {
+        if format == "vagrant" {
+            vagrant::prepare_rootfs(profile, rootfs)
+        } else {
+            cloud::install_agents(profile, format, rootfs)?;
+            cloud::configure_cloud_init(profile, rootfs)
+        }
+    })?;
+    let raw = build_raw_image(profile, format, rootfs, build_dir)?;
+    match format {
+        // AWS imports raw snapshots directly
+        "aws" => Ok(raw),
+        "gce" => pack_gce_image(profile, &raw, build_dir),
+        "vagrant" => {
+            vagrant::package_boxes(profile, &raw, build_dir)?;
+            Ok(build_dir.to_path_buf())
+        }
+        format => {
+            let image = convert_image(profile, &raw, format, build_dir)?;
+            fs::remove_file(&raw).context(format!("Failed to remove {}", raw.display()))?;
+            Ok(image)
+        }
+    }
+}
+
+/// Builds a GPT disk image with an ESP and an ext4 root partition, copies the rootfs into it
+/// and installs the bootloader onto the image itself, with the kernel arguments `format` needs.
+/// Returns the path of the .img, which can be written to a disk with dd as is.
+pub fn build_raw_image(profile: &Profile, format: &str, rootfs: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Building disk image...".yellow());
+    if !profile.uefi_support && !profile.bios_support && profile.bootloader != "extlinux" {
+        return Err(anyhow::anyhow!("Must support at least UEFI or BIOS"));
+    }
+    if matches!(profile.bootloader.as_str(), "extlinux" | "refind") && profile.uki {
+        return Err(anyhow::anyhow!("uki needs bootloader = \"grub\" or \"systemd-boot\""));
+    }
+    if profile.bootloader == "systemd-boot" && !profile.uefi_support {
+        return Err(anyhow::anyhow!("systemd-boot needs uefi_support = true"));
+    }
+
+    // Provider images get their own raw image so they never clobber the plain one
+    let image_name = if format == "raw" {
+        format!("{}-{}.img", profile.distro_name, profile.version)
+    } else {
+        format!("{}-{}-{}.img", profile.distro_name, profile.version, format)
+    };
+    let ab = is_ab_layout(profile)?;
+    let verity = is_verity(profile)?;
+    let encryption = encryption(profile)?;
+    let subvolumes = btrfs_subvolumes(profile)?;
+    let uboot = board::uboot(profile)?;
+    let mut size = image_size(profile)?;
+    let (esp_config, esp_size) = esp(profile)?;
+
+    let mut partitions = vec![format!("size={}MiB, type=uefi, name=ESP", esp_size.unwrap_or(ESP_SIZE))];
+    if profile.bios_support {
+        partitions.push(format!("size=1MiB, type={}, name=bios", BIOS_BOOT_TYPE));
+    }
+    // The kernel and initramfs need an unencrypted /boot to unlock the root from
+    if encryption.is_some() {
+        partitions.push(format!("size={}, type=linux, name=boot{}", BOOT_SIZE, legacy_bootable(profile)));
+    }
+    let root_part = partitions.len() + 1;
+    let package_manager = crate::package_manager(profile)?;
+    let mut open_root = String::new();
+    let mut mount_boot = String::new();
+    let mut unlock = String::new();
+    let mut env = Vec::new();
+    if let Some(config) = &encryption {
+        let passphrase = config.passphrase_env.as_str();
+        env.push(passphrase);
+        open_root = format!(
+            r#"printf '%s' "${passphrase}" | cryptsetup luksFormat --type luks2 --batch-mode --key-file=- $ROOT_DEV
+printf '%s' "${passphrase}" | cryptsetup open --key-file=- $ROOT_DEV ulb-root
+LUKS_UUID=$(cryptsetup luksUUID $ROOT_DEV)
+ROOT_DEV=/dev/mapper/ulb-root
+mkfs.ext4 -q -L boot ${{LOOP}}p{boot}"#,
+            boot = root_part - 1,
+        );
+        mount_boot = format!("mkdir -p /mnt/image/boot && mount ${{LOOP}}p{} /mnt/image/boot", root_part - 1);
+        unlock = format!(
+            r#"BOOT_UUID=$(blkid -s UUID -o value ${{LOOP}}p{boot})
+sed -i "1a UUID=$BOOT_UUID /boot ext4 defaults 0 2" /mnt/image/etc/fstab
+echo "root UUID=$LUKS_UUID none luks,discard,initramfs" > /mnt/image/etc/crypttab"#,
+            boot = root_part - 1,
+        );
+    }
+
+    let mut make_root = "mkfs.ext4 -q -L root $ROOT_DEV\nmkdir -p /mnt/image && mount $ROOT_DEV /mnt/image".to_string();
+    let mut fstab_root = "printf 'UUID=%s / ext4 defaults 0 1\\n' $ROOT_UUID".to_string();
+    if let Some(subvolumes) = &subvolumes {
+        let compression = profile.disk.as_ref().and_then(|disk| disk.compression.as_deref()).unwrap_or("zstd:1");
+        let options = |subvolume: &Subvolume| format!("subvol={},compress={}", subvolume.name, compression);
+        let mut lines = vec!["mkfs.btrfs -q -L root $ROOT_DEV".to_string(), "mkdir -p /mnt/image && mount $ROOT_DEV /mnt/image".to_string()];
+        lines.extend(subvolumes.iter().map(|s| format!("btrfs subvolume create /mnt/image/{}", s.name)));
+        let root = &subvolumes[0];
+        lines.push(format!("btrfs subvolume set-default /mnt/image/{}", root.name));
+        lines.push("umount /mnt/image".to_string());
+        lines.push(format!("mount -o {} $ROOT_DEV /mnt/image", options(root)));
+        lines.extend(subvolumes[1..].iter().map(|s| {
+            format!("mkdir -p /mnt/image{path} && mount -o {} $ROOT_DEV /mnt/image{path}", options(s), path = s.path)
+        }));
+        make_root = lines.join("\n");
+        fstab_root = subvolumes
+            .iter()
+            .map(|s| format!("printf 'UUID=%s {} btrfs {} 0 0\\n' $ROOT_UUID", s.path, options(s)))
+            .collect::<Vec<_>>()
+            .join("; ");
+    }
+    // Settings only known once the image is mounted (crypttab, the real root filesystem) need
+    // the initramfs rebuilt inside it
+    let initramfs = if encryption.is_some() || subvolumes.is_some() {
+        format!("chroot /mnt/image sh -c '{}'", initramfs_command(package_manager))
+    } else {
+        String::new()
+    };
+    // Slot sizes are worked out in MiB by the shell, before the image is created
+    let mut slots = String::new();
+    let mut slot_b = String::new();
+    let mut seal = String::new();
+    if verity {
+        // The hash tree takes well under 1/64 of the data it covers
+        slots = "ROOT=$(( $(du -sm /rootfs | cut -f1) * 12 / 10 + 256 ))\nHASH=$(( ROOT / 64 + 16 ))".to_string();
+        if profile.disk.as_ref().is_some_and(|disk| disk.size.is_none()) {
+            size = "$(( ROOT + HASH + 1024 ))M".to_string();
+        }
+        partitions.push("size=${ROOT}MiB, type=linux, name=root".to_string());
+        // The hash partition is found by PARTUUID, so other architectures can do with a plain type
+        let verity_type = match crate::target_arch(profile)? {
+            "x86_64" => ROOT_VERITY_TYPE_X86_64,
+            "aarch64" => ROOT_VERITY_TYPE_AARCH64,
+            _ => "linux",
+        };
+        partitions.push(format!("size=${{HASH}}MiB, type={}, name=root-verity", verity_type));
+        // Once formatted the root is never mounted read-write again, or it would stop verifying
+        seal = format!(
+            r#"sed -i "s|^UUID=$ROOT_UUID / ext4 defaults 0 1|/dev/mapper/root / ext4 ro 0 0|" /mnt/image/etc/fstab
+umount -R /mnt/image
+ROOT_HASH=$(veritysetup format ${{LOOP}}p{root} ${{LOOP}}p{hash} | sed -n 's/^Root hash:[[:space:]]*//p')
+ROOT_PARTUUID=$(blkid -s PARTUUID -o value ${{LOOP}}p{root})
+HASH_PARTUUID=$(blkid -s PARTUUID -o value ${{LOOP}}p{hash})
+ROOT_ARGS="root=/dev/mapper/root ro roothash=$ROOT_HASH systemd.verity_root_data=PARTUUID=$ROOT_PARTUUID systemd.verity_root_hash=PARTUUID=$HASH_PARTUUID"
+mount -o ro ${{LOOP}}p{root} /mnt/image && mount ${{LOOP}}p1 /mnt/image/boot/efi
+for fs in dev proc sys; do mount --bind /$fs /mnt/image/$fs; done"#,
+            root = root_part,
+            hash = root_part + 1,
+        );
+    } else if ab {
+        let config = profile.disk.clone().unwrap_or_default();
+        slots = format!(
+            "SLOT={}\nDATA=$(( $(numfmt --from=iec {}) >> 20 ))",
+            match &config.slot_size {
+                Some(slot_size) => format!("$(( $(numfmt --from=iec {}) >> 20 ))", slot_size),
+                None => "$(( $(du -sm /rootfs | cut -f1) * 12 / 10 + 256 ))".to_string(),
+            },
+            config.data_size.as_deref().unwrap_or("1G")
+        );
+        if config.size.is_none() {
+            size = "$(( SLOT * 2 + DATA + 1024 ))M".to_string();
+        }
+        partitions.push(format!("size=${{SLOT}}MiB, type=linux, name=root_a{}", legacy_bootable(profile)));
+        partitions.push("size=${SLOT}MiB, type=linux, name=root_b".to_string());
+        partitions.push("type=linux, name=data".to_string());
+        slot_b = format!(
+            r#"mkfs.ext4 -q -L root_b ${{LOOP}}p{b}
+mkfs.ext4 -q -L data ${{LOOP}}p{data}
+SLOT_B_UUID=$(blkid -s UUID -o value ${{LOOP}}p{b})
+DATA_UUID=$(blkid -s UUID -o value ${{LOOP}}p{data})
+mkdir -p /mnt/image/data && echo "UUID=$DATA_UUID /data ext4 defaults 0 2" >> /mnt/image/etc/fstab
+mkdir -p /mnt/slot_b && mount ${{LOOP}}p{b} /mnt/slot_b
+cp -a /rootfs/. /mnt/slot_b/ && mkdir -p /mnt/slot_b/data /mnt/slot_b/boot/efi
+sed "s/$ROOT_UUID/$SLOT_B_UUID/" /mnt/image/etc/fstab > /mnt/slot_b/etc/fstab
+umount /mnt/slot_b"#,
+            b = root_part + 1,
+            data = root_part + 2,
+        );
+    } else if encryption.is_some() {
+        partitions.push("type=linux, name=root".to_string());
+    } else {
+        partitions.push(format!("type=linux, name=root{}", legacy_bootable(profile)));
+    }
+
+    let mut tools = disk_tools(package_manager).to_vec();
+    if verity {
+        tools.push(veritysetup_package(package_manager));
+    }
+    if subvolumes.is_some() {
+        tools.push(btrfs_package(package_manager));
+    }
+    if profile.bootloader == "refind" {
+        tools.push(boot::refind_package(package_manager)?);
+    }
+    if let Some(config) = &uboot {
+        tools.push(board::uboot_tools_package(package_manager));
+        tools.extend(config.package.as_deref());
+    }
+    if encryption.is_some() {
+        tools.push(match package_manager {
+            PackageManager::Apt => "cryptsetup-bin",
+            PackageManager::Portage => "sys-fs/cryptsetup",
+            _ => "cryptsetup",
+        });
+    }
+    let keys = secureboot::keys(profile)?;
+    let mut sign = String::new();
+    if let Some(keys) = &keys {
+        tools.extend(secureboot::tools(profile, keys)?);
+        sign = secureboot::sign_esp_command(profile, keys, "/mnt/image/boot/efi")?;
+    }
+    let tools = package_manager.refresh_and_install(&tools);
+    let mut label = "label: gpt".to_string();
+    let mut uboot_locate = String::new();
+    let mut uboot_write = String::new();
+    let mut volumes = vec![crate::rootfs_volume(rootfs), format!("{}:/out:z", build_dir.display())];
+    if let Some(config) = &uboot {
+        label = format!("{}\n{}", label, board::uboot_label(config));
+        uboot_locate = board::uboot_locate(config);
+        uboot_write = board::uboot_write(config);
+        if let Some(binary) = &config.binary {
+            let binary = fs::canonicalize(binary).context(format!("U-Boot directory {} not found", binary))?;
+            volumes.push(format!("{}:/uboot:ro,z", binary.display()));
+        }
+    }
+    volumes.extend(keys.iter().flat_map(|keys| keys.volumes()));
+    let disk_cmd = format!(
+        r#"set -e
+{tools}
+{slots}
+{uboot_locate}
+IMG=/out/{image}
+rm -f $IMG && truncate -s {size} $IMG
+sfdisk $IMG <<EOF
+{label}
+{partitions}
+EOF
+LOOP=$(losetup --find --show --partscan $IMG)
+trap 'umount /mnt/slot_b 2>/dev/null; umount -R /mnt/image 2>/dev/null; cryptsetup close ulb-root 2>/dev/null; losetup -d $LOOP' EXIT
+mkfs.vfat -F 32 -n {esp_label} ${{LOOP}}p1
+ROOT_DEV=${{LOOP}}p{root}
+{open_root}
+{make_root}
+{mount_boot}
+cp -a /rootfs/. /mnt/image/
+mkdir -p /mnt/image/boot/efi && mount ${{LOOP}}p1 /mnt/image/boot/efi
+for fs in dev proc sys; do mount --bind /$fs /mnt/image/$fs; done
+ROOT_UUID=$(blkid -s UUID -o value $ROOT_DEV)
+ESP_UUID=$(blkid -s UUID -o value ${{LOOP}}p1)
+{{ {fstab_root}; printf 'UUID=%s /boot/efi vfat umask=0077 0 2\n' $ESP_UUID; }} > /mnt/image/etc/fstab
+{unlock}
+{initramfs}
+{slot_b}
+{seal}
+{bootloader}
+{esp_files}
+{sign}
+{uboot_write}
+"#,
+        esp_label = esp_config.label.as_deref().unwrap_or("ESP"),
+        esp_files = esp_files_command(profile, "/mnt/image/boot/efi")?,
+        tools = tools,
+        open_root = open_root,
+        mount_boot = mount_boot,
+        unlock = unlock,
+        make_root = make_root,
+        fstab_root = fstab_root,
+        initramfs = initramfs,
+        slots = slots,
+        slot_b = slot_b,
+        seal = seal,
+        label = label,
+        uboot_locate = uboot_locate,
+        uboot_write = uboot_write,
+        sign = sign,
+        image = image_name,
+        size = size,
+        partitions = partitions.join("\n"),
+        root = root_part,
+        bootloader = bootloader_command(profile, format)?,
+    );
+    crate::run_in_builder_with_env(profile, volumes, &env, &disk_cmd, "Disk image build")?;
+
+    let image_path = build_dir.join(&image_name);
+    info!("Disk image built at {}", image_path.display());
+    Ok(image_path)
+}
+
+/// Size for `truncate -s`: [disk] size, or a shell expression for the rootfs mounted at
+/// /rootfs with 20% headroom plus room for the boot partitions.
+pub fn image_size(profile: &Profile) -> Result<String> {
+    Ok(match profile.disk.as_ref().and_then(|disk| disk.size.clone()) {
+        Some(size) => size,
+        None => format!("$(( $(du -sm /rootfs | cut -f1) * 12 / 10 + 512 + {} ))M", esp(profile)?.1.unwrap_or(ESP_SIZE)),
+    })
+}
+
+/// Converts the raw image with qemu-img into `format`, next to it in the build directory.
+pub fn convert_image(profile: &Profile, raw: &Path, format: &str, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", format!("Converting disk image to {}...", format).yellow());
+
+    let raw_name = raw
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Disk image has no valid file name")?;
+    let (qemu_format, extension, options) = match format {
+        // Compressed qcow2 is a fraction of the size and still boots and grows as usual
+        "qcow2" => ("qcow2", "qcow2", "-c"),
+        // Hyper-V wants VHDX files on 1 MiB block boundaries
+        "vhdx" => ("vhdx", "vhdx", "-o subformat=dynamic,block_size=1M"),
+        // Azure only accepts fixed VHDs whose size is exactly the raw size
+        "azure" => ("vpc", "vhd", "-o subformat=fixed,force_size"),
+        format => (format, format, ""),
+    };
+    let image_name = format!("{}-{}.{}", profile.distro_name, profile.version, extension);
+    let package_manager = crate::package_manager(profile)?;
+    let convert_cmd = format!(
+        "{} && qemu-img convert {} -f raw -O {} /out/{} /out/{}",
+        package_manager.refresh_and_install(&[qemu_img_package(package_manager)]),
+        options,
+        qemu_format,
+        raw_name,
+        image_name
+    );
+
+    let volumes = vec![format!("{}:/out:z", build_dir.display())];
+    crate::run_in_builder(profile, volumes, &convert_cmd, "Disk image conversion")?;
+
+    let image_path = build_dir.join(&image_name);
+    info!("Disk image converted to {}", image_path.display());
+    Ok(image_path)
+}
+
+// GCE imports a gzipped tarball holding a single sparse disk.raw
+fn pack_gce_image(profile: &Profile, raw: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Packing GCE image...".yellow());
+
+    let raw_name = raw
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Disk image has no valid file name")?;
+    let image_name = format!("{}-{}-gce.tar.gz", profile.distro_name, profile.version);
+    let pack_cmd = format!(
+        "cd /out && mv {} disk.raw && tar --format=oldgnu -Sczf {} disk.raw && rm -f disk.raw",
+        raw_name, image_name
+    );
+    let volumes = vec![format!("{}:/out:z", build_dir.display())];
+    crate::run_in_builder(profile, volumes, &pack_cmd, "GCE image packing")?;
+
+    let image_path = build_dir.join(&image_name);
+    info!("GCE image built at {}", image_path.display());
+    Ok(image_path)
+}
+
+pub fn qemu_img_package(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Apt => "qemu-utils",
+        PackageManager::Portage => "app-emulation/qemu",
+        _ => "qemu-img",
+    }
+}
+
+// Partitioning and filesystem tools for the builder container
+pub fn disk_tools(package_manager: PackageManager) -> &'static [&'static str] {
+    match package_manager {
+        PackageManager::Apt => &["fdisk", "dosfstools", "e2fsprogs"],
+        PackageManager::Portage => &["sys-fs/dosfstools"],
+        _ => &["util-linux", "dosfstools", "e2fsprogs"],
+    }
+}
+
+// Regenerates the initramfs inside the mounted image, for settings only known at image build time
+pub fn initramfs_command(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Apt => "update-initramfs -u -k all",
+        PackageManager::Pacman => "mkinitcpio -P",
+        _ => "dracut -f /boot/initramfs.img $(ls /lib/modules | sort -V | tail -n1)",
+    }
+}
+
+// sfdisk attribute marking the partition U-Boot looks for extlinux.conf on
+fn legacy_bootable(profile: &Profile) -> &'static str {
+    if profile.bootloader == "extlinux" {
+        ", attrs=\"LegacyBIOSBootable\""
+    } else {
+        ""
+    }
+}
+
+// Boot entry lines pointing the bootloader at the device trees copied to DEVICETREE_DIR,
+// in printf format (escaped newlines)
+fn devicetree_lines(config: &board::DevicetreeConfig, dtb_key: &str, overlay_key: &str, prefix: &str) -> String {
+    let path = |file: &String| format!("{}/{}/{}", prefix, board::DEVICETREE_DIR, file);
+    let mut lines = config.dtb.iter().map(|dtb| format!("{} {}\\n", dtb_key, path(dtb))).collect::<String>();
+    if !config.overlays.is_empty() {
+        lines.push_str(&format!("{} {}\\n", overlay_key, config.overlays.iter().map(path).collect::<Vec<_>>().join(" ")));
+    }
+    lines
+}
+
+pub fn veritysetup_package(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Apt => "cryptsetup-bin",
+        PackageManager::Dnf => "veritysetup",
+        PackageManager::Portage => "sys-fs/cryptsetup",
+        _ => "cryptsetup",
+    }
+}
+
+// Installs the bootloader chrooted into the mounted image, with $LOOP and $ROOT_UUID set
+// (plus $SLOT_B_UUID with the ab layout, and $ROOT_ARGS with verity)
+fn bootloader_command(profile: &Profile, format: &str) -> Result<String> {
+    let chroot = "chroot /mnt/image";
+    // kernel_cmdline already sits in the GRUB defaults of the rootfs, the entries ULB writes
+    // itself need all of it
+    let grub_cmdline = [cloud::kernel_cmdline(format), overlayroot::kernel_args(profile)?, boot::splash_args(profile)]
+        .into_iter()
+        .filter(|args| !args.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let cmdline = match boot::kernel_cmdline(profile)? {
+        Some(args) if grub_cmdline.is_empty() => args.to_string(),
+        Some(args) => format!("{} {}", grub_cmdline, args),
+        None => grub_cmdline.clone(),
+    };
+    let id = profile.distro_name.to_lowercase();
+    let ab = is_ab_layout(profile)?;
+    let encrypted = encryption(profile)?.is_some();
+    let root_args = if is_verity(profile)? {
+        "$ROOT_ARGS"
+    } else if encrypted {
+        "root=UUID=$ROOT_UUID rw rd.luks.name=$LUKS_UUID=root"
+    } else {
+        "root=UUID=$ROOT_UUID rw"
+    };
+    // The UKI lands in EFI/Linux, where systemd-boot finds it without a loader entry
+    let uki = if profile.uki {
+        if !profile.uefi_support {
+            return Err(anyhow::anyhow!("uki needs uefi_support = true"));
+        }
+        format!(
+            "export ROOT_UUID ROOT_ARGS LUKS_UUID\n{chroot} bash <<'UKI'\n{}\nUKI",
+            boot::uki_command(&format!("/boot/efi/EFI/Linux/{}.efi", id), &format!("{} {}", root_args, cmdline))
+        )
+    } else {
+        String::new()
+    };
+    let devicetree = board::devicetree(profile)?;
+    if devicetree.as_ref().is_some_and(|dt| dt.dtb.is_none()) {
+        return Err(anyhow::anyhow!("[devicetree] overlays need a dtb to apply to on disk images"));
+    }
+    // Entry file suffix, title suffix, kernel arguments and the filesystem holding the kernel,
+    // for the system (or each ab slot) followed by its [[boot_entries]]
+    let slots: &[(&str, &str, &str, &str)] = if ab {
+        &[
+            ("-a", " (slot A)", "root=UUID=$ROOT_UUID rw", "$ROOT_UUID"),
+            ("-b", " (slot B)", "root=UUID=$SLOT_B_UUID rw", "$SLOT_B_UUID"),
+        ]
+    } else if encrypted {
+        &[("", "", root_args, "$BOOT_UUID")]
+    } else {
+        &[("", "", root_args, "$ROOT_UUID")]
+    };
+    let mut entries = Vec::new();
+    for (suffix, title, args, boot_fs) in slots {
+        entries.push((suffix.to_string(), title.to_string(), args.to_string(), *boot_fs));
+        for (index, entry) in boot::boot_entries(profile)?.iter().enumerate() {
+            entries.push((
+                format!("{}-{}", suffix, index + 1),
+                format!("{} ({})", title, entry.title),
+                format!("{} {}", args, entry.cmdline),
+                *boot_fs,
+            ));
+        }
+    }
+    match profile.bootloader.as_str() {
+        "grub" | "refind" if devicetree.is_some() => Err(anyhow::anyhow!("[devicetree] needs bootloader = \"extlinux\" or \"systemd-boot\"")),
+        "grub" => {
+            // Fedora and EL name the tools grub2-*
+            let mut cmd = format!(
+                "GRUB=grub; {chroot} sh -c 'command -v grub2-install' >/dev/null && GRUB=grub2\n\
+                 mkdir -p /mnt/image/boot/$GRUB"
+            );
+            if !grub_cmdline.is_empty() {
+                cmd.push_str(&format!(
+                    "\nif grep -q '^GRUB_CMDLINE_LINUX=\"' /mnt/image/etc/default/grub 2>/dev/null; then \
+                     sed -i 's|^GRUB_CMDLINE_LINUX=\"|&{grub_cmdline} |' /mnt/image/etc/default/grub; \
+                     else echo 'GRUB_CMDLINE_LINUX=\"{grub_cmdline}\"' >> /mnt/image/etc/default/grub; fi"
+                ));
+            }
+            if encrypted {
+                // /etc/default/grub is sourced by grub-mkconfig, so this appends to whatever is set above
+                cmd.push_str(
+                    r#"
+echo "GRUB_CMDLINE_LINUX=\"\$GRUB_CMDLINE_LINUX rd.luks.name=$LUKS_UUID=root\"" >> /mnt/image/etc/default/grub"#,
+                );
+            }
+            if profile.uefi_support {
+                // --removable puts GRUB at the fallback path, as there are no NVRAM entries to rely on
+                cmd.push_str(&format!(
+                    "\n{chroot} $GRUB-install --target={} --efi-directory=/boot/efi --removable --no-nvram",
+                    crate::grub_efi_target(crate::target_arch(profile)?)
+                ));
+            }
+            if profile.bios_support {
+                cmd.push_str(&format!("\n{chroot} $GRUB-install --target=i386-pc $LOOP"));
+            }
+            if profile.uki {
+                // GRUB can't read a UKI's sections, so it chainloads it from the ESP instead
+                cmd.push_str(&format!(
+                    "\n{uki}\nmkdir -p /mnt/image/etc/grub.d\n\
+                     cat > /mnt/image/etc/grub.d/09_ulb_uki <<EOF\n#!/bin/sh\ncat <<'ENTRY'\n\
+                     menuentry '{name} (UKI)' {{\n  search --no-floppy --fs-uuid --set=root $ESP_UUID\n  chainloader /EFI/Linux/{id}.efi\n}}\n\
+                     ENTRY\nEOF\nchmod +x /mnt/image/etc/grub.d/09_ulb_uki",
+                    name = profile.distro_name,
+                ));
+            }
+            // The ab slots are listed ahead of the generated entries, so slot A stays the default
+            // and `grub-reboot ulb-slot-b` tries the other slot once. [[boot_entries]] of a
+            // single system follow its generated entry.
+            let (script, id_prefix, custom) = if ab {
+                ("08_ulb_slots", "ulb-slot", &entries[..])
+            } else {
+                ("11_ulb_entries", "ulb", &entries[1..])
+            };
+            if !custom.is_empty() {
+                let kernel_dir = if encrypted { "" } else { "/boot" };
+                cmd.push_str(&format!(
+                    "\nKERNEL=$(basename $(ls /mnt/image/boot/vmlinuz* | sort -V | tail -n1))\n\
+                     INITRD=$(basename $(ls /mnt/image/boot/initr* | sort -V | tail -n1))\n\
+                     mkdir -p /mnt/image/etc/grub.d\n\
+                     cat > /mnt/image/etc/grub.d/{script} <<EOF\n#!/bin/sh\ncat <<'ENTRY'\n{entries}ENTRY\nEOF\n\
+                     chmod +x /mnt/image/etc/grub.d/{script}",
+                    entries = custom
+                        .iter()
+                        .map(|(suffix, title, args, boot_fs)| format!(
+                            "menuentry '{name}{title}' --id {id_prefix}{suffix} {{\n  \
+                             search --no-floppy --fs-uuid --set=root {boot_fs}\n  \
+                             linux {kernel_dir}/$KERNEL {args} {cmdline}\n  \
+                             initrd {kernel_dir}/$INITRD\n}}\n",
+                            name = profile.distro_name,
+                        ))
+                        .collect::<String>(),
+                ));
+            }
+            if boot::memtest(profile)? {
+                // On the ESP both builds work whether or not /boot is a separate partition, so
+                // this replaces the entry the memtest86+ package adds itself
+                cmd.push_str(&format!(
+                    "\n{locate}\n\
+                     mkdir -p /mnt/image/boot/efi/EFI/memtest\n\
+                     cp $MEMTEST_BIN /mnt/image/boot/efi/EFI/memtest/memtest.bin && cp $MEMTEST_EFI /mnt/image/boot/efi/EFI/memtest/memtest.efi\n\
+                     rm -f /mnt/image/etc/grub.d/20_memtest86+ && mkdir -p /mnt/image/etc/grub.d\n\
+                     cat > /mnt/image/etc/grub.d/21_ulb_memtest <<EOF\n#!/bin/sh\ncat <<'ENTRY'\n\
+                     menuentry 'Memory test (memtest86+)' {{\n  search --no-floppy --fs-uuid --set=root $ESP_UUID\n  \
+                     if [ \"\\$grub_platform\" = efi ]; then chainloader /EFI/memtest/memtest.efi; else linux16 /EFI/memtest/memtest.bin; fi\n}}\n\
+                     ENTRY\nEOF\nchmod +x /mnt/image/etc/grub.d/21_ulb_memtest",
+                    locate = boot::memtest_locate(),
+                ));
+            }
+            if profile.boot_menu.is_some() {
+                // Appended settings win, /etc/default/grub is sourced as a shell script
+                let menu = boot::boot_menu(profile)?;
+                let mut settings = vec![format!("GRUB_TIMEOUT={}", boot::menu_timeout(profile)?)];
+                settings.extend(menu.default.map(|default| format!("GRUB_DEFAULT={}", default)));
+                settings.extend(menu.theme.map(|theme| format!("GRUB_THEME=\"{}/theme.txt\"", theme)));
+                settings.extend(menu.background.map(|background| format!("GRUB_BACKGROUND=\"{}\"", background)));
+                cmd.push_str(&format!(
+                    "\nprintf '%s\\n' {} >> /mnt/image/etc/default/grub",
+                    settings.iter().map(|setting| format!("'{}'", setting)).collect::<Vec<_>>().join(" ")
+                ));
+            }
+            cmd.push_str(&format!("\n{chroot} $GRUB-mkconfig -o /boot/$GRUB/grub.cfg"));
+            Ok(cmd)
+        }
+        "systemd-boot" if profile.uki => Ok(format!("{chroot} bootctl --esp-path=/boot/efi --no-variables install\n{uki}")),
+        "systemd-boot" => {
+            let mut cmd = format!(
+                "{chroot} bootctl --esp-path=/boot/efi --no-variables install\n\
+                 KERNEL=$(ls /mnt/image/boot/vmlinuz* | sort -V | tail -n1)\n\
+                 INITRD=$(ls /mnt/image/boot/initr* | sort -V | tail -n1)\n\
+                 cp $KERNEL /mnt/image/boot/efi/vmlinuz && cp $INITRD /mnt/image/boot/efi/initrd.img"
+            );
+            let mut fdt = String::new();
+            if let Some(config) = &devicetree {
+                cmd.push_str(&format!("\n{}", board::install_devicetree_command(config, &format!("/mnt/image/boot/efi/{}", board::DEVICETREE_DIR))));
+                fdt = devicetree_lines(config, "devicetree", "devicetree-overlay", "");
+            }
+            for (suffix, title, args, _) in &entries {
+                cmd.push_str(&format!(
+                    "\nprintf 'title {name}{title}\\nlinux /vmlinuz\\ninitrd /initrd.img\\n{fdt}options %s {cmdline}\\n' \"{args}\" \
+                     > /mnt/image/boot/efi/loader/entries/{id}{suffix}.conf",
+                    name = profile.distro_name,
+                ));
+            }
+            let mut names: Vec<String> = entries.iter().map(|(suffix, ..)| format!("{id}{suffix}.conf")).collect();
+            if boot::memtest(profile)? {
+                cmd.push_str(&format!(
+                    "\n{}\ncp $MEMTEST_EFI /mnt/image/boot/efi/memtest.efi\n\
+                     printf 'title Memory test (memtest86+)\\nefi /memtest.efi\\n' > /mnt/image/boot/efi/loader/entries/memtest.conf",
+                    boot::memtest_locate()
+                ));
+                names.push("memtest.conf".to_string());
+            }
+            if ab || profile.boot_menu.is_some() {
+                cmd.push_str(&format!(
+                    "\nprintf 'default %s\\ntimeout %s\\n' {} {} >> /mnt/image/boot/efi/loader/loader.conf",
+                    boot::menu_default(profile, &names)?,
+                    boot::menu_timeout(profile)?
+                ));
+            }
+            Ok(cmd)
+        }
+        "refind" => {
+            if !profile.uefi_support || profile.bios_support {
+                return Err(anyhow::anyhow!("bootloader = \"refind\" needs uefi_support = true and bios_support = false"));
+            }
+            // Kernel and initramfs go on the ESP, which rEFInd reads without extra drivers
+            let mut cmd = "KERNEL=$(ls /mnt/image/boot/vmlinuz* | sort -V | tail -n1)\n\
+                           INITRD=$(ls /mnt/image/boot/initr* | sort -V | tail -n1)\n\
+                           cp $KERNEL /mnt/image/boot/efi/vmlinuz && cp $INITRD /mnt/image/boot/efi/initrd.img\n"
+                .to_string();
+            let menu: Vec<(String, String)> = entries
+                .iter()
+                .map(|(_, title, args, _)| (format!("{}{}", profile.distro_name, title), format!("{} {}", args, cmdline)))
+                .collect();
+            cmd.push_str(&boot::refind_command(profile, "/mnt/image/boot/efi", &menu)?);
+            Ok(cmd)
+        }
+        // U-Boot's distro boot reads extlinux.conf from the partition flagged legacy bootable,
+        // which is the separate /boot when the root is encrypted
+        "extlinux" => {
+            let prefix = if encrypted { "" } else { "/boot" };
+            let labels: Vec<String> = entries.iter().map(|(suffix, ..)| format!("{id}{suffix}")).collect();
+            let mut cmd = format!(
+                "KERNEL=$(basename $(ls /mnt/image/boot/vmlinu[xz]* | sort -V | tail -n1))\n\
+                 INITRD=$(basename $(ls /mnt/image/boot/initr* | sort -V | tail -n1))\n\
+                 mkdir -p /mnt/image/boot/extlinux\n\
+                 printf 'default {default}\\ntimeout {timeout}\\nmenu title {title}\\n' > /mnt/image/boot/extlinux/extlinux.conf",
+                default = boot::menu_default(profile, &labels)?,
+                // extlinux counts in tenths of a second
+                timeout = boot::menu_timeout(profile)? * 10,
+                title = boot::menu_title(profile)?,
+            );
+            let mut fdt = String::new();
+            if let Some(config) = &devicetree {
+                cmd.push_str(&format!("\n{}", board::install_devicetree_command(config, &format!("/mnt/image/boot/{}", board::DEVICETREE_DIR))));
+                fdt = devicetree_lines(config, "  fdt", "  fdtoverlays", prefix);
+            }
+            for (suffix, title, args, _) in &entries {
+                cmd.push_str(&format!(
+                    "\nprintf '\\nlabel {id}{suffix}\\n  menu label {name}{title}\\n  linux {prefix}/%s\\n  initrd {prefix}/%s\\n{fdt}  append %s {cmdline}\\n' \
+                     $KERNEL $INITRD \"{args}\" >> /mnt/image/boot/extlinux/extlinux.conf",
+                    name = profile.distro_name,
+                ));
+            }
+            // boot.scr for boards whose U-Boot runs a script rather than reading extlinux.conf,
+            // booting the first entry with the [devicetree] or else the one U-Boot was built with
+            if profile.board.is_some() {
+                let load = |addr: &str, file: &str| format!("load \\${{devtype}} \\${{devnum}}:\\${{distro_bootpart}} \\${{{addr}}} {prefix}/{file}\n");
+                let mut script = String::new();
+                let mut fdt_addr = "fdtcontroladdr";
+                if let Some(config) = &devicetree {
+                    fdt_addr = "fdt_addr_r";
+                    if let Some(dtb) = &config.dtb {
+                        script.push_str(&load("fdt_addr_r", &format!("{}/{}", board::DEVICETREE_DIR, dtb)));
+                        script.push_str("fdt addr \\${fdt_addr_r}\nfdt resize 65536\n");
+                    }
+                    for overlay in &config.overlays {
+                        script.push_str(&load("fdtoverlay_addr_r", &format!("{}/{}", board::DEVICETREE_DIR, overlay)));
+                        script.push_str("fdt apply \\${fdtoverlay_addr_r}\n");
+                    }
+                }
+                // The initrd goes last, so $filesize is its size
+                script.push_str(&load("kernel_addr_r", "$KERNEL"));
+                script.push_str(&load("ramdisk_addr_r", "$INITRD"));
+                cmd.push_str(&format!(
+                    "\ncat > /mnt/image/boot/boot.cmd <<EOF\n\
+                     setenv bootargs \"{args} {cmdline}\"\n\
+                     {script}\
+                     booti \\${{kernel_addr_r}} \\${{ramdisk_addr_r}}:\\${{filesize}} \\${{{fdt_addr}}}\n\
+                     EOF\n\
+                     mkimage -A {arch} -O linux -T script -C none -d /mnt/image/boot/boot.cmd /mnt/image/boot/boot.scr",
+                    args = entries[0].2,
+                    arch = board::mkimage_arch(crate::target_arch(profile)?),
+                ));
+            }
+            Ok(cmd)
+        }
+        _ => Err(anyhow::anyhow!("Unsupported bootloader: {}", profile.bootloader)),
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Optional [drivers] section: proprietary drivers and the repositories they come from
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct DriversConfig {
+    #[serde(default)]
+    pub nvidia: bool, // NVIDIA's driver, with nouveau blacklisted
+    pub nvidia_package: Option<String>, // Replaces the base's NVIDIA driver package, e.g. "nvidia-driver-535" on ubuntu
+    #[serde(default)]
+    pub broadcom: bool, // Broadcom's wl Wi-Fi driver, with the open drivers for the same chips blacklisted
+}
+
+/// Whether the [drivers] packages are built through DKMS, which then needs the kernel headers.
+/// RPM Fusion builds them with akmods instead.
+pub fn uses_dkms(profile: &Profile) -> Result<bool> {
+    let config = profile.drivers.clone().unwrap_or_default();
+    Ok((config.nvidia || config.broadcom) && crate::package_manager(profile)? != PackageManager::Dnf)
+}
+
+/// Enables the non-free repositories, installs the [drivers] packages and blacklists the open
+/// drivers they replace. The blacklist lands in the initramfs generated after this.
+pub fn install(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(config) = profile.drivers.as_ref() else {
+        return Ok(());
+    };
+    if !config.nvidia && !config.broadcom {
+        return Ok(());
+    }
+    println!("{}", "Installing proprietary drivers...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    if package_manager == PackageManager::Portage {
+        return Err(anyhow::anyhow!("[drivers] is not supported on the gentoo base"));
+    }
+    let mut packages = Vec::new();
+    if config.nvidia {
+        let nvidia: &[&str] = match (package_manager, profile.base.as_str()) {
+            (PackageManager::Apt, "ubuntu") => &["nvidia-driver-550"],
+            (PackageManager::Apt, _) => &["nvidia-driver", "firmware-misc-nonfree"],
+            (PackageManager::Dnf, _) => &["akmod-nvidia"],
+            (PackageManager::Pacman, _) => &["nvidia-dkms", "nvidia-utils"],
+            _ => &["nvidia"],
+        };
+        match &config.nvidia_package {
+            Some(package) => packages.push(package.clone()),
+            None => packages.extend(nvidia.iter().map(|p| p.to_string())),
+        }
+    }
+    if config.broadcom {
+        packages.push(
+            match (package_manager, profile.base.as_str()) {
+                (PackageManager::Apt, "ubuntu") => "bcmwl-kernel-source",
+                (PackageManager::Apt, _) => "broadcom-sta-dkms",
+                (PackageManager::Dnf, _) => "akmod-wl",
+                _ => "broadcom-wl-dkms",
+            }
+            .to_string(),
+        );
+    }
+
+    let mut lines = vec!["set -e".to_string()];
+    lines.extend(nonfree_repositories_command(profile, package_manager)?);
+    lines.push(package_manager.install(&packages));
+    if package_manager == PackageManager::Dnf {
+        // akmods builds for the running kernel by default, which is the host's in a container
+        lines.push(format!(
+            "KVER=$(ls /lib/modules | sort -V | tail -n1)\n{}\nakmods --force --kernels $KVER",
+            package_manager.install(&["kernel-devel-$KVER".to_string()])
+        ));
+    }
+    let mut blacklist = Vec::new();
+    if config.nvidia {
+        blacklist.extend(["blacklist nouveau", "options nouveau modeset=0", "options nvidia-drm modeset=1"]);
+    }
+    if config.broadcom {
+        blacklist.extend(["blacklist b43", "blacklist bcma", "blacklist ssb", "blacklist brcmsmac"]);
+    }
+    lines.push(format!(
+        "mkdir -p /etc/modprobe.d && printf '%s\\n' '{}' > /etc/modprobe.d/ulb-drivers.conf",
+        blacklist.join("' '")
+    ));
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Proprietary driver installation")
+}
+
+/// Shell lines enabling the non-free repositories: non-free and contrib, restricted and
+/// multiverse, RPM Fusion or void-repo-nonfree.
+pub fn nonfree_repositories_command(profile: &Profile, package_manager: PackageManager) -> Result<Vec<String>> {
+    const RPMFUSION: &str = "https://mirrors.rpmfusion.org";
+    Ok(match (package_manager, profile.base.as_str()) {
+        // debootstrap only enables main
+        (PackageManager::Apt, "ubuntu") => vec![
+            "sed -i -E '/^deb /s/ main$/ main restricted multiverse/' /etc/apt/sources.list".to_string(),
+            "apt-get update".to_string(),
+        ],
+        (PackageManager::Apt, _) => vec![
+            "sed -i -E '/^deb /s/ main$/ main contrib non-free non-free-firmware/' /etc/apt/sources.list".to_string(),
+            "apt-get update".to_string(),
+        ],
+        (PackageManager::Dnf, "fedora") => vec![format!(
+            "dnf install -y {RPMFUSION}/free/fedora/rpmfusion-free-release-$(rpm -E %fedora).noarch.rpm \
+             {RPMFUSION}/nonfree/fedora/rpmfusion-nonfree-release-$(rpm -E %fedora).noarch.rpm"
+        )],
+        (PackageManager::Dnf, _) if !profile.epel => {
+            return Err(anyhow::anyhow!("[drivers] on Enterprise Linux needs epel = true for RPM Fusion"))
+        }
+        (PackageManager::Dnf, _) => vec![format!(
+            "dnf install -y {RPMFUSION}/free/el/rpmfusion-free-release-$(rpm -E %rhel).noarch.rpm \
+             {RPMFUSION}/nonfree/el/rpmfusion-nonfree-release-$(rpm -E %rhel).noarch.rpm"
+        )],
+        (PackageManager::Xbps, _) => vec!["xbps-install -Sy void-repo-nonfree".to_string()],
+        _ => Vec::new(),
+    })
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use colored::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{apps, live, Profile};
+
+/// Converts a profile into a kickstart for Anaconda: locale, keyboard, timezone and hostname,
+/// the first user, services, partitioning hints from [disk], [[repositories]], %packages and a
+/// %post for what kickstart has no command for. Package names are the profile's own, so
+/// profiles of non-dnf bases need them mapped by hand.
+pub fn kickstart(profile: &Profile) -> Result<String> {
+    let mut ks = vec![format!("# {} {}, exported from a ulb profile (base {})", profile.distro_name, profile.version, profile.base)];
+    if crate::package_manager(profile)? != crate::PackageManager::Dnf {
+        ks.push("# The package names below come from a non-dnf base and may differ on Fedora/EL".to_string());
+    }
+    ks.push("text".to_string());
+    ks.push(format!("lang {}", profile.locales.first().map_or("en_US.UTF-8", String::as_str)));
+    if let Some(keymap) = &profile.keymap {
+        ks.push(format!("keyboard --vckeymap={} --xlayouts='{}'", keymap, profile.x11_layout.as_deref().unwrap_or(keymap)));
+    } else if let Some(layout) = &profile.x11_layout {
+        ks.push(format!("keyboard --xlayouts='{}'", layout));
+    }
+    ks.push(format!("timezone {} --utc", profile.timezone.as_deref().unwrap_or("UTC")));
+    let hostname = profile.hostname.as_ref().map(|hostname| format!(" --hostname={}", hostname)).unwrap_or_default();
+    ks.push(format!("network --bootproto=dhcp --activate{}", hostname));
+
+    // The hands-free install user if there is one, otherwise the live user
+    ks.push("rootpw --lock".to_string());
+    if let Some(config) = &profile.unattended {
+        let gecos = config.full_name.as_ref().map(|name| format!(" --gecos=\"{}\"", name)).unwrap_or_default();
+        ks.push(format!("user --name={} --groups=wheel --iscrypted --password={}{}", config.user, config.password_hash, gecos));
+        for key in &config.ssh_keys {
+            ks.push(format!("sshkey --username={} \"{}\"", config.user, key));
+        }
+    } else if let Some(config) = live::config(profile)? {
+        let password = match &config.password {
+            Some(password) => format!(" --plaintext --password={}", password),
+            None => String::new(),
+        };
+        ks.push(format!("user --name={} --groups=wheel{}", config.user, password));
+    }
+
+    let mut services = String::new();
+    if !profile.services_enable.is_empty() {
+        services.push_str(&format!(" --enabled={}", profile.services_enable.join(",")));
+    }
+    if !profile.services_disable.is_empty() {
+        services.push_str(&format!(" --disabled={}", profile.services_disable.join(",")));
+    }
+    if !services.is_empty() {
+        ks.push(format!("services{}", services));
+    }
+
+    let mut bootloader = "bootloader --location=mbr".to_string();
+    if let Some(cmdline) = &profile.kernel_cmdline {
+        bootloader.push_str(&format!(" --append=\"{}\"", cmdline));
+    }
+    ks.push(bootloader);
+    ks.push("zerombr".to_string());
+    let disk = profile.unattended.as_ref().and_then(|config| config.disk.as_ref()).map(|disk| disk.trim_start_matches("/dev/"));
+    ks.push(format!("clearpart --all --initlabel{}", disk.map(|disk| format!(" --drives={}", disk)).unwrap_or_default()));
+    let filesystem = profile.disk.as_ref().and_then(|disk| disk.filesystem.as_deref());
+    let encrypted = profile.disk.as_ref().is_some_and(|disk| disk.encryption.is_some());
+    let kind = match (filesystem, profile.unattended.as_ref().is_some_and(|config| config.lvm)) {
+        (Some("btrfs"), _) => "btrfs",
+        (_, true) => "lvm",
+        _ => "plain",
+    };
+    let mut autopart = format!("autopart --type={}", kind);
+    if kind == "plain" {
+        autopart.push_str(" --fstype=ext4");
+    }
+    if encrypted {
+        // The passphrase stays out of the file, Anaconda asks for it
+        autopart.push_str(" --encrypted --luks-version=luks2");
+    }
+    ks.push(autopart);
+
+    for repo in profile.repositories.iter().filter(|r| r.enabled && !r.url.starts_with("ppa:")) {
+        ks.push(format!("repo --name={} --baseurl={}", repo.name, repo.url));
+    }
+
+    ks.push(String::new());
+    ks.push("%packages".to_string());
+    ks.push("@core".to_string());
+    ks.extend(profile.packages.iter().map(|p| p.replacen('=', "-", 1)));
+    ks.extend(profile.packages_to_remove.iter().map(|p| format!("-{}", p)));
+    ks.push("%end".to_string());
+
+    let mut post = Vec::new();
+    if !profile.services_mask.is_empty() {
+        post.push(format!("systemctl mask {}", profile.services_mask.join(" ")));
+    }
+    if !profile.flatpaks.is_empty() {
+        let (remote_name, remote_url) = apps::flatpak_remote(profile)?;
+        post.push(format!("flatpak remote-add --system --if-not-exists {} {}", remote_name, remote_url));
+        post.push(format!("flatpak install --system -y --noninteractive {} {}", remote_name, profile.flatpaks.join(" ")));
+    }
+    if !post.is_empty() {
+        ks.push(String::new());
+        ks.push("%post".to_string());
+        ks.extend(post);
+        ks.push("%end".to_string());
+    }
+    Ok(ks.join("\n") + "\n")
+}
+
+/// Writes the profile's kickstart to `output`, by default build/kickstart/<profile>.ks.
+pub fn write_kickstart(profile: &Profile, profile_path: &Path, output: Option<PathBuf>, build_root: &Path) -> Result<PathBuf> {
+    let output = output.unwrap_or_else(|| {
+        let stem = profile_path.file_stem().map_or("profile".into(), |stem| stem.to_string_lossy());
+        build_root.join("kickstart").join(format!("{}.ks", stem))
+    });
+    if let Some(parent) = output.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(&output, kickstart(profile)?).context(format!("Failed to write {}", output.display()))?;
+    println!("{}", format!("Kickstart written to {}", output.display()).green());
+    Ok(output)
+}
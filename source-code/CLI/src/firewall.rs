@@ -0,0 +1,187 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Optional [firewall] section: the firewall of the built system and what it lets in
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FirewallConfig {
+    pub backend: Option<String>, // "ufw" (apt default), "firewalld" (dnf default) or "nftables" (default elsewhere)
+    #[serde(default)]
+    pub services: Vec<String>, // Allowed services by name, e.g. ["ssh", "http"]
+    #[serde(default)]
+    pub ports: Vec<String>, // Allowed ports, e.g. ["8080/tcp", "60000-61000/udp"]
+    pub default_zone: Option<String>, // firewalld zone of the interfaces not in [firewall.zones]
+    #[serde(default)]
+    pub zones: BTreeMap<String, Zone>, // [firewall.zones.<name>], firewalld only
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Zone {
+    #[serde(default)]
+    pub interfaces: Vec<String>, // Interfaces in the zone, e.g. ["eth1"]
+    #[serde(default)]
+    pub sources: Vec<String>, // Source networks in the zone, e.g. ["10.0.0.0/8"]
+    #[serde(default)]
+    pub services: Vec<String>,
+    #[serde(default)]
+    pub ports: Vec<String>,
+}
+
+/// The [firewall] settings and backend, once checked.
+pub fn config(profile: &Profile) -> Result<Option<(FirewallConfig, &'static str)>> {
+    let Some(config) = profile.firewall.clone() else {
+        return Ok(None);
+    };
+    let backend = match (config.backend.as_deref(), crate::package_manager(profile)?) {
+        (None, PackageManager::Apt) | (Some("ufw"), _) => "ufw",
+        (None, PackageManager::Dnf) | (Some("firewalld"), _) => "firewalld",
+        (None, _) | (Some("nftables"), _) => "nftables",
+        (Some(backend), _) => return Err(anyhow::anyhow!("Unsupported [firewall] backend: {}. Supported: ufw, firewalld, nftables", backend)),
+    };
+    if backend != "firewalld" && (config.default_zone.is_some() || !config.zones.is_empty()) {
+        return Err(anyhow::anyhow!("[firewall] default_zone and zones need backend = \"firewalld\""));
+    }
+    let services = config.services.iter().chain(config.zones.values().flat_map(|zone| &zone.services));
+    let names = services.chain(config.zones.keys()).chain(&config.default_zone).chain(config.zones.values().flat_map(|zone| &zone.interfaces));
+    if let Some(name) = names.into_iter().find(|name| name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))) {
+        return Err(anyhow::anyhow!("Invalid [firewall] name: {:?}", name));
+    }
+    for port in config.ports.iter().chain(config.zones.values().flat_map(|zone| &zone.ports)) {
+        port_range(port)?;
+    }
+    if let Some(source) = config.zones.values().flat_map(|zone| &zone.sources).find(|s| !s.chars().all(|c| c.is_ascii_hexdigit() || matches!(c, '.' | ':' | '/'))) {
+        return Err(anyhow::anyhow!("Invalid [firewall] source: {:?}", source));
+    }
+    Ok(Some((config, backend)))
+}
+
+/// Splits "8080/tcp" or "60000-61000/udp" into the range and protocol.
+fn port_range(port: &str) -> Result<(&str, &str)> {
+    let invalid = || anyhow::anyhow!("Invalid [firewall] port: {:?}, expected e.g. \"8080/tcp\" or \"60000-61000/udp\"", port);
+    let (range, protocol) = port.split_once('/').ok_or_else(invalid)?;
+    let numbers: Vec<&str> = range.split('-').collect();
+    if numbers.len() > 2 || !numbers.iter().all(|n| n.parse::<u16>().is_ok_and(|n| n > 0)) || !matches!(protocol, "tcp" | "udp") {
+        return Err(invalid());
+    }
+    Ok((range, protocol))
+}
+
+// An nftables ruleset dropping everything inbound but replies, loopback, ICMP and the allowed ports
+fn nftables_ruleset(config: &FirewallConfig) -> Result<String> {
+    let mut rules = vec![
+        "#!/usr/sbin/nft -f".to_string(),
+        "# Generated by ulb from [firewall]".to_string(),
+        "flush ruleset".to_string(),
+        String::new(),
+        "table inet filter {".to_string(),
+        "    chain input {".to_string(),
+        "        type filter hook input priority filter; policy drop;".to_string(),
+        "        ct state established,related accept".to_string(),
+        "        ct state invalid drop".to_string(),
+        "        iif lo accept".to_string(),
+        "        meta l4proto { icmp, ipv6-icmp } accept".to_string(),
+    ];
+    // nft resolves service names from /etc/services, which lists the usual ones for tcp
+    if !config.services.is_empty() {
+        rules.push(format!("        tcp dport {{ {} }} accept", config.services.join(", ")));
+    }
+    for protocol in ["tcp", "udp"] {
+        let mut ranges = Vec::new();
+        for port in &config.ports {
+            let (range, port_protocol) = port_range(port)?;
+            if port_protocol == protocol {
+                ranges.push(range);
+            }
+        }
+        if !ranges.is_empty() {
+            rules.push(format!("        {} dport {{ {} }} accept", protocol, ranges.join(", ")));
+        }
+    }
+    rules.extend(
+        [
+            "    }",
+            "    chain forward {",
+            "        type filter hook forward priority filter; policy drop;",
+            "    }",
+            "    chain output {",
+            "        type filter hook output priority filter; policy accept;",
+            "    }",
+            "}",
+        ]
+        .map(String::from),
+    );
+    Ok(rules.join("\n") + "\n")
+}
+
+/// Installs the [firewall] backend, allows the services and ports (in their zones with
+/// firewalld) and enables it at boot. Everything else inbound is dropped.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some((config, backend)) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Configuring firewall...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let portage = package_manager == PackageManager::Portage;
+    let package = match backend {
+        "ufw" if portage => "net-firewall/ufw",
+        "firewalld" if portage => "net-firewall/firewalld",
+        "nftables" if portage => "net-firewall/nftables",
+        backend => backend,
+    };
+    let mut lines = vec!["set -e".to_string(), package_manager.install(&[package.to_string()])];
+    match backend {
+        // ufw only edits its rule files while inactive, ENABLED=yes turns it on at boot
+        "ufw" => {
+            lines.push("ufw default deny incoming && ufw default allow outgoing".to_string());
+            lines.extend(config.services.iter().map(|service| format!("ufw allow {}", service)));
+            for port in &config.ports {
+                let (range, protocol) = port_range(port)?;
+                lines.push(format!("ufw allow {}/{}", range.replace('-', ":"), protocol));
+            }
+            lines.push("sed -i 's/^ENABLED=.*/ENABLED=yes/' /etc/ufw/ufw.conf".to_string());
+        }
+        "firewalld" => {
+            // The services and ports outside [firewall.zones] go to the default zone
+            let offline = "firewall-offline-cmd";
+            if let Some(zone) = &config.default_zone {
+                lines.push(format!("{0} --get-zones | grep -qw {1} || {0} --new-zone={1}", offline, zone));
+                lines.push(format!("{} --set-default-zone={}", offline, zone));
+            }
+            lines.extend(config.services.iter().map(|service| format!("{} --add-service={}", offline, service)));
+            lines.extend(config.ports.iter().map(|port| format!("{} --add-port={}", offline, port)));
+            for (name, zone) in &config.zones {
+                lines.push(format!("{0} --get-zones | grep -qw {1} || {0} --new-zone={1}", offline, name));
+                let zone_options: Vec<String> = zone
+                    .interfaces
+                    .iter()
+                    .map(|interface| format!("--add-interface={}", interface))
+                    .chain(zone.sources.iter().map(|source| format!("--add-source={}", source)))
+                    .chain(zone.services.iter().map(|service| format!("--add-service={}", service)))
+                    .chain(zone.ports.iter().map(|port| format!("--add-port={}", port)))
+                    .collect();
+                lines.extend(zone_options.iter().map(|option| format!("{} --zone={} {}", offline, name, option)));
+            }
+        }
+        _ => {
+            // Fedora's service loads the sysconfig file, everything else /etc/nftables.conf
+            let path = if package_manager == PackageManager::Dnf { "etc/sysconfig/nftables.conf" } else { "etc/nftables.conf" };
+            let ruleset_path = rootfs.join(path);
+            crate::write_file(&ruleset_path, &nftables_ruleset(&config)?)?;
+            // Gentoo's OpenRC service restores its saved rules instead
+            if portage {
+                lines.push(format!("mkdir -p /var/lib/nftables && cp /{} /var/lib/nftables/rules-save", path));
+            }
+        }
+    }
+    lines.push(match profile.init_system.as_str() {
+        "openrc" => format!("rc-update add {} default", backend),
+        "runit" | "s6" => format!("ln -sf /etc/sv/{} /etc/runit/runsvdir/default/", backend),
+        _ => format!("systemctl enable {}", backend),
+    });
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Firewall configuration")
+}
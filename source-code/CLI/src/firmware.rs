@@ -0,0 +1,135 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Optional [firmware] section: how much of linux-firmware ends up in the image
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FirmwareConfig {
+    #[serde(default = "default_include", deserialize_with = "crate::string_or_list")]
+    pub include: Vec<String>, // "all" (default), "none", or hardware classes, e.g. ["wifi", "gpu", "bluetooth"]
+    #[serde(default)]
+    pub packages: Vec<String>, // Firmware packages installed on top, e.g. "firmware-iwlwifi"
+}
+
+fn default_include() -> Vec<String> {
+    vec!["all".to_string()]
+}
+
+// Files kept under /lib/firmware for each hardware class, as find -path patterns
+const CLASSES: &[(&str, &[&str])] = &[
+    (
+        "wifi",
+        &[
+            "iwlwifi-*", "ath6k/*", "ath9k_htc/*", "ath10k/*", "ath11k/*", "ath12k/*", "ar9170*", "htc_*", "rtlwifi/*",
+            "rtw88/*", "rtw89/*", "brcm/brcmfmac*", "cypress/*", "mediatek/*", "mt7*", "mrvl/*", "libertas/*",
+            "ti-connectivity/*", "regulatory.db*",
+        ],
+    ),
+    ("gpu", &["amdgpu/*", "radeon/*", "i915/*", "xe/*", "nvidia/*"]),
+    ("bluetooth", &["intel/ibt-*", "qca/*", "rtl_bt/*", "brcm/*.hcd", "mediatek/*", "ar3k/*"]),
+];
+
+// CPU microcode is loaded from the same tree and is never trimmed
+const ALWAYS_KEPT: &[&str] = &["amd-ucode/*", "intel-ucode/*"];
+
+/// The [firmware] settings, once checked.
+pub fn config(profile: &Profile) -> Result<Option<FirmwareConfig>> {
+    let Some(config) = profile.firmware.clone() else {
+        return Ok(None);
+    };
+    let classes: Vec<&str> = CLASSES.iter().map(|(class, _)| *class).collect();
+    for include in &config.include {
+        if matches!(include.as_str(), "all" | "none") {
+            if config.include.len() > 1 {
+                return Err(anyhow::anyhow!("[firmware] include = \"{}\" can't be combined with other entries", include));
+            }
+        } else if !classes.contains(&include.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Unknown firmware class: {}. Supported: all, none, {}",
+                include,
+                classes.join(", ")
+            ));
+        }
+    }
+    if crate::package_manager(profile)? == PackageManager::Portage && !config.packages.is_empty() {
+        return Err(anyhow::anyhow!("[firmware] packages is not supported on the gentoo base, it ships a single sys-kernel/linux-firmware"));
+    }
+    Ok(Some(config))
+}
+
+/// Whether the base's bootstrap should leave linux-firmware to [firmware].
+pub fn managed(profile: &Profile) -> bool {
+    profile.firmware.is_some()
+}
+
+/// Installs the base's complete firmware, trims it down to the [firmware] hardware classes and
+/// then installs the explicit firmware packages. Trimming deletes files rather than packages,
+/// since the kernel meta packages of some bases depend on the monolithic linux-firmware.
+pub fn install(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(config) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Installing firmware...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let all = config.include.iter().any(|i| i == "all");
+    let none = config.include.is_empty() || config.include.iter().any(|i| i == "none");
+    let mut lines = vec!["set -e".to_string()];
+    if package_manager == PackageManager::Apt && profile.base != "ubuntu" && (!none || !config.packages.is_empty()) {
+        // Debian's firmware lives in non-free-firmware
+        lines.extend(crate::drivers::nonfree_repositories_command(profile, package_manager)?);
+    }
+    if !none {
+        let complete: &[&str] = match (package_manager, profile.base.as_str()) {
+            (PackageManager::Apt, "ubuntu") => &["linux-firmware"],
+            (PackageManager::Apt, _) => &[
+                "firmware-linux", "firmware-misc-nonfree", "firmware-amd-graphics", "firmware-iwlwifi", "firmware-realtek",
+                "firmware-atheros", "firmware-brcm80211", "firmware-libertas",
+            ],
+            (PackageManager::Portage, _) => &["sys-kernel/linux-firmware"],
+            _ => &["linux-firmware"],
+        };
+        if package_manager == PackageManager::Portage {
+            lines.push(
+                "mkdir -p /etc/portage/package.license && \
+                 echo 'sys-kernel/linux-firmware linux-fw-redistributable' > /etc/portage/package.license/ulb-firmware"
+                    .to_string(),
+            );
+        }
+        lines.push(package_manager.install(&complete.iter().map(|p| p.to_string()).collect::<Vec<_>>()));
+    }
+    if !all {
+        let kept: Vec<String> = CLASSES
+            .iter()
+            .filter(|(class, _)| config.include.iter().any(|i| i == class))
+            .flat_map(|(_, patterns)| patterns.iter())
+            .chain(ALWAYS_KEPT)
+            .map(|pattern| format!("-path \"$FW/{}\"", pattern))
+            .collect();
+        // /lib is a symlink to /usr/lib on most bases; dangling links and empty directories go too
+        lines.push(format!(
+            "FW=$(readlink -f /lib/firmware)\n\
+             if [ -d \"$FW\" ]; then\n\
+             find \"$FW\" -mindepth 1 \\( -type f -o -type l \\) ! \\( {} \\) -delete\n\
+             find \"$FW\" -xtype l -delete\n\
+             find \"$FW\" -mindepth 1 -type d -empty -delete\n\
+             fi",
+            kept.join(" -o ")
+        ));
+    }
+    if !config.packages.is_empty() {
+        // Reinstall, as trimming may have removed the files of packages linux-firmware depends on
+        let packages = config.packages.join(" ");
+        lines.push(match package_manager {
+            PackageManager::Apt => format!("apt install -y --reinstall {}", packages),
+            PackageManager::Dnf => format!("dnf install -y {0} && dnf reinstall -y {0}", packages),
+            PackageManager::Pacman => format!("pacman -S --noconfirm {}", packages),
+            PackageManager::Xbps => format!("xbps-install -Syf {}", packages),
+            PackageManager::Portage => unreachable!("rejected by firmware::config"),
+        });
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Firmware installation")
+}
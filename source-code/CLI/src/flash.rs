@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::{PackageManager, Profile};
+
+/// Filesystem label the live system looks for when [persistence] doesn't name one.
+pub const DEFAULT_LABEL: &str = "persistence";
+
+// Optional [persistence] section: keep changes to the live system on a labelled partition
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PersistenceConfig {
+    pub label: Option<String>, // Label of the persistence filesystem, defaults to "persistence"
+}
+
+/// The persistence label of the live system, or None when every boot starts fresh.
+pub fn persistence_label(profile: &Profile) -> Result<Option<String>> {
+    let Some(config) = profile.persistence.as_ref() else {
+        return Ok(None);
+    };
+    let label = config.label.clone().unwrap_or_else(|| DEFAULT_LABEL.to_string());
+    check_label(&label)?;
+    Ok(Some(label))
+}
+
+// ext4 labels hold 16 bytes, and the label ends up unquoted on the kernel command line
+fn check_label(label: &str) -> Result<()> {
+    if label.is_empty() || label.len() > 16 || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow::anyhow!("Persistence label must be 1-16 letters, digits, - or _, got {:?}", label));
+    }
+    Ok(())
+}
+
+/// Kernel arguments making the live initramfs overlay the persistence filesystem labelled
+/// `label`: live-boot reads persistence.conf from it, dracut keeps an overlayfs upper
+/// directory in /LiveOS/overlay on it.
+pub fn persistence_args(package_manager: PackageManager, label: &str) -> String {
+    match package_manager {
+        PackageManager::Apt => format!("persistence persistence-label={}", label),
+        _ => format!("rd.live.overlay=LABEL={}:/LiveOS/overlay rd.live.overlay.overlayfs=1", label),
+    }
+}
+
+// Whether `source` is `device` or one of its partitions: sda1 of sda, or nvme0n1p1 and
+// mmcblk0p1 of devices whose names end in a digit, but not sdab
+fn is_on_device(source: &str, device: &str) -> bool {
+    let Some(suffix) = source.strip_prefix(device) else {
+        return false;
+    };
+    let number = match suffix.strip_prefix('p') {
+        Some(number) if device.ends_with(|c: char| c.is_ascii_digit()) => number,
+        _ => suffix,
+    };
+    suffix.is_empty() || (!number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Writes `image` to `device` and, with `persistence`, adds a partition labelled `label`
+/// behind it, set up for both live-boot and dracut so it works whatever base the image has.
+/// `size` is the partition size for sfdisk, e.g. "4G"; the rest of the device when None.
+pub fn flash(image: &Path, device: &Path, persistence: bool, size: Option<&str>, label: &str, yes: bool) -> Result<()> {
+    if !image.is_file() {
+        return Err(anyhow::anyhow!("Image not found: {}", image.display()));
+    }
+    let metadata = std::fs::metadata(device).context(format!("Device not found: {}", device.display()))?;
+    if !metadata.file_type().is_block_device() {
+        return Err(anyhow::anyhow!("{} is not a block device", device.display()));
+    }
+    if persistence {
+        check_label(label)?;
+    }
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    let device_name = device.display().to_string();
+    if mounts.lines().any(|line| line.split_whitespace().next().is_some_and(|source| is_on_device(source, &device_name))) {
+        return Err(anyhow::anyhow!("{} is mounted, unmount it first", device.display()));
+    }
+    if !yes && !crate::prompt_bool(&format!("Everything on {} will be erased. Continue? (y/n): ", device.display()))? {
+        return Err(anyhow::anyhow!("Flashing cancelled"));
+    }
+
+    println!("{}", format!("Writing {} to {}...", image.display(), device.display()).yellow());
+    let mut flash_cmd = format!(
+        "set -e\ndd if='{}' of='{}' bs=4M conv=fsync status=progress",
+        image.display(),
+        device.display()
+    );
+    if persistence {
+        // The hybrid ISO's backup GPT sits at the end of the image, not of the device
+        flash_cmd.push_str(&format!(
+            r#"
+DEV='{device}'
+sfdisk --relocate gpt-bak-std $DEV 2>/dev/null || true
+echo ',{size},L' | sfdisk --append --no-reread $DEV
+partprobe $DEV 2>/dev/null || blockdev --rereadpt $DEV
+udevadm settle 2>/dev/null || sleep 2
+PART=$(lsblk -lnpo NAME $DEV | tail -n1)
+mkfs.ext4 -q -F -L {label} $PART
+MNT=$(mktemp -d)
+mount $PART $MNT
+echo '/ union' > $MNT/persistence.conf
+mkdir -p $MNT/LiveOS/overlay $MNT/LiveOS/ovlwork
+umount $MNT && rmdir $MNT"#,
+            device = device.display(),
+            size = size.unwrap_or(""),
+            label = label,
+        ));
+    }
+    let status = Command::new("sh")
+        .args(["-c", &flash_cmd])
+        .status()
+        .context("Failed to run the flash commands")?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("Flashing {} failed", device.display()));
+    }
+    info!("Flashed {} to {}", image.display(), device.display());
+    println!("{}", "Flash completed!".green());
+    Ok(())
+}
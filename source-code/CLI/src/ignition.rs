@@ -0,0 +1,71 @@
+use anyhow::Result;
+use colored::*;
+use std::path::{Path, PathBuf};
+
+use crate::Profile;
+
+/// The config Ignition falls back to when the platform provides none; its dracut module
+/// carries it into the initramfs.
+pub const USER_CONFIG: &str = "/usr/lib/ignition/user.ign";
+// Stamp the Ignition GRUB snippet checks to add ignition.firstboot, removed once it ran
+const FIRSTBOOT_STAMP: &str = "/boot/ignition.firstboot";
+// Ignition and its GRUB snippet
+const PACKAGES: &[&str] = &["ignition", "ignition-grub"];
+// Name of the compiled config in the work directory
+const COMPILED: &str = "user.ign";
+
+/// The butane config, once checked. Ignition provisions on first boot from the initramfs,
+/// which only the atomic (rpm-ostree) images set up for.
+pub fn butane(profile: &Profile) -> Result<Option<&str>> {
+    let Some(file) = profile.butane.as_deref() else {
+        return Ok(None);
+    };
+    if !profile.atomic {
+        return Err(anyhow::anyhow!("butane needs atomic = true"));
+    }
+    if !matches!(Path::new(file).extension().and_then(|ext| ext.to_str()), Some("bu" | "yaml" | "yml")) {
+        return Err(anyhow::anyhow!("butane must be a .bu (or .yaml) file in files/: {}", file));
+    }
+    Ok(Some(file))
+}
+
+/// Kernel arguments pointing Ignition at the embedded config rather than a cloud's.
+pub fn kernel_args(profile: &Profile) -> Result<&'static str> {
+    Ok(if butane(profile)?.is_some() { "ignition.platform.id=metal" } else { "" })
+}
+
+/// Packages the atomic compose adds for Ignition: itself and its GRUB snippet.
+pub fn packages(profile: &Profile) -> Result<&'static [&'static str]> {
+    Ok(if butane(profile)?.is_some() { PACKAGES } else { &[] })
+}
+
+/// dracut arguments of the atomic compose putting Ignition into the initramfs.
+pub fn initramfs_args(profile: &Profile) -> Result<&'static [&'static str]> {
+    Ok(if butane(profile)?.is_some() { &["--add", "ignition"] } else { &[] })
+}
+
+/// Kickstart %post line setting the first boot stamp on the installed system, so Ignition
+/// runs on its first boot.
+pub fn firstboot_command(profile: &Profile) -> Result<Option<String>> {
+    Ok(butane(profile)?.map(|_| format!("touch {}", FIRSTBOOT_STAMP)))
+}
+
+/// Compiles the Butane config to Ignition with `butane --strict` (local files it references
+/// resolve against files/) into `work_dir`, returning the compiled file. The atomic compose
+/// embeds it as the user config, with Ignition in the initramfs and the first boot stamp set.
+pub fn compile(profile: &Profile, files_dir: &Path, work_dir: &Path) -> Result<Option<PathBuf>> {
+    let Some(file) = butane(profile)? else {
+        return Ok(None);
+    };
+    println!("{}", "Compiling Butane config to Ignition...".yellow());
+
+    let compile_cmd = format!(
+        "{} && butane --strict --files-dir /files -o /work/{} /files/{}",
+        crate::package_manager(profile)?.refresh_and_install(&["butane"]),
+        COMPILED,
+        file.trim_start_matches('/')
+    );
+    let volumes = vec![format!("{}:/work:z", work_dir.display()), format!("{}:/files:ro,z", files_dir.display())];
+    crate::run_in_builder(profile, volumes, &compile_cmd, "Butane compile")?;
+    Ok(Some(work_dir.join(COMPILED)))
+}
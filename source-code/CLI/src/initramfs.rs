@@ -0,0 +1,124 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Optional [initramfs] section, applied to dracut, initramfs-tools and mkinitcpio alike
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InitramfsConfig {
+    #[serde(default)]
+    pub drivers: Vec<String>, // Kernel modules always included, e.g. ["nvme", "virtio_blk"]
+    #[serde(default)]
+    pub omit: Vec<String>, // Kernel modules left out, e.g. ["nouveau"]
+    pub compression: Option<String>, // "zstd", "xz", "gzip" or "lz4", defaults to the generator's
+    #[serde(default)]
+    pub hostonly: bool, // Only include what the build container needs; never right for live media
+}
+
+/// The [initramfs] settings, once checked.
+pub fn config(profile: &Profile) -> Result<InitramfsConfig> {
+    let config = profile.initramfs.clone().unwrap_or_default();
+    if let Some(module) = config.drivers.iter().chain(&config.omit).find(|m| m.is_empty() || !m.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')) {
+        return Err(anyhow::anyhow!("Invalid kernel module name in [initramfs]: {:?}", module));
+    }
+    if let Some(compression) = config.compression.as_deref() {
+        if !matches!(compression, "zstd" | "xz" | "gzip" | "lz4") {
+            return Err(anyhow::anyhow!("Unsupported initramfs compression: {}. Supported: zstd, xz, gzip, lz4", compression));
+        }
+    }
+    if config.hostonly && profile.format.iter().any(|f| f == "iso") {
+        return Err(anyhow::anyhow!("[initramfs] hostonly can't boot live media, the ISO runs on other machines"));
+    }
+    if !config.omit.is_empty() && crate::package_manager(profile)? == PackageManager::Pacman {
+        return Err(anyhow::anyhow!("[initramfs] omit is not supported with mkinitcpio"));
+    }
+    Ok(config)
+}
+
+/// Writes the initramfs generator configuration: the [initramfs] settings, hostonly off unless
+/// asked for (the build container is not the machine the image boots on), and on live media
+/// the modules that find and mount the live image.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let config = config(profile)?;
+    println!("{}", "Configuring initramfs...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let live = profile.format.iter().any(|f| f == "iso");
+    let mut lines = vec!["set -e".to_string()];
+    match package_manager {
+        PackageManager::Apt if live => lines.push(package_manager.install(&["live-boot".to_string()])),
+        // dmsquash-live moved to its own package; it only carries checkisomd5 when that's installed
+        PackageManager::Dnf if live && crate::boot::media_check(profile)?.is_some() => {
+            lines.push(package_manager.install(&["dracut-live".to_string(), "isomd5sum".to_string()]))
+        }
+        PackageManager::Dnf if live => lines.push(package_manager.install(&["dracut-live".to_string()])),
+        _ => {}
+    }
+
+    // dracut also serves the apt and pacman bases once verity or EROFS pull it in
+    let mut dracut = vec![format!("hostonly=\"{}\"", if config.hostonly { "yes" } else { "no" })];
+    if live {
+        dracut.push("add_dracutmodules+=\" dmsquash-live \"".to_string());
+    }
+    if !config.drivers.is_empty() {
+        dracut.push(format!("add_drivers+=\" {} \"", config.drivers.join(" ")));
+    }
+    if !config.omit.is_empty() {
+        dracut.push(format!("omit_drivers+=\" {} \"", config.omit.join(" ")));
+    }
+    if let Some(compression) = &config.compression {
+        dracut.push(format!("compress=\"{}\"", compression));
+    }
+    lines.push(format!("mkdir -p /etc/dracut.conf.d && printf '%s\\n' {} > /etc/dracut.conf.d/ulb.conf", quote_all(&dracut)));
+
+    match package_manager {
+        PackageManager::Apt => {
+            let mut conf = vec![format!("MODULES={}", if config.hostonly { "dep" } else { "most" })];
+            if let Some(compression) = &config.compression {
+                conf.push(format!("COMPRESS={}", compression));
+            }
+            lines.push(format!(
+                "mkdir -p /etc/initramfs-tools/conf.d && printf '%s\\n' {} > /etc/initramfs-tools/conf.d/ulb.conf",
+                quote_all(&conf)
+            ));
+            if !config.drivers.is_empty() {
+                lines.push(format!("printf '%s\\n' {} >> /etc/initramfs-tools/modules", config.drivers.join(" ")));
+            }
+            // initramfs-tools has no exclude list, so a hook drops the modules once they're copied
+            if !config.omit.is_empty() {
+                let names: Vec<String> = config.omit.iter().map(|m| format!("-name \"{}.ko*\"", m)).collect();
+                lines.push(format!(
+                    "mkdir -p /etc/initramfs-tools/hooks && printf '%s\\n' '#!/bin/sh' '[ \"$1\" = prereqs ] && exit 0' \
+                     'find \"${{DESTDIR}}\" \\( {} \\) -delete' > /etc/initramfs-tools/hooks/ulb-omit && \
+                     chmod +x /etc/initramfs-tools/hooks/ulb-omit",
+                    names.join(" -o ")
+                ));
+            }
+        }
+        PackageManager::Pacman => {
+            let mut conf = Vec::new();
+            if !config.drivers.is_empty() {
+                conf.push(format!("MODULES+=({})", config.drivers.join(" ")));
+            }
+            if let Some(compression) = &config.compression {
+                conf.push(format!("COMPRESSION=\"{}\"", compression));
+            }
+            if !conf.is_empty() {
+                lines.push(format!("mkdir -p /etc/mkinitcpio.conf.d && printf '%s\\n' {} > /etc/mkinitcpio.conf.d/ulb.conf", quote_all(&conf)));
+            }
+            // autodetect is what makes mkinitcpio host-only
+            if !config.hostonly {
+                lines.push("sed -i '/^HOOKS=/s/ autodetect//' /etc/mkinitcpio.conf".to_string());
+            }
+        }
+        _ => {}
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Initramfs configuration")
+}
+
+// Single-quoted shell words, for printf '%s\n'
+fn quote_all(lines: &[String]) -> String {
+    lines.iter().map(|line| format!("'{}'", line)).collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,304 @@
+use anyhow::{Context, Result};
+use colored::*;
+use std::fs;
+use std::path::Path;
+
+use crate::{boot, branding, live, netinstall, PackageManager, Profile};
+
+// Calamares reads /etc/calamares before its packaged defaults in /usr/share/calamares
+const CALAMARES_DIR: &str = "etc/calamares";
+
+// The live user's files that must not reach the installed system, and the autologin settings
+// [live] wrote. Commands starting with "-" may fail.
+pub const LIVE_CLEANUP: &[&str] = &[
+    "-rm -f /etc/sudoers.d/ulb-live /etc/systemd/system/getty@tty1.service.d/ulb-live.conf",
+    "-rm -f /etc/sddm.conf.d/ulb-live.conf /etc/lightdm/lightdm.conf.d/50-ulb-live.conf",
+    "-sed -i -e /^AutomaticLogin/d /etc/gdm/custom.conf /etc/gdm3/custom.conf",
+    "-sed -i -e s/--autologin.[a-z0-9_-]*// /etc/inittab",
+];
+
+// Anaconda's kickstart defaults for interactive installs, which live installs are
+const ANACONDA_KICKSTART: &str = "usr/share/anaconda/interactive-defaults.ks";
+
+/// Which installer the live media carries, if any: Calamares on live ISOs of bases that package
+/// it, Anaconda on the dnf bases.
+pub fn check(profile: &Profile) -> Result<Option<&'static str>> {
+    let installer = match profile.installer.as_deref() {
+        None => return Ok(None),
+        Some("calamares") => "calamares",
+        Some("anaconda") => "anaconda",
+        Some(installer) => return Err(anyhow::anyhow!("Unsupported installer: {}. Supported: calamares, anaconda", installer)),
+    };
+    if !profile.format.iter().any(|f| f == "iso") || netinstall::is_netinstall(profile)? {
+        return Err(anyhow::anyhow!("installer = \"{}\" needs a live iso in format", installer));
+    }
+    match (installer, crate::package_manager(profile)?) {
+        ("anaconda", PackageManager::Dnf) => Ok(Some(installer)),
+        ("anaconda", _) => Err(anyhow::anyhow!("installer = \"anaconda\" needs a Fedora or Enterprise Linux base")),
+        (_, PackageManager::Dnf) if profile.base != "fedora" && !profile.epel => {
+            Err(anyhow::anyhow!("installer = \"calamares\" on Enterprise Linux needs epel = true"))
+        }
+        (_, PackageManager::Apt | PackageManager::Dnf | PackageManager::Portage) => Ok(Some(installer)),
+        _ => Err(anyhow::anyhow!("Calamares is not packaged for the {} base", profile.base)),
+    }
+}
+
+/// Puts a launcher for the installer on the [live] user's desktop.
+fn launcher_command(profile: &Profile, exec: &str, icon: &str) -> Result<Option<String>> {
+    Ok(live::config(profile)?.map(|config| {
+        format!(
+            "mkdir -p /home/{0}/Desktop\n\
+             printf '%s\\n' '[Desktop Entry]' 'Type=Application' 'Name=Install {1}' 'Exec={2}' 'Icon={3}' 'Terminal=false' \
+             > /home/{0}/Desktop/install.desktop\n\
+             chmod +x /home/{0}/Desktop/install.desktop && chown -R {0}: /home/{0}/Desktop",
+            config.user,
+            profile.distro_name.replace('\'', ""),
+            exec,
+            icon
+        )
+    }))
+}
+
+/// Calamares' settings.conf: the pages shown and the jobs run, in order.
+fn settings(profile: &Profile, id: &str) -> Result<String> {
+    let package_manager = crate::package_manager(profile)?;
+    let mut exec = vec!["partition", "mount", "unpackfs", "machineid", "fstab", "locale", "keyboard", "localecfg", "users"];
+    if live::config(profile)?.is_some() {
+        exec.extend(["removeuser", "shellprocess"]);
+    }
+    exec.extend(["displaymanager", "packages", "networkcfg", "hwclock"]);
+    match profile.init_system.as_str() {
+        "systemd" => exec.push("services-systemd"),
+        "openrc" => exec.push("services-openrc"),
+        _ => {}
+    }
+    if package_manager == PackageManager::Apt {
+        exec.extend(["initramfscfg", "initramfs"]);
+    } else {
+        exec.push("dracut");
+    }
+    exec.extend(["grubcfg", "bootloader", "umount"]);
+    Ok(format!(
+        "---\n\
+         modules-search: [ local, /usr/lib/calamares/modules, /usr/lib64/calamares/modules ]\n\
+         sequence:\n\
+         - show: [ welcome, locale, keyboard, partition, users, summary ]\n\
+         - exec: [ {} ]\n\
+         - show: [ finished ]\n\
+         branding: {}\n\
+         prompt-install: true\n\
+         dont-chroot: false\n",
+        exec.join(", "),
+        id
+    ))
+}
+
+/// Module configurations differing from Calamares' defaults: where the live image is, how
+/// disks are partitioned, which packages leave with the live session and how GRUB installs.
+fn modules(profile: &Profile, id: &str) -> Result<Vec<(&'static str, String)>> {
+    let package_manager = crate::package_manager(profile)?;
+    let live_medium = match package_manager {
+        PackageManager::Apt => "/run/live/medium",
+        _ => "/run/initramfs/live",
+    };
+    let (_, esp_size) = crate::disk::esp(profile)?;
+    let mut modules = vec![
+        (
+            "unpackfs",
+            format!(
+                "---\nunpack:\n    - source: \"{}/{}\"\n      sourcefs: \"{}\"\n      destination: \"\"\n",
+                live_medium,
+                boot::live_squashfs_path(profile)?,
+                crate::artifacts::live_fs(profile)?
+            ),
+        ),
+        (
+            "partition",
+            format!(
+                "---\nefiSystemPartition: \"/boot/efi\"\nefiSystemPartitionSize: {}M\nuserSwapChoices: [ none, small, file ]\n\
+                 defaultFileSystemType: \"ext4\"\navailableFileSystemTypes: [ \"ext4\", \"btrfs\", \"xfs\" ]\n",
+                esp_size.unwrap_or(crate::disk::ESP_SIZE)
+            ),
+        ),
+    ];
+
+    // The live session's own tools have no place on the installed system
+    let mut remove = vec!["calamares"];
+    match package_manager {
+        PackageManager::Apt => remove.extend(["live-boot", "live-boot-initramfs-tools", "live-config", "live-config-systemd", "live-tools"]),
+        PackageManager::Dnf => remove.push("dracut-live"),
+        _ => {}
+    }
+    let backend = match package_manager {
+        PackageManager::Apt => "apt",
+        PackageManager::Dnf => "dnf",
+        _ => "portage",
+    };
+    modules.push((
+        "packages",
+        format!("---\nbackend: {}\nupdate_db: false\noperations:\n    - try_remove: [ {} ]\n", backend, remove.join(", ")),
+    ));
+
+    let (grub, grub_cfg) = match package_manager {
+        PackageManager::Dnf => ("grub2", "/boot/grub2/grub.cfg"),
+        _ => ("grub", "/boot/grub/grub.cfg"),
+    };
+    modules.push((
+        "bootloader",
+        format!(
+            "---\nefiBootLoader: \"grub\"\ngrubInstall: \"{grub}-install\"\ngrubMkconfig: \"{grub}-mkconfig\"\ngrubCfg: \"{grub_cfg}\"\n\
+             grubProbe: \"{grub}-probe\"\nefiBootMgr: \"efibootmgr\"\nefiBootloaderId: \"{id}\"\ninstallEFIFallback: true\n\
+             kernel: \"/vmlinuz\"\nimg: \"/initramfs.img\"\ntimeout: \"10\"\n"
+        ),
+    ));
+
+    let sudo_group = if package_manager == PackageManager::Apt { "sudo" } else { "wheel" };
+    modules.push((
+        "users",
+        format!(
+            "---\ndefaultGroups:\n    - users\n    - audio\n    - video\n    - {0}\nautologinGroup: autologin\nsudoersGroup: {0}\n\
+             setRootPassword: true\ndoAutologin: false\n",
+            sudo_group
+        ),
+    ));
+
+    if let Some(config) = live::config(profile)? {
+        modules.push(("removeuser", format!("---\nusername: {}\n", config.user)));
+        let script: Vec<String> = LIVE_CLEANUP.iter().map(|command| format!("    - {}\n", crate::quoted(command))).collect();
+        modules.push(("shellprocess", format!("---\ndontChroot: false\ntimeout: 30\nscript:\n{}", script.concat())));
+        if let Some(display_manager) = &config.display_manager {
+            modules.push(("displaymanager", format!("---\ndisplaymanagers:\n    - {}\nbasicSetup: false\n", display_manager)));
+        }
+    }
+    Ok(modules)
+}
+
+/// Calamares' branding.desc: names and links from the profile and [branding]. The images are
+/// filled in by the install script from whichever logo the system has.
+fn branding_desc(profile: &Profile, id: &str) -> String {
+    let config = profile.branding.clone().unwrap_or_default();
+    let pretty_name = branding::pretty_name(profile, &config);
+    let url = |url: &Option<String>| crate::quoted(url.as_deref().unwrap_or_default());
+    format!(
+        "---\ncomponentName: {id}\nwelcomeStyleCalamares: false\nstrings:\n\
+         \x20   productName: {name}\n    shortProductName: {name}\n    version: {version}\n    shortVersion: {version}\n\
+         \x20   versionedName: {pretty}\n    shortVersionedName: {pretty}\n    bootloaderEntryName: {name}\n\
+         \x20   productUrl: {homepage}\n    supportUrl: {support}\n    knownIssuesUrl: {bugs}\n    releaseNotesUrl: {homepage}\n\
+         images:\n    productLogo: \"@LOGO@\"\n    productIcon: \"@LOGO@\"\n    productWelcome: \"@LOGO@\"\n\
+         slideshow: \"show.qml\"\n\
+         style:\n    sidebarBackground: \"#292F34\"\n    sidebarText: \"#FFFFFF\"\n    sidebarTextSelect: \"#292F34\"\n    sidebarTextCurrent: \"#292F34\"\n",
+        id = id,
+        name = crate::quoted(&profile.distro_name),
+        version = crate::quoted(&profile.version),
+        pretty = crate::quoted(&pretty_name),
+        homepage = url(&config.homepage),
+        support = url(&config.support_url),
+        bugs = url(&config.bug_report_url),
+    )
+}
+
+/// Sets up the profile's installer on the live system.
+pub fn install(profile: &Profile, rootfs: &Path) -> Result<()> {
+    match check(profile)? {
+        Some("anaconda") => install_anaconda(profile, rootfs),
+        Some(_) => install_calamares(profile, rootfs),
+        None => Ok(()),
+    }
+}
+
+/// Installs Calamares with the GRUB packages it installs the bootloader from, writes its
+/// settings, module configs and branding, and puts a launcher on the live user's desktop.
+fn install_calamares(profile: &Profile, rootfs: &Path) -> Result<()> {
+    println!("{}", "Installing Calamares...".yellow());
+
+    let config = profile.branding.clone().unwrap_or_default();
+    let id = branding::id(profile, &config);
+    let calamares_dir = rootfs.join(CALAMARES_DIR);
+    let branding_dir = calamares_dir.join("branding").join(&id);
+    fs::create_dir_all(calamares_dir.join("modules")).context("Failed to create the Calamares module directory")?;
+    fs::create_dir_all(&branding_dir).context("Failed to create the Calamares branding directory")?;
+    fs::write(calamares_dir.join("settings.conf"), settings(profile, &id)?).context("Failed to write Calamares settings.conf")?;
+    for (module, content) in modules(profile, &id)? {
+        fs::write(calamares_dir.join("modules").join(format!("{}.conf", module)), content)
+            .context(format!("Failed to write Calamares {}.conf", module))?;
+    }
+    fs::write(branding_dir.join("branding.desc"), branding_desc(profile, &id)).context("Failed to write Calamares branding.desc")?;
+    let slideshow = format!(
+        "import QtQuick 2.0;\nimport calamares.slideshow 1.0;\n\n\
+         Presentation {{\n    Slide {{\n        Text {{\n            anchors.centerIn: parent\n            text: {}\n        }}\n    }}\n}}\n",
+        crate::quoted(&format!("Installing {}", profile.distro_name))
+    );
+    fs::write(branding_dir.join("show.qml"), slideshow).context("Failed to write Calamares show.qml")?;
+
+    let package_manager = crate::package_manager(profile)?;
+    let x86 = crate::target_arch(profile)? == "x86_64";
+    let mut packages: Vec<String> = match package_manager {
+        PackageManager::Portage => vec!["app-admin/calamares", "sys-boot/efibootmgr"],
+        _ => vec!["calamares", "efibootmgr"],
+    }
+    .into_iter()
+    .chain(boot::grub_efi_packages(package_manager, crate::target_arch(profile)?))
+    .map(|p| p.to_string())
+    .collect();
+    match package_manager {
+        PackageManager::Apt if x86 => packages.push("grub-pc-bin".to_string()),
+        PackageManager::Dnf if x86 => packages.extend(["grub2-pc", "grub2-efi-x64", "shim-x64"].map(String::from)),
+        PackageManager::Dnf => packages.extend(["grub2-efi-aa64", "shim-aa64"].map(String::from)),
+        _ => {}
+    }
+    let logo = branding::logo_name(profile, &config).unwrap_or_else(|| "calamares".to_string());
+    let mut lines = vec![
+        "set -e".to_string(),
+        package_manager.install(&packages),
+        format!(
+            "LOGO=$(ls /usr/share/pixmaps/{0}.* /usr/share/icons/hicolor/*/apps/{0}.* /usr/share/icons/hicolor/scalable/apps/calamares.svg 2>/dev/null | head -n1)\n\
+             sed -i \"s|@LOGO@|$LOGO|\" /{1}/branding/{2}/branding.desc",
+            logo, CALAMARES_DIR, id
+        ),
+    ];
+    lines.extend(launcher_command(profile, "pkexec calamares", &logo)?);
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Calamares installation")
+}
+
+/// The interactive-defaults kickstart: the profile's language, keyboard and timezone preselected,
+/// and a %post dropping the live user, its autologin and the installer from the installed system.
+fn anaconda_kickstart(profile: &Profile) -> Result<String> {
+    let mut kickstart = vec!["# Defaults for installs from the live system, generated by ulb".to_string()];
+    if let Some(lang) = profile.locales.first() {
+        kickstart.push(format!("lang {}", lang));
+    }
+    if let Some(keymap) = &profile.keymap {
+        let layout = profile.x11_layout.as_deref().unwrap_or(keymap);
+        kickstart.push(format!("keyboard --vckeymap={} --xlayouts='{}'", keymap, layout));
+    }
+    if let Some(timezone) = &profile.timezone {
+        kickstart.push(format!("timezone {}", timezone));
+    }
+    kickstart.push("firstboot --disable".to_string());
+
+    kickstart.push("\n%post".to_string());
+    if let Some(config) = live::config(profile)? {
+        kickstart.push(format!("userdel -r {} || true", config.user));
+        kickstart.extend(LIVE_CLEANUP.iter().map(|command| format!("{} || true", command.trim_start_matches('-'))));
+    }
+    kickstart.push("rm -f /home/*/Desktop/install.desktop".to_string());
+    kickstart.push("dnf -C remove -y anaconda-live dracut-live || true".to_string());
+    kickstart.push("%end".to_string());
+    Ok(kickstart.join("\n") + "\n")
+}
+
+/// Installs anaconda-live, which installs the running live image the way Fedora's spins do,
+/// replaces its interactive-defaults kickstart and puts liveinst on the live user's desktop.
+fn install_anaconda(profile: &Profile, rootfs: &Path) -> Result<()> {
+    println!("{}", "Installing Anaconda...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut lines = vec!["set -e".to_string(), package_manager.install(&["anaconda-live".to_string()])];
+    lines.extend(launcher_command(profile, "liveinst", "anaconda")?);
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Anaconda installation")?;
+
+    // Written after the install, the package ships its own
+    let kickstart = rootfs.join(ANACONDA_KICKSTART);
+    crate::write_file(&kickstart, &anaconda_kickstart(profile)?)?;
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{drivers, zfs, PackageManager, Profile};
+
+// Where the builder clones and compiles a [kernel.custom] kernel
+const SOURCE_DIR: &str = "/tmp/linux";
+
+// `kernel = "linux-zen"` or a [kernel] section
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct KernelConfig {
+    pub package: Option<String>, // Kernel package instead of the base's, e.g. "linux-zen", "kernel-lt" or a pinned version
+    pub custom: Option<CustomKernel>, // Kernel compiled from source instead of any package
+}
+
+// Optional [kernel.custom] section: a kernel built from a git tree in the builder
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CustomKernel {
+    pub git: String, // Repository URL, e.g. "https://git.kernel.org/pub/scm/linux/kernel/git/stable/linux.git"
+    pub tag: Option<String>, // Tag or branch to build, defaults to the repository's default branch
+    pub config: Option<String>, // .config as a path under files/, defaults to the architecture's defconfig
+    pub localversion: Option<String>, // Appended to the kernel release, e.g. "-acme"
+}
+
+/// Reads `kernel` as either a package name or a [kernel] table.
+pub fn package_or_table<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<KernelConfig>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PackageOrTable {
+        Package(String),
+        Table(KernelConfig),
+    }
+    Ok(Some(match PackageOrTable::deserialize(deserializer)? {
+        PackageOrTable::Package(package) => KernelConfig { package: Some(package), custom: None },
+        PackageOrTable::Table(config) => config,
+    }))
+}
+
+/// The kernel package: the profile's, or the one the base installs by default where its
+/// bootstrap installs one at all. None with [kernel.custom].
+pub fn package(profile: &Profile) -> Result<Option<String>> {
+    let config = profile.kernel.clone().unwrap_or_default();
+    if let Some(kernel) = &config.package {
+        if config.custom.is_some() {
+            return Err(anyhow::anyhow!("[kernel] takes either a package or a [kernel.custom] build, not both"));
+        }
+        if kernel.is_empty() || kernel.contains(['\'', ' ']) {
+            return Err(anyhow::anyhow!("kernel must be a single package, got {:?}", kernel));
+        }
+        return Ok(Some(kernel.clone()));
+    }
+    if config.custom.is_some() {
+        return Ok(None);
+    }
+    Ok(match profile.base.as_str() {
+        "arch" => Some("linux".to_string()),
+        "gentoo" => Some("sys-kernel/gentoo-kernel-bin".to_string()),
+        _ => None,
+    })
+}
+
+/// The [kernel.custom] build, once checked.
+pub fn custom(profile: &Profile) -> Result<Option<&CustomKernel>> {
+    let Some(custom) = profile.kernel.as_ref().and_then(|config| config.custom.as_ref()) else {
+        return Ok(None);
+    };
+    for value in [Some(&custom.git), custom.tag.as_ref(), custom.config.as_ref()].into_iter().flatten() {
+        if value.is_empty() || value.contains(['\'', ' ']) {
+            return Err(anyhow::anyhow!("[kernel.custom] values can't be empty or contain quotes or spaces, got {:?}", value));
+        }
+    }
+    if custom.config.as_ref().is_some_and(|config| config.split('/').any(|part| part == "..")) {
+        return Err(anyhow::anyhow!("[kernel.custom] config must be a path under files/"));
+    }
+    if let Some(localversion) = &custom.localversion {
+        if !localversion.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+')) {
+            return Err(anyhow::anyhow!("[kernel.custom] localversion may only hold letters, digits, -, _, . and +"));
+        }
+    }
+    Ok(Some(custom))
+}
+
+/// Installs the kernel on the bases whose bootstrap doesn't already take it, or builds the
+/// [kernel.custom] one. It has to be the only kernel, as every later stage boots the newest one
+/// in /lib/modules: debootstrap and dnf's @core come without one, and Void's default is removed.
+pub fn install(profile: &Profile, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    if let Some(custom) = custom(profile)? {
+        build_custom(profile, custom, files_dir, rootfs)?;
+        // The initramfs is built for the newest kernel left, so the default has to go first
+        remove_void_default(profile, rootfs, None)?;
+        return custom_initramfs(profile, rootfs);
+    }
+    // Arch and Gentoo bootstrap with the kernel package already
+    let requested = profile.kernel.as_ref().is_some_and(|config| config.package.is_some());
+    if !requested || matches!(profile.base.as_str(), "arch" | "gentoo") {
+        return Ok(());
+    }
+    let Some(kernel) = package(profile)? else {
+        return Ok(());
+    };
+    println!("{}", format!("Installing kernel {}...", kernel).yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let kernel_cmd = match package_manager {
+        // Version specs as in packages: "kernel-lt=6.6.30" becomes "kernel-lt-6.6.30"
+        PackageManager::Dnf => package_manager.install(&[format!("'{}'", kernel.replacen('=', "-", 1))]),
+        _ => package_manager.install(std::slice::from_ref(&kernel)),
+    };
+    crate::run_in_chroot(profile, rootfs, &kernel_cmd, "Kernel installation")?;
+    remove_void_default(profile, rootfs, Some(&kernel))
+}
+
+// base-system pulls in the linux meta package, which would keep bringing the default back
+fn remove_void_default(profile: &Profile, rootfs: &Path, kernel: Option<&str>) -> Result<()> {
+    if profile.base != "void" || kernel.is_some_and(|kernel| crate::package_name(kernel) == "linux") {
+        return Ok(());
+    }
+    crate::run_in_chroot(
+        profile,
+        rootfs,
+        "mkdir -p /etc/xbps.d && echo 'ignorepkg=linux' > /etc/xbps.d/ulb-kernel.conf && xbps-remove -Ry linux",
+        "Default kernel removal",
+    )
+}
+
+// Clones and compiles the kernel in the builder, then installs its image, modules and device
+// trees into the rootfs
+fn build_custom(profile: &Profile, custom: &CustomKernel, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    println!("{}", format!("Building kernel from {}...", custom.git).yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let arch = crate::target_arch(profile)?;
+    let mut volumes = vec![crate::rootfs_volume(rootfs)];
+    let config = match &custom.config {
+        Some(config) => {
+            let path = files_dir.join(config.trim_start_matches('/'));
+            let path = path.canonicalize().context(format!("Kernel config {} not found", path.display()))?;
+            volumes.push(format!("{}:/kernel.config:ro,z", path.display()));
+            "cp /kernel.config .config && make olddefconfig"
+        }
+        None => "make defconfig",
+    };
+    let localversion = match &custom.localversion {
+        Some(localversion) => format!("\n./scripts/config --set-str LOCALVERSION '{}' && make olddefconfig", localversion),
+        None => String::new(),
+    };
+    // Device trees go where the [devicetree] and boot.scr setup look for them
+    let dtbs = if arch == "x86_64" {
+        String::new()
+    } else {
+        "\nmake INSTALL_DTBS_PATH=/rootfs/boot/dtb-$KVER dtbs_install".to_string()
+    };
+    let build_cmd = format!(
+        r#"set -e
+{tools}
+rm -rf {src} && git clone --depth 1 {branch}'{git}' {src}
+cd {src}
+{config}{localversion}
+make -j$(nproc)
+KVER=$(make -s kernelrelease)
+make INSTALL_MOD_PATH=/rootfs INSTALL_MOD_STRIP=1 modules_install
+mkdir -p /rootfs/boot
+cp {image} /rootfs/boot/vmlinuz-$KVER
+cp System.map /rootfs/boot/System.map-$KVER && cp .config /rootfs/boot/config-$KVER{dtbs}"#,
+        tools = package_manager.refresh_and_install(build_packages(package_manager)),
+        src = SOURCE_DIR,
+        branch = custom.tag.as_ref().map(|tag| format!("--branch '{}' ", tag)).unwrap_or_default(),
+        git = custom.git,
+        image = match arch {
+            "x86_64" => "arch/x86/boot/bzImage",
+            "aarch64" => "arch/arm64/boot/Image",
+            _ => "arch/riscv/boot/Image",
+        },
+    );
+    crate::run_in_builder(profile, volumes, &build_cmd, "Kernel build")
+}
+
+// Builds the initramfs of the custom kernel, the only one in /lib/modules by now, the way the
+// base would; mkinitcpio only builds the kernels it has a preset for
+fn custom_initramfs(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let package_manager = crate::package_manager(profile)?;
+    let initramfs = match package_manager {
+        PackageManager::Apt => "update-initramfs -c -k $KVER".to_string(),
+        PackageManager::Pacman => "printf \"ALL_kver='/boot/vmlinuz-%s'\\nPRESETS=('default')\\ndefault_image='/boot/initramfs-%s.img'\\n\" $KVER $KVER \
+                                   > /etc/mkinitcpio.d/ulb-custom.preset && mkinitcpio -p ulb-custom"
+            .to_string(),
+        PackageManager::Portage => format!(
+            "{} && dracut -f /boot/initramfs.img $KVER",
+            package_manager.install(&["sys-kernel/dracut".to_string()])
+        ),
+        _ => "dracut -f /boot/initramfs.img $KVER".to_string(),
+    };
+    let initramfs_cmd = format!("set -e\nKVER=$(ls /lib/modules | sort -V | tail -n1)\n{}", initramfs);
+    crate::run_in_chroot(profile, rootfs, &initramfs_cmd, "Custom kernel initramfs")
+}
+
+// Compilers and tools the kernel build needs in the builder
+fn build_packages(package_manager: PackageManager) -> &'static [&'static str] {
+    match package_manager {
+        PackageManager::Apt => &["git", "build-essential", "bc", "bison", "flex", "libelf-dev", "libssl-dev", "cpio", "kmod", "rsync"],
+        PackageManager::Dnf => &["git", "gcc", "make", "bc", "bison", "flex", "elfutils-libelf-devel", "openssl-devel", "perl", "cpio", "kmod", "diffutils", "rsync"],
+        PackageManager::Pacman => &["git", "base-devel", "bc", "cpio", "libelf", "perl", "kmod", "rsync"],
+        PackageManager::Xbps => &["git", "base-devel", "bc", "bison", "flex", "elfutils-devel", "openssl-devel", "perl", "kmod", "cpio", "rsync"],
+        PackageManager::Portage => &["dev-vcs/git", "sys-devel/bc", "sys-devel/bison", "sys-devel/flex", "dev-libs/elfutils", "app-arch/cpio", "net-misc/rsync"],
+    }
+}
+
+/// Installs DKMS, the headers of the image's kernel and the dkms_modules packages, then builds
+/// every DKMS module for that kernel, [drivers] and ZFS ones included. The packages' own
+/// scripts build for the running kernel, which in a container is the host's, so the build is
+/// run again against the image's kernel.
+pub fn build_dkms_modules(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.dkms_modules.is_empty() && !drivers::uses_dkms(profile)? && !zfs::uses_dkms(profile)? {
+        return Ok(());
+    }
+    if custom(profile)?.is_some() {
+        return Err(anyhow::anyhow!("DKMS modules need a packaged kernel, build them into the [kernel.custom] one instead"));
+    }
+    println!("{}", "Building DKMS modules...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let (dkms, headers) = match package_manager {
+        PackageManager::Apt => ("dkms", "linux-headers-$KVER"),
+        PackageManager::Dnf => ("dkms", "kernel-devel-$KVER"),
+        PackageManager::Pacman => ("dkms", "$(cat /lib/modules/$KVER/pkgbase)-headers"),
+        PackageManager::Xbps => ("dkms", "linux$(echo $KVER | cut -d. -f1,2)-headers"),
+        // Distribution kernels keep their build tree in /lib/modules
+        PackageManager::Portage => ("sys-kernel/dkms", ""),
+    };
+    let mut packages = vec![dkms.to_string()];
+    if !headers.is_empty() {
+        packages.push(headers.to_string());
+    }
+    packages.extend(profile.dkms_modules.iter().cloned());
+    let dkms_cmd = format!(
+        r#"set -e
+KVER=$(ls /lib/modules | sort -V | tail -n1)
+{}
+dkms autoinstall -k $KVER
+if dkms status -k $KVER | grep -qv ': installed'; then
+  dkms status -k $KVER >&2; echo "DKMS modules failed to build for $KVER" >&2; exit 1
+fi"#,
+        package_manager.install(&packages)
+    );
+    crate::run_in_chroot(profile, rootfs, &dkms_cmd, "DKMS module build")
+}
@@ -0,0 +1,189 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+const KIOSK_APP: &str = "/usr/libexec/ulb-kiosk";
+
+// Optional [kiosk] section: the application preset = "kiosk" runs fullscreen
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct KioskConfig {
+    pub url: Option<String>, // Web page shown in a browser in kiosk mode
+    pub command: Option<String>, // Or the command of a Wayland application
+    pub compositor: Option<String>, // "cage" (default) or "weston" with its kiosk shell
+    pub browser: Option<String>, // "chromium" (default) or "firefox", for url
+    pub user: Option<String>, // Account the session runs as, defaults to "kiosk"
+    pub watchdog: Option<u32>, // Hardware watchdog timeout in seconds, rebooting a hung system
+}
+
+/// The [kiosk] settings with the user, compositor and browser filled in, once checked.
+/// Only the kiosk preset exists; it needs systemd, which restarts the session.
+pub fn config(profile: &Profile) -> Result<Option<KioskConfig>> {
+    match profile.preset.as_deref() {
+        None => {
+            if profile.kiosk.is_some() {
+                return Err(anyhow::anyhow!("[kiosk] needs preset = \"kiosk\""));
+            }
+            return Ok(None);
+        }
+        Some("kiosk") => {}
+        Some(preset) => return Err(anyhow::anyhow!("Unsupported preset: {}. Supported: kiosk", preset)),
+    }
+    let mut config = profile.kiosk.clone().ok_or_else(|| anyhow::anyhow!("preset = \"kiosk\" needs a [kiosk] section with url or command"))?;
+    if profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("preset = \"kiosk\" needs init_system = \"systemd\""));
+    }
+    if config.url.is_some() == config.command.is_some() {
+        return Err(anyhow::anyhow!("[kiosk] needs exactly one of url and command"));
+    }
+    if let Some(url) = &config.url {
+        if !(url.starts_with("http://") || url.starts_with("https://") || url.starts_with("file://")) || url.contains(['\'', '"', ' ', '\n']) {
+            return Err(anyhow::anyhow!("Invalid [kiosk] url: {}", url));
+        }
+        if profile.base == "ubuntu" {
+            return Err(anyhow::anyhow!("Ubuntu ships its browsers as snaps, set [kiosk] command instead of url"));
+        }
+    }
+    let compositor = config.compositor.get_or_insert_with(|| "cage".to_string());
+    if !matches!(compositor.as_str(), "cage" | "weston") {
+        return Err(anyhow::anyhow!("Unsupported [kiosk] compositor: {}. Supported: cage, weston", compositor));
+    }
+    let browser = config.browser.get_or_insert_with(|| "chromium".to_string());
+    if !matches!(browser.as_str(), "chromium" | "firefox") {
+        return Err(anyhow::anyhow!("Unsupported [kiosk] browser: {}. Supported: chromium, firefox", browser));
+    }
+    let user = config.user.get_or_insert_with(|| "kiosk".to_string());
+    let mut chars = user.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        || !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+        || user == "root"
+    {
+        return Err(anyhow::anyhow!("Invalid [kiosk] user: {:?}", user));
+    }
+    if profile.live.as_ref().is_some_and(|live| live.display_manager.is_some()) {
+        return Err(anyhow::anyhow!("preset = \"kiosk\" runs its own session, drop [live] display_manager"));
+    }
+    Ok(Some(config))
+}
+
+fn packages(package_manager: PackageManager, config: &KioskConfig) -> Vec<String> {
+    let portage = package_manager == PackageManager::Portage;
+    let mut packages = vec![match (config.compositor.as_deref(), portage) {
+        (Some("weston"), true) => "dev-libs/weston",
+        (Some("weston"), false) => "weston",
+        (_, true) => "gui-wm/cage",
+        _ => "cage",
+    }];
+    if config.url.is_some() {
+        packages.push(match (config.browser.as_deref(), package_manager) {
+            (Some("firefox"), PackageManager::Apt) => "firefox-esr",
+            (Some("firefox"), PackageManager::Portage) => "www-client/firefox-bin",
+            (Some("firefox"), _) => "firefox",
+            (_, PackageManager::Portage) => "www-client/chromium",
+            _ => "chromium",
+        });
+    }
+    packages.into_iter().map(String::from).collect()
+}
+
+/// The application the compositor runs: the browser on the url, or the command.
+fn app_script(config: &KioskConfig) -> String {
+    let exec = match (&config.url, config.browser.as_deref()) {
+        (Some(url), Some("firefox")) => format!(
+            "export MOZ_ENABLE_WAYLAND=1\nexec \"$(command -v firefox-esr || command -v firefox)\" --kiosk --private-window '{}'",
+            url
+        ),
+        // Fedora names the binary chromium-browser
+        (Some(url), _) => format!(
+            "exec \"$(command -v chromium || command -v chromium-browser)\" --kiosk --ozone-platform=wayland --noerrdialogs \
+             --disable-infobars --no-first-run --disable-session-crashed-bubble --check-for-update-interval=31536000 '{}'",
+            url
+        ),
+        (None, _) => format!("exec {}", config.command.as_deref().unwrap_or_default()),
+    };
+    format!("#!/bin/sh\n{}\n", exec)
+}
+
+// The compositor owns tty1 as the kiosk user, logged in through PAM like a console login and
+// restarted whenever it or the application exits
+fn unit(config: &KioskConfig, user: &str) -> String {
+    let exec = match config.compositor.as_deref() {
+        Some("weston") => format!("/usr/bin/weston --shell=kiosk-shell.so -- {}", KIOSK_APP),
+        // Without -s cage refuses to switch VTs
+        _ => format!("/usr/bin/cage -d -- {}", KIOSK_APP),
+    };
+    format!(
+        "[Unit]
+Description=Kiosk session
+After=systemd-user-sessions.service plymouth-quit-wait.service network-online.target
+Wants=network-online.target
+Conflicts=getty@tty1.service
+StartLimitIntervalSec=0
+
+[Service]
+User={user}
+PAMName=login
+TTYPath=/dev/tty1
+TTYReset=yes
+TTYVHangup=yes
+TTYVTDisallocate=yes
+StandardInput=tty-fail
+StandardOutput=journal
+UtmpIdentifier=tty1
+UtmpMode=user
+Environment=XDG_SESSION_TYPE=wayland
+ExecStartPre=-/usr/bin/plymouth quit
+ExecStart={exec}
+Restart=always
+RestartSec=2
+
+[Install]
+WantedBy=graphical.target
+"
+    )
+}
+
+/// Sets up preset = "kiosk": the compositor and browser, the kiosk user, a session service
+/// on tty1 restarting the application, no other consoles or VT switching, no SysRq, and the
+/// hardware watchdog when asked for.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(config) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Configuring kiosk...".yellow());
+    let user = config.user.clone().unwrap_or_default();
+
+    let mut files = vec![
+        (KIOSK_APP.to_string(), app_script(&config)),
+        ("/etc/systemd/system/ulb-kiosk.service".to_string(), unit(&config, &user)),
+        ("/etc/systemd/logind.conf.d/ulb-kiosk.conf".to_string(), "[Login]\nNAutoVTs=0\nReserveVT=0\n".to_string()),
+        ("/etc/sysctl.d/90-ulb-kiosk.conf".to_string(), "kernel.sysrq = 0\n".to_string()),
+        ("/etc/xdg/weston/weston.ini".to_string(), "[core]\nidle-time=0\n\n[keyboard]\nvt-switching=false\n".to_string()),
+    ];
+    if let Some(seconds) = config.watchdog {
+        files.push(("/etc/systemd/system.conf.d/ulb-kiosk.conf".to_string(), format!("[Manager]\nRuntimeWatchdogSec={}\n", seconds)));
+    }
+    for (path, content) in files {
+        let path = rootfs.join(path.trim_start_matches('/'));
+        crate::write_file(&path, &content)?;
+    }
+
+    let package_manager = crate::package_manager(profile)?;
+    let kiosk_cmd = format!(
+        "set -e
+{install}
+chmod 755 {app}
+id {user} >/dev/null 2>&1 || useradd -m -s /bin/bash -c 'Kiosk' {user}
+for GROUP in video input render audio; do getent group $GROUP >/dev/null && usermod -aG $GROUP {user}; done; true
+passwd -l {user}
+systemctl enable ulb-kiosk.service
+systemctl mask getty@tty1.service
+systemctl set-default graphical.target",
+        install = package_manager.install(&packages(package_manager, &config)),
+        app = KIOSK_APP,
+        user = user,
+    );
+    crate::run_in_chroot(profile, rootfs, &kiosk_cmd, "Kiosk configuration")
+}
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Groups the live user joins where the base has them, for audio, video, networking and removable media
+const LIVE_GROUPS: &[&str] = &["audio", "video", "netdev", "plugdev", "cdrom", "wheel", "sudo", "autologin"];
+
+// Optional [live] section: the user the live session runs as
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LiveConfig {
+    #[serde(default = "default_user")]
+    pub user: String, // Login name, defaults to "live"
+    pub full_name: Option<String>, // Shown by the display manager, e.g. "Live User"
+    pub password: Option<String>, // Defaults to none at all
+    pub display_manager: Option<String>, // "gdm", "sddm" or "lightdm" to log the user in graphically
+    #[serde(default = "crate::default_true")]
+    pub autologin: bool, // Log in on the display manager and on tty1 without a prompt
+    #[serde(default = "crate::default_true")]
+    pub sudo: bool, // Passwordless sudo
+}
+
+fn default_user() -> String {
+    "live".to_string()
+}
+
+/// The [live] settings, once checked.
+pub fn config(profile: &Profile) -> Result<Option<LiveConfig>> {
+    let Some(config) = profile.live.clone() else {
+        return Ok(None);
+    };
+    let mut chars = config.user.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        || !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+        || config.user.len() > 32
+        || config.user == "root"
+    {
+        return Err(anyhow::anyhow!("Invalid [live] user: {:?}", config.user));
+    }
+    for (what, text) in [("full_name", &config.full_name), ("password", &config.password)] {
+        if text.as_deref().is_some_and(|text| text.contains(['\'', ':', ',', '\n'])) {
+            return Err(anyhow::anyhow!("[live] {} can't contain quotes, colons, commas or newlines", what));
+        }
+    }
+    if let Some(display_manager) = &config.display_manager {
+        if !matches!(display_manager.as_str(), "gdm" | "sddm" | "lightdm") {
+            return Err(anyhow::anyhow!("Unsupported [live] display_manager: {}. Supported: gdm, sddm, lightdm", display_manager));
+        }
+    }
+    Ok(Some(config))
+}
+
+/// Creates the [live] user with its groups, password and sudo rights, and sets up autologin on
+/// the display manager and tty1.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(config) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Configuring live user...".yellow());
+
+    let user = config.user.as_str();
+    let package_manager = crate::package_manager(profile)?;
+    let mut lines = vec!["set -e".to_string()];
+    if config.sudo {
+        lines.push(package_manager.install(&[match package_manager {
+            PackageManager::Portage => "app-admin/sudo".to_string(),
+            _ => "sudo".to_string(),
+        }]));
+    }
+    lines.push(format!(
+        "id {user} >/dev/null 2>&1 || useradd -m -s /bin/bash -c '{}' {user}",
+        config.full_name.as_deref().unwrap_or("Live User")
+    ));
+    // Some display managers only log members of autologin in without a password
+    if config.autologin && config.display_manager.is_some() {
+        lines.push("getent group autologin >/dev/null || groupadd -r autologin".to_string());
+    }
+    lines.push(format!(
+        "for GROUP in {}; do getent group $GROUP >/dev/null && usermod -aG $GROUP {user}; done; true",
+        LIVE_GROUPS.join(" ")
+    ));
+    lines.push(match &config.password {
+        Some(password) => format!("echo '{user}:{password}' | chpasswd"),
+        None => format!("passwd -d {user}"),
+    });
+    if config.sudo {
+        lines.push(format!(
+            "mkdir -p /etc/sudoers.d && echo '{user} ALL=(ALL) NOPASSWD: ALL' > /etc/sudoers.d/ulb-live && chmod 440 /etc/sudoers.d/ulb-live"
+        ));
+    }
+
+    if config.autologin {
+        match config.display_manager.as_deref() {
+            // Debian's GDM keeps its configuration in gdm3
+            Some("gdm") => lines.push(format!(
+                "GDM_DIR=/etc/gdm; [ -d /etc/gdm3 ] && GDM_DIR=/etc/gdm3\n\
+                 mkdir -p $GDM_DIR && printf '%s\\n' '[daemon]' 'AutomaticLoginEnable=True' 'AutomaticLogin={user}' > $GDM_DIR/custom.conf"
+            )),
+            // SDDM needs a session to start, the first installed one will do
+            Some("sddm") => lines.push(format!(
+                "SESSION=$(ls /usr/share/wayland-sessions /usr/share/xsessions 2>/dev/null | grep '\\.desktop$' | head -n1)\n\
+                 mkdir -p /etc/sddm.conf.d && printf '%s\\n' '[Autologin]' 'User={user}' \"Session=$SESSION\" > /etc/sddm.conf.d/ulb-live.conf"
+            )),
+            Some(_) => lines.push(format!(
+                "mkdir -p /etc/lightdm/lightdm.conf.d && printf '%s\\n' '[Seat:*]' 'autologin-user={user}' 'autologin-user-timeout=0' \
+                 > /etc/lightdm/lightdm.conf.d/50-ulb-live.conf"
+            )),
+            None => {}
+        }
+        lines.push(match profile.init_system.as_str() {
+            // sysvinit's inittab starts the OpenRC consoles
+            "openrc" => format!("sed -i 's|^c1:.*|c1:12345:respawn:/sbin/agetty --noclear --autologin {user} 38400 tty1 linux|' /etc/inittab"),
+            // s6 runs the runit getty services, conf included
+            "runit" | "s6" => format!("sed -i '/^GETTY_ARGS=/d' /etc/sv/agetty-tty1/conf && echo 'GETTY_ARGS=\"--noclear --autologin {user}\"' >> /etc/sv/agetty-tty1/conf"),
+            _ => format!(
+                "mkdir -p /etc/systemd/system/getty@tty1.service.d && printf '%s\\n' '[Service]' 'ExecStart=' \
+                 'ExecStart=-/sbin/agetty --noclear --autologin {user} %I $TERM' > /etc/systemd/system/getty@tty1.service.d/ulb-live.conf"
+            ),
+        });
+    }
+    if let Some(display_manager) = &config.display_manager {
+        lines.push(match (profile.init_system.as_str(), display_manager.as_str()) {
+            ("runit" | "s6", _) => format!("ln -sf /etc/sv/{} /etc/runit/runsvdir/default/", display_manager),
+            // Gentoo starts the display manager through the display-manager service
+            ("openrc", _) if profile.base == "gentoo" => format!(
+                "sed -i 's/^DISPLAYMANAGER=.*/DISPLAYMANAGER=\"{}\"/' /etc/conf.d/display-manager && rc-update add display-manager default",
+                display_manager
+            ),
+            ("openrc", _) => format!("rc-update add {} default", display_manager),
+            // Debian names the unit gdm3
+            (_, "gdm") => "systemctl enable gdm 2>/dev/null || systemctl enable gdm3\nsystemctl set-default graphical.target".to_string(),
+            _ => format!("systemctl enable {}\nsystemctl set-default graphical.target", display_manager),
+        });
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Live user setup")
+}
@@ -0,0 +1,142 @@
+use anyhow::Result;
+use colored::*;
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+/// Checks hostname, locales, timezone and keymaps before anything is built.
+pub fn check(profile: &Profile) -> Result<()> {
+    if let Some(hostname) = &profile.hostname {
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        if hostname.len() > 253 || !hostname.split('.').all(valid_label) {
+            return Err(anyhow::anyhow!("Invalid hostname: {}", hostname));
+        }
+    }
+    for locale in &profile.locales {
+        // locale.gen wants the charset spelled out next to the name
+        let valid = locale.split_once('.').is_some_and(|(name, charset)| {
+            !name.is_empty()
+                && !charset.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '@')
+                && charset.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+        if !valid {
+            return Err(anyhow::anyhow!("Invalid locale: {}, expected e.g. \"de_DE.UTF-8\"", locale));
+        }
+    }
+    let names = profile.timezone.iter().chain(&profile.keymap).chain(&profile.x11_layout);
+    for name in names {
+        if name.is_empty() || name.starts_with('/') || name.contains("..") || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '/' | '+')) {
+            return Err(anyhow::anyhow!("Invalid timezone or keymap: {}", name));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the hostname, generates the locales with the first as the default, links the timezone
+/// and sets the console and X keymaps, each the way the base's own tools would.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.hostname.is_none() && profile.locales.is_empty() && profile.timezone.is_none() && profile.keymap.is_none() && profile.x11_layout.is_none() {
+        return Ok(());
+    }
+    check(profile)?;
+    println!("{}", "Configuring hostname, locale, timezone and keymap...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut lines = vec!["set -e".to_string()];
+    if let Some(hostname) = &profile.hostname {
+        lines.push(format!(
+            "echo {0} > /etc/hostname && touch /etc/hosts && sed -i '/^127\\.0\\.1\\.1\\s/d' /etc/hosts && echo '127.0.1.1 {0}' >> /etc/hosts",
+            hostname
+        ));
+    }
+
+    if let Some(lang) = profile.locales.first() {
+        match package_manager {
+            // Fedora and EL ship compiled locales as one langpack per language
+            PackageManager::Dnf => {
+                let mut langpacks: Vec<String> = profile
+                    .locales
+                    .iter()
+                    .map(|locale| format!("glibc-langpack-{}", locale.split(['_', '.', '@']).next().unwrap_or(locale)))
+                    .collect();
+                langpacks.sort();
+                langpacks.dedup();
+                lines.push(package_manager.install(&langpacks));
+            }
+            // Void keeps the list in libc-locales and generates it when glibc-locales is reconfigured
+            PackageManager::Xbps => {
+                for locale in &profile.locales {
+                    let charset = locale.split_once('.').map_or("UTF-8", |(_, charset)| charset);
+                    lines.push(format!("sed -i 's/^#\\s*\\({} {}\\)/\\1/' /etc/default/libc-locales", locale.replace('.', "\\."), charset));
+                }
+                lines.push("xbps-reconfigure -f glibc-locales".to_string());
+            }
+            _ => {
+                if package_manager == PackageManager::Apt {
+                    lines.push(package_manager.install(&["locales".to_string()]));
+                }
+                let entries: Vec<String> = profile
+                    .locales
+                    .iter()
+                    .map(|locale| format!("'{} {}'", locale, locale.split_once('.').map_or("UTF-8", |(_, charset)| charset)))
+                    .collect();
+                lines.push(format!("printf '%s\\n' {} >> /etc/locale.gen && locale-gen", entries.join(" ")));
+            }
+        }
+        lines.push(match package_manager {
+            PackageManager::Apt => format!("echo 'LANG={}' > /etc/default/locale", lang),
+            PackageManager::Portage => format!("echo 'LANG=\"{}\"' > /etc/env.d/02locale", lang),
+            _ => format!("echo 'LANG={}' > /etc/locale.conf", lang),
+        });
+    }
+
+    if let Some(timezone) = &profile.timezone {
+        lines.push(format!(
+            "[ -f /usr/share/zoneinfo/{0} ] || {{ echo \"Unknown timezone: {0}\" >&2; exit 1; }}\n\
+             ln -sf /usr/share/zoneinfo/{0} /etc/localtime && echo {0} > /etc/timezone",
+            timezone
+        ));
+    }
+
+    if let Some(keymap) = &profile.keymap {
+        lines.push(format!("echo 'KEYMAP={}' > /etc/vconsole.conf", keymap));
+        match (package_manager, profile.init_system.as_str()) {
+            (PackageManager::Xbps, _) => lines.push(format!("sed -i '/^#\\?KEYMAP=/d' /etc/rc.conf && echo 'KEYMAP=\"{}\"' >> /etc/rc.conf", keymap)),
+            (_, "openrc") => lines.push(format!("mkdir -p /etc/conf.d && echo 'keymap=\"{}\"' > /etc/conf.d/keymaps", keymap)),
+            _ => {}
+        }
+    }
+    if let Some(layout) = profile.x11_layout.clone().or(profile.keymap.as_deref().map(xkb_layout)) {
+        // console-setup reads the X layout for the console too on the apt bases
+        lines.push(match package_manager {
+            PackageManager::Apt => format!("printf '%s\\n' 'XKBMODEL=\"pc105\"' 'XKBLAYOUT=\"{}\"' > /etc/default/keyboard", layout),
+            _ => format!(
+                "mkdir -p /etc/X11/xorg.conf.d && printf '%s\\n' 'Section \"InputClass\"' '    Identifier \"system-keyboard\"' \
+                 '    MatchIsKeyboard \"on\"' '    Option \"XkbLayout\" \"{}\"' 'EndSection' > /etc/X11/xorg.conf.d/00-keyboard.conf",
+                layout
+            ),
+        });
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Locale configuration")
+}
+
+// The XKB layout of a console keymap: its name without the variant and encoding suffixes
+// ("de-latin1" and "de-nodeadkeys" are "de", "pl2" is "pl"), and XKB's names where they differ
+fn xkb_layout(keymap: &str) -> String {
+    let layout = keymap.split(['-', '_']).next().unwrap_or(keymap).trim_end_matches(|c: char| c.is_ascii_digit());
+    match layout {
+        "uk" => "gb",
+        "cf" => "ca",
+        "sg" => "ch",
+        "" => "us",
+        layout => layout,
+    }
+    .to_string()
+}
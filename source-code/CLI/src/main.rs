@@ -3,16 +3,60 @@ use clap::{Parser, Subcommand};
 use colored::*;
 use log::{error, info, LevelFilter};
 use serde::{Deserialize, Serialize};
-use simplelog::{Config, TermLogger, WriteLogger};
+use simplelog::{CombinedLogger, Config, TermLogger, WriteLogger};
 use std::fs::{self, OpenOptions};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use toml;
 use walkdir::WalkDir;
 
+mod accessibility;
+mod apparmor;
+mod apps;
+mod archive;
+mod artifacts;
+mod atomic;
+mod board;
+mod branding;
+mod boot;
+mod channel;
+mod cloud;
+mod desktop;
+mod disk;
+mod drivers;
+mod export;
+mod firewall;
+mod firmware;
+mod flash;
+mod ignition;
+mod initramfs;
+mod installer;
+mod kernel;
+mod kiosk;
+mod live;
+mod locale;
+mod minimize;
+mod multiarch;
+mod netboot;
+mod netinstall;
+mod network;
+mod nixos;
+mod oem;
+mod ostree;
+mod overlayroot;
+mod repos;
+mod secureboot;
+mod selinux;
+mod swap;
+mod sysext;
+mod timers;
+mod udev;
+mod unattended;
+mod vagrant;
+mod zfs;
+
 // Define the Profile struct based on TOML fields
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 struct Profile {
     packages: Vec<String>,
     distro_name: String,
@@ -23,8 +67,193 @@ struct Profile {
     bootloader: String,
     uefi_support: bool,
     bios_support: bool,
-    format: String, // e.g., "iso"
+    #[serde(default)]
+    uki: bool, // Boot a unified kernel image from the ESP (needs uefi_support)
+    #[serde(default)]
+    boot_menu: Option<boot::BootMenuConfig>, // Title, timeout, default entry and theme of the boot menus
+    #[serde(default, deserialize_with = "kernel::package_or_table")]
+    kernel: Option<kernel::KernelConfig>, // Kernel package, or [kernel.custom] to build one from source
+    #[serde(default)]
+    initramfs: Option<initramfs::InitramfsConfig>, // Drivers, omitted modules and compression of the initramfs
+    #[serde(default)]
+    drivers: Option<drivers::DriversConfig>, // Proprietary NVIDIA and Broadcom drivers
+    #[serde(default)]
+    firmware: Option<firmware::FirmwareConfig>, // All of linux-firmware, hardware classes of it, or explicit packages
+    #[serde(default)]
+    dkms_modules: Vec<String>, // Packages with DKMS module sources built for the image's kernel, e.g. "zfs-dkms"
+    #[serde(default)]
+    zfs: bool, // ZFS module, tools and initramfs support for importing pools
+    #[serde(default)]
+    kernel_cmdline: Option<String>, // Extra kernel arguments for every boot entry, e.g. "quiet mitigations=off"
+    #[serde(default)]
+    boot_entries: Vec<boot::BootEntry>, // Extra [[boot_entries]] with their own kernel arguments
+    #[serde(default)]
+    languages: Vec<boot::Language>, // [[languages]] offered in a language and keyboard submenu of live media
+    #[serde(default)]
+    plymouth_theme: Option<String>, // Plymouth boot splash theme, from packages or files/
+    #[serde(default)]
+    services_enable: Vec<String>, // Services started at boot, e.g. ["sshd", "NetworkManager"]
+    #[serde(default)]
+    services_disable: Vec<String>, // Services not started at boot
+    #[serde(default)]
+    services_mask: Vec<String>, // systemd units that can't be started at all
+    #[serde(default)]
+    services_preset_all: bool, // Reset every systemd unit to its preset first; Debian presets enable everything
+    #[serde(default)]
+    sysctl: std::collections::BTreeMap<String, toml::Value>, // [sysctl] kernel parameters, e.g. "vm.swappiness" = 10
+    #[serde(default)]
+    selinux: Option<String>, // "enforcing", "permissive" or "disabled" (fedora, EL); labelled either way
+    #[serde(default)]
+    apparmor: Option<apparmor::AppArmorConfig>, // AppArmor with extra and custom profiles (debian, ubuntu)
+    #[serde(default)]
+    swap: Option<swap::SwapConfig>, // zram, a swapfile on disk images, or no swap
+    #[serde(default)]
+    minimize: Option<minimize::MinimizeConfig>, // Docs, translations and caches stripped before packing
+    #[serde(default)]
+    overlay_root: Option<overlayroot::OverlayRootConfig>, // Read-only root with changes kept in RAM until reboot
+    #[serde(default)]
+    udev_rules: Vec<udev::UdevRule>, // [[udev_rules]] installed to /etc/udev/rules.d and verified
+    #[serde(default)]
+    timers: Vec<timers::Timer>, // [[timers]] as systemd timer and service units
+    #[serde(default)]
+    cron: Vec<timers::CronJob>, // [[cron]] jobs in /etc/cron.d
+    #[serde(default)]
+    hostname: Option<String>, // Written to /etc/hostname and /etc/hosts
+    #[serde(default, deserialize_with = "string_or_list")]
+    locales: Vec<String>, // Locales to generate, the first is the default, e.g. ["en_US.UTF-8", "de_DE.UTF-8"]
+    #[serde(default)]
+    timezone: Option<String>, // e.g. "Europe/Warsaw"
+    #[serde(default)]
+    keymap: Option<String>, // Console keymap, e.g. "de-latin1"; its layout ("de") is the X one unless x11_layout is set
+    #[serde(default)]
+    x11_layout: Option<String>, // X keyboard layout, e.g. "de"
+    #[serde(default)]
+    network: Option<network::NetworkConfig>, // Static addresses, VLANs, bridges and Wi-Fi baked into the image
+    #[serde(default)]
+    firewall: Option<firewall::FirewallConfig>, // Firewall installed and enabled with the allowed services and ports
+    #[serde(default)]
+    live: Option<live::LiveConfig>, // Live session user, autologin and sudo
+    #[serde(default)]
+    accessibility: Option<accessibility::AccessibilityConfig>, // Live boot entries with a screen reader, high contrast or magnifier
+    #[serde(default)]
+    branding: Option<branding::BrandingConfig>, // os-release, lsb-release, issue and motd naming the distro
+    #[serde(default)]
+    dconf: desktop::Settings, // [dconf."org/gnome/..."] keys compiled into the system dconf database
+    #[serde(default)]
+    plasma: std::collections::BTreeMap<String, desktop::Settings>, // [plasma.<file>.<group>] keys for /etc/xdg/<file>
+    #[serde(default)]
+    toram: bool, // Add a boot menu entry loading the live image into RAM
+    #[serde(default)]
+    include_memtest: bool, // Add a memtest86+ boot menu entry (x86_64)
+    #[serde(deserialize_with = "string_or_list")]
+    format: Vec<String>, // e.g., "iso" or ["iso", "qcow2", "tar"]; "sysext"/"confext" alone
     atomic: bool,   // Whether it's atomic distro or classic
+    #[serde(default)]
+    butane: Option<String>, // Butane config in files/, compiled to Ignition for first boot of atomic images
+    #[serde(default)]
+    ostree: Option<atomic::OstreeConfig>, // Ref and update server of atomic builds
+    #[serde(default)]
+    live_fs: Option<String>, // "squashfs" (default) or "erofs" for the live root image
+    #[serde(default)]
+    iso: Option<artifacts::IsoConfig>, // Settings for the iso format
+    #[serde(default)]
+    persistence: Option<flash::PersistenceConfig>, // Live system changes kept on a labelled partition
+    #[serde(default)]
+    squashfs: Option<artifacts::SquashfsConfig>, // mksquashfs compression settings
+    #[serde(default)]
+    variant: Option<String>, // "live" (default) or "netinstall" for installer-only media
+    #[serde(default)]
+    unattended: Option<unattended::UnattendedConfig>, // Hands-free preseed/autoinstall answers for netinstall media
+    #[serde(default)]
+    installer: Option<String>, // "calamares" or "anaconda" (dnf bases) to install the live system to disk from the ISO
+    #[serde(default)]
+    oem_setup: bool, // Disk images ask for the user, locale, keymap, timezone and hostname on first boot
+    #[serde(default)]
+    preset: Option<String>, // "kiosk" for a single fullscreen application, set up in [kiosk]
+    #[serde(default)]
+    kiosk: Option<kiosk::KioskConfig>, // The url or command of preset = "kiosk" and how it runs
+    #[serde(default)]
+    extension: Option<sysext::ExtensionConfig>, // Metadata for sysext/confext builds
+    #[serde(default)]
+    disk: Option<disk::DiskConfig>, // Partitioning for disk image formats
+    #[serde(default)]
+    esp: Option<disk::EspConfig>, // Size, label and extra files of the EFI system partition
+    #[serde(default)]
+    tar: Option<archive::TarConfig>, // Compression for the tar format
+    #[serde(default)]
+    wsl: Option<archive::WslConfig>, // Settings for the wsl format
+    #[serde(default)]
+    pxe: Option<netboot::PxeConfig>, // Settings for the pxe format
+    #[serde(default)]
+    vagrant: Option<vagrant::VagrantConfig>, // Providers for the vagrant format
+    #[serde(default)]
+    cloud_init: Option<cloud::CloudInitConfig>, // cloud-init and a NoCloud seed for disk and cloud images
+    #[serde(default)]
+    rpi: Option<board::RpiConfig>, // Firmware and boot config for the rpi format
+    #[serde(default)]
+    board: Option<board::BoardConfig>, // U-Boot for single-board computer disk images
+    #[serde(default)]
+    devicetree: Option<board::DevicetreeConfig>, // Device tree and overlays for ARM/riscv boards
+    #[serde(default)]
+    secure_boot: Option<secureboot::SecureBootConfig>, // Signing keys for Secure Boot
+    #[serde(default)]
+    arch: Option<String>, // Target architecture, "x86_64" (default) or "aarch64"
+    #[serde(default)]
+    base_version: Option<String>, // Release of the base, e.g. "24.04", "bookworm", "40"
+    #[serde(default)]
+    mirror: Option<String>, // Replaces the base's upstream archive URL
+    #[serde(default)]
+    mirrors: Vec<String>, // Fallback mirrors tried after `mirror`
+    #[serde(default)]
+    upgrade: bool, // Full upgrade of the base before installing packages
+    #[serde(default)]
+    aur_packages: Vec<String>, // AUR packages built with makepkg (arch only)
+    #[serde(default)]
+    packages_hold: Vec<String>, // Packages the built system never upgrades
+    #[serde(default)]
+    flatpaks: Vec<String>, // Flatpak app IDs preinstalled system-wide
+    #[serde(default)]
+    flatpak_remote: Option<String>, // .flatpakrepo URL, defaults to Flathub
+    #[serde(default)]
+    snaps: Vec<String>, // Snaps seeded on ubuntu, as "name" or "name=channel"
+    #[serde(default)]
+    appimages: Vec<apps::AppImage>, // [[appimages]] downloaded and verified at build time
+    #[serde(default)]
+    repositories: Vec<repos::Repository>, // Extra [[repositories]] added before package install
+    #[serde(default)]
+    debconf: std::collections::BTreeMap<String, toml::Value>, // [debconf] answers, e.g. "postfix/main_mailer_type" (debian, ubuntu)
+    #[serde(default)]
+    alternatives: std::collections::BTreeMap<String, String>, // [alternatives] defaults, e.g. editor = "/usr/bin/vim.basic"
+    #[serde(default)]
+    ca_certificates: Vec<String>, // CA certificates trusted system-wide, PEM blobs or files/ paths
+    #[serde(default)]
+    epel: bool, // Enable EPEL and CRB on Enterprise Linux bases
+    #[serde(default)]
+    make_conf: Option<MakeConf>, // Portage settings for the gentoo base
+}
+
+// `format = "iso"` and `format = ["iso", "qcow2"]` are both accepted
+fn string_or_list<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(format) => vec![format],
+        StringOrList::List(formats) => formats,
+    })
+}
+
+// Optional [make_conf] section, appended to /etc/portage/make.conf on the gentoo base
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+struct MakeConf {
+    #[serde(default)]
+    use_flags: Vec<String>,   // USE="..."
+    makeopts: Option<String>, // e.g. "-j8"
+    #[serde(default)]
+    extra: std::collections::BTreeMap<String, String>, // Any other make.conf variables
 }
 
 #[derive(Parser)]
@@ -53,6 +282,107 @@ enum Commands {
     ShowBuild,
     /// Initialize a new project with example structure
     Init,
+    /// Write an image to a USB stick or disk, optionally with a persistence partition
+    Flash {
+        /// Image to write, e.g. build/iso/mydistro-1.0.iso
+        image: PathBuf,
+        /// Target block device, e.g. /dev/sdb
+        device: PathBuf,
+        /// Add a persistence partition behind the image
+        #[arg(long)]
+        persistence: bool,
+        /// Size of the persistence partition, e.g. 4G (defaults to the rest of the device)
+        #[arg(long)]
+        persistence_size: Option<String>,
+        /// Label of the persistence partition, matching [persistence] label of the profile
+        #[arg(long, default_value = flash::DEFAULT_LABEL)]
+        persistence_label: String,
+        /// Don't ask for confirmation before erasing the device
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Manage update channels for image-based fleets
+    Channel {
+        #[command(subcommand)]
+        action: ChannelCommands,
+    },
+    /// Manage the OSTree repository atomic builds commit to
+    Ostree {
+        /// Repository to work on (defaults to build/iso/ostree-repo)
+        #[arg(long)]
+        repo: Option<PathBuf>,
+        #[command(subcommand)]
+        action: OstreeCommands,
+    },
+    /// Convert a profile into another tool's format
+    Export {
+        #[command(subcommand)]
+        format: ExportCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExportCommands {
+    /// Write an Anaconda kickstart with the profile's packages, users, services and partitioning
+    Kickstart {
+        /// TOML profile file name (optional if only one exists)
+        profile: Option<String>,
+        /// Kickstart file to write (defaults to build/kickstart/<profile>.ks)
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum OstreeCommands {
+    /// Create an empty repository
+    InitRepo {
+        /// Repository mode: archive (served over HTTP), bare, bare-user or bare-user-only
+        #[arg(long, default_value = "archive")]
+        mode: String,
+    },
+    /// List the commits of a ref, or of every ref
+    List {
+        /// Ref to list (defaults to all refs)
+        ostree_ref: Option<String>,
+    },
+    /// Delete all but the newest commits of each ref
+    Prune {
+        /// Commits kept per ref
+        #[arg(long, default_value_t = 3)]
+        keep: u32,
+    },
+    /// Copy refs from another local repository
+    PullLocal {
+        /// Repository to pull from
+        source: PathBuf,
+        /// Refs to pull (defaults to all)
+        refs: Vec<String>,
+    },
+    /// Write the tree of a ref or commit as a tar archive
+    Export {
+        /// Ref or commit checksum
+        ostree_ref: String,
+        /// Archive to write (defaults to build/ostree/<ref>.tar)
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ChannelCommands {
+    /// Publish a built image as the latest build of a channel
+    Publish {
+        /// Channel name
+        #[arg(long, default_value = "stable")]
+        channel: String,
+        /// Artifact to publish (defaults to the newest file in build/iso)
+        #[arg(long)]
+        artifact: Option<PathBuf>,
+        /// GPG key used to sign the channel metadata
+        #[arg(long)]
+        sign_key: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
@@ -61,15 +391,16 @@ fn main() -> Result<()> {
     fs::create_dir_all(&log_dir).context("Failed to create log directory")?;
     let log_path = log_dir.join("ulb.log");
     let log_file = OpenOptions::new()
-        .write(true)
         .create(true)
         .append(true)
         .open(&log_path)
         .context("Failed to open log file")?;
 
-    TermLogger::init(LevelFilter::Info, Config::default(), simplelog::TerminalMode::Mixed, simplelog::ColorChoice::Auto)
-        .context("Failed to initialize term logger")?;
-    WriteLogger::init(LevelFilter::Info, Config::default(), log_file).context("Failed to initialize write logger")?;
+    CombinedLogger::init(vec![
+        TermLogger::new(LevelFilter::Info, Config::default(), simplelog::TerminalMode::Mixed, simplelog::ColorChoice::Auto),
+        WriteLogger::new(LevelFilter::Info, Config::default(), log_file),
+    ])
+    .context("Failed to initialize logger")?;
 
     info!("Starting Universal Live Builder (ULB)");
 
@@ -79,6 +410,7 @@ fn main() -> Result<()> {
     let profiles_dir = current_dir.join("profiles");
     let files_dir = current_dir.join("files");
     let scripts_dir = current_dir.join("scripts");
+    let packages_dir = current_dir.join("packages");
     let build_dir = current_dir.join("build/iso");
 
     match cli.command {
@@ -89,6 +421,7 @@ fn main() -> Result<()> {
                 profile.as_deref(),
                 &files_dir,
                 &scripts_dir,
+                &packages_dir,
                 &build_dir,
             )?;
         }
@@ -97,9 +430,43 @@ fn main() -> Result<()> {
         Commands::Settings => configure_settings()?,
         Commands::ShowBuild => {
             fs::create_dir_all(&build_dir).context("Failed to create build directory")?;
-            interactive_build(&profiles_dir, &files_dir, &scripts_dir, &build_dir)?;
+            interactive_build(&profiles_dir, &files_dir, &scripts_dir, &packages_dir, &build_dir)?;
         }
         Commands::Init => init_project(&current_dir)?,
+        Commands::Flash { image, device, persistence, persistence_size, persistence_label, yes } => {
+            flash::flash(&image, &device, persistence, persistence_size.as_deref(), &persistence_label, yes)?;
+        }
+        Commands::Channel { action } => match action {
+            ChannelCommands::Publish { channel, artifact, sign_key } => {
+                let artifact = match artifact {
+                    Some(path) => path,
+                    None => channel::latest_artifact(&build_dir)?,
+                };
+                channel::publish(&current_dir.join("build/channels"), &channel, &artifact, sign_key.as_deref())?;
+            }
+        },
+        Commands::Ostree { repo, action } => {
+            let repo = repo.map_or_else(|| build_dir.join(atomic::REPO_DIR), |repo| current_dir.join(repo));
+            match action {
+                OstreeCommands::InitRepo { mode } => ostree::init_repo(&repo, &mode)?,
+                OstreeCommands::List { ostree_ref } => ostree::list(&repo, ostree_ref.as_deref())?,
+                OstreeCommands::Prune { keep } => ostree::prune(&repo, keep)?,
+                OstreeCommands::PullLocal { source, refs } => ostree::pull_local(&repo, &current_dir.join(source), &refs)?,
+                OstreeCommands::Export { ostree_ref, output } => {
+                    let output = output.unwrap_or_else(|| current_dir.join("build/ostree").join(format!("{}.tar", ostree_ref.replace('/', "-"))));
+                    ostree::export(&repo, &ostree_ref, &output)?;
+                }
+            }
+        }
+        Commands::Export { format } => match format {
+            ExportCommands::Kickstart { profile, output } => {
+                let profile_path = find_profile(&profiles_dir, profile.as_deref())?;
+                let profile_content = fs::read_to_string(&profile_path)
+                    .context(format!("Failed to read profile: {}", profile_path.display()))?;
+                let profile: Profile = toml::from_str(&profile_content).context("Failed to parse TOML")?;
+                export::write_kickstart(&profile, &profile_path, output, &current_dir.join("build"))?;
+            }
+        },
     }
 
     info!("ULB execution completed");
@@ -112,6 +479,7 @@ fn init_project(current_dir: &Path) -> Result<()> {
     fs::create_dir_all(current_dir.join("profiles")).context("Failed to create profiles dir")?;
     fs::create_dir_all(current_dir.join("files")).context("Failed to create files dir")?;
     fs::create_dir_all(current_dir.join("scripts")).context("Failed to create scripts dir")?;
+    fs::create_dir_all(current_dir.join("packages")).context("Failed to create packages dir")?;
     fs::create_dir_all(current_dir.join("build/iso")).context("Failed to create build/iso dir")?;
 
     let example_toml = r#"
@@ -132,7 +500,7 @@ atomic = false
     fs::write(&profile_path, example_toml).context("Failed to write example.toml")?;
 
     println!("{}", "Project initialized with example profile!".green());
-    println!("Folders created: profiles, files, scripts, build/iso");
+    println!("Folders created: profiles, files, scripts, packages, build/iso");
     println!("Example profile: profiles/example.toml");
     println!("You can now run 'ulb build example' to build.");
 
@@ -144,6 +512,7 @@ fn build_distro(
     profile_name: Option<&str>,
     files_dir: &Path,
     scripts_dir: &Path,
+    packages_dir: &Path,
     build_dir: &Path,
 ) -> Result<()> {
     let profile_path = find_profile(profiles_dir, profile_name)?;
@@ -157,39 +526,144 @@ fn build_distro(
     let profile: Profile = toml::from_str(&profile_content).context("Failed to parse TOML")?;
 
     info!("Parsed profile: {:?}", profile);
+    artifacts::validate_formats(&profile)?;
+
+    // A multi-architecture ISO runs the whole rootfs pipeline once per architecture
+    if let Some(arches) = multiarch::arches(&profile)? {
+        multiarch::build_iso(&profile, &arches, files_dir, scripts_dir, packages_dir, build_dir)?;
+        println!("{}", "Build completed!".green());
+        return Ok(());
+    }
 
     // Setup Podman container for build tools
     setup_podman_container(&profile)?;
 
-    // Prepare rootfs
-    let rootfs = PathBuf::from("/tmp/.ulb/rootfs");
-    fs::create_dir_all(&rootfs).context("Failed to create rootfs directory")?;
-
-    // Install base system based on 'base'
-    install_base_system(&profile, &rootfs)?;
-
-    // Install packages
-    install_packages(&profile, &rootfs)?;
+    // NixOS is declarative, so it skips the rootfs pipeline entirely
+    if profile.base == "nixos" {
+        nixos::build(&profile, files_dir, scripts_dir, build_dir)?;
+        println!("{}", "Build completed!".green());
+        return Ok(());
+    }
 
-    // Remove packages
-    remove_packages(&profile, &rootfs)?;
+    // Atomic builds compose an OSTree commit from the profile instead of assembling a rootfs
+    if profile.atomic {
+        atomic::build(&profile, files_dir, scripts_dir, build_dir)?;
+        println!("{}", "Build completed!".green());
+        return Ok(());
+    }
 
-    // Copy files
-    copy_files(files_dir, &rootfs)?;
+    // Netinstall media only carries the upstream installer, so no rootfs is built
+    if netinstall::is_netinstall(&profile)? {
+        netinstall::build(&profile, build_dir)?;
+        println!("{}", "Build completed!".green());
+        return Ok(());
+    }
 
-    // Run scripts
-    run_scripts(scripts_dir, &rootfs)?;
+    // Extension images reuse the package/file stages but skip the bootable system
+    if let Some(kind) = profile.format.first().and_then(|format| sysext::ExtensionKind::from_format(format)) {
+        sysext::build_extension(&profile, kind, files_dir, scripts_dir, packages_dir, build_dir)?;
+        println!("{}", "Build completed!".green());
+        return Ok(());
+    }
 
-    // Configure bootloader, init, etc.
-    configure_system(&profile, &rootfs)?;
+    // Prepare rootfs
+    let rootfs = PathBuf::from("/tmp/.ulb/rootfs");
+    fs::create_dir_all(&rootfs).context("Failed to create rootfs directory")?;
+    prepare_rootfs(&profile, &rootfs, files_dir, scripts_dir, packages_dir)?;
 
-    // Build ISO
-    build_iso(&profile, &rootfs, build_dir)?;
+    // Build every requested output from the prepared rootfs
+    artifacts::build_artifacts(&profile, &rootfs, files_dir, build_dir)?;
 
     println!("{}", "Build completed!".green());
     Ok(())
 }
 
+/// Runs every stage that turns an empty directory into the finished system, up to but not
+/// including the output formats.
+fn prepare_rootfs(profile: &Profile, rootfs: &Path, files_dir: &Path, scripts_dir: &Path, packages_dir: &Path) -> Result<()> {
+    // Install base system based on 'base'
+    install_base_system(profile, rootfs)?;
+
+    // Answers for the questions packages ask while installing
+    preseed_debconf(profile, rootfs)?;
+
+    // Trust the internal CAs, then add extra repositories for every stage that installs packages,
+    // up to the SELinux tools; the credentials are scrubbed after it, even when a stage fails
+    repos::install_ca_certificates(profile, files_dir, rootfs)?;
+    repos::with_repositories(profile, files_dir, rootfs, || {
+        // Upgrade the base
+        upgrade_system(profile, rootfs)?;
+
+        // Swap in the init system before anything registers services with it
+        install_init_system(profile, rootfs)?;
+
+        // Install the kernel and packages
+        kernel::install(profile, files_dir, rootfs)?;
+        drivers::install(profile, rootfs)?;
+        zfs::install(profile, rootfs)?;
+        install_packages(profile, rootfs)?;
+        install_local_packages(profile, packages_dir, rootfs)?;
+        install_aur_packages(profile, rootfs)?;
+        kernel::build_dkms_modules(profile, rootfs)?;
+        firmware::install(profile, rootfs)?;
+
+        // Remove packages
+        remove_packages(profile, rootfs)?;
+
+        // Hold packages
+        hold_packages(profile, rootfs)?;
+
+        // Preinstall flatpaks, snaps and AppImages
+        apps::install_flatpaks(profile, rootfs)?;
+        apps::seed_snaps(profile, rootfs)?;
+        apps::install_appimages(profile, files_dir, rootfs)?;
+
+        // Assistive tools the [accessibility] boot entries switch on
+        accessibility::install(profile, rootfs)?;
+
+        // Hostname, locales, timezone, keymaps and network, then the user the live session logs in as
+        locale::configure(profile, rootfs)?;
+        network::configure(profile, rootfs)?;
+        firewall::configure(profile, rootfs)?;
+        live::configure(profile, rootfs)?;
+
+        // Name the system after the distro rather than its base, with the desktop defaults
+        branding::apply(profile, files_dir, rootfs)?;
+        desktop::configure(profile, rootfs)?;
+        kiosk::configure(profile, rootfs)?;
+
+        // The installer, set up for the live user and branding configured above
+        installer::install(profile, rootfs)?;
+
+        // First boot wizard for disk images
+        oem::configure(profile, rootfs)?;
+
+        // Copy files
+        copy_files(files_dir, rootfs)?;
+        udev::install(profile, files_dir, rootfs)?;
+        apparmor::configure(profile, files_dir, rootfs)?;
+
+        // Run scripts
+        run_scripts(scripts_dir, rootfs)?;
+
+        // Configure bootloader, init, etc.
+        configure_system(profile, rootfs)?;
+        boot::install_uki_tools(profile, rootfs)?;
+        boot::install_verity_tools(profile, rootfs)?;
+        boot::install_encryption_tools(profile, rootfs)?;
+        disk::install_filesystem_tools(profile, rootfs)?;
+        boot::install_live_fs_support(profile, rootfs)?;
+        boot::install_memtest(profile, rootfs)?;
+        boot::install_plymouth(profile, rootfs)?;
+        boot::persist_kernel_cmdline(profile, rootfs)?;
+        secureboot::sign_kernels(profile, rootfs)?;
+
+        // Strip what [minimize] leaves out, then label everything written above
+        minimize::run(profile, rootfs)?;
+        selinux::configure(profile, rootfs)
+    })
+}
+
 fn find_profile(profiles_dir: &Path, profile_name: Option<&str>) -> Result<PathBuf> {
     let mut profiles = Vec::new();
     for entry in WalkDir::new(profiles_dir)
@@ -234,14 +708,18 @@ fn setup_podman_container(profile: &Profile) -> Result<()> {
     let container_dir = PathBuf::from("/tmp/.ulb/build-files");
     fs::create_dir_all(&container_dir).context("Failed to create container directory")?;
 
+    if profile.base_version.is_some() && matches!(profile.base.as_str(), "arch" | "void" | "gentoo") {
+        println!("{}", format!("Warning: {} is a rolling release, base_version is ignored.", profile.base).yellow());
+    }
+
+    setup_emulation(profile)?;
+
     // Pull base image based on profile.base
-    let base_image = match profile.base.as_str() {
-        "ubuntu" | "debian" => "ubuntu:latest",
-        "fedora" => "fedora:latest",
-        _ => return Err(anyhow::anyhow!("Unsupported base: {}. Supported: ubuntu, debian, fedora", profile.base)),
-    };
+    // Every later `podman run` uses whichever platform was pulled last under the tag
+    let base_image = base_image(profile)?;
+    let platform = format!("linux/{}", debian_arch(target_arch(profile)?));
     let output = Command::new("podman")
-        .args(&["pull", base_image])
+        .args(["pull", "--platform", &platform, &base_image])
         .output()
         .context("Failed to pull base image")?;
     if !output.status.success() {
@@ -249,27 +727,38 @@ fn setup_podman_container(profile: &Profile) -> Result<()> {
         return Err(anyhow::anyhow!("Failed to pull image"));
     }
 
+    // The nix image builds everything itself
+    if profile.base == "nixos" {
+        info!("Podman container setup complete");
+        return Ok(());
+    }
+
     // Install required tools in container
-    let tools = if profile.atomic {
-        vec!["ostree", "rpm-ostree", "xorriso", "mksquashfs"] // For atomic
+    let tools = if profile.format.iter().any(|format| sysext::ExtensionKind::from_format(format).is_some()) {
+        vec!["debootstrap", "erofs-utils", "squashfs-tools"]
+    } else if profile.atomic {
+        vec!["ostree", "rpm-ostree", "xorriso"] // For atomic
+    } else if profile.base == "arch" {
+        vec!["arch-install-scripts", "libisoburn", "squashfs-tools"]
+    } else if is_enterprise_linux(&profile.base) {
+        vec!["lorax", "xorriso", "squashfs-tools"]
+    } else if profile.base == "void" {
+        vec!["xorriso", "squashfs-tools"]
+    } else if profile.base == "gentoo" {
+        vec!["dev-libs/libisoburn", "sys-fs/squashfs-tools"]
     } else {
         vec!["debootstrap", "live-build", "xorriso", "lorax", "mksquashfs"]
     };
 
-    let pkg_manager = if profile.base == "fedora" { "dnf" } else { "apt" };
-    let install_cmd = if pkg_manager == "apt" {
-        format!("apt update && apt install -y {}", tools.join(" "))
-    } else {
-        format!("dnf install -y {}", tools.join(" "))
-    };
+    let install_cmd = package_manager(profile)?.refresh_and_install(&tools);
 
     let output = Command::new("podman")
-        .args(&[
+        .args([
             "run",
             "--rm",
             "-v",
             &format!("{}:/build:z", container_dir.display()),
-            base_image,
+            &base_image,
             "bash",
             "-c",
             &install_cmd,
@@ -285,92 +774,795 @@ fn setup_podman_container(profile: &Profile) -> Result<()> {
     Ok(())
 }
 
+const SUPPORTED_BASES: &str = "ubuntu, debian, fedora, rocky, almalinux, centos-stream, arch, void, gentoo, nixos";
+
+const SUPPORTED_ARCHES: &str = "x86_64, aarch64, riscv64";
+
+const SUPPORTED_FORMATS: &str = "iso, raw, qcow2, vmdk, vdi, vhdx, tar, wsl, lxc, pxe, rpi, aws, gce, azure, vagrant, sysext, confext";
+
+// Registers qemu-user-static binfmt_misc handlers with the fix-binary flag, so foreign
+// binaries also run inside containers and chroots
+const QEMU_USER_STATIC_IMAGE: &str = "docker.io/multiarch/qemu-user-static";
+
+const VOID_MIRROR: &str = "https://repo-default.voidlinux.org";
+
+const GENTOO_MIRROR: &str = "https://distfiles.gentoo.org";
+
+// Portage tree shared between builds and bind-mounted into the Gentoo chroot
+const PORTAGE_DIR: &str = "/tmp/.ulb/portage";
+
+fn is_enterprise_linux(base: &str) -> bool {
+    matches!(base, "rocky" | "almalinux" | "centos-stream")
+}
+
+/// Release of the base to build from: `base_version` if set, otherwise the base's default.
+/// Rolling bases have no release to pin and return "latest".
+fn release(profile: &Profile) -> String {
+    if let Some(version) = &profile.base_version {
+        return version.clone();
+    }
+    match profile.base.as_str() {
+        "ubuntu" => "24.04",
+        "debian" => "stable",
+        "rocky" | "almalinux" | "centos-stream" => "9",
+        "nixos" => "24.05",
+        _ => "latest",
+    }
+    .to_string()
+}
+
+fn base_image(profile: &Profile) -> Result<String> {
+    let release = release(profile);
+    match profile.base.as_str() {
+        "ubuntu" => Ok(format!("ubuntu:{}", release)),
+        "debian" => Ok(format!("debian:{}", release)),
+        "fedora" => Ok(format!("fedora:{}", release)),
+        "rocky" => Ok(format!("rockylinux:{}", release)),
+        "almalinux" => Ok(format!("almalinux:{}", release)),
+        "centos-stream" => Ok(format!("quay.io/centos/centos:stream{}", release)),
+        "arch" => Ok("archlinux:latest".to_string()),
+        "void" => Ok("ghcr.io/void-linux/void-glibc-full:latest".to_string()),
+        "gentoo" => Ok("gentoo/stage3:latest".to_string()),
+        "nixos" => Ok("nixos/nix:latest".to_string()),
+        _ => Err(anyhow::anyhow!("Unsupported base: {}. Supported: {}", profile.base, SUPPORTED_BASES)),
+    }
+}
+
+/// Target architecture by its kernel name; the Debian names are accepted too.
+fn target_arch(profile: &Profile) -> Result<&'static str> {
+    let arch = match profile.arch.as_deref() {
+        None | Some("x86_64") | Some("amd64") => "x86_64",
+        Some("aarch64") | Some("arm64") => "aarch64",
+        Some("riscv64") => "riscv64",
+        Some(arch) => return Err(anyhow::anyhow!("Unsupported arch: {}. Supported: {}", arch, SUPPORTED_ARCHES)),
+    };
+    // Only these publish riscv64 container images
+    if arch == "riscv64" && !matches!(profile.base.as_str(), "debian" | "ubuntu" | "gentoo") {
+        return Err(anyhow::anyhow!("riscv64 is only supported on the debian, ubuntu and gentoo bases"));
+    }
+    if arch != "x86_64" {
+        if profile.base == "arch" {
+            return Err(anyhow::anyhow!("The arch base only ships x86_64, Arch Linux ARM isn't supported"));
+        }
+        if profile.bios_support {
+            return Err(anyhow::anyhow!("bios_support is x86_64 only, set it to false for {}", arch));
+        }
+    }
+    Ok(arch)
+}
+
+/// Registers qemu-user-static emulation when the target architecture isn't the host's, so
+/// the base container, chroot package installs and scripts run as they would natively.
+fn setup_emulation(profile: &Profile) -> Result<()> {
+    let arch = target_arch(profile)?;
+    if arch == std::env::consts::ARCH || Path::new(&format!("/proc/sys/fs/binfmt_misc/qemu-{}", arch)).exists() {
+        return Ok(());
+    }
+    println!("{}", format!("Setting up qemu-user-static emulation for {}...", arch).yellow());
+
+    let output = Command::new("podman")
+        .args(["run", "--rm", "--privileged", QEMU_USER_STATIC_IMAGE, "--reset", "-p", "yes"])
+        .output()
+        .context("Failed to run qemu-user-static")?;
+    if !output.status.success() {
+        error!("qemu-user-static setup failed: {}", String::from_utf8_lossy(&output.stderr));
+        return Err(anyhow::anyhow!("Failed to register qemu-user-static for {}", arch));
+    }
+    if !Path::new(&format!("/proc/sys/fs/binfmt_misc/qemu-{}", arch)).exists() {
+        return Err(anyhow::anyhow!("No binfmt_misc handler for {} after qemu-user-static setup", arch));
+    }
+    info!("Emulating {} with qemu-user-static", arch);
+    Ok(())
+}
+
+/// Debian's name for `arch`, also used by container platforms.
+fn debian_arch(arch: &str) -> &'static str {
+    match arch {
+        "aarch64" => "arm64",
+        "riscv64" => "riscv64",
+        _ => "amd64",
+    }
+}
+
+// Gentoo's release directory and stage3 name for `arch`
+fn gentoo_stage_arch(arch: &str) -> (&'static str, &'static str) {
+    match arch {
+        "aarch64" => ("arm64", "arm64"),
+        "riscv64" => ("riscv", "rv64_lp64d"),
+        _ => ("amd64", "amd64"),
+    }
+}
+
+/// GRUB's EFI platform for `arch`.
+fn grub_efi_target(arch: &str) -> &'static str {
+    match arch {
+        "aarch64" => "arm64-efi",
+        "riscv64" => "riscv64-efi",
+        _ => "x86_64-efi",
+    }
+}
+
+/// debootstrap only understands codenames, while container tags also accept version numbers.
+fn debootstrap_suite(profile: &Profile) -> String {
+    let release = release(profile);
+    match release.as_str() {
+        "20.04" => "focal",
+        "22.04" => "jammy",
+        "24.04" => "noble",
+        "24.10" => "oracular",
+        "25.04" => "plucky",
+        "11" => "bullseye",
+        "12" => "bookworm",
+        "13" => "trixie",
+        other => other,
+    }
+    .to_string()
+}
+
+/// dnf --releasever; "latest" follows whatever release the container image ships.
+fn dnf_releasever(profile: &Profile) -> String {
+    match release(profile).as_str() {
+        "latest" | "rawhide" if profile.base == "fedora" => "$(rpm -E %fedora)".to_string(),
+        release => release.to_string(),
+    }
+}
+
+/// Archive mirrors in order of preference: `mirror` first, then `mirrors`, falling back to the
+/// upstream archive when none are configured.
+fn mirror_list(profile: &Profile) -> Vec<String> {
+    let mut mirrors: Vec<String> = profile.mirror.iter().chain(profile.mirrors.iter()).cloned().collect();
+    if mirrors.is_empty() {
+        let upstream = match profile.base.as_str() {
+            // Ubuntu keeps everything but x86 on the ports archive
+            "ubuntu" if target_arch(profile).is_ok_and(|arch| arch != "x86_64") => "http://ports.ubuntu.com/ubuntu-ports",
+            "ubuntu" => "http://archive.ubuntu.com/ubuntu",
+            "debian" => "http://deb.debian.org/debian",
+            "void" => VOID_MIRROR,
+            "gentoo" => GENTOO_MIRROR,
+            _ => return mirrors,
+        };
+        mirrors.push(upstream.to_string());
+    }
+    mirrors.iter().map(|m| m.trim_end_matches('/').to_string()).collect()
+}
+
+// Upstream URL prefix in the stock .repo files that a dnf mirror replaces
+fn dnf_upstream_prefix(base: &str) -> Option<&'static str> {
+    match base {
+        "fedora" => Some("http://download.example/pub/fedora/linux"),
+        "rocky" => Some("http://dl.rockylinux.org/$contentdir"),
+        "almalinux" => Some("https://repo.almalinux.org/almalinux"),
+        "centos-stream" => Some("https://mirror.stream.centos.org"),
+        _ => None,
+    }
+}
+
+// Void keeps the non-x86 packages in a subdirectory of each repository
+fn void_repository(profile: &Profile, mirror: &str) -> String {
+    match target_arch(profile) {
+        Ok("aarch64") => format!("{}/current/aarch64", mirror),
+        _ => format!("{}/current", mirror),
+    }
+}
+
+/// Shell snippet pointing the package manager under `root` ("" for the builder container,
+/// "/rootfs" for the image) at the configured mirrors. None when nothing needs rewriting.
+fn mirror_setup_command(profile: &Profile, root: &str) -> Option<String> {
+    if profile.mirror.is_none() && profile.mirrors.is_empty() {
+        return None;
+    }
+    let mirrors = mirror_list(profile);
+    match profile.base.as_str() {
+        // debootstrap writes the primary mirror itself, so only the fallbacks are added
+        "ubuntu" | "debian" if root == "/rootfs" => {
+            let lines: Vec<String> = mirrors[1..]
+                .iter()
+                .map(|m| format!("echo 'deb {} {} main' >> /rootfs/etc/apt/sources.list", m, debootstrap_suite(profile)))
+                .collect();
+            (!lines.is_empty()).then(|| lines.join(" && "))
+        }
+        "ubuntu" | "debian" => None,
+        base if dnf_upstream_prefix(base).is_some() => {
+            // Keep each repo's path and put it behind every mirror; metalinks would override baseurl
+            let baseurls: Vec<String> = mirrors.iter().map(|m| format!("{}\\1", m)).collect();
+            Some(format!(
+                "sed -i -e 's|^metalink=|#metalink=|' -e 's|^mirrorlist=|#mirrorlist=|' -e 's|^#\\? \\?baseurl={}\\(.*\\)$|baseurl={}|' {}/etc/yum.repos.d/*.repo",
+                dnf_upstream_prefix(base).unwrap_or_default(),
+                baseurls.join(" "),
+                root
+            ))
+        }
+        "arch" => {
+            let servers: Vec<String> = mirrors.iter().map(|m| format!("Server = {}/$repo/os/$arch", m)).collect();
+            Some(format!("printf '%s\\n' '{}' > {}/etc/pacman.d/mirrorlist", servers.join("' '"), root))
+        }
+        "void" => {
+            let repositories: Vec<String> =
+                mirrors.iter().map(|m| format!("repository={}", void_repository(profile, m))).collect();
+            Some(format!(
+                "mkdir -p {root}/etc/xbps.d && printf '%s\\n' '{}' > {root}/etc/xbps.d/00-repository-main.conf",
+                repositories.join("' '"),
+                root = root
+            ))
+        }
+        // Gentoo mirrors go into make.conf, see write_make_conf
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PackageManager {
+    Apt,
+    Dnf,
+    Pacman,
+    Xbps,
+    Portage,
+}
+
+impl PackageManager {
+    /// Non-interactive install command, as run inside the chroot.
+    fn install(self, packages: &[String]) -> String {
+        match self {
+            PackageManager::Apt => format!("apt install -y {}", packages.join(" ")),
+            PackageManager::Dnf => format!("dnf install -y {}", packages.join(" ")),
+            PackageManager::Pacman => format!("pacman -S --noconfirm --needed {}", packages.join(" ")),
+            PackageManager::Xbps => format!("xbps-install -Sy {}", packages.join(" ")),
+            PackageManager::Portage => format!("emerge --noreplace --quiet-build {}", packages.join(" ")),
+        }
+    }
+
+    /// Non-interactive removal command, as run inside the chroot.
+    fn remove(self, packages: &[String]) -> String {
+        match self {
+            PackageManager::Apt => format!("apt remove -y {}", packages.join(" ")),
+            PackageManager::Dnf => format!("dnf remove -y {}", packages.join(" ")),
+            PackageManager::Pacman => format!("pacman -Rns --noconfirm {}", packages.join(" ")),
+            PackageManager::Xbps => format!("xbps-remove -Ry {}", packages.join(" ")),
+            // --depclean refuses to remove anything still needed, unlike --unmerge
+            PackageManager::Portage => format!("emerge --depclean {}", packages.join(" ")),
+        }
+    }
+
+    /// Full upgrade of everything installed, as run inside the chroot.
+    fn upgrade(self) -> String {
+        match self {
+            PackageManager::Apt => "apt-get update && apt-get full-upgrade -y".to_string(),
+            PackageManager::Dnf => "dnf upgrade -y --refresh".to_string(),
+            PackageManager::Pacman => "pacman -Syu --noconfirm".to_string(),
+            PackageManager::Xbps => "xbps-install -Syu".to_string(),
+            PackageManager::Portage => "emerge --update --deep --newuse @world".to_string(),
+        }
+    }
+
+    /// Refreshes the package index and installs build tools in the builder container.
+    fn refresh_and_install(self, tools: &[&str]) -> String {
+        match self {
+            PackageManager::Apt => format!("apt update && apt install -y {}", tools.join(" ")),
+            PackageManager::Dnf => format!("dnf install -y {}", tools.join(" ")),
+            PackageManager::Pacman => format!("pacman -Sy --noconfirm --needed {}", tools.join(" ")),
+            // xbps refuses to install anything until it is itself up to date
+            PackageManager::Xbps => format!("xbps-install -Syu xbps && xbps-install -y {}", tools.join(" ")),
+            PackageManager::Portage => format!("emerge-webrsync && emerge --noreplace {}", tools.join(" ")),
+        }
+    }
+}
+
+fn package_manager(profile: &Profile) -> Result<PackageManager> {
+    match profile.base.as_str() {
+        "ubuntu" | "debian" => Ok(PackageManager::Apt),
+        "fedora" | "rocky" | "almalinux" | "centos-stream" => Ok(PackageManager::Dnf),
+        "arch" => Ok(PackageManager::Pacman),
+        "void" => Ok(PackageManager::Xbps),
+        "gentoo" => Ok(PackageManager::Portage),
+        _ => Err(anyhow::anyhow!("Unsupported base: {}. Supported: {}", profile.base, SUPPORTED_BASES)),
+    }
+}
+
+/// Runs `command` in a throwaway privileged build container, failing with `stage` in the error.
+fn podman_run(image: &str, volumes: &[String], command: &[&str], stage: &str) -> Result<()> {
+    podman_run_with_env(image, volumes, &[], command, stage)
+}
+
+/// Like `podman_run`, passing the host's `env` variables through by name so secrets never
+/// appear on the command line.
+fn podman_run_with_env(image: &str, volumes: &[String], env: &[&str], command: &[&str], stage: &str) -> Result<()> {
+    let mut args = vec!["run", "--rm", "--privileged"];
+    for volume in volumes {
+        args.push("-v");
+        args.push(volume);
+    }
+    for name in env {
+        args.push("-e");
+        args.push(name);
+    }
+    args.push(image);
+    args.extend_from_slice(command);
+
+    let output = Command::new("podman")
+        .args(&args)
+        .output()
+        .context(format!("Failed to run {}", stage.to_lowercase()))?;
+    if !output.status.success() {
+        error!("{} failed: {}", stage, String::from_utf8_lossy(&output.stderr));
+        return Err(anyhow::anyhow!("{} failed", stage));
+    }
+    Ok(())
+}
+
+fn rootfs_volume(rootfs: &Path) -> String {
+    format!("{}:/rootfs:z", rootfs.display())
+}
+
+/// Runs a shell command in the build container with the rootfs mounted at /rootfs.
+fn run_in_container(image: &str, rootfs: &Path, cmd: &str, stage: &str) -> Result<()> {
+    podman_run(image, &[rootfs_volume(rootfs)], &["bash", "-c", cmd], stage)
+}
+
+/// Runs a shell command in the build container with the given volumes, sharing the portage
+/// tree on gentoo so tools can be emerged without another sync.
+fn run_in_builder(profile: &Profile, volumes: Vec<String>, cmd: &str, stage: &str) -> Result<()> {
+    run_in_builder_with_env(profile, volumes, &[], cmd, stage)
+}
+
+fn run_in_builder_with_env(profile: &Profile, mut volumes: Vec<String>, env: &[&str], cmd: &str, stage: &str) -> Result<()> {
+    if profile.base == "gentoo" {
+        volumes.push(format!("{}:/var/db/repos/gentoo:z", PORTAGE_DIR));
+    }
+    podman_run_with_env(&base_image(profile)?, &volumes, env, &["bash", "-c", cmd], stage)
+}
+
+/// Runs a shell command chrooted into the rootfs, with any base-specific mounts in place.
+fn run_in_chroot(profile: &Profile, rootfs: &Path, cmd: &str, stage: &str) -> Result<()> {
+    let mut volumes = vec![rootfs_volume(rootfs)];
+    if profile.base == "gentoo" {
+        volumes.push(format!("{}:/rootfs/var/db/repos/gentoo:z", PORTAGE_DIR));
+    }
+    podman_run(&base_image(profile)?, &volumes, &["chroot", "/rootfs", "bash", "-c", cmd], stage)
+}
+
 fn install_base_system(profile: &Profile, rootfs: &Path) -> Result<()> {
     println!("{}", "Installing base system...".yellow());
 
-    let base_image = match profile.base.as_str() {
-        "ubuntu" | "debian" => "ubuntu:latest",
-        "fedora" => "fedora:latest",
-        _ => unreachable!(),
-    };
+    let base_image = base_image(profile)?;
 
     let base_cmd = match profile.base.as_str() {
         "debian" | "ubuntu" => "debootstrap",
-        "fedora" if profile.atomic => "rpm-ostree",
         "fedora" => "dnf",
+        base if is_enterprise_linux(base) => "dnf-el",
+        "arch" => "pacstrap",
+        "void" => "xbps",
+        "gentoo" => "stage3",
         _ => return Err(anyhow::anyhow!("Unsupported base: {}", profile.base)),
     };
 
     let install_cmd = match base_cmd {
         "debootstrap" => {
-            format!("debootstrap --arch=amd64 stable /rootfs http://deb.debian.org/debian/")
-        }
-        "rpm-ostree" => {
-            // Placeholder for atomic Fedora
-            "rpm-ostree install --repo=/rootfs/ostree-repo base-packages".to_string()
+            format!(
+                "debootstrap --arch={} {} /rootfs {}/",
+                debian_arch(target_arch(profile)?),
+                debootstrap_suite(profile),
+                mirror_list(profile)[0]
+            )
         }
         "dnf" => {
-            format!("dnf install -y --installroot=/rootfs --releasever=latest @core")
+            format!(
+                "dnf install -y --installroot=/rootfs --releasever={} --forcearch={} @core",
+                dnf_releasever(profile),
+                target_arch(profile)?
+            )
+        }
+        "dnf-el" => {
+            // EL pulls in weak deps aggressively
+            format!(
+                "dnf install -y --installroot=/rootfs --releasever={} --forcearch={} --setopt=install_weak_deps=False @core",
+                dnf_releasever(profile),
+                target_arch(profile)?
+            )
+        }
+        "pacstrap" => {
+            // Tools don't persist between --rm containers, so fetch pacstrap alongside the bootstrap
+            format!(
+                "pacman -Sy --noconfirm --needed arch-install-scripts && pacstrap -c -K /rootfs base {} {} mkinitcpio",
+                kernel::package(profile)?.unwrap_or_default(),
+                if firmware::managed(profile) { "" } else { "linux-firmware" }
+            )
+        }
+        "xbps" => {
+            // The rootfs needs the repository keys before xbps will trust anything in it
+            format!(
+                "mkdir -p /rootfs/var/db/xbps/keys && cp /var/db/xbps/keys/* /rootfs/var/db/xbps/keys/ && XBPS_ARCH={} xbps-install -Sy -r /rootfs -R {} base-system",
+                target_arch(profile)?,
+                void_repository(profile, &mirror_list(profile)[0])
+            )
+        }
+        "stage3" => {
+            let variant = if profile.init_system == "openrc" { "openrc" } else { "systemd" };
+            let (dir, stage) = gentoo_stage_arch(target_arch(profile)?);
+            // The latest-*.txt index is clearsigned, so pick the tarball path out of it
+            format!(
+                "[ -f /var/db/repos/gentoo/metadata/timestamp.chk ] || emerge-webrsync; \
+                 STAGE3=$(wget -qO- {mirror}/releases/{dir}/autobuilds/latest-stage3-{stage}-{variant}.txt | grep -m1 -o '^[0-9TZ]*/stage3-[^ ]*\\.tar\\.xz') && \
+                 wget -qO /tmp/stage3.tar.xz {mirror}/releases/{dir}/autobuilds/$STAGE3 && \
+                 tar xpf /tmp/stage3.tar.xz --xattrs-include='*.*' --numeric-owner -C /rootfs && \
+                 cp -L /etc/resolv.conf /rootfs/etc/",
+                mirror = mirror_list(profile)[0],
+                dir = dir,
+                stage = stage,
+                variant = variant
+            )
         }
         _ => unreachable!(),
     };
 
-    let output = Command::new("podman")
-        .args(&[
-            "run",
-            "--rm",
-            "--privileged",  // May need for some installs
-            "-v",
-            &format!("{}:/rootfs:z", rootfs.display()),
-            base_image,
-            "bash",
-            "-c",
-            &install_cmd,
-        ])
-        .output()
-        .context("Failed to run base install")?;
-    if !output.status.success() {
-        error!("Base install failed: {}", String::from_utf8_lossy(&output.stderr));
-        return Err(anyhow::anyhow!("Base system installation failed"));
+    // Point the builder at the mirrors before bootstrapping and the image at them afterwards
+    let mut install_cmd = install_cmd;
+    if let Some(before) = mirror_setup_command(profile, "") {
+        install_cmd = format!("{} && {}", before, install_cmd);
+    }
+    if let Some(after) = mirror_setup_command(profile, "/rootfs") {
+        install_cmd = format!("{} && {}", install_cmd, after);
+    }
+
+    let mut volumes = vec![rootfs_volume(rootfs)];
+    if profile.base == "gentoo" {
+        fs::create_dir_all(PORTAGE_DIR).context("Failed to create portage tree directory")?;
+        volumes.push(format!("{}:/var/db/repos/gentoo:z", PORTAGE_DIR));
+    }
+    podman_run(&base_image, &volumes, &["bash", "-c", &install_cmd], "Base system installation")?;
+
+    if profile.base == "gentoo" {
+        write_make_conf(profile, rootfs)?;
+        // Stage3 ships without a kernel; the dist-kernel also builds the initramfs on install
+        if let Some(kernel) = kernel::package(profile)? {
+            let kernel_cmd = format!("emerge --noreplace '{}'", kernel);
+            run_in_chroot(profile, rootfs, &kernel_cmd, "Kernel installation")?;
+        }
+    }
+
+    if is_enterprise_linux(&profile.base) && profile.epel {
+        // CRB carries the -devel packages a lot of EPEL depends on
+        let epel_cmd = if profile.base == "centos-stream" {
+            "dnf install -y dnf-plugins-core epel-release epel-next-release && dnf config-manager --set-enabled crb"
+        } else {
+            "dnf install -y dnf-plugins-core epel-release && dnf config-manager --set-enabled crb"
+        };
+        run_in_chroot(profile, rootfs, epel_cmd, "EPEL setup")?;
     }
 
     Ok(())
 }
 
+fn write_make_conf(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let has_mirrors = profile.mirror.is_some() || !profile.mirrors.is_empty();
+    if profile.make_conf.is_none() && !has_mirrors {
+        return Ok(());
+    }
+
+    let mut settings = String::from("\n# Added by ULB\n");
+    if has_mirrors {
+        settings.push_str(&format!("GENTOO_MIRRORS=\"{}\"\n", mirror_list(profile).join(" ")));
+    }
+    if let Some(make_conf) = &profile.make_conf {
+        if !make_conf.use_flags.is_empty() {
+            settings.push_str(&format!("USE=\"${{USE}} {}\"\n", make_conf.use_flags.join(" ")));
+        }
+        if let Some(makeopts) = &make_conf.makeopts {
+            settings.push_str(&format!("MAKEOPTS=\"{}\"\n", makeopts));
+        }
+        for (key, value) in &make_conf.extra {
+            settings.push_str(&format!("{}=\"{}\"\n", key, value));
+        }
+    }
+
+    let path = rootfs.join("etc/portage/make.conf");
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .context(format!("Failed to open {}", path.display()))?;
+    file.write_all(settings.as_bytes()).context("Failed to write make.conf")?;
+    Ok(())
+}
+
+fn upgrade_system(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.upgrade {
+        println!("{}", "Upgrading base system...".yellow());
+        let upgrade_cmd = package_manager(profile)?.upgrade();
+        run_in_chroot(profile, rootfs, &upgrade_cmd, "System upgrade")?;
+    }
+    Ok(())
+}
+
+/// The [debconf] answers as debconf-set-selections lines: "owner question type value", the
+/// owner being the package the question is named after. Strings and booleans get their type
+/// from the value, a { type, value } table sets it explicitly (e.g. "select").
+fn debconf_selections(profile: &Profile) -> Result<Vec<String>> {
+    if !profile.debconf.is_empty() && package_manager(profile)? != PackageManager::Apt {
+        return Err(anyhow::anyhow!("[debconf] needs the debian or ubuntu base"));
+    }
+    let mut selections = Vec::new();
+    for (question, answer) in &profile.debconf {
+        let owner = match question.split_once('/') {
+            Some((owner, name)) if !owner.is_empty() && !name.is_empty() && !question.contains(char::is_whitespace) => owner,
+            _ => return Err(anyhow::anyhow!("Invalid [debconf] question: {:?}, expected e.g. \"postfix/main_mailer_type\"", question)),
+        };
+        let (kind, value) = match answer {
+            toml::Value::String(value) => ("string", value.clone()),
+            toml::Value::Boolean(value) => ("boolean", value.to_string()),
+            toml::Value::Table(table) => match (table.get("type"), table.get("value")) {
+                (Some(toml::Value::String(kind)), Some(value)) if matches!(kind.as_str(), "string" | "boolean" | "select" | "multiselect" | "password" | "note") => {
+                    let value = match value {
+                        toml::Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    };
+                    (kind.as_str(), value)
+                }
+                _ => return Err(anyhow::anyhow!("[debconf] {} needs type (string, boolean, select, multiselect, password or note) and value", question)),
+            },
+            _ => return Err(anyhow::anyhow!("[debconf] {} must be a string, boolean or {{ type, value }} table", question)),
+        };
+        if value.contains('\n') {
+            return Err(anyhow::anyhow!("[debconf] {} can't span lines", question));
+        }
+        selections.push(format!("{} {} {} {}", owner, question, kind, value));
+    }
+    Ok(selections)
+}
+
+fn check_debconf(profile: &Profile) -> Result<()> {
+    debconf_selections(profile).map(|_| ())
+}
+
+/// Feeds [debconf] to debconf-set-selections before any package is installed, then reconfigures
+/// the packages debootstrap already installed (tzdata, keyboard-configuration) with the answers.
+fn preseed_debconf(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let selections = debconf_selections(profile)?;
+    if selections.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Preseeding debconf...".yellow());
+
+    let mut owners: Vec<&str> = selections.iter().filter_map(|line| line.split(' ').next()).collect();
+    owners.dedup();
+    let debconf_cmd = format!(
+        "set -e\ncat <<'EOF' | debconf-set-selections\n{}\nEOF\nfor PACKAGE in {}; do\n    if dpkg -s $PACKAGE >/dev/null 2>&1; then DEBIAN_FRONTEND=noninteractive dpkg-reconfigure -f noninteractive $PACKAGE; fi\ndone",
+        selections.join("\n"),
+        owners.join(" ")
+    );
+    run_in_chroot(profile, rootfs, &debconf_cmd, "debconf preseeding")
+}
+
+/// Replaces systemd with sysvinit and OpenRC on Debian, and runit with s6-linux-init on Void.
+/// Gentoo's openrc stage3 boots OpenRC already and Void ships runit.
+fn install_init_system(profile: &Profile, rootfs: &Path) -> Result<()> {
+    check_services(profile)?;
+    let package_manager = package_manager(profile)?;
+    match (profile.init_system.as_str(), profile.base.as_str()) {
+        ("openrc", "debian") => {
+            println!("{}", "Installing OpenRC...".yellow());
+            let install_cmd = package_manager.install(&["sysvinit-core".to_string(), "openrc".to_string(), "orphan-sysvinit-scripts".to_string()]);
+            run_in_chroot(profile, rootfs, &format!("set -e\n{}\napt-get purge -y systemd-sysv 2>/dev/null || true", install_cmd), "OpenRC installation")
+        }
+        // runit-void stays installed: its core services bring the system up and its service
+        // directories are what gets converted for s6-rc
+        ("s6", _) => {
+            println!("{}", "Installing s6...".yellow());
+            let install_cmd = package_manager.install(&["s6".to_string(), "s6-rc".to_string(), "s6-linux-init".to_string()]);
+            let init_cmd = "rm -rf /etc/s6-linux-init/current\n\
+                            s6-linux-init-maker -1 -c /etc/s6-linux-init/current /etc/s6-linux-init/current\n\
+                            cp -af /etc/s6-linux-init/current/bin/. /usr/bin/";
+            run_in_chroot(profile, rootfs, &format!("set -e\n{}\n{}", install_cmd, init_cmd), "s6 installation")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Shell commands turning the services linked into runit's default runlevel into s6-rc longruns
+/// in the default bundle, after a oneshot running Void's core services, and compiling the
+/// database. Definitions already in the s6-rc source directory are kept, for run scripts that
+/// need more than s6-supervise offers (e.g. ones calling `sv check`).
+fn s6_rc_command() -> String {
+    format!(
+        "rm -rf {src}/ulb-core {src}/default {compiled}\n\
+         mkdir -p {src}/ulb-core {src}/default/contents.d\n\
+         echo oneshot > {src}/ulb-core/type\n\
+         echo '/bin/sh -c \". /etc/runit/functions; for f in /etc/runit/core-services/*.sh; do [ -r $f ] && . $f; done; true\"' > {src}/ulb-core/up\n\
+         echo bundle > {src}/default/type && touch {src}/default/contents.d/ulb-core\n\
+         for LINK in /etc/runit/runsvdir/default/*; do\n\
+             SV=$(basename $LINK); DIR={src}/$SV\n\
+             [ -d \"$(readlink -f $LINK)\" ] || continue\n\
+             if [ ! -e $DIR ]; then\n\
+                 mkdir -p $DIR/dependencies.d && cp -a $(readlink -f $LINK)/. $DIR/ && rm -rf $DIR/supervise $DIR/log\n\
+                 echo longrun > $DIR/type && touch $DIR/dependencies.d/ulb-core\n\
+             fi\n\
+             touch {src}/default/contents.d/$SV\n\
+         done\n\
+         s6-rc-compile {compiled} {src}",
+        src = S6_RC_SOURCE,
+        compiled = S6_RC_COMPILED
+    )
+}
+
 fn install_packages(profile: &Profile, rootfs: &Path) -> Result<()> {
     if !profile.packages.is_empty() {
         println!("{}", "Installing packages...".yellow());
 
-        let base_image = match profile.base.as_str() {
-            "ubuntu" | "debian" => "ubuntu:latest",
-            "fedora" => "fedora:latest",
-            _ => unreachable!(),
-        };
+        let package_manager = package_manager(profile)?;
+        let pins: Vec<(&str, &str)> = profile.packages.iter().filter_map(|p| p.split_once('=')).collect();
 
-        let pkg_manager = if profile.base == "fedora" { "dnf" } else { "apt" };
-        let install_cmd = format!("{} install -y {}", pkg_manager, profile.packages.join(" "));
-
-        let output = Command::new("podman")
-            .args(&[
-                "run",
-                "--rm",
-                "-v",
-                &format!("{}:/rootfs:z", rootfs.display()),
-                base_image,
-                "chroot",
-                "/rootfs",
-                "bash",
-                "-c",
-                &install_cmd,
-            ])
-            .output()
-            .context("Failed to install packages")?;
-        if !output.status.success() {
-            error!("Package install failed: {}", String::from_utf8_lossy(&output.stderr));
-            return Err(anyhow::anyhow!("Package installation failed"));
+        // Version constraints ("nginx=1.24.*") are pinned so later upgrades keep them too
+        let mut install_cmd = match package_manager {
+            PackageManager::Apt => {
+                write_apt_pins(rootfs, &pins)?;
+                let names: Vec<String> = profile.packages.iter().map(|p| package_name(p).to_string()).collect();
+                package_manager.install(&names)
+            }
+            PackageManager::Dnf => {
+                // Quoted so version globs reach dnf instead of the shell
+                let specs: Vec<String> = profile.packages.iter().map(|p| format!("'{}'", p.replacen('=', "-", 1))).collect();
+                package_manager.install(&specs)
+            }
+            _ if !pins.is_empty() => {
+                return Err(anyhow::anyhow!("Package version constraints are not supported on the {} base", profile.base))
+            }
+            _ => package_manager.install(&profile.packages),
+        };
+        if package_manager == PackageManager::Dnf && !pins.is_empty() {
+            let locks: Vec<String> = pins.iter().map(|(name, version)| format!("'{}-{}'", name, version)).collect();
+            install_cmd.push_str(&format!(
+                " && dnf install -y 'dnf-command(versionlock)' && dnf versionlock add {}",
+                locks.join(" ")
+            ));
         }
+        run_in_chroot(profile, rootfs, &install_cmd, "Package installation")?;
+    }
+
+    Ok(())
+}
+
+/// Installs the .deb/.rpm files from packages/ through apt/dnf, so their dependencies are
+/// pulled from the configured repositories like for any other package.
+fn install_local_packages(profile: &Profile, packages_dir: &Path, rootfs: &Path) -> Result<()> {
+    if !packages_dir.exists() {
+        return Ok(());
+    }
+    let mut local_packages: Vec<PathBuf> = fs::read_dir(packages_dir)
+        .context("Failed to read packages dir")?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "deb" || ext == "rpm"))
+        .collect();
+    if local_packages.is_empty() {
+        return Ok(());
+    }
+    local_packages.sort();
+
+    let package_manager = package_manager(profile)?;
+    let extension = match package_manager {
+        PackageManager::Apt => "deb",
+        PackageManager::Dnf => "rpm",
+        _ => return Err(anyhow::anyhow!("Local packages are not supported on the {} base", profile.base)),
+    };
+    if let Some(foreign) = local_packages.iter().find(|path| path.extension().is_some_and(|ext| ext != extension)) {
+        return Err(anyhow::anyhow!("{} can't be installed on the {} base", foreign.display(), profile.base));
     }
+    println!("{}", "Installing local packages...".yellow());
+
+    let staging = rootfs.join("tmp/ulb-local");
+    fs::create_dir_all(&staging).context("Failed to create local package staging directory")?;
+    let mut specs = Vec::new();
+    for package in &local_packages {
+        let file_name = package.file_name().context("Local package has no file name")?;
+        fs::copy(package, staging.join(file_name)).context(format!("Failed to copy {}", package.display()))?;
+        // A path (rather than a name) makes apt/dnf install the file and resolve its dependencies
+        specs.push(format!("'/tmp/ulb-local/{}'", file_name.to_string_lossy()));
+    }
+
+    let install_cmd = format!("{}; status=$?; rm -rf /tmp/ulb-local; exit $status", package_manager.install(&specs));
+    run_in_chroot(profile, rootfs, &install_cmd, "Local package installation")
+}
+
+/// Builds the profile's AUR packages in the builder container and installs the results into
+/// the rootfs. makepkg refuses to run as root, so the build runs as a throwaway user.
+fn install_aur_packages(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.aur_packages.is_empty() {
+        return Ok(());
+    }
+    if profile.base != "arch" {
+        return Err(anyhow::anyhow!("aur_packages can only be used on the arch base"));
+    }
+    println!("{}", "Building AUR packages...".yellow());
+
+    // -i installs each package in the builder too, so later AUR packages can depend on it. The
+    // <pkgbase>-debug packages makepkg splits off stay behind.
+    let builds: Vec<String> = profile
+        .aur_packages
+        .iter()
+        .map(|pkg| {
+            format!(
+                "sudo -u builder git clone https://aur.archlinux.org/{pkg}.git /home/builder/{pkg} && \
+                 (cd /home/builder/{pkg} && sudo -u builder makepkg -si --noconfirm) && \
+                 find /home/builder/{pkg} -maxdepth 1 -name '*.pkg.tar.*' ! -name '*.sig' ! -name '*-debug-*' -exec cp {{}} /rootfs/tmp/ulb-aur/ \\;",
+                pkg = pkg
+            )
+        })
+        .collect();
+    let build_cmd = format!(
+        "pacman -Sy --noconfirm --needed base-devel git sudo && \
+         useradd -m builder && echo 'builder ALL=(ALL) NOPASSWD: ALL' > /etc/sudoers.d/builder && \
+         mkdir -p /rootfs/tmp/ulb-aur && {}",
+        builds.join(" && ")
+    );
+    run_in_container(&base_image(profile)?, rootfs, &build_cmd, "AUR build")?;
+
+    run_in_chroot(
+        profile,
+        rootfs,
+        "pacman -U --noconfirm --needed /tmp/ulb-aur/*.pkg.tar.* && rm -rf /tmp/ulb-aur",
+        "AUR package installation",
+    )
+}
+
+/// Strips a version constraint from a package spec: "nginx=1.24.*" -> "nginx".
+fn package_name(spec: &str) -> &str {
+    spec.split_once('=').map_or(spec, |(name, _)| name)
+}
+
+/// Writes `content` to `path`, creating the directories leading to it.
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(path, content).context(format!("Failed to write {}", path.display()))
+}
+
+/// serde default of the settings that are on unless turned off.
+fn default_true() -> bool {
+    true
+}
+
+/// A double-quoted string literal, valid as both JSON and YAML.
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
 
+/// A single-quoted shell word.
+fn shell_quoted(text: impl std::fmt::Display) -> String {
+    format!("'{}'", text.to_string().replace('\'', "'\\''"))
+}
+
+fn write_apt_pins(rootfs: &Path, pins: &[(&str, &str)]) -> Result<()> {
+    let path = rootfs.join("etc/apt/preferences.d/ulb-pins");
+    if pins.is_empty() {
+        return Ok(());
+    }
+    // Priority above 1000 lets apt downgrade to the pinned version if needed
+    let content: Vec<String> = pins
+        .iter()
+        .map(|(name, version)| format!("Package: {}\nPin: version {}\nPin-Priority: 1001\n", name, version))
+        .collect();
+    fs::create_dir_all(path.parent().unwrap_or(rootfs)).context("Failed to create apt preferences directory")?;
+    fs::write(&path, content.join("\n")).context("Failed to write apt pins")?;
     Ok(())
 }
 
@@ -378,38 +1570,35 @@ fn remove_packages(profile: &Profile, rootfs: &Path) -> Result<()> {
     if !profile.packages_to_remove.is_empty() {
         println!("{}", "Removing packages...".yellow());
 
-        let base_image = match profile.base.as_str() {
-            "ubuntu" | "debian" => "ubuntu:latest",
-            "fedora" => "fedora:latest",
-            _ => unreachable!(),
-        };
-
-        let pkg_manager = if profile.base == "fedora" { "dnf" } else { "apt" };
-        let remove_cmd = format!("{} remove -y {}", pkg_manager, profile.packages_to_remove.join(" "));
-
-        let output = Command::new("podman")
-            .args(&[
-                "run",
-                "--rm",
-                "-v",
-                &format!("{}:/rootfs:z", rootfs.display()),
-                base_image,
-                "chroot",
-                "/rootfs",
-                "bash",
-                "-c",
-                &remove_cmd,
-            ])
-            .output()
-            .context("Failed to remove packages")?;
-        if !output.status.success() {
-            error!("Package remove failed: {}", String::from_utf8_lossy(&output.stderr));
-            return Err(anyhow::anyhow!("Package removal failed"));
-        }
+        let remove_cmd = package_manager(profile)?.remove(&profile.packages_to_remove);
+        run_in_chroot(profile, rootfs, &remove_cmd, "Package removal")?;
     }
     Ok(())
 }
 
+fn hold_packages(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if profile.packages_hold.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Holding packages...".yellow());
+
+    let held = profile.packages_hold.join(" ");
+    let hold_cmd = match package_manager(profile)? {
+        PackageManager::Apt => format!("apt-mark hold {}", held),
+        // dnf.conf only carries [main], so appending lands in the right section
+        PackageManager::Dnf => format!("echo 'excludepkgs={}' >> /etc/dnf/dnf.conf", profile.packages_hold.join(",")),
+        PackageManager::Pacman => format!(
+            "sed -i 's/^#\\?IgnorePkg *=.*/IgnorePkg = {}/' /etc/pacman.conf",
+            held
+        ),
+        PackageManager::Xbps => format!("xbps-pkgdb -m hold {}", held),
+        PackageManager::Portage => {
+            return Err(anyhow::anyhow!("packages_hold is not supported on the {} base", profile.base))
+        }
+    };
+    run_in_chroot(profile, rootfs, &hold_cmd, "Package hold")
+}
+
 fn copy_files(src_dir: &Path, dest_dir: &Path) -> Result<()> {
     if src_dir.exists() {
         println!("{}", "Copying files...".yellow());
@@ -433,7 +1622,7 @@ fn run_scripts(scripts_dir: &Path, rootfs: &Path) -> Result<()> {
         let mut scripts: Vec<_> = fs::read_dir(scripts_dir)
             .context("Failed to read scripts dir")?
             .filter_map(|e| e.ok())
-            .filter(|e| e.path().extension().map_or(false, |ext| ext == "sh"))
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "sh"))
             .collect();
         
         // Sort scripts alphabetically to ensure consistent order
@@ -444,7 +1633,7 @@ fn run_scripts(scripts_dir: &Path, rootfs: &Path) -> Result<()> {
         for entry in scripts {
             info!("Running script: {}", entry.path().display());
             let output = Command::new("podman")
-                .args(&[
+                .args([
                     "run",
                     "--rm",
                     "-v",
@@ -471,53 +1660,42 @@ fn run_scripts(scripts_dir: &Path, rootfs: &Path) -> Result<()> {
 fn configure_system(profile: &Profile, rootfs: &Path) -> Result<()> {
     println!("{}", "Configuring system...".yellow());
 
-    let base_image = match profile.base.as_str() {
-        "ubuntu" | "debian" => "ubuntu:latest",
-        "fedora" => "fedora:latest",
-        _ => unreachable!(),
-    };
+    // Configure init system and services
+    configure_services(profile, rootfs)?;
+    configure_sysctl(profile, rootfs)?;
+    swap::configure(profile, rootfs)?;
+    timers::configure(profile, rootfs)?;
+    configure_alternatives(profile, rootfs)?;
+    overlayroot::configure(profile, rootfs)?;
 
-    // Configure init system
-    let init_cmd = match profile.init_system.as_str() {
-        "systemd" => "systemctl enable systemd-sysv-install",
-        "openrc" => "rc-update add ...", // Placeholder
-        _ => return Err(anyhow::anyhow!("Unsupported init system: {}", profile.init_system)),
-    };
+    let base_image = base_image(profile)?;
 
-    let output = Command::new("podman")
-        .args(&[
-            "run",
-            "--rm",
-            "-v",
-            &format!("{}:/rootfs:z", rootfs.display()),
-            base_image,
-            "chroot",
-            "/rootfs",
-            "bash",
-            "-c",
-            init_cmd,
-        ])
-        .output()
-        .context("Failed to configure init")?;
-    if !output.status.success() {
-        error!("Init config failed: {}", String::from_utf8_lossy(&output.stderr));
+    // Disk images get their bootloader installed onto the image itself, other formats need none
+    if !profile.format.iter().any(|format| format == "iso") {
+        return generate_initramfs(profile, rootfs);
     }
 
     // Configure bootloader
     let bootloader_cmd = match profile.bootloader.as_str() {
-        "grub" => "grub-install --target=x86_64-efi --efi-directory=/boot/efi --bootloader-id=GRUB",
+        "grub" => &format!(
+            "grub-install --target={} --efi-directory=/boot/efi --bootloader-id=GRUB",
+            grub_efi_target(target_arch(profile)?)
+        ),
         "systemd-boot" => "bootctl --path=/boot install",
+        "extlinux" => return Err(anyhow::anyhow!("bootloader = \"extlinux\" only boots disk images")),
+        // rEFInd goes onto the ISO's EFI image when the ISO is put together
+        "refind" => return generate_initramfs(profile, rootfs),
         _ => return Err(anyhow::anyhow!("Unsupported bootloader: {}", profile.bootloader)),
     };
 
     let output = Command::new("podman")
-        .args(&[
+        .args([
             "run",
             "--rm",
             "--privileged",
             "-v",
             &format!("{}:/rootfs:z", rootfs.display()),
-            base_image,
+            &base_image,
             "chroot",
             "/rootfs",
             "bash",
@@ -535,81 +1713,221 @@ fn configure_system(profile: &Profile, rootfs: &Path) -> Result<()> {
     if !profile.uefi_support && !profile.bios_support {
         return Err(anyhow::anyhow!("Must support at least UEFI or BIOS"));
     }
-    // Additional config if needed, e.g., generate initramfs
+    generate_initramfs(profile, rootfs)
+}
 
-    let mkinit_cmd = if profile.base == "fedora" {
-        "dracut -f /boot/initramfs.img"
-    } else {
-        "update-initramfs -u"
+fn generate_initramfs(profile: &Profile, rootfs: &Path) -> Result<()> {
+    initramfs::configure(profile, rootfs)?;
+    let mkinit_cmd = &match profile.base.as_str() {
+        // dracut defaults to the running kernel, which is the host's in a container
+        "fedora" | "rocky" | "almalinux" | "centos-stream" => {
+            "dracut -f /boot/initramfs.img $(ls /lib/modules | sort -V | tail -n1)".to_string()
+        }
+        "arch" => "mkinitcpio -P".to_string(),
+        "void" => "xbps-reconfigure -fa".to_string(),
+        "gentoo" => match kernel::package(profile)? {
+            Some(kernel) => format!("emerge --config '{}'", kernel),
+            None => "dracut -f /boot/initramfs.img $(ls /lib/modules | sort -V | tail -n1)".to_string(),
+        },
+        _ => "update-initramfs -u -k all".to_string(),
     };
 
-    let output = Command::new("podman")
-        .args(&[
-            "run",
-            "--rm",
-            "-v",
-            &format!("{}:/rootfs:z", rootfs.display()),
-            base_image,
-            "chroot",
-            "/rootfs",
-            "bash",
-            "-c",
-            mkinit_cmd,
-        ])
-        .output()
-        .context("Failed to generate initramfs")?;
-    if !output.status.success() {
-        error!("Initramfs failed: {}", String::from_utf8_lossy(&output.stderr));
+    // An initramfs that can't mount the root is worse than a failed build
+    run_in_chroot(profile, rootfs, mkinit_cmd, "Initramfs generation")
+}
+
+// Kernel parameters from [sysctl], applied at boot by systemd-sysctl or the procps service
+const SYSCTL_CONF: &str = "etc/sysctl.d/99-ulb.conf";
+
+/// The [sysctl] entries as sysctl.d lines, once checked.
+fn sysctl_lines(profile: &Profile) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for (key, value) in &profile.sysctl {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '/' | '_' | '-' | '*')) {
+            return Err(anyhow::anyhow!("Invalid [sysctl] key: {:?}", key));
+        }
+        let value = match value {
+            toml::Value::String(text) if !text.contains('\n') => text.clone(),
+            toml::Value::Integer(number) => number.to_string(),
+            toml::Value::Boolean(flag) => u8::from(*flag).to_string(),
+            _ => return Err(anyhow::anyhow!("[sysctl] {} must be a number, boolean or single-line string", key)),
+        };
+        lines.push(format!("{} = {}", key, value));
     }
+    Ok(lines)
+}
+
+fn check_sysctl(profile: &Profile) -> Result<()> {
+    sysctl_lines(profile).map(|_| ())
+}
 
+/// Writes [sysctl] to /etc/sysctl.d/99-ulb.conf, after the distro's own files.
+fn configure_sysctl(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let lines = sysctl_lines(profile)?;
+    if lines.is_empty() {
+        return Ok(());
+    }
+    let path = rootfs.join(SYSCTL_CONF);
+    write_file(&path, &format!("# Generated by ulb from [sysctl]\n{}\n", lines.join("\n")))
+}
+
+/// Checks [alternatives]: names and choices, on the bases with an alternatives system.
+fn check_alternatives(profile: &Profile) -> Result<()> {
+    if profile.alternatives.is_empty() {
+        return Ok(());
+    }
+    if !matches!(package_manager(profile)?, PackageManager::Apt | PackageManager::Dnf) {
+        return Err(anyhow::anyhow!("[alternatives] needs a debian, ubuntu, fedora or EL base"));
+    }
+    let valid = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | '+'));
+    if let Some((name, choice)) = profile.alternatives.iter().find(|(name, choice)| !valid(name) || name.contains('/') || !valid(choice)) {
+        return Err(anyhow::anyhow!("Invalid [alternatives] entry: {} = {:?}", name, choice));
+    }
     Ok(())
 }
 
-fn build_iso(profile: &Profile, rootfs: &Path, build_dir: &Path) -> Result<()> {
-    println!("{}", "Building ISO...".yellow());
+/// Selects the [alternatives] with update-alternatives (alternatives on dnf bases). A choice
+/// without a path picks the registered alternative of that file name, e.g. firefox for
+/// /usr/bin/firefox.
+fn configure_alternatives(profile: &Profile, rootfs: &Path) -> Result<()> {
+    check_alternatives(profile)?;
+    if profile.alternatives.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Setting alternatives...".yellow());
 
-    let iso_path = build_dir.join(format!("{}-{}.iso", profile.distro_name, profile.version));
-    let tmp_output = PathBuf::from("/tmp/.ulb/output.iso");
+    let tool = if package_manager(profile)? == PackageManager::Dnf { "alternatives" } else { "update-alternatives" };
+    let mut lines = vec!["set -e".to_string()];
+    for (name, choice) in &profile.alternatives {
+        if choice.starts_with('/') {
+            lines.push(format!("{} --set {} {}", tool, name, choice));
+        } else {
+            lines.push(format!(
+                "CHOICE=$({tool} --list {name} | grep -m1 '/{choice}$') || {{ echo \"No {choice} alternative for {name}\" >&2; exit 1; }}\n{tool} --set {name} \"$CHOICE\""
+            ));
+        }
+    }
+    run_in_chroot(profile, rootfs, &lines.join("\n"), "Alternatives configuration")
+}
 
-    let base_image = match profile.base.as_str() {
-        "ubuntu" | "debian" => "ubuntu:latest",
-        "fedora" => "fedora:latest",
-        _ => unreachable!(),
-    };
+// Preset written from services_enable and services_disable
+const SYSTEMD_PRESET_DIR: &str = "/etc/systemd/system-preset";
+const SYSTEMD_PRESET: &str = "90-ulb.preset";
+
+// OpenRC services added to their runlevels where installed, so the live system gets devices
+// and a network
+const OPENRC_DEFAULT_SERVICES: &[(&str, &str)] = &[("udev", "sysinit"), ("udev-trigger", "sysinit"), ("dhcpcd", "default")];
+
+// Services linked into the default runit runlevel where installed, so the live system gets
+// consoles, devices, a session bus and a network
+const RUNIT_DEFAULT_SERVICES: &[&str] = &["agetty-tty1", "agetty-tty2", "udevd", "dbus", "dhcpcd"];
+
+// s6-rc service definitions and the database compiled from them, where s6-linux-init's rc.init
+// looks for it
+const S6_RC_SOURCE: &str = "/etc/s6-rc/source";
+const S6_RC_COMPILED: &str = "/etc/s6-rc/compiled";
+
+/// Checks the init system against the base and the service lists against it.
+fn check_services(profile: &Profile) -> Result<()> {
+    match (profile.init_system.as_str(), profile.base.as_str()) {
+        ("systemd", "void") => return Err(anyhow::anyhow!("Void Linux does not ship systemd, use init_system = \"runit\"")),
+        ("runit" | "s6", "void") | ("systemd", _) | ("openrc", "debian" | "gentoo") => {}
+        ("openrc", base) => return Err(anyhow::anyhow!("openrc is only supported on the debian and gentoo bases, not {}", base)),
+        ("runit" | "s6", base) => return Err(anyhow::anyhow!("{} is only supported on the void base, not {}", profile.init_system, base)),
+        _ => return Err(anyhow::anyhow!("Unsupported init system: {}", profile.init_system)),
+    }
+    let services = profile.services_enable.iter().chain(&profile.services_disable).chain(&profile.services_mask);
+    if let Some(service) = services.into_iter().find(|s| s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '@' | '.' | '_' | ':' | '-'))) {
+        return Err(anyhow::anyhow!("Invalid service name: {:?}", service));
+    }
+    if !profile.services_mask.is_empty() && profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("services_mask needs init_system = \"systemd\""));
+    }
+    Ok(())
+}
 
-    let build_cmd = if profile.atomic {
-        // Placeholder for atomic build
-        "rpm-ostree compose tree --repo=/rootfs/ostree-repo /rootfs/tree.yaml && mksquashfs /rootfs /filesystem.squashfs -comp xz && xorriso -as mkisofs -o /output.iso -V 'MyDistro' -e /filesystem.squashfs -no-emul-boot /rootfs"
-    } else {
-        // For classic, use mksquashfs + xorriso
-        "mksquashfs /rootfs /filesystem.squashfs -comp xz && xorriso -as mkisofs -o /output.iso -b isolinux/isolinux.bin -c isolinux/boot.cat -no-emul-boot -boot-load-size 4 -boot-info-table -eltorito-alt-boot -e boot/efi.img -no-emul-boot -V 'MyDistro' /rootfs"
-    };
+/// Enables, disables and masks the profile's services. systemd units are handled offline with
+/// systemctl --root, which only reads the unit files and needs nothing running.
+fn configure_services(profile: &Profile, rootfs: &Path) -> Result<()> {
+    check_services(profile)?;
+    let mut lines = vec!["set -e".to_string()];
+    match profile.init_system.as_str() {
+        "systemd" => {
+            // The preset keeps units installed or reset later (package scripts, preset-all) in the
+            // state the profile asks for
+            let preset: Vec<String> = profile
+                .services_enable
+                .iter()
+                .map(|service| format!("'enable {}'", service))
+                .chain(profile.services_disable.iter().map(|service| format!("'disable {}'", service)))
+                .collect();
+            if !preset.is_empty() {
+                lines.push(format!(
+                    "mkdir -p /rootfs{0} && printf '%s\\n' {1} > /rootfs{0}/{2}",
+                    SYSTEMD_PRESET_DIR,
+                    preset.join(" "),
+                    SYSTEMD_PRESET
+                ));
+            }
+            // Applied first, so the explicit lists below still win over the base's own presets
+            if profile.services_preset_all {
+                lines.push("systemctl --root=/rootfs preset-all".to_string());
+            }
+            for (action, services) in [("enable", &profile.services_enable), ("disable", &profile.services_disable), ("mask", &profile.services_mask)] {
+                if !services.is_empty() {
+                    lines.push(format!("systemctl --root=/rootfs {} {}", action, services.join(" ")));
+                }
+            }
+            if lines.len() == 1 {
+                return Ok(());
+            }
+            let systemd = if profile.base == "gentoo" { "sys-apps/systemd" } else { "systemd" };
+            lines.insert(1, format!("command -v systemctl >/dev/null || {{ {}; }}", package_manager(profile)?.refresh_and_install(&[systemd])));
+            run_in_builder(profile, vec![rootfs_volume(rootfs)], &lines.join("\n"), "Service configuration")
+        }
+        // Services linked into the default runlevel are started, the runit equivalent of enabling.
+        // s6 starts the same services, converted from that runlevel.
+        "runit" | "s6" => {
+            lines.extend(RUNIT_DEFAULT_SERVICES.iter().map(|sv| format!("[ -d /etc/sv/{0} ] && ln -sf /etc/sv/{0} /etc/runit/runsvdir/default/; true", sv)));
+            lines.extend(profile.services_enable.iter().map(|sv| {
+                format!("[ -d /etc/sv/{0} ] || {{ echo \"No runit service {0}\" >&2; exit 1; }}\nln -sf /etc/sv/{0} /etc/runit/runsvdir/default/", sv)
+            }));
+            lines.extend(profile.services_disable.iter().map(|sv| format!("rm -f /etc/runit/runsvdir/default/{}", sv)));
+            if profile.init_system == "s6" {
+                lines.push(s6_rc_command());
+            }
+            run_in_chroot(profile, rootfs, &lines.join("\n"), "Service configuration")
+        }
+        _ => {
+            lines.extend(
+                OPENRC_DEFAULT_SERVICES
+                    .iter()
+                    .map(|(service, runlevel)| format!("[ -e /etc/init.d/{0} ] && rc-update add {0} {1}; true", service, runlevel)),
+            );
+            lines.extend(profile.services_enable.iter().map(|service| {
+                format!("[ -e /etc/init.d/{0} ] || {{ echo \"No OpenRC service {0}\" >&2; exit 1; }}\nrc-update add {0} default", service)
+            }));
+            lines.extend(profile.services_disable.iter().map(|service| format!("rc-update del {} default || true", service)));
+            run_in_chroot(profile, rootfs, &lines.join("\n"), "Service configuration")
+        }
+    }
+}
 
-    let output = Command::new("podman")
-        .args(&[
-            "run",
-            "--rm",
-            "--privileged",
-            "-v",
-            &format!("{}:/rootfs:z", rootfs.display()),
-            "-v",
-            &format!("{}:/output.iso:z", tmp_output.display()),
-            base_image,
-            "bash",
-            "-c",
-            build_cmd,
-        ])
+/// SHA-256 of a file on the host, as a hex string.
+fn sha256sum(path: &Path) -> Result<String> {
+    let output = Command::new("sha256sum")
+        .arg(path)
         .output()
-        .context("Failed to build ISO")?;
+        .context("Failed to run sha256sum")?;
     if !output.status.success() {
-        error!("ISO build failed: {}", String::from_utf8_lossy(&output.stderr));
-        return Err(anyhow::anyhow!("ISO build failed"));
+        error!("sha256sum failed: {}", String::from_utf8_lossy(&output.stderr));
+        return Err(anyhow::anyhow!("Checksum calculation failed"));
     }
-
-    fs::rename(&tmp_output, &iso_path).context("Failed to move ISO")?;
-
-    info!("ISO built at {}", iso_path.display());
-    Ok(())
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|s| s.to_string())
+        .context("Unexpected sha256sum output")
 }
 
 fn clean_tmp() -> Result<()> {
@@ -627,23 +1945,162 @@ fn show_tutorials() {
     println!("1. Run 'ulb init' to create project structure.");
     println!("2. Edit profiles/*.toml with your settings.");
     println!("   Fields:");
-    println!("   - packages: list of packages to install");
+    println!("   - packages: list of packages to install, optionally pinned as name=version (apt/dnf)");
     println!("   - distro_name: name of your distro");
-    println!("   - base: base distro (ubuntu, debian, fedora)");
+    println!("   - base: base distro ({})", SUPPORTED_BASES);
     println!("   - version: version string");
-    println!("   - init_system: systemd or openrc");
+    println!("   - arch: x86_64 (default), aarch64 (no BIOS, not on the arch base) or riscv64 (debian, ubuntu, gentoo)");
+    println!("     other architectures than the host's run under qemu-user-static, registered automatically");
+    println!("   - base_version: release of the base, e.g. 24.04, bookworm, 40, 9 (optional)");
+    println!("   - init_system: systemd, openrc (debian, gentoo), runit or s6 (void only; s6 runs the runit services");
+    println!("     under s6-rc)");
     println!("   - packages_to_remove: list to remove");
-    println!("   - bootloader: grub or systemd-boot");
+    println!("   - upgrade: true to fully upgrade the base before installing packages");
+    println!("   - flatpaks: Flatpak app IDs to preinstall, flatpak_remote: .flatpakrepo URL (default Flathub)");
+    println!("   - snaps: snaps to seed on ubuntu, as name or name=channel");
+    println!("   - [[appimages]]: url, sha256, path, name or desktop_file for the menu entry");
+    println!("   - aur_packages: AUR packages to build and install (arch only)");
+    println!("   - packages_hold: packages excluded from upgrades in the built system");
+    println!("   - bootloader: grub, systemd-boot, refind (UEFI only, not on dnf bases)");
+    println!("     or extlinux (extlinux.conf for U-Boot, disk images only)");
     println!("   - uefi_support: true/false");
-    println!("   - bios_support: true/false");
-    println!("   - format: iso (only supported)");
-    println!("   - atomic: true for atomic (fedora only), false for classic");
+    println!("   - bios_support: true/false (isolinux on x86_64 ISOs, GRUB on disk images)");
+    println!("   - [boot_menu]: title, timeout (seconds), default (entry index from 0), background (PNG) and");
+    println!("     theme (GRUB theme directory) as paths in the rootfs, e.g. added through files/");
+    println!("   - kernel: kernel package replacing the base's, e.g. \"linux-zen\", \"linux-image-generic-hwe-24.04\",");
+    println!("     \"kernel-lt\" or a pinned \"linux-image-6.1.0-18-amd64\" (the only kernel in the image)");
+    println!("   - [kernel.custom]: git URL, tag, config (path under files/, default defconfig) and localversion");
+    println!("     of a kernel compiled in the builder instead; its config needs what the live boot uses (squashfs, overlayfs)");
+    println!("   - [drivers]: nvidia and broadcom = true install the proprietary drivers from non-free/contrib,");
+    println!("     restricted/multiverse, RPM Fusion (EL needs epel) or void-repo-nonfree and blacklist the open ones;");
+    println!("     nvidia_package replaces the driver package (ubuntu defaults to nvidia-driver-550)");
+    println!("   - [firmware]: include = \"all\" (default), \"none\" or hardware classes [\"wifi\", \"gpu\", \"bluetooth\"]");
+    println!("     trimming /lib/firmware (CPU microcode is kept), and packages with firmware installed on top");
+    println!("   - [initramfs]: drivers to include, omit (modules to leave out), compression (zstd, xz, gzip, lz4)");
+    println!("     and hostonly (default false; never for ISOs) for dracut, initramfs-tools or mkinitcpio");
+    println!("   - zfs: true installs the ZFS module (Ubuntu's prebuilt, EL kmods, DKMS elsewhere) and tools with an");
+    println!("     initramfs able to import pools; arch needs the archzfs repository in [[repositories]], EL needs epel");
+    println!("   - dkms_modules: packages with DKMS sources (zfs-dkms, nvidia-dkms, v4l2loopback-dkms), built for the");
+    println!("     image's kernel against its headers (not with [kernel.custom])");
+    println!("   - kernel_cmdline: extra kernel arguments for the live and disk image boot entries, also kept in");
+    println!("     the GRUB defaults of the installed system, e.g. \"quiet splash mitigations=auto\"");
+    println!("   - [[languages]]: locale, keymap and title of the live menu's language and keyboard submenu (GRUB and");
+    println!("     isolinux), passed as locales=/keyboard-layouts= to live-config or rd.locale.LANG=/rd.vconsole.keymap=");
+    println!("   - [[boot_entries]]: title and cmdline of extra boot menu entries, e.g. \"Safe graphics\" with");
+    println!("     \"nomodeset\", \"Recovery\" with \"single\" or \"Verbose boot\" with \"debug\" (not with uki)");
+    println!("   - plymouth_theme: boot splash theme, e.g. \"spinner\", from its plymouth-theme-* package or");
+    println!("     files/usr/share/plymouth/themes/<name>");
+    println!("   - services_enable, services_disable: services started or not at boot (systemd units, runit or OpenRC");
+    println!("     services, kept in /etc/systemd/system-preset/90-ulb.preset); services_mask: systemd units that can't");
+    println!("     be started at all; services_preset_all: true resets every unit to its preset first");
+    println!("   - [sysctl]: kernel parameters for /etc/sysctl.d/99-ulb.conf, e.g. \"vm.swappiness\" = 10");
+    println!("   - selinux: enforcing, permissive or disabled (fedora, EL); the rootfs is labelled with setfiles");
+    println!("   - [minimize]: docs, locales (keeping keep_locales or those of locales), caches, pycache, each");
+    println!("     true by default, stripped before any image is packed");
+    println!("   - [apparmor]: profiles (files/ paths, checked with apparmor_parser), extra_profiles, complain (debian,");
+    println!("     ubuntu); kernel_cmdline must leave AppArmor on");
+    println!("   - [swap]: kind zram (size, compression, priority; systemd), file (size, created on first boot of disk");
+    println!("     images) or none (turns off default zram)");
+    println!("   - [overlay_root]: mode overlay (whole root on a tmpfs overlay; overlayroot on debian, ubuntu,");
+    println!("     systemd.volatile elsewhere) or state (read-only root, /var on tmpfs) for kiosk and exam images");
+    println!("   - [[udev_rules]]: name (e.g. 70-scanner) with rules or a files/ file, checked with udevadm verify");
+    println!("   - [[timers]]: name, command, on_calendar (e.g. \"daily\"), user, persistent, randomized_delay (systemd);");
+    println!("     [[cron]]: name, schedule (e.g. \"0 3 * * *\"), command, user for /etc/cron.d");
+    println!("   - hostname, locales (the first is the default, e.g. [\"en_US.UTF-8\", \"de_DE.UTF-8\"]), timezone");
+    println!("     (e.g. \"Europe/Warsaw\"), keymap (console, e.g. \"de-latin1\") and x11_layout (defaults to keymap's layout)");
+    println!("   - [network]: backend netplan (ubuntu default), networkmanager or networkd with [[network.ethernets]]");
+    println!("     (name, mac), [[network.vlans]] (name, id, link), [[network.bridges]] (name, interfaces) and");
+    println!("     [[network.wifis]] (ssid, psk, interface, hidden), each with dhcp, addresses, gateway and dns");
+    println!("   - [firewall]: backend ufw (apt default), firewalld (dnf default) or nftables, allowed services and ports");
+    println!("     (e.g. \"8080/tcp\"); firewalld also takes default_zone and [firewall.zones.<name>] interfaces, sources,");
+    println!("     services and ports");
+    println!("   - [live]: user (default live), full_name, password (default none), display_manager (gdm, sddm, lightdm),");
+    println!("     autologin (default true, display manager and tty1) and sudo (default true, passwordless)");
+    println!("   - [accessibility]: screen_reader (Orca), high_contrast and magnifier = true each add a live boot entry");
+    println!("     starting the desktop session with it turned on (GNOME settings, Orca started elsewhere)");
+    println!("   - [branding]: id, pretty_name, version_codename, homepage, support_url, bug_report_url, logo and motd;");
+    println!("     rewrites os-release (the base becomes ID_LIKE), lsb-release, issue and motd with distro_name and version");
+    println!("   - [branding.assets]: wallpaper and logo (files in files/), icon_theme, gtk_theme and plasma_theme, made");
+    println!("     the defaults of GNOME, Cinnamon, MATE, other GTK desktops and Plasma");
+    println!("   - [dconf.\"org/gnome/desktop/interface\"]: keys like color-scheme = \"prefer-dark\" or favorite-apps =");
+    println!("     [\"firefox.desktop\"], compiled into a system dconf database; typed values as {{ variant = \"uint32 300\" }}");
+    println!("   - [plasma.<file>.<group>]: KDE defaults written to /etc/xdg/<file>, e.g. [plasma.kdeglobals.General]");
+    println!("     ColorScheme = \"BreezeDark\"; nested groups as \"Parent/Child\"");
+    println!("   - toram: true to add an ISO boot entry that copies the live image to RAM (medium removable after boot)");
+    println!("   - include_memtest: true to add a memtest86+ entry to the boot menus (x86_64, not uki ISOs)");
+    println!("   - uki: true to boot a unified kernel image (ukify) from the ESP of ISOs and disk images");
+    println!("   - format: one format or a list built from the same rootfs, e.g. [\"iso\", \"qcow2\"]:");
+    println!("     iso, raw (dd-able disk image), qcow2/vmdk/vdi/vhdx (VM disks), tar (rootfs tarball),");
+    println!("     wsl (tarball for wsl --import), pxe (netboot directory), aws/gce/azure (cloud images),");
+    println!("     vagrant (.box per provider), lxc (metadata + rootfs for lxc/incus image import),");
+    println!("     rpi (Raspberry Pi SD card image, needs an aarch64 rootfs),");
+    println!("     or sysext/confext for an extension image");
+    println!("   - [tar]: compression gzip, xz (default), zstd or none; set SOURCE_DATE_EPOCH to pin timestamps");
+    println!("   - [wsl]: default_user, icon (.ico path in the image), bundle = true for a .wsl file");
+    println!("   - [pxe]: url the netboot directory is served from over HTTP");
+    println!("   - [vagrant]: providers libvirt and/or virtualbox (default both)");
+    println!("   - [cloud_init]: user_data, meta_data, network_config, datasources, seed = \"embedded\" (in the");
+    println!("     image, default) or \"iso\" (cidata seed ISO next to it) for disk and cloud images");
+    println!("   - [rpi]: firmware_ref (default stable), config (extra config.txt lines), cmdline");
+    println!("   - [board]: name, package or binary (directory) with U-Boot, [[board.writes]] file/offset in sectors");
+    println!("     for aarch64/riscv64 disk images with bootloader = \"extlinux\" (extlinux.conf plus boot.scr)");
+    println!("   - [secure_boot]: key and cert (PEM) signing kernels and EFI binaries with sbsign, shim (default true);");
+    println!("     defaults to db.key/db.crt in $ULB_SECUREBOOT_DIR or ~/.config/ulb/secureboot (ISOs need uki or refind)");
+    println!("   - [devicetree]: dtb and overlays (paths under the kernel's dtbs), for extlinux, systemd-boot and rpi");
+    println!("   - [disk]: size of the disk image, e.g. 8G (defaults to the rootfs size plus headroom)");
+    println!("     layout = \"ab\" for two root slots (slot_size) plus a /data partition (data_size, default 1G)");
+    println!("     verity = true for a read-only root checked by dm-verity (systemd-boot, systemd init)");
+    println!("     encryption = {{ type = \"luks2\", passphrase_env = \"VAR\" }} for a LUKS root unlocked at boot");
+    println!("     filesystem = \"btrfs\" with [[disk.subvolumes]] name/path (default @, @home, @snapshots), compression");
+    println!("   - [esp]: size (e.g. \"1GiB\", disk default 512MiB, ISOs fit their contents), label (FAT, up to 11");
+    println!("     characters) and files = {{ \"/EFI/tools/shellx64.efi\" = \"/usr/share/...\" }} copied from the rootfs");
+    println!("   - atomic: true composes an OSTree commit into build/iso/ostree-repo with rpm-ostree (fedora only) and");
+    println!("     builds an Anaconda ISO installing it, from packages, [[repositories]], services, files/ and scripts/");
+    println!("     only; [ostree]: ref (default <distro>/<version>/<arch>), remote_url installs update from");
+    println!("   - butane: Butane config in files/ compiled to Ignition for the first boot of atomic images");
+    println!("   - live_fs: squashfs (default) or erofs for the live image of iso/pxe (dracut bases, kernel with EROFS)");
+    println!("   - [persistence]: label (default persistence) of the partition live changes are kept on");
+    println!("   - [iso]: arches = [\"x86_64\", \"aarch64\"] for one EFI ISO booting each architecture's own system");
+    println!("     checksums = [\"md5\", \"sha256\"] implants an md5 (checkisomd5, apt and dnf bases) and adds a sha256sum.txt");
+    println!("     manifest (GRUB's sha256sum -c, live-boot's verify-checksums); media_check = true adds a \"check media\"");
+    println!("     boot entry verifying the media before the live session (apt and dnf bases, implies the checksum it needs)");
+    println!("   - [squashfs]: compressor xz (default), zstd, lz4, gzip or lzo, level, block_size, no_duplicates, no_recovery");
+    println!("   - variant: live (default) or netinstall for a small installer ISO (debian, fedora, EL; ubuntu remasters");
+    println!("     the live server ISO)");
+    println!("   - [unattended]: user, password_hash (openssl passwd -6), full_name, ssh_keys, disk, lvm and reboot make");
+    println!("     debian/ubuntu netinstall media install hands-free via a preseed or autoinstall file, with hostname,");
+    println!("     locales, keymap and timezone as answers");
+    println!("   - installer: calamares (apt, dnf, gentoo) or anaconda (dnf, with an interactive-defaults kickstart from");
+    println!("     locales, keymap and timezone) to install the live ISO's system to disk, with a launcher on the [live]");
+    println!("     user's desktop; the live user stays behind");
+    println!("   - oem_setup: true for disk images (systemd) that ask for the user, locale, keymap, timezone and");
+    println!("     hostname on first boot, removing the [live] user");
+    println!("   - preset = \"kiosk\" with [kiosk]: url (chromium or firefox as browser) or command, compositor cage");
+    println!("     (default) or weston, user (default kiosk), watchdog seconds; autologin on tty1, restarts the app,");
+    println!("     no VT switching (systemd)");
+    println!("   - mirror / mirrors: archive mirror replacing the upstream URL, plus fallbacks");
+    println!("   - [[repositories]]: name, url (or ppa:user/name), suite, components, gpg_key (URL or files/ path), keyring, credentials, enabled");
+    println!("     credentials are read from ULB_CRED_<NAME>_USERNAME/_PASSWORD or ~/.config/ulb/credentials.toml");
+    println!("   - ca_certificates: PEM blobs or files/ paths added to the system trust store, before [[repositories]]");
+    println!("   - [debconf]: answers set before packages install (debian, ubuntu), e.g. \"wireshark-common/install-setuid\" = true");
+    println!("     or \"postfix/main_mailer_type\" = {{ type = \"select\", value = \"Internet Site\" }}");
+    println!("   - [alternatives]: update-alternatives choices (apt, dnf), a path or a file name, e.g. editor =");
+    println!("     \"/usr/bin/vim.basic\" or x-www-browser = \"firefox\"");
+    println!("   - epel: true to enable EPEL and CRB (rocky, almalinux, centos-stream)");
+    println!("   - [make_conf]: use_flags, makeopts and extra variables (gentoo only)");
+    println!("   - [extension]: name, os_id, version_id, level, filesystem (erofs/squashfs) for sysext/confext");
     println!("3. Add files to /files to overlay on rootfs /");
+    println!("   Add .deb/.rpm files to /packages to install them with their dependencies");
     println!("4. Add .sh scripts to /scripts (executed in alphabetical order post-install)");
     println!("5. Run 'ulb build' or 'ulb build profile_name'");
-    println!("6. Output ISO in build/iso");
+    println!("6. Output ISO (hybrid, dd-able to USB), disk images and other artifacts in build/iso");
     println!("7. Use 'ulb clean' to clean /tmp/.ulb");
     println!("8. 'ulb show-build' for interactive mode");
+    println!("9. 'ulb flash build/iso/<name>.iso /dev/sdX --persistence' to write a USB stick with persistence");
+    println!("10. 'ulb channel publish --channel stable' to publish the latest build to build/channels");
+    println!("11. 'ulb export kickstart profile_name' to write an Anaconda kickstart of a profile to build/kickstart");
+    println!("12. 'ulb ostree list|prune --keep 3|pull-local <repo>|export <ref>|init-repo' to manage the OSTree repo");
+    println!("    of atomic builds (build/iso/ostree-repo, or --repo)");
 }
 
 fn configure_settings() -> Result<()> {
@@ -658,6 +2115,7 @@ fn interactive_build(
     profiles_dir: &Path,
     files_dir: &Path,
     scripts_dir: &Path,
+    packages_dir: &Path,
     build_dir: &Path,
 ) -> Result<()> {
     println!("{}", "Interactive Build Mode".blue());
@@ -665,20 +2123,21 @@ fn interactive_build(
 
     let mut profile = Profile {
         distro_name: prompt("Distro name (e.g., MyDistro): ")?,
-        base: prompt("Base (ubuntu, debian, fedora): ")?,
+        base: prompt(&format!("Base ({}): ", SUPPORTED_BASES))?,
         version: prompt("Version (e.g., 1.0): ")?,
-        init_system: prompt("Init system (systemd, openrc): ")?,
-        bootloader: prompt("Bootloader (grub, systemd-boot): ")?,
+        init_system: prompt("Init system (systemd, openrc, runit, s6): ")?,
+        bootloader: prompt("Bootloader (grub, systemd-boot, refind): ")?,
         uefi_support: prompt_bool("UEFI support? (y/n): ")?,
         bios_support: prompt_bool("BIOS support? (y/n): ")?,
-        format: "iso".to_string(),
+        format: vec!["iso".to_string()],
         atomic: prompt_bool("Atomic distro? (y/n, recommended for fedora): ")?,
         packages: prompt_list("Packages to install (comma-separated, e.g., vim,git): ")?,
         packages_to_remove: prompt_list("Packages to remove (comma-separated): ")?,
+        ..Default::default()
     };
 
     // Basic validation
-    if profile.base != "ubuntu" && profile.base != "debian" && profile.base != "fedora" {
+    if !SUPPORTED_BASES.split(", ").any(|base| base == profile.base) {
         return Err(anyhow::anyhow!("Invalid base: {}", profile.base));
     }
     if profile.atomic && profile.base != "fedora" {
@@ -692,7 +2151,7 @@ fn interactive_build(
     fs::write(&temp_profile_path, toml_str).context("Failed to write temp profile")?;
 
     // Build
-    build_distro(profiles_dir, Some("interactive"), files_dir, scripts_dir, build_dir)?;
+    build_distro(profiles_dir, Some("interactive"), files_dir, scripts_dir, packages_dir, build_dir)?;
 
     // Cleanup
     fs::remove_file(&temp_profile_path).context("Failed to remove temp profile")?;
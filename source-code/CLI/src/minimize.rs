@@ -0,0 +1,108 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// Optional [minimize] section: what is stripped from the rootfs before it is packed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MinimizeConfig {
+    #[serde(default = "crate::default_true")]
+    pub docs: bool, // Documentation, man and info pages (licenses stay); later installs skip them too
+    #[serde(default = "crate::default_true")]
+    pub locales: bool, // Translations of languages other than keep_locales
+    #[serde(default)]
+    pub keep_locales: Vec<String>, // Languages whose translations stay, e.g. ["de", "pt_BR"]; defaults to those of locales
+    #[serde(default = "crate::default_true")]
+    pub caches: bool, // Downloaded packages; the indexes stay for the image stages that still install
+    #[serde(default = "crate::default_true")]
+    pub pycache: bool, // Python bytecode caches, rebuilt on demand
+}
+
+/// The languages whose translations are kept: keep_locales, or the language and territory of
+/// each of the profile's locales ("de_DE.UTF-8" keeps de and de_DE), with English always kept.
+fn kept_languages(profile: &Profile, config: &MinimizeConfig) -> Vec<String> {
+    let mut languages = vec!["en".to_string(), "en_US".to_string()];
+    if config.keep_locales.is_empty() {
+        for locale in &profile.locales {
+            let name = locale.split(['.', '@']).next().unwrap_or(locale);
+            languages.push(name.split('_').next().unwrap_or(name).to_string());
+            languages.push(name.to_string());
+        }
+    } else {
+        languages.extend(config.keep_locales.iter().cloned());
+    }
+    languages.sort();
+    languages.dedup();
+    languages
+}
+
+/// Checks keep_locales.
+pub fn check(profile: &Profile) -> Result<()> {
+    let Some(config) = &profile.minimize else {
+        return Ok(());
+    };
+    if let Some(locale) = config.keep_locales.iter().find(|l| l.is_empty() || !l.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '@' | '-'))) {
+        return Err(anyhow::anyhow!("Invalid [minimize] keep_locales entry: {:?}", locale));
+    }
+    Ok(())
+}
+
+/// Strips what [minimize] asks for from the rootfs, after everything is installed and before
+/// any image is packed. Documentation exclusions are also written for dpkg and dnf, so packages
+/// installed on the running system stay small.
+pub fn run(profile: &Profile, rootfs: &Path) -> Result<()> {
+    check(profile)?;
+    let Some(config) = &profile.minimize else {
+        return Ok(());
+    };
+    println!("{}", "Minimizing rootfs...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut lines = vec!["set -e".to_string()];
+    if config.docs {
+        lines.push("rm -rf /usr/share/man/* /usr/share/info/* /usr/share/gtk-doc".to_string());
+        // Licenses have to ship with the binaries
+        lines.push("[ -d /usr/share/doc ] && find /usr/share/doc -depth -type f ! -name 'copyright' ! -iname 'LICENSE*' ! -iname 'COPYING*' -delete".to_string());
+        lines.push("[ -d /usr/share/doc ] && find /usr/share/doc -depth -type d -empty -delete; true".to_string());
+        match package_manager {
+            PackageManager::Apt => {
+                let excludes = "path-exclude /usr/share/doc/*\npath-include /usr/share/doc/*/copyright\npath-exclude /usr/share/man/*\npath-exclude /usr/share/info/*\npath-exclude /usr/share/gtk-doc/*\n";
+                let path = rootfs.join("etc/dpkg/dpkg.cfg.d/90-ulb-minimize");
+                crate::write_file(&path, excludes)?;
+            }
+            PackageManager::Dnf => lines.push(
+                "grep -q '^tsflags=' /etc/dnf/dnf.conf && sed -i 's/^tsflags=.*/tsflags=nodocs/' /etc/dnf/dnf.conf || echo 'tsflags=nodocs' >> /etc/dnf/dnf.conf"
+                    .to_string(),
+            ),
+            PackageManager::Pacman => lines.push(
+                "sed -i 's|^#\\?NoExtract *=.*|NoExtract = usr/share/doc/* usr/share/man/* usr/share/info/* usr/share/gtk-doc/*|' /etc/pacman.conf".to_string(),
+            ),
+            _ => {}
+        }
+    }
+    if config.locales {
+        let keep = kept_languages(profile, config);
+        lines.push(format!(
+            "for L in /usr/share/locale/*/; do L=$(basename $L); case \" {} \" in *\" $L \"*) ;; *) [ -d /usr/share/locale/$L/LC_MESSAGES ] && rm -rf /usr/share/locale/$L;; esac; done; true",
+            keep.join(" ")
+        ));
+    }
+    if config.caches {
+        lines.push(
+            match package_manager {
+                PackageManager::Apt => "apt-get clean",
+                PackageManager::Dnf => "dnf clean packages",
+                PackageManager::Pacman => "rm -rf /var/cache/pacman/pkg/*",
+                PackageManager::Xbps => "xbps-remove -yO && rm -rf /var/cache/xbps/*",
+                PackageManager::Portage => "rm -rf /var/cache/distfiles/* /var/cache/binpkgs/*",
+            }
+            .to_string(),
+        );
+    }
+    if config.pycache {
+        lines.push("find /usr /opt -xdev -type d -name __pycache__ -prune -exec rm -rf {} + 2>/dev/null; true".to_string());
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Rootfs minimization")
+}
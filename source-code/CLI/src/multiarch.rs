@@ -0,0 +1,204 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{artifacts, boot, netinstall, Profile};
+
+// ISO tree the per-architecture builds fill in: /<arch>/ with kernel, initrd and live image,
+// EFI/BOOT with one GRUB per architecture, and the shared boot/grub/grub.cfg
+const STAGING: &str = "/tmp/.ulb/multiarch";
+
+/// The architectures of a multi-architecture ISO, from [iso] arches, or None for a regular
+/// single-architecture build.
+pub fn arches(profile: &Profile) -> Result<Option<Vec<String>>> {
+    let requested = profile.iso.as_ref().map(|iso| iso.arches.clone()).unwrap_or_default();
+    match requested.len() {
+        0 => return Ok(None),
+        1 => return Err(anyhow::anyhow!("[iso] arches needs two or more architectures, use arch for one")),
+        _ => {}
+    }
+    if profile.format.iter().any(|f| f != "iso") {
+        return Err(anyhow::anyhow!("[iso] arches only produces an iso"));
+    }
+    if profile.base == "nixos" || profile.atomic || profile.uki || netinstall::is_netinstall(profile)? {
+        return Err(anyhow::anyhow!("[iso] arches isn't supported with nixos, atomic, uki or netinstall builds"));
+    }
+    if !profile.uefi_support {
+        return Err(anyhow::anyhow!("[iso] arches boots through EFI only, set uefi_support = true"));
+    }
+
+    let mut arches = Vec::new();
+    for arch in requested {
+        let arch = crate::target_arch(&with_arch(profile, &arch))?.to_string();
+        if arches.contains(&arch) {
+            return Err(anyhow::anyhow!("{} is listed twice in [iso] arches", arch));
+        }
+        arches.push(arch);
+    }
+    Ok(Some(arches))
+}
+
+fn with_arch(profile: &Profile, arch: &str) -> Profile {
+    let mut profile = profile.clone();
+    profile.arch = Some(arch.to_string());
+    profile
+}
+
+/// Builds the rootfs and live image once per architecture, then lays them out side by side on
+/// one ISO. Each architecture's firmware picks its own GRUB from EFI/BOOT, and the shared menu
+/// only shows the entries for the CPU it runs on.
+pub fn build_iso(
+    profile: &Profile,
+    arches: &[String],
+    files_dir: &Path,
+    scripts_dir: &Path,
+    packages_dir: &Path,
+    build_dir: &Path,
+) -> Result<PathBuf> {
+    let staging = PathBuf::from(STAGING);
+    if staging.exists() {
+        fs::remove_dir_all(&staging).context("Failed to clear multi-architecture staging directory")?;
+    }
+    fs::create_dir_all(staging.join("boot/grub")).context("Failed to create multi-architecture staging directory")?;
+
+    let volume_id = profile.distro_name.to_uppercase();
+    let mut menu = Vec::new();
+    let mut last_profile = profile.clone();
+    for arch in arches {
+        println!("{}", format!("Building {} system...", arch).yellow());
+        let mut arch_profile = with_arch(profile, arch);
+        // memtest86+ only exists for x86, the other architectures simply go without
+        arch_profile.include_memtest &= arch == "x86_64";
+        // Pulling re-tags the base image, so every later container runs as this architecture
+        crate::setup_podman_container(&arch_profile)?;
+
+        let rootfs = PathBuf::from(format!("/tmp/.ulb/rootfs-{}", arch));
+        fs::create_dir_all(&rootfs).context("Failed to create rootfs directory")?;
+        crate::prepare_rootfs(&arch_profile, &rootfs, files_dir, scripts_dir, packages_dir)?;
+        artifacts::build_live_image(&arch_profile, &rootfs, &staging.join(arch))?;
+        stage_arch(&arch_profile, arch, &rootfs, &volume_id)?;
+
+        let memtest = if arch_profile.include_memtest {
+            format!("\n  menuentry 'Memory test (memtest86+)' {{\n    chainloader /{arch}/memtest.efi\n  }}")
+        } else {
+            String::new()
+        };
+        let live = boot::live_entries(&arch_profile, &volume_id, arch)?;
+        if menu.is_empty() {
+            // The language submenu counts as one entry, before memtest
+            let count = live.len() + usize::from(!arch_profile.languages.is_empty()) + usize::from(arch_profile.include_memtest);
+            menu.push(boot::grub_menu_header(&arch_profile, "/boot/grub", count)?);
+        }
+        let entries: String = live
+            .iter()
+            .map(|(title, cmdline)| {
+                format!(
+                    "\n  menuentry '{name} {version} ({arch}){title}' {{\n    linux /{arch}/vmlinuz {cmdline}\n    initrd /{arch}/initrd.img\n  }}",
+                    name = profile.distro_name,
+                    version = profile.version,
+                )
+            })
+            .collect();
+        let languages: String = boot::language_entries(&arch_profile, &volume_id, arch)?
+            .iter()
+            .map(|(title, cmdline)| {
+                format!("\n    menuentry '{title}' {{\n      linux /{arch}/vmlinuz {cmdline}\n      initrd /{arch}/initrd.img\n    }}")
+            })
+            .collect();
+        let languages = if languages.is_empty() {
+            String::new()
+        } else {
+            format!("\n  submenu 'Language and keyboard' {{{}\n  }}", languages)
+        };
+        menu.push(format!("if [ \"$grub_cpu\" = \"{}\" ]; then{}{}{}\nfi", grub_cpu(arch), entries, languages, memtest));
+        last_profile = arch_profile;
+    }
+    fs::write(staging.join("boot/grub/grub.cfg"), menu.join("\n") + "\n").context("Failed to write grub.cfg")?;
+
+    println!("{}", "Building multi-architecture ISO...".yellow());
+    let iso_name = format!("{}-{}-multiarch.iso", profile.distro_name, profile.version);
+    let package_manager = crate::package_manager(&last_profile)?;
+    let checksums = artifacts::iso_checksums(&last_profile)?;
+    let mut tools = vec!["dosfstools", "mtools", "xorriso"];
+    let mut checksum = String::new();
+    if checksums.iter().any(|c| c == "sha256") {
+        // Every architecture's kernel, initrd and live image, relative to the ISO root
+        checksum.push_str("(cd /staging && find . -type f -exec sha256sum {} + > /tmp/sha256sum.txt) && mv /tmp/sha256sum.txt /staging/\n");
+    }
+    let md5 = checksums.iter().any(|c| c == "md5");
+    if md5 {
+        tools.push("isomd5sum");
+    }
+    let iso_cmd = format!(
+        r#"set -e
+{tools}
+truncate -s $(( $(du -sm /staging/EFI | cut -f1) + 4 ))M /tmp/efiboot.img
+mkfs.vfat /tmp/efiboot.img
+mcopy -s -i /tmp/efiboot.img /staging/EFI ::/
+{checksum}xorriso -as mkisofs -o /out/{iso} -V '{volid}' -e EFI/efiboot.img -no-emul-boot -isohybrid-gpt-basdat -graft-points /staging /EFI/efiboot.img=/tmp/efiboot.img
+{implant}"#,
+        tools = package_manager.refresh_and_install(&tools),
+        checksum = checksum,
+        iso = iso_name,
+        volid = volume_id,
+        implant = if md5 { format!("implantisomd5 /out/{}\n", iso_name) } else { String::new() },
+    );
+    let volumes = vec![format!("{}:/staging:z", staging.display()), format!("{}:/out:z", build_dir.display())];
+    crate::run_in_builder(&last_profile, volumes, &iso_cmd, "Multi-architecture ISO build")?;
+
+    let iso_path = build_dir.join(&iso_name);
+    info!("Multi-architecture ISO built at {}", iso_path.display());
+    Ok(iso_path)
+}
+
+// Copies one architecture's kernel, initrd and live image into the staging tree and builds its
+// standalone GRUB, which finds the ISO by label and loads the shared menu from it
+fn stage_arch(profile: &Profile, arch: &str, rootfs: &Path, volume_id: &str) -> Result<()> {
+    let package_manager = crate::package_manager(profile)?;
+    let live_path = boot::live_squashfs_path(profile)?;
+    let memtest = if boot::memtest(profile)? {
+        format!("\n{}\ncp $MEMTEST_EFI /staging/{}/memtest.efi", boot::memtest_locate(), arch)
+    } else {
+        String::new()
+    };
+    let stage_cmd = format!(
+        r#"set -e
+{tools}
+KVER=$(ls /rootfs/lib/modules | sort -V | tail -n1)
+for KERNEL in /rootfs/boot/vmlinuz-$KVER /rootfs/lib/modules/$KVER/vmlinuz /rootfs/boot/vmlinux-$KVER; do [ -f $KERNEL ] && break; done
+for INITRD in /rootfs/boot/initrd.img-$KVER /rootfs/boot/initramfs-$KVER.img /rootfs/boot/initramfs.img; do [ -f $INITRD ] && break; done
+[ -f $KERNEL ] && [ -f $INITRD ] || {{ echo "No kernel or initramfs found for $KVER" >&2; exit 1; }}
+mkdir -p $(dirname /staging/{arch}/{live}) /staging/EFI/BOOT
+cp $KERNEL /staging/{arch}/vmlinuz && cp $INITRD /staging/{arch}/initrd.img
+mv /staging/{arch}/filesystem.squashfs /staging/{arch}/{live}{memtest}{theme}
+cat > /tmp/embed.cfg <<'EOF'
+search --no-floppy --set=root --label {volid}
+set prefix=($root)/boot/grub
+configfile /boot/grub/grub.cfg
+EOF
+MKSTANDALONE=grub-mkstandalone; command -v grub2-mkstandalone >/dev/null && MKSTANDALONE=grub2-mkstandalone
+$MKSTANDALONE -O {target} -o /staging/EFI/BOOT/{efi} "boot/grub/grub.cfg=/tmp/embed.cfg"
+"#,
+        tools = package_manager.refresh_and_install(&boot::grub_efi_packages(package_manager, arch)),
+        arch = arch,
+        live = live_path,
+        volid = volume_id,
+        target = crate::grub_efi_target(arch),
+        efi = boot::efi_fallback_binary(arch),
+        memtest = memtest,
+        theme = boot::grub_theme_copy(profile, "/staging/boot/grub")?,
+    );
+    let volumes = vec![crate::rootfs_volume(rootfs), format!("{}:/staging:z", STAGING)];
+    crate::run_in_builder(profile, volumes, &stage_cmd, "Multi-architecture staging")
+}
+
+// GRUB's name for the CPU, as $grub_cpu reports it
+fn grub_cpu(arch: &str) -> &'static str {
+    match arch {
+        "aarch64" => "arm64",
+        "riscv64" => "riscv64",
+        _ => "x86_64",
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{PackageManager, Profile};
+
+// Netboot initramfs built next to the regular one and moved out of the rootfs afterwards
+const NETBOOT_INITRD: &str = "boot/ulb-netboot.img";
+
+// Optional [pxe] section for the pxe format
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PxeConfig {
+    pub url: Option<String>, // HTTP URL the netboot directory is served from
+}
+
+/// Builds a directory ready to be served over TFTP/HTTP: kernel, an initramfs able to fetch
+/// the root filesystem over the network, the live squashfs, and pxelinux and GRUB configs
+/// pointing at it.
+pub fn build_pxe(profile: &Profile, rootfs: &Path, squashfs: &Path, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Building netboot directory...".yellow());
+
+    let config = profile.pxe.clone().unwrap_or_default();
+    let url = config
+        .url
+        .as_deref()
+        .map(|url| url.trim_end_matches('/'))
+        .ok_or_else(|| anyhow::anyhow!("format = \"pxe\" needs [pxe] url, the HTTP URL the directory is served from"))?;
+
+    let package_manager = crate::package_manager(profile)?;
+    // live-boot on Debian/Ubuntu, dracut's dmsquash-live and livenet modules everywhere else
+    let (initrd_cmd, mut cmdline) = match package_manager {
+        PackageManager::Apt => (
+            format!(
+                "{} && mkinitramfs -o /{} $KVER",
+                package_manager.install(&["live-boot".to_string()]),
+                NETBOOT_INITRD
+            ),
+            format!("boot=live fetch={}/filesystem.squashfs", url),
+        ),
+        _ => {
+            let dracut: &[&str] = match package_manager {
+                PackageManager::Dnf => &["dracut", "dracut-live"],
+                PackageManager::Portage => &["sys-kernel/dracut"],
+                _ => &["dracut"],
+            };
+            let dracut: Vec<String> = dracut.iter().map(|p| p.to_string()).collect();
+            (
+                format!(
+                    "{} && dracut --force --no-hostonly --add 'dmsquash-live livenet' --add-drivers {} /{} $KVER",
+                    package_manager.install(&dracut),
+                    crate::artifacts::live_fs(profile)?,
+                    NETBOOT_INITRD
+                ),
+                format!("root=live:{}/filesystem.squashfs rd.live.image", url),
+            )
+        }
+    };
+    if let Some(args) = crate::boot::kernel_cmdline(profile)? {
+        cmdline.push(' ');
+        cmdline.push_str(args);
+    }
+    crate::run_in_chroot(
+        profile,
+        rootfs,
+        &format!("KVER=$(ls /lib/modules | sort -V | tail -n1) && {}", initrd_cmd),
+        "Netboot initramfs",
+    )?;
+
+    let dir_name = format!("{}-{}-pxe", profile.distro_name, profile.version);
+    let netboot_cmd = format!(
+        r#"set -e
+{tools}
+OUT=/out/{dir}
+rm -rf $OUT && mkdir -p $OUT/pxelinux.cfg $OUT/grub
+KVER=$(ls /rootfs/lib/modules | sort -V | tail -n1)
+PKGBASE=$(cat /rootfs/lib/modules/$KVER/pkgbase 2>/dev/null)
+for kernel in /rootfs/boot/vmlinuz-$KVER /rootfs/lib/modules/$KVER/vmlinuz /rootfs/boot/vmlinuz-$PKGBASE; do
+  [ -f $kernel ] && cp $kernel $OUT/vmlinuz && break
+done
+[ -f $OUT/vmlinuz ] || {{ echo "No kernel found for $KVER" >&2; exit 1; }}
+mv /rootfs/{initrd} $OUT/initrd.img
+cp /filesystem.squashfs $OUT/filesystem.squashfs
+for file in pxelinux.0 ldlinux.c32; do
+  found=$(find /usr/lib /usr/share -name $file -not -path '*efi*' 2>/dev/null | head -n1)
+  if [ -n "$found" ]; then cp $found $OUT/; fi
+done
+"#,
+        tools = package_manager.refresh_and_install(netboot_tools(package_manager)),
+        dir = dir_name,
+        initrd = NETBOOT_INITRD,
+    );
+    let volumes = vec![
+        crate::rootfs_volume(rootfs),
+        format!("{}:/filesystem.squashfs:z,ro", squashfs.display()),
+        format!("{}:/out:z", build_dir.display()),
+    ];
+    crate::run_in_builder(profile, volumes, &netboot_cmd, "Netboot build")?;
+
+    let netboot_dir = build_dir.join(&dir_name);
+    let pxelinux = format!(
+        "DEFAULT {id}\nPROMPT 0\nTIMEOUT 50\n\nLABEL {id}\n  MENU LABEL {name} {version}\n  KERNEL vmlinuz\n  INITRD initrd.img\n  APPEND {cmdline}\n",
+        id = profile.distro_name.to_lowercase(),
+        name = profile.distro_name,
+        version = profile.version,
+        cmdline = cmdline
+    );
+    // GRUB netboot images look for grub/grub.cfg and resolve paths against the TFTP root
+    let grub = format!(
+        "set timeout=5\n\nmenuentry '{} {}' {{\n  linux /vmlinuz {}\n  initrd /initrd.img\n}}\n",
+        profile.distro_name, profile.version, cmdline
+    );
+    fs::write(netboot_dir.join("pxelinux.cfg/default"), pxelinux).context("Failed to write pxelinux config")?;
+    fs::write(netboot_dir.join("grub/grub.cfg"), grub).context("Failed to write GRUB netboot config")?;
+
+    info!("Netboot directory built at {}", netboot_dir.display());
+    Ok(netboot_dir)
+}
+
+// syslinux for the BIOS PXE loader
+fn netboot_tools(package_manager: PackageManager) -> &'static [&'static str] {
+    match package_manager {
+        PackageManager::Apt => &["pxelinux", "syslinux-common"],
+        PackageManager::Portage => &["sys-boot/syslinux"],
+        _ => &["syslinux"],
+    }
+}
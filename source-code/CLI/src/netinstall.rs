@@ -0,0 +1,222 @@
+use anyhow::Result;
+use colored::*;
+use log::info;
+use std::path::{Path, PathBuf};
+
+use crate::{unattended, PackageManager, Profile};
+
+/// Whether the profile asks for installer media instead of a live system. Only "live" (the
+/// default) and "netinstall" are valid variants.
+pub fn is_netinstall(profile: &Profile) -> Result<bool> {
+    match profile.variant.as_deref() {
+        None | Some("live") => Ok(false),
+        Some("netinstall") if profile.format.iter().any(|f| f != "iso") => {
+            Err(anyhow::anyhow!("variant = \"netinstall\" only produces an iso"))
+        }
+        Some("netinstall") => Ok(true),
+        Some(variant) => Err(anyhow::anyhow!("Unsupported variant: {}. Supported: live, netinstall", variant)),
+    }
+}
+
+/// Builds a small installer ISO: the distribution's own network installer kernel and initrd,
+/// pointed at the profile's mirror, packages and [[repositories]], with no rootfs at all.
+/// debian uses debian-installer with a preseed.cfg appended to its initrd; fedora and the EL
+/// bases boot Anaconda with inst.repo and inst.addrepo, and a kickstart selecting the packages.
+/// ubuntu has no network installer left, so its live server ISO is remastered with autoinstall
+/// data instead.
+pub fn build(profile: &Profile, build_dir: &Path) -> Result<PathBuf> {
+    println!("{}", "Building netinstall ISO...".yellow());
+    if profile.base == "ubuntu" {
+        return ubuntu_server(profile, build_dir);
+    }
+
+    let package_manager = crate::package_manager(profile)?;
+    let installer_cmd = match profile.base.as_str() {
+        "debian" => debian_installer(profile)?,
+        base if crate::dnf_upstream_prefix(base).is_some() => anaconda(profile)?,
+        base => return Err(anyhow::anyhow!("Netinstall media is not supported on the {} base", base)),
+    };
+    let tools: &[&str] = match (package_manager, crate::target_arch(profile)?) {
+        (PackageManager::Apt, "aarch64") => &["curl", "cpio", "grub-common", "grub-efi-arm64-bin", "mtools", "xorriso"],
+        (PackageManager::Apt, "riscv64") => &["curl", "cpio", "grub-common", "grub-efi-riscv64-bin", "mtools", "xorriso"],
+        (PackageManager::Apt, _) => &["curl", "cpio", "grub-common", "grub-pc-bin", "grub-efi-amd64-bin", "mtools", "xorriso"],
+        (_, "aarch64") => &["curl", "grub2-tools-extra", "grub2-efi-aa64-modules", "mtools", "xorriso"],
+        _ => &["curl", "grub2-tools-extra", "grub2-pc-modules", "grub2-efi-x64-modules", "mtools", "xorriso"],
+    };
+
+    let iso_name = format!("{}-{}-netinst.iso", profile.distro_name, profile.version);
+    let iso_cmd = format!(
+        r#"set -e
+{tools}
+mkdir -p /iso/boot/grub
+{installer}
+MKRESCUE=grub-mkrescue; command -v grub2-mkrescue >/dev/null && MKRESCUE=grub2-mkrescue
+$MKRESCUE -o /out/{iso} /iso -- -volid '{volid}'
+"#,
+        tools = package_manager.refresh_and_install(tools),
+        installer = installer_cmd,
+        iso = iso_name,
+        volid = profile.distro_name.to_uppercase(),
+    );
+    crate::run_in_builder(profile, vec![format!("{}:/out:z", build_dir.display())], &iso_cmd, "Netinstall ISO build")?;
+
+    let iso_path = build_dir.join(&iso_name);
+    info!("Netinstall ISO built at {}", iso_path.display());
+    Ok(iso_path)
+}
+
+/// Shell command writing the GRUB menu that boots the installer kernel and initrd from
+/// /iso/boot with `args`.
+pub fn grub_cfg(profile: &Profile, args: &str) -> String {
+    format!(
+        "cat > /iso/boot/grub/grub.cfg <<'EOF'\nset timeout=5\nmenuentry 'Install {} {}' {{\n  linux /boot/vmlinuz {}\n  initrd /boot/initrd.img\n}}\nEOF",
+        profile.distro_name, profile.version, args
+    )
+}
+
+fn debian_installer(profile: &Profile) -> Result<String> {
+    let mirror = &crate::mirror_list(profile)[0];
+    let suite = crate::debootstrap_suite(profile);
+    let (protocol, rest) = mirror
+        .split_once("://")
+        .ok_or_else(|| anyhow::anyhow!("Mirror must be an http(s) URL: {}", mirror))?;
+    let (hostname, directory) = rest.split_once('/').map_or((rest, "/".to_string()), |(h, d)| (h, format!("/{}", d)));
+
+    let mut preseed = vec![
+        "d-i mirror/country string manual".to_string(),
+        format!("d-i mirror/protocol string {}", protocol),
+        format!("d-i mirror/{}/hostname string {}", protocol, hostname),
+        format!("d-i mirror/{}/directory string {}", protocol, directory),
+        format!("d-i mirror/{}/proxy string", protocol),
+        format!("d-i mirror/suite string {}", suite),
+    ];
+    if !profile.packages.is_empty() {
+        let packages: Vec<&str> = profile.packages.iter().map(|p| crate::package_name(p)).collect();
+        preseed.push(format!("d-i pkgsel/include string {}", packages.join(" ")));
+    }
+    preseed.extend(unattended::preseed(profile)?);
+    let repositories = profile.repositories.iter().filter(|r| r.enabled && !r.url.starts_with("ppa:"));
+    for (index, repo) in repositories.enumerate() {
+        let components = if repo.components.is_empty() { "main".to_string() } else { repo.components.join(" ") };
+        preseed.push(format!(
+            "d-i apt-setup/local{}/repository string deb {} {} {}",
+            index,
+            repo.url,
+            repo.suite.clone().unwrap_or_else(|| suite.clone()),
+            components
+        ));
+        if let Some(key) = repo.gpg_key.as_ref().filter(|key| key.starts_with("http")) {
+            preseed.push(format!("d-i apt-setup/local{}/key string {}", index, key));
+        }
+    }
+
+    let arch = crate::debian_arch(crate::target_arch(profile)?);
+    let images = format!("{}/dists/{}/main/installer-{arch}/current/images/netboot/debian-installer/{arch}", mirror, suite);
+    // debian-installer loads /preseed.cfg from its initrd, and a second cpio archive can simply be appended
+    Ok(format!(
+        "curl -fsSL -o /iso/boot/vmlinuz {images}/linux\n\
+         curl -fsSL -o /iso/boot/initrd.img {images}/initrd.gz\n\
+         mkdir -p /tmp/preseed && cd /tmp/preseed\n\
+         cat > preseed.cfg <<'EOF'\n{preseed}\nEOF\n\
+         echo preseed.cfg | cpio -H newc -o | gzip >> /iso/boot/initrd.img\n\
+         cd /\n\
+         {grub}",
+        images = images,
+        preseed = preseed.join("\n"),
+        grub = grub_cfg(profile, &format!("vga=788 {} --- quiet", unattended::preseed_boot_args(profile)?)),
+    ))
+}
+
+/// The installation tree Anaconda's kernel, initrd and install.img come from, with the
+/// releasever left to the shell.
+pub fn anaconda_tree(profile: &Profile) -> Result<String> {
+    let releasever = crate::dnf_releasever(profile);
+    let base_url = match crate::mirror_list(profile).first() {
+        Some(mirror) => mirror.clone(),
+        None => match profile.base.as_str() {
+            "fedora" => "https://dl.fedoraproject.org/pub/fedora/linux",
+            "rocky" => "https://dl.rockylinux.org/pub/rocky",
+            _ => crate::dnf_upstream_prefix(&profile.base).unwrap_or_default(),
+        }
+        .to_string(),
+    };
+    let arch = crate::target_arch(profile)?;
+    Ok(match profile.base.as_str() {
+        "fedora" => format!("{}/releases/{}/Everything/{}/os", base_url, releasever, arch),
+        "centos-stream" => format!("{}/{}-stream/BaseOS/{}/os", base_url, releasever, arch),
+        _ => format!("{}/{}/BaseOS/{}/os", base_url, releasever, arch),
+    })
+}
+
+fn anaconda(profile: &Profile) -> Result<String> {
+    let tree = anaconda_tree(profile)?;
+    let mut args = vec![format!("inst.repo={}", tree)];
+    for repo in profile.repositories.iter().filter(|r| r.enabled) {
+        args.push(format!("inst.addrepo={},{}", repo.name, repo.url));
+    }
+    // A kickstart holding only the software selection; Anaconda still asks for everything else
+    let mut kickstart = String::new();
+    if !profile.packages.is_empty() || !profile.packages_to_remove.is_empty() {
+        let mut ks = vec!["%packages".to_string(), "@core".to_string()];
+        ks.extend(profile.packages.iter().map(|p| p.replacen('=', "-", 1)));
+        ks.extend(profile.packages_to_remove.iter().map(|p| format!("-{}", p)));
+        ks.push("%end".to_string());
+        kickstart = format!("cat > /iso/ks.cfg <<'KS'\n{}\nKS\n", ks.join("\n"));
+        args.push(format!("inst.ks=hd:LABEL={}:/ks.cfg", profile.distro_name.to_uppercase()));
+    }
+    // The kernel arguments are only known once the shell has expanded releasever
+    Ok(format!(
+        "curl -fsSL -o /iso/boot/vmlinuz {tree}/images/pxeboot/vmlinuz\n\
+         curl -fsSL -o /iso/boot/initrd.img {tree}/images/pxeboot/initrd.img\n\
+         {kickstart}{grub}",
+        tree = tree,
+        kickstart = kickstart,
+        grub = grub_cfg(profile, &args.join(" ")).replacen("<<'EOF'", "<<EOF", 1),
+    ))
+}
+
+/// Remasters Ubuntu's live server ISO: Subiquity reads the autoinstall user-data from the
+/// nocloud seed added to the ISO, and the replaced grub.cfg points it there.
+fn ubuntu_server(profile: &Profile, build_dir: &Path) -> Result<PathBuf> {
+    if crate::target_arch(profile)? != "x86_64" {
+        return Err(anyhow::anyhow!("Ubuntu netinstall media is only built for x86_64"));
+    }
+    let iso_name = format!("{}-{}-netinst.iso", profile.distro_name, profile.version);
+    let autoinstall = if unattended::config(profile)?.is_some() { "autoinstall " } else { "" };
+    let iso_cmd = format!(
+        r#"set -e
+{tools}
+URL=https://releases.ubuntu.com/{release}
+curl -fsSL -o /tmp/SHA256SUMS $URL/SHA256SUMS
+SERVER_ISO=$(grep -o 'ubuntu-[0-9.]*-live-server-amd64\.iso' /tmp/SHA256SUMS | head -n1)
+[ -n "$SERVER_ISO" ] || {{ echo "No live server ISO in $URL" >&2; exit 1; }}
+curl -fsSL -o /tmp/server.iso $URL/$SERVER_ISO
+echo "$(grep "$SERVER_ISO" /tmp/SHA256SUMS | cut -d' ' -f1)  /tmp/server.iso" | sha256sum -c -
+mkdir -p /tmp/nocloud
+cat > /tmp/nocloud/user-data <<'EOF'
+{user_data}EOF
+touch /tmp/nocloud/meta-data
+cat > /tmp/grub.cfg <<'EOF'
+set timeout=5
+menuentry 'Install {name} {version}' {{
+  set gfxpayload=keep
+  linux /casper/vmlinuz {autoinstall}ds=nocloud\;s=/cdrom/nocloud/ ---
+  initrd /casper/initrd
+}}
+EOF
+xorriso -indev /tmp/server.iso -outdev /out/{iso} -map /tmp/nocloud /nocloud -map /tmp/grub.cfg /boot/grub/grub.cfg -boot_image any replay
+"#,
+        tools = crate::package_manager(profile)?.refresh_and_install(&["curl", "xorriso"]),
+        release = crate::release(profile),
+        user_data = unattended::autoinstall(profile)?,
+        name = profile.distro_name,
+        version = profile.version,
+        autoinstall = autoinstall,
+        iso = iso_name,
+    );
+    crate::run_in_builder(profile, vec![format!("{}:/out:z", build_dir.display())], &iso_cmd, "Netinstall ISO build")?;
+
+    let iso_path = build_dir.join(&iso_name);
+    info!("Netinstall ISO built at {}", iso_path.display());
+    Ok(iso_path)
+}
@@ -0,0 +1,449 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+const NETPLAN_FILE: &str = "etc/netplan/90-ulb.yaml";
+const NM_CONNECTIONS: &str = "etc/NetworkManager/system-connections";
+const NETWORKD_DIR: &str = "etc/systemd/network";
+
+// Optional [network] section: interfaces configured in the image
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct NetworkConfig {
+    pub backend: Option<String>, // "netplan" (ubuntu default), "networkmanager" (default elsewhere) or "networkd"
+    #[serde(default)]
+    pub ethernets: Vec<Ethernet>, // [[network.ethernets]]
+    #[serde(default)]
+    pub vlans: Vec<Vlan>, // [[network.vlans]]
+    #[serde(default)]
+    pub bridges: Vec<Bridge>, // [[network.bridges]]
+    #[serde(default)]
+    pub wifis: Vec<Wifi>, // [[network.wifis]]
+}
+
+// Addressing shared by every kind of interface
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Addressing {
+    pub dhcp: Option<bool>, // Defaults to true without addresses
+    #[serde(default)]
+    pub addresses: Vec<String>, // CIDR addresses, e.g. ["192.168.1.10/24", "fd00::10/64"]
+    pub gateway: Option<String>, // Default IPv4 gateway
+    #[serde(default)]
+    pub dns: Vec<String>, // Name servers
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Ethernet {
+    pub name: String, // Interface name, e.g. "eth0" or "enp1s0"
+    pub mac: Option<String>, // Match the NIC by MAC address instead, naming it `name`
+    #[serde(flatten)]
+    pub ip: Addressing,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Vlan {
+    pub name: String, // e.g. "vlan10"
+    pub id: u16, // VLAN ID, 1-4094
+    pub link: String, // Parent interface
+    #[serde(flatten)]
+    pub ip: Addressing,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Bridge {
+    pub name: String, // e.g. "br0"
+    #[serde(default)]
+    pub interfaces: Vec<String>, // Ports of the bridge
+    #[serde(flatten)]
+    pub ip: Addressing,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Wifi {
+    pub ssid: String,
+    pub psk: Option<String>, // WPA2/WPA3 passphrase, none for an open network
+    pub interface: Option<String>, // Required with networkd, e.g. "wlan0"
+    #[serde(default)]
+    pub hidden: bool, // The network doesn't broadcast its SSID
+    #[serde(flatten)]
+    pub ip: Addressing,
+}
+
+impl Addressing {
+    fn dhcp(&self) -> bool {
+        self.dhcp.unwrap_or(self.addresses.is_empty())
+    }
+}
+
+/// The [network] settings and backend, once checked.
+pub fn config(profile: &Profile) -> Result<Option<(NetworkConfig, &'static str)>> {
+    let Some(config) = profile.network.clone() else {
+        return Ok(None);
+    };
+    let backend = match config.backend.as_deref() {
+        None if profile.base == "ubuntu" => "netplan",
+        None | Some("networkmanager") => "networkmanager",
+        Some("netplan") => "netplan",
+        Some("networkd") => "networkd",
+        Some(backend) => return Err(anyhow::anyhow!("Unsupported [network] backend: {}. Supported: netplan, networkmanager, networkd", backend)),
+    };
+    if backend == "networkd" && profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("[network] backend = \"networkd\" needs init_system = \"systemd\""));
+    }
+    if backend == "netplan" && !matches!(profile.base.as_str(), "debian" | "ubuntu") {
+        return Err(anyhow::anyhow!("[network] backend = \"netplan\" needs the debian or ubuntu base"));
+    }
+
+    let valid_name = |name: &str| !name.is_empty() && name.len() <= 15 && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    let names = config
+        .ethernets
+        .iter()
+        .map(|e| &e.name)
+        .chain(config.vlans.iter().flat_map(|v| [&v.name, &v.link]))
+        .chain(config.bridges.iter().flat_map(|b| std::iter::once(&b.name).chain(&b.interfaces)))
+        .chain(config.wifis.iter().filter_map(|w| w.interface.as_ref()));
+    for name in names {
+        if !valid_name(name) {
+            return Err(anyhow::anyhow!("Invalid [network] interface name: {:?}", name));
+        }
+    }
+    if let Some(vlan) = config.vlans.iter().find(|v| v.id == 0 || v.id > 4094) {
+        return Err(anyhow::anyhow!("[network] vlan {} needs an id from 1 to 4094", vlan.name));
+    }
+    if let Some(mac) = config.ethernets.iter().filter_map(|e| e.mac.as_ref()).find(|mac| {
+        mac.len() != 17 || !mac.split(':').all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()))
+    }) {
+        return Err(anyhow::anyhow!("Invalid [network] mac: {}", mac));
+    }
+    for wifi in &config.wifis {
+        if wifi.ssid.is_empty() || wifi.ssid.len() > 32 || wifi.ssid.contains(['"', '\'', '\\', '\n']) {
+            return Err(anyhow::anyhow!("Invalid [network] wifi ssid: {:?}", wifi.ssid));
+        }
+        if wifi.psk.as_ref().is_some_and(|psk| psk.len() < 8 || psk.len() > 63 || psk.contains(['"', '\'', '\\', '\n'])) {
+            return Err(anyhow::anyhow!("[network] wifi {} psk must be 8 to 63 characters without quotes or backslashes", wifi.ssid));
+        }
+        if backend == "networkd" && wifi.interface.is_none() {
+            return Err(anyhow::anyhow!("[network] wifi {} needs an interface with backend = \"networkd\"", wifi.ssid));
+        }
+    }
+    for ip in addressings(&config) {
+        let addresses = ip.addresses.iter().chain(&ip.gateway).chain(&ip.dns);
+        if let Some(address) = addresses.into_iter().find(|a| a.is_empty() || !a.chars().all(|c| c.is_ascii_hexdigit() || matches!(c, '.' | ':' | '/'))) {
+            return Err(anyhow::anyhow!("Invalid [network] address: {:?}", address));
+        }
+        if let Some(address) = ip.addresses.iter().find(|a| !a.contains('/')) {
+            return Err(anyhow::anyhow!("[network] address {} needs a prefix length, e.g. {}/24", address, address));
+        }
+    }
+    Ok(Some((config, backend)))
+}
+
+fn addressings(config: &NetworkConfig) -> impl Iterator<Item = &Addressing> {
+    config
+        .ethernets
+        .iter()
+        .map(|e| &e.ip)
+        .chain(config.vlans.iter().map(|v| &v.ip))
+        .chain(config.bridges.iter().map(|b| &b.ip))
+        .chain(config.wifis.iter().map(|w| &w.ip))
+}
+
+// Netplan's addressing keys for one interface, indented under it
+fn netplan_addressing(ip: &Addressing) -> String {
+    let mut yaml = format!("      dhcp4: {}\n", ip.dhcp());
+    if !ip.addresses.is_empty() {
+        yaml.push_str(&format!("      addresses: [ {} ]\n", ip.addresses.join(", ")));
+    }
+    if let Some(gateway) = &ip.gateway {
+        yaml.push_str(&format!("      routes:\n        - to: default\n          via: {}\n", gateway));
+    }
+    if !ip.dns.is_empty() {
+        yaml.push_str(&format!("      nameservers:\n        addresses: [ {} ]\n", ip.dns.join(", ")));
+    }
+    yaml
+}
+
+fn netplan(config: &NetworkConfig) -> String {
+    let mut yaml = "network:\n  version: 2\n".to_string();
+    if !config.ethernets.is_empty() {
+        yaml.push_str("  ethernets:\n");
+        for ethernet in &config.ethernets {
+            yaml.push_str(&format!("    {}:\n", ethernet.name));
+            if let Some(mac) = &ethernet.mac {
+                yaml.push_str(&format!("      match:\n        macaddress: \"{}\"\n      set-name: {}\n", mac.to_lowercase(), ethernet.name));
+            }
+            yaml.push_str(&netplan_addressing(&ethernet.ip));
+        }
+    }
+    if !config.vlans.is_empty() {
+        yaml.push_str("  vlans:\n");
+        for vlan in &config.vlans {
+            yaml.push_str(&format!("    {}:\n      id: {}\n      link: {}\n", vlan.name, vlan.id, vlan.link));
+            yaml.push_str(&netplan_addressing(&vlan.ip));
+        }
+    }
+    if !config.bridges.is_empty() {
+        yaml.push_str("  bridges:\n");
+        for bridge in &config.bridges {
+            yaml.push_str(&format!("    {}:\n      interfaces: [ {} ]\n", bridge.name, bridge.interfaces.join(", ")));
+            yaml.push_str(&netplan_addressing(&bridge.ip));
+        }
+    }
+    if !config.wifis.is_empty() {
+        yaml.push_str("  wifis:\n");
+        for (index, wifi) in config.wifis.iter().enumerate() {
+            // Without an interface the first wireless NIC takes it
+            match &wifi.interface {
+                Some(interface) => yaml.push_str(&format!("    {}:\n", interface)),
+                None => yaml.push_str(&format!("    wifi{}:\n      match:\n        name: \"wl*\"\n", index)),
+            }
+            let mut access_point = String::new();
+            if let Some(psk) = &wifi.psk {
+                access_point.push_str(&format!("          password: \"{}\"\n", psk));
+            }
+            if wifi.hidden {
+                access_point.push_str("          hidden: true\n");
+            }
+            if access_point.is_empty() {
+                access_point = "          {}\n".to_string();
+            }
+            yaml.push_str(&format!("      access-points:\n        \"{}\":\n{}", wifi.ssid, access_point));
+            yaml.push_str(&netplan_addressing(&wifi.ip));
+        }
+    }
+    yaml
+}
+
+// The [ipv4] and [ipv6] sections of a NetworkManager keyfile
+fn keyfile_addressing(ip: &Addressing) -> String {
+    let (v6, v4): (Vec<&String>, Vec<&String>) = ip.addresses.iter().partition(|a| a.contains(':'));
+    let (dns6, dns4): (Vec<&String>, Vec<&String>) = ip.dns.iter().partition(|a| a.contains(':'));
+    let mut keyfile = String::new();
+    for (section, addresses, dns, gateway) in [("ipv4", v4, dns4, ip.gateway.as_ref()), ("ipv6", v6, dns6, None)] {
+        let method = match (ip.dhcp(), addresses.is_empty()) {
+            (true, _) => "auto",
+            (false, false) => "manual",
+            (false, true) if section == "ipv4" => "disabled",
+            _ => "ignore",
+        };
+        keyfile.push_str(&format!("\n[{}]\nmethod={}\n", section, method));
+        for (index, address) in addresses.iter().enumerate() {
+            match gateway.filter(|_| index == 0) {
+                Some(gateway) => keyfile.push_str(&format!("address{}={},{}\n", index + 1, address, gateway)),
+                None => keyfile.push_str(&format!("address{}={}\n", index + 1, address)),
+            }
+        }
+        if !dns.is_empty() {
+            keyfile.push_str(&format!("dns={};\n", dns.iter().map(|d| d.as_str()).collect::<Vec<_>>().join(";")));
+        }
+    }
+    keyfile
+}
+
+// Every connection as (file name, keyfile)
+fn keyfiles(config: &NetworkConfig) -> Vec<(String, String)> {
+    let mut keyfiles = Vec::new();
+    for ethernet in &config.ethernets {
+        let mut keyfile = match &ethernet.mac {
+            Some(mac) => format!("[connection]\nid={}\ntype=ethernet\n\n[ethernet]\nmac-address={}\n", ethernet.name, mac.to_uppercase()),
+            None => format!("[connection]\nid={0}\ntype=ethernet\ninterface-name={0}\n", ethernet.name),
+        };
+        keyfile.push_str(&keyfile_addressing(&ethernet.ip));
+        keyfiles.push((ethernet.name.clone(), keyfile));
+    }
+    for vlan in &config.vlans {
+        let mut keyfile = format!("[connection]\nid={0}\ntype=vlan\ninterface-name={0}\n\n[vlan]\nparent={1}\nid={2}\n", vlan.name, vlan.link, vlan.id);
+        keyfile.push_str(&keyfile_addressing(&vlan.ip));
+        keyfiles.push((vlan.name.clone(), keyfile));
+    }
+    for bridge in &config.bridges {
+        let mut keyfile = format!("[connection]\nid={0}\ntype=bridge\ninterface-name={0}\n\n[bridge]\nstp=false\n", bridge.name);
+        keyfile.push_str(&keyfile_addressing(&bridge.ip));
+        keyfiles.push((bridge.name.clone(), keyfile));
+        for port in &bridge.interfaces {
+            keyfiles.push((
+                format!("{}-{}", bridge.name, port),
+                format!("[connection]\nid={0}-{1}\ntype=ethernet\ninterface-name={1}\nmaster={0}\nslave-type=bridge\n", bridge.name, port),
+            ));
+        }
+    }
+    for wifi in &config.wifis {
+        let mut keyfile = format!("[connection]\nid={}\ntype=wifi\n", wifi.ssid);
+        if let Some(interface) = &wifi.interface {
+            keyfile.push_str(&format!("interface-name={}\n", interface));
+        }
+        keyfile.push_str(&format!("\n[wifi]\nmode=infrastructure\nssid={}\n", wifi.ssid));
+        if wifi.hidden {
+            keyfile.push_str("hidden=true\n");
+        }
+        if let Some(psk) = &wifi.psk {
+            keyfile.push_str(&format!("\n[wifi-security]\nkey-mgmt=wpa-psk\npsk={}\n", psk));
+        }
+        keyfile.push_str(&keyfile_addressing(&wifi.ip));
+        keyfiles.push((format!("wifi-{}", wifi.ssid.replace(['/', ' '], "_")), keyfile));
+    }
+    keyfiles
+}
+
+// The [Network] addressing lines of a systemd-networkd .network file
+fn networkd_addressing(ip: &Addressing) -> String {
+    let mut network = format!("DHCP={}\n", if ip.dhcp() { "yes" } else { "no" });
+    for address in &ip.addresses {
+        network.push_str(&format!("Address={}\n", address));
+    }
+    if let Some(gateway) = &ip.gateway {
+        network.push_str(&format!("Gateway={}\n", gateway));
+    }
+    for dns in &ip.dns {
+        network.push_str(&format!("DNS={}\n", dns));
+    }
+    network
+}
+
+// Every .netdev and .network file, plus the wpa_supplicant configs networkd leaves Wi-Fi to
+fn networkd_files(config: &NetworkConfig) -> Vec<(String, String)> {
+    let mut files = Vec::new();
+    let member_of = |name: &str| {
+        let mut lines = String::new();
+        for vlan in config.vlans.iter().filter(|v| v.link == name) {
+            lines.push_str(&format!("VLAN={}\n", vlan.name));
+        }
+        if let Some(bridge) = config.bridges.iter().find(|b| b.interfaces.iter().any(|i| i == name)) {
+            lines.push_str(&format!("Bridge={}\n", bridge.name));
+        }
+        lines
+    };
+    for ethernet in &config.ethernets {
+        let matching = match &ethernet.mac {
+            Some(mac) => format!("MACAddress={}", mac.to_lowercase()),
+            None => format!("Name={}", ethernet.name),
+        };
+        if let Some(mac) = &ethernet.mac {
+            files.push((
+                format!("{}/50-ulb-{}.link", NETWORKD_DIR, ethernet.name),
+                format!("[Match]\nMACAddress={}\n\n[Link]\nName={}\n", mac.to_lowercase(), ethernet.name),
+            ));
+        }
+        files.push((
+            format!("{}/50-ulb-{}.network", NETWORKD_DIR, ethernet.name),
+            format!("[Match]\n{}\n\n[Network]\n{}{}", matching, networkd_addressing(&ethernet.ip), member_of(&ethernet.name)),
+        ));
+    }
+    // Parents and bridge ports the profile doesn't configure themselves still need to carry them
+    let configured: Vec<&String> = config.ethernets.iter().map(|e| &e.name).collect();
+    let mut links: Vec<&String> = config.vlans.iter().map(|v| &v.link).chain(config.bridges.iter().flat_map(|b| &b.interfaces)).collect();
+    links.sort();
+    links.dedup();
+    for link in links.into_iter().filter(|link| !configured.contains(link) && !config.bridges.iter().any(|b| &b.name == *link)) {
+        files.push((
+            format!("{}/50-ulb-{}.network", NETWORKD_DIR, link),
+            format!("[Match]\nName={}\n\n[Network]\nLinkLocalAddressing=no\n{}", link, member_of(link)),
+        ));
+    }
+    for vlan in &config.vlans {
+        files.push((format!("{}/50-ulb-{}.netdev", NETWORKD_DIR, vlan.name), format!("[NetDev]\nName={}\nKind=vlan\n\n[VLAN]\nId={}\n", vlan.name, vlan.id)));
+        files.push((
+            format!("{}/50-ulb-{}.network", NETWORKD_DIR, vlan.name),
+            format!("[Match]\nName={}\n\n[Network]\n{}{}", vlan.name, networkd_addressing(&vlan.ip), member_of(&vlan.name)),
+        ));
+    }
+    for bridge in &config.bridges {
+        files.push((format!("{}/50-ulb-{}.netdev", NETWORKD_DIR, bridge.name), format!("[NetDev]\nName={}\nKind=bridge\n", bridge.name)));
+        files.push((
+            format!("{}/50-ulb-{}.network", NETWORKD_DIR, bridge.name),
+            format!("[Match]\nName={}\n\n[Network]\n{}{}", bridge.name, networkd_addressing(&bridge.ip), member_of(&bridge.name)),
+        ));
+    }
+    for wifi in &config.wifis {
+        let interface = wifi.interface.as_deref().unwrap_or_default();
+        let security = match &wifi.psk {
+            Some(psk) => format!("    psk=\"{}\"\n", psk),
+            None => "    key_mgmt=NONE\n".to_string(),
+        };
+        let scan = if wifi.hidden { "    scan_ssid=1\n" } else { "" };
+        let supplicant = format!("etc/wpa_supplicant/wpa_supplicant-{}.conf", interface);
+        let network = format!("network={{\n    ssid=\"{}\"\n{}{}}}\n", wifi.ssid, security, scan);
+        match files.iter_mut().find(|(path, _)| *path == supplicant) {
+            Some((_, content)) => content.push_str(&network),
+            None => files.push((supplicant, format!("ctrl_interface=/run/wpa_supplicant\nupdate_config=0\n\n{}", network))),
+        }
+        files.push((
+            format!("{}/50-ulb-{}.network", NETWORKD_DIR, interface),
+            format!("[Match]\nName={}\n\n[Network]\n{}", interface, networkd_addressing(&wifi.ip)),
+        ));
+    }
+    files
+}
+
+fn write_private(path: &Path, content: &str) -> Result<()> {
+    crate::write_file(path, content)?;
+    // Wi-Fi passphrases live in these files
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).context(format!("Failed to restrict {}", path.display()))
+}
+
+/// Renders [network] for its backend: a netplan file, NetworkManager keyfiles or
+/// systemd-networkd units with wpa_supplicant for Wi-Fi. Installs and enables what the backend
+/// needs to apply them at boot.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some((config, backend)) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Configuring network...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut packages = Vec::new();
+    let mut lines = vec!["set -e".to_string()];
+    match backend {
+        "netplan" => {
+            write_private(&rootfs.join(NETPLAN_FILE), &netplan(&config))?;
+            packages.push("netplan.io");
+            // Netplan hands its config to networkd unless told otherwise
+            lines.push("systemctl enable systemd-networkd".to_string());
+            if !config.wifis.is_empty() {
+                packages.push("wpasupplicant");
+            }
+        }
+        "networkd" => {
+            for (path, content) in networkd_files(&config) {
+                write_private(&rootfs.join(path), &content)?;
+            }
+            lines.push("systemctl enable systemd-networkd".to_string());
+            lines.push("systemctl enable systemd-resolved 2>/dev/null || true".to_string());
+            let mut interfaces: Vec<&str> = config.wifis.iter().filter_map(|w| w.interface.as_deref()).collect();
+            interfaces.dedup();
+            for interface in interfaces {
+                lines.push(format!("systemctl enable wpa_supplicant@{}.service", interface));
+            }
+            if !config.wifis.is_empty() {
+                packages.push(match package_manager {
+                    PackageManager::Apt => "wpasupplicant",
+                    PackageManager::Portage => "net-wireless/wpa_supplicant",
+                    _ => "wpa_supplicant",
+                });
+            }
+        }
+        _ => {
+            for (name, keyfile) in keyfiles(&config) {
+                write_private(&rootfs.join(NM_CONNECTIONS).join(format!("{}.nmconnection", name)), &keyfile)?;
+            }
+            packages.push(match package_manager {
+                PackageManager::Apt => "network-manager",
+                PackageManager::Pacman => "networkmanager",
+                PackageManager::Portage => "net-misc/networkmanager",
+                _ => "NetworkManager",
+            });
+            lines.push(match profile.init_system.as_str() {
+                "openrc" => "rc-update add NetworkManager default".to_string(),
+                "runit" | "s6" => "ln -sf /etc/sv/NetworkManager /etc/runit/runsvdir/default/".to_string(),
+                _ => "systemctl enable NetworkManager".to_string(),
+            });
+        }
+    }
+    let packages: Vec<String> = packages.into_iter().map(String::from).collect();
+    lines.insert(1, package_manager.install(&packages));
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Network configuration")
+}
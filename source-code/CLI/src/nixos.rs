@@ -0,0 +1,215 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::Profile;
+
+/// Checks that the profile fits the nixos base: it only produces a live ISO, and takes no
+/// settings the generated configuration.nix doesn't render.
+pub fn check(profile: &Profile) -> Result<()> {
+    if profile.base != "nixos" {
+        return Ok(());
+    }
+    if profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("The nixos base only supports init_system = \"systemd\""));
+    }
+    if profile.atomic {
+        return Err(anyhow::anyhow!("The nixos base is declarative already, set atomic = false"));
+    }
+    if profile.format.iter().any(|format| format != "iso") {
+        return Err(anyhow::anyhow!("The nixos base only produces an iso"));
+    }
+    // Settings configuration.nix has no equivalent for, which would be left out silently
+    let unsupported = [
+        ("packages_to_remove", !profile.packages_to_remove.is_empty()),
+        ("packages_hold", !profile.packages_hold.is_empty()),
+        ("aur_packages", !profile.aur_packages.is_empty()),
+        ("[[repositories]]", !profile.repositories.is_empty()),
+        ("mirror", profile.mirror.is_some() || !profile.mirrors.is_empty()),
+        ("upgrade", profile.upgrade),
+        ("epel", profile.epel),
+        ("[make_conf]", profile.make_conf.is_some()),
+        ("[debconf]", !profile.debconf.is_empty()),
+        ("[alternatives]", !profile.alternatives.is_empty()),
+        ("ca_certificates", !profile.ca_certificates.is_empty()),
+        ("flatpaks", !profile.flatpaks.is_empty() || profile.flatpak_remote.is_some()),
+        ("snaps", !profile.snaps.is_empty()),
+        ("[[appimages]]", !profile.appimages.is_empty()),
+        ("locales", !profile.locales.is_empty()),
+        ("timezone", profile.timezone.is_some()),
+        ("keymap", profile.keymap.is_some() || profile.x11_layout.is_some()),
+        ("services", !profile.services_enable.is_empty() || !profile.services_disable.is_empty() || !profile.services_mask.is_empty() || profile.services_preset_all),
+        ("kernel_cmdline", profile.kernel_cmdline.is_some()),
+        ("[kernel]", profile.kernel.is_some()),
+        ("[initramfs]", profile.initramfs.is_some()),
+        ("[drivers]", profile.drivers.is_some()),
+        ("[firmware]", profile.firmware.is_some()),
+        ("dkms_modules", !profile.dkms_modules.is_empty()),
+        ("zfs", profile.zfs),
+        ("uki", profile.uki),
+        ("bootloader", profile.bootloader != "grub"),
+        ("[boot_menu]", profile.boot_menu.is_some()),
+        ("[[boot_entries]]", !profile.boot_entries.is_empty()),
+        ("[[languages]]", !profile.languages.is_empty()),
+        ("plymouth_theme", profile.plymouth_theme.is_some()),
+        ("[sysctl]", !profile.sysctl.is_empty()),
+        ("selinux", profile.selinux.is_some()),
+        ("[apparmor]", profile.apparmor.is_some()),
+        ("[swap]", profile.swap.is_some()),
+        ("[minimize]", profile.minimize.is_some()),
+        ("[overlay_root]", profile.overlay_root.is_some()),
+        ("[[udev_rules]]", !profile.udev_rules.is_empty()),
+        ("[[timers]] and [[cron]]", !profile.timers.is_empty() || !profile.cron.is_empty()),
+        ("[network]", profile.network.is_some()),
+        ("[firewall]", profile.firewall.is_some()),
+        ("[live]", profile.live.is_some()),
+        ("[accessibility]", profile.accessibility.is_some()),
+        ("[branding]", profile.branding.is_some()),
+        ("[dconf] and [plasma]", !profile.dconf.is_empty() || !profile.plasma.is_empty()),
+        ("toram", profile.toram),
+        ("include_memtest", profile.include_memtest),
+        ("live_fs", profile.live_fs.is_some()),
+        ("[iso]", profile.iso.is_some()),
+        ("[persistence]", profile.persistence.is_some()),
+        ("[squashfs]", profile.squashfs.is_some()),
+        ("variant", profile.variant.as_deref().is_some_and(|variant| variant != "live")),
+        ("[unattended]", profile.unattended.is_some()),
+        ("installer", profile.installer.is_some()),
+        ("oem_setup", profile.oem_setup),
+        ("preset", profile.preset.is_some() || profile.kiosk.is_some()),
+        ("[secure_boot]", profile.secure_boot.is_some()),
+        ("butane", profile.butane.is_some()),
+        ("[ostree]", profile.ostree.is_some()),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(anyhow::anyhow!("{} isn't supported on the nixos base; declare it in files/etc or the package list instead", name));
+    }
+    Ok(())
+}
+
+/// Builds a NixOS live ISO from the profile. Instead of assembling a rootfs, the package list
+/// and the files under files/etc are translated into a configuration.nix, and the ISO comes
+/// from the upstream iso-image module via `nix build`.
+pub fn build(profile: &Profile, files_dir: &Path, scripts_dir: &Path, build_dir: &Path) -> Result<()> {
+    check(profile)?;
+    if scripts_dir.exists() {
+        warn!("Scripts are not run on the nixos base; move their logic into files/ or the package list");
+    }
+
+    println!("{}", "Generating NixOS configuration...".yellow());
+    let work_dir = PathBuf::from("/tmp/.ulb/nixos");
+    if work_dir.exists() {
+        fs::remove_dir_all(&work_dir).context("Failed to clear NixOS work directory")?;
+    }
+    fs::create_dir_all(&work_dir).context("Failed to create NixOS work directory")?;
+
+    let etc_files = collect_etc_files(files_dir)?;
+    if files_dir.exists() {
+        crate::copy_files(files_dir, &work_dir.join("files"))?;
+    }
+    // base_version selects the nixpkgs release branch, e.g. "24.05" -> nixos-24.05
+    let release = crate::release(profile);
+    fs::write(work_dir.join("flake.nix"), flake_nix(&release, crate::target_arch(profile)?)).context("Failed to write flake.nix")?;
+    fs::write(work_dir.join("configuration.nix"), configuration_nix(profile, &etc_files, &release))
+        .context("Failed to write configuration.nix")?;
+
+    println!("{}", "Building NixOS ISO...".yellow());
+    let iso_name = format!("{}-{}.iso", profile.distro_name, profile.version);
+    let build_cmd = format!(
+        "cd /work && nix --extra-experimental-features 'nix-command flakes' --option sandbox false \
+         build .#nixosConfigurations.ulb.config.system.build.isoImage && cp -L result/iso/*.iso /out/{}",
+        iso_name
+    );
+    crate::podman_run(
+        &crate::base_image(profile)?,
+        &[format!("{}:/work:z", work_dir.display()), format!("{}:/out:z", build_dir.display())],
+        &["bash", "-c", &build_cmd],
+        "NixOS build",
+    )?;
+
+    info!("ISO built at {}", build_dir.join(&iso_name).display());
+    Ok(())
+}
+
+// Paths under files/etc, relative to etc/, mapped onto environment.etc entries
+fn collect_etc_files(files_dir: &Path) -> Result<Vec<String>> {
+    let mut etc_files = Vec::new();
+    if !files_dir.exists() {
+        return Ok(etc_files);
+    }
+    for entry in WalkDir::new(files_dir) {
+        let entry = entry.context("Failed to walk dir")?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(files_dir).context("Failed to strip prefix")?;
+        match relative.strip_prefix("etc") {
+            Ok(etc_path) => etc_files.push(etc_path.to_string_lossy().to_string()),
+            Err(_) => warn!("Skipping {}: only files under files/etc can be declared on the nixos base", relative.display()),
+        }
+    }
+    etc_files.sort();
+    Ok(etc_files)
+}
+
+fn flake_nix(release: &str, arch: &str) -> String {
+    format!(
+        r#"{{
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-{}";
+
+  outputs = {{ self, nixpkgs }}: {{
+    nixosConfigurations.ulb = nixpkgs.lib.nixosSystem {{
+      system = "{}-linux";
+      modules = [
+        "${{nixpkgs}}/nixos/modules/installer/cd-dvd/iso-image.nix"
+        ./configuration.nix
+      ];
+    }};
+  }};
+}}
+"#,
+        release, arch
+    )
+}
+
+fn configuration_nix(profile: &Profile, etc_files: &[String], release: &str) -> String {
+    let mut config = String::from("# Generated by ULB from the build profile, do not edit\n{ config, pkgs, lib, ... }:\n\n{\n");
+    config.push_str(&format!("  isoImage.isoBaseName = \"{}-{}\";\n", profile.distro_name, profile.version));
+    config.push_str(&format!("  isoImage.volumeID = \"{}\";\n", profile.distro_name.to_uppercase()));
+    config.push_str(&format!("  isoImage.makeEfiBootable = {};\n", profile.uefi_support));
+    config.push_str(&format!("  isoImage.makeUsbBootable = {};\n", profile.bios_support));
+    let hostname = profile.hostname.clone().unwrap_or_else(|| profile.distro_name.to_lowercase());
+    config.push_str(&format!("  networking.hostName = \"{}\";\n\n", hostname));
+
+    config.push_str("  environment.systemPackages = with pkgs; [\n");
+    for package in &profile.packages {
+        config.push_str(&format!("    {}\n", package));
+    }
+    config.push_str("  ];\n");
+
+    if !etc_files.is_empty() {
+        config.push('\n');
+        for path in etc_files {
+            config.push_str(&format!("  environment.etc.\"{}\".source = ./files/etc/{};\n", path, path));
+        }
+    }
+
+    config.push_str(&format!("\n  system.stateVersion = {};\n}}\n", state_version(release)));
+    config
+}
+
+// A numbered branch is its own release; unstable (or any other branch) takes the release of the
+// nixpkgs the flake actually locked, as stateVersion only accepts release numbers
+fn state_version(release: &str) -> String {
+    let numbered = release.split_once('.').is_some_and(|(year, month)| {
+        !year.is_empty() && !month.is_empty() && year.chars().chain(month.chars()).all(|c| c.is_ascii_digit())
+    });
+    if numbered {
+        format!("\"{}\"", release)
+    } else {
+        "lib.trivial.release".to_string()
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use colored::*;
+use std::fs;
+use std::path::Path;
+
+use crate::{disk, installer, live, PackageManager, Profile};
+
+// Present until the wizard has run, so it runs exactly once
+const OEM_STAMP: &str = "/var/lib/ulb/oem-setup";
+const OEM_WIZARD: &str = "/usr/libexec/ulb-oem-setup";
+
+// Runs the wizard on tty1 before the console login and display manager, once the image has
+// booted on the end user's machine and never on live media
+const OEM_UNIT: &str = "[Unit]
+Description=OEM first boot setup
+ConditionPathExists=/var/lib/ulb/oem-setup
+ConditionKernelCommandLine=!boot=live
+ConditionKernelCommandLine=!rd.live.image
+After=systemd-user-sessions.service plymouth-quit-wait.service systemd-vconsole-setup.service
+Before=getty@tty1.service display-manager.service
+Conflicts=getty@tty1.service
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStartPre=-/usr/bin/plymouth quit
+ExecStart=/usr/libexec/ulb-oem-setup
+StandardInput=tty
+StandardOutput=tty
+StandardError=tty
+TTYPath=/dev/tty1
+TTYReset=yes
+TTYVHangup=yes
+
+[Install]
+WantedBy=multi-user.target
+";
+
+/// Whether oem_setup applies, once checked: the wizard is a systemd service for disk images,
+/// and cloud-init or Ignition would provision the same things without asking.
+pub fn check(profile: &Profile) -> Result<bool> {
+    if !profile.oem_setup {
+        return Ok(false);
+    }
+    if profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("oem_setup needs init_system = \"systemd\""));
+    }
+    if !profile.format.iter().any(|f| disk::is_vm_format(f) || f == "rpi") {
+        return Err(anyhow::anyhow!("oem_setup needs a disk image format"));
+    }
+    if profile.cloud_init.is_some() || profile.butane.is_some() {
+        return Err(anyhow::anyhow!("oem_setup can't be combined with [cloud_init] or butane, which provision the user themselves"));
+    }
+    Ok(true)
+}
+
+/// The wizard: systemd-firstboot asks for the locale, keymap, timezone and hostname, then the
+/// first user is created with admin rights. The live user, if any, goes away with it.
+fn wizard(profile: &Profile) -> Result<String> {
+    let admin_group = match crate::package_manager(profile)? {
+        PackageManager::Apt => "sudo",
+        _ => "wheel",
+    };
+    let mut script = vec![
+        "#!/bin/bash".to_string(),
+        format!("# First boot setup of {}, generated by ulb", profile.distro_name),
+        "clear".to_string(),
+        format!("echo 'Welcome to {}. Let us set up this computer.'", profile.distro_name),
+        "echo".to_string(),
+        "systemd-firstboot --force --prompt-locale --prompt-keymap --prompt-timezone --prompt-hostname".to_string(),
+        "[ -f /etc/hostname ] && hostname \"$(cat /etc/hostname)\"".to_string(),
+        "while true; do".to_string(),
+        "    read -r -p 'Your full name: ' FULL_NAME".to_string(),
+        "    read -r -p 'Username: ' NAME".to_string(),
+        "    [[ \"$NAME\" =~ ^[a-z_][a-z0-9_-]{0,31}$ ]] && ! id \"$NAME\" >/dev/null 2>&1 && break".to_string(),
+        "    echo 'Use lowercase letters, digits, _ and -, starting with a letter, and a name not taken yet.'".to_string(),
+        "done".to_string(),
+        format!("useradd -m -s /bin/bash -G {} -c \"${{FULL_NAME//:/}}\" \"$NAME\"", admin_group),
+        "until passwd \"$NAME\"; do echo 'Please try again.'; done".to_string(),
+    ];
+    if let Some(config) = live::config(profile)? {
+        script.push(format!("userdel -r {} 2>/dev/null", config.user));
+        script.extend(installer::LIVE_CLEANUP.iter().map(|command| format!("{} 2>/dev/null", command.trim_start_matches('-'))));
+    }
+    script.push(format!("rm -f {}", OEM_STAMP));
+    script.push("systemctl disable ulb-oem-setup.service".to_string());
+    Ok(script.join("\n") + "\n")
+}
+
+/// Installs the OEM first boot wizard and arms it: the image ships without a machine-id and
+/// the end user names the machine and creates their account on first boot.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if !check(profile)? {
+        return Ok(());
+    }
+    println!("{}", "Installing OEM first boot setup...".yellow());
+
+    let wizard_path = rootfs.join(OEM_WIZARD.trim_start_matches('/'));
+    let unit_path = rootfs.join("etc/systemd/system/ulb-oem-setup.service");
+    let stamp_path = rootfs.join(OEM_STAMP.trim_start_matches('/'));
+    for path in [&wizard_path, &unit_path, &stamp_path] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+    }
+    fs::write(&wizard_path, wizard(profile)?).context("Failed to write the OEM setup wizard")?;
+    fs::write(&unit_path, OEM_UNIT).context("Failed to write the OEM setup service")?;
+    fs::write(&stamp_path, "").context("Failed to arm the OEM setup")?;
+
+    // systemd-firstboot has its own package on newer Debian and Ubuntu releases
+    let oem_cmd = format!(
+        "set -e\ncommand -v systemd-firstboot >/dev/null || {}\nchmod 755 {}\nsystemctl enable ulb-oem-setup.service\n: > /etc/machine-id",
+        crate::package_manager(profile)?.install(&["systemd-firstboot".to_string()]),
+        OEM_WIZARD
+    );
+    crate::run_in_chroot(profile, rootfs, &oem_cmd, "OEM setup")
+}
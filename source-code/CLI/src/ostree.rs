@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::{error, info};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Image the ostree CLI runs in when the host has none
+const OSTREE_IMAGE: &str = "fedora:latest";
+
+// Runs an ostree command line on the host, or in a Fedora container with `dirs` mounted at
+// the same paths when ostree isn't installed, and returns what it printed
+fn run(dirs: &[&Path], cmd: &str, stage: &str) -> Result<String> {
+    let output = if Command::new("ostree").arg("--version").output().is_ok() {
+        Command::new("bash").args(["-c", cmd]).output()
+    } else {
+        let mut args = vec!["run".to_string(), "--rm".to_string()];
+        for dir in dirs {
+            args.push("-v".to_string());
+            args.push(format!("{}:{}:z", dir.display(), dir.display()));
+        }
+        args.extend([OSTREE_IMAGE.to_string(), "bash".to_string(), "-c".to_string(), format!("dnf install -y -q ostree >/dev/null && {}", cmd)]);
+        Command::new("podman").args(&args).output()
+    }
+    .context(format!("Failed to run {}", stage.to_lowercase()))?;
+    if !output.status.success() {
+        error!("{} failed: {}", stage, String::from_utf8_lossy(&output.stderr));
+        return Err(anyhow::anyhow!("{} failed", stage));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn check_repo(repo: &Path) -> Result<()> {
+    if !repo.join("config").is_file() {
+        return Err(anyhow::anyhow!("No OSTree repository at {}. Run an atomic 'ulb build' or 'ulb ostree init-repo' first.", repo.display()));
+    }
+    Ok(())
+}
+
+fn check_ref(ostree_ref: &str) -> Result<()> {
+    if ostree_ref.is_empty() || !ostree_ref.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/' | ':')) {
+        return Err(anyhow::anyhow!("Invalid ref: {:?}", ostree_ref));
+    }
+    Ok(())
+}
+
+// The summary file is what clients fetch first, so it follows every change to the refs
+fn update_summary(repo: &Path) -> Result<()> {
+    run(&[repo], &format!("ostree summary --repo={} --update", crate::shell_quoted(repo.display())), "Summary update")?;
+    Ok(())
+}
+
+/// Creates an empty repository at `repo`; "archive" (the default) is what atomic builds commit
+/// to and what can be served over HTTP.
+pub fn init_repo(repo: &Path, mode: &str) -> Result<()> {
+    if !matches!(mode, "archive" | "bare" | "bare-user" | "bare-user-only") {
+        return Err(anyhow::anyhow!("Unsupported repository mode: {}. Supported: archive, bare, bare-user, bare-user-only", mode));
+    }
+    if repo.join("config").exists() {
+        return Err(anyhow::anyhow!("{} is already an OSTree repository", repo.display()));
+    }
+    fs::create_dir_all(repo).context(format!("Failed to create {}", repo.display()))?;
+    run(&[repo], &format!("ostree init --repo={} --mode={}", crate::shell_quoted(repo.display()), mode), "Repository init")?;
+    println!("{}", format!("Initialized {} repository at {}", mode, repo.display()).green());
+    Ok(())
+}
+
+/// Prints the commits of `ostree_ref`, or of every ref in the repository, newest first.
+pub fn list(repo: &Path, ostree_ref: Option<&str>) -> Result<()> {
+    check_repo(repo)?;
+    let refs = match ostree_ref {
+        Some(ostree_ref) => vec![ostree_ref.to_string()],
+        None => run(&[repo], &format!("ostree refs --repo={}", crate::shell_quoted(repo.display())), "Ref listing")?.lines().map(str::to_string).collect(),
+    };
+    if refs.is_empty() {
+        println!("{}", format!("No refs in {}", repo.display()).yellow());
+    }
+    for ostree_ref in refs {
+        check_ref(&ostree_ref)?;
+        println!("{}", ostree_ref.blue());
+        print!("{}", run(&[repo], &format!("ostree log --repo={} {}", crate::shell_quoted(repo.display()), ostree_ref), "Commit listing")?);
+    }
+    Ok(())
+}
+
+/// Deletes every commit but the newest `keep` of each ref, and the objects only they used.
+pub fn prune(repo: &Path, keep: u32) -> Result<()> {
+    check_repo(repo)?;
+    if keep == 0 {
+        return Err(anyhow::anyhow!("--keep must be at least 1, the ref tips are always kept"));
+    }
+    println!("{}", format!("Pruning {} to the newest {} commit(s) per ref...", repo.display(), keep).yellow());
+    // depth counts the parents kept behind each tip
+    let output = run(&[repo], &format!("ostree prune --repo={} --refs-only --depth={}", crate::shell_quoted(repo.display()), keep - 1), "Repository prune")?;
+    update_summary(repo)?;
+    print!("{}", output);
+    Ok(())
+}
+
+/// Copies `refs` (or all of them) from the repository at `source` into `repo`, e.g. to promote
+/// commits from a test repository to the one that is served.
+pub fn pull_local(repo: &Path, source: &Path, refs: &[String]) -> Result<()> {
+    check_repo(repo)?;
+    check_repo(source)?;
+    for ostree_ref in refs {
+        check_ref(ostree_ref)?;
+    }
+    println!("{}", format!("Pulling from {} into {}...", source.display(), repo.display()).yellow());
+    run(&[repo, source], &format!("ostree pull-local --repo={} {} {}", crate::shell_quoted(repo.display()), crate::shell_quoted(source.display()), refs.join(" ")), "Local pull")?;
+    update_summary(repo)?;
+    println!("{}", "Pulled!".green());
+    Ok(())
+}
+
+/// Writes the tree of `ostree_ref` (or a commit checksum) as a tar archive to `output`.
+pub fn export(repo: &Path, ostree_ref: &str, output: &Path) -> Result<()> {
+    check_repo(repo)?;
+    check_ref(ostree_ref)?;
+    let output_dir = output.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(output_dir).context(format!("Failed to create {}", output_dir.display()))?;
+    let output_dir = output_dir.canonicalize().context(format!("Failed to resolve {}", output_dir.display()))?;
+    let output = output_dir.join(output.file_name().context("Export output has no file name")?);
+    println!("{}", format!("Exporting {} to {}...", ostree_ref, output.display()).yellow());
+    run(&[repo, &output_dir], &format!("ostree export --repo={} {} > {}", crate::shell_quoted(repo.display()), ostree_ref, crate::shell_quoted(output.display())), "Commit export")?;
+    info!("Exported {} to {}", ostree_ref, output.display());
+    println!("{}", format!("Exported {}", output.display()).green());
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::{flash, PackageManager, Profile};
+
+const OVERLAYROOT_CONF: &str = "etc/overlayroot.local.conf";
+
+// Optional [overlay_root] section: a read-only root whose changes live in RAM until the next boot
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OverlayRootConfig {
+    #[serde(default = "default_mode")]
+    pub mode: String, // "overlay": the whole root behind a tmpfs overlay (default); "state": read-only root with /var on tmpfs (systemd)
+}
+
+fn default_mode() -> String {
+    "overlay".to_string()
+}
+
+/// The [overlay_root] settings, once checked. Debian and Ubuntu get overlayroot in their
+/// initramfs-tools initramfs; elsewhere the overlay is systemd's volatile mode, set up by the
+/// systemd units of a dracut initramfs. Live media discard their changes already, so [persistence]
+/// and everything else meant to keep writes on the root are turned down.
+pub fn config(profile: &Profile) -> Result<Option<OverlayRootConfig>> {
+    let Some(config) = profile.overlay_root.clone() else {
+        return Ok(None);
+    };
+    let package_manager = crate::package_manager(profile)?;
+    match config.mode.as_str() {
+        "overlay" if package_manager == PackageManager::Apt => {}
+        "overlay" if package_manager == PackageManager::Pacman => {
+            return Err(anyhow::anyhow!("[overlay_root] mode = \"overlay\" needs a dracut initramfs, arch uses mkinitcpio; use mode = \"state\""))
+        }
+        "overlay" | "state" if profile.init_system != "systemd" => {
+            return Err(anyhow::anyhow!("[overlay_root] mode = \"{}\" needs init_system = \"systemd\" (systemd.volatile)", config.mode))
+        }
+        "overlay" | "state" => {}
+        mode => return Err(anyhow::anyhow!("Unsupported [overlay_root] mode: {}. Supported: overlay, state", mode)),
+    }
+    if flash::persistence_label(profile)?.is_some() {
+        return Err(anyhow::anyhow!("[overlay_root] discards every change, it can't be combined with [persistence]"));
+    }
+    if profile.swap.as_ref().is_some_and(|swap| swap.kind == "file") {
+        return Err(anyhow::anyhow!("[swap] kind = \"file\" can't be created on the read-only root of [overlay_root]"));
+    }
+    if profile.oem_setup {
+        return Err(anyhow::anyhow!("oem_setup would be forgotten on every boot with [overlay_root]"));
+    }
+    Ok(Some(config))
+}
+
+/// Kernel arguments of disk images making the root volatile; overlayroot reads its own config
+/// from the initramfs instead.
+pub fn kernel_args(profile: &Profile) -> Result<&'static str> {
+    let Some(config) = config(profile)? else {
+        return Ok("");
+    };
+    Ok(match config.mode.as_str() {
+        "overlay" if crate::package_manager(profile)? == PackageManager::Apt => "",
+        "overlay" => "systemd.volatile=overlay",
+        _ => "systemd.volatile=state",
+    })
+}
+
+/// Kernel arguments of live media: overlayroot stays out of live-boot's way, and dracut's live
+/// root uses an overlayfs on tmpfs like the installed system rather than a device-mapper snapshot.
+pub fn live_args(profile: &Profile) -> Result<&'static str> {
+    if config(profile)?.is_none() {
+        return Ok("");
+    }
+    Ok(match crate::package_manager(profile)? {
+        PackageManager::Apt => "overlayroot=disabled",
+        _ => "rd.live.overlay.overlayfs=1",
+    })
+}
+
+/// Installs overlayroot with a tmpfs overlay for [overlay_root] on Debian and Ubuntu, before the
+/// initramfs is generated. The systemd.volatile modes need nothing in the rootfs.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(config) = config(profile)? else {
+        return Ok(());
+    };
+    let package_manager = crate::package_manager(profile)?;
+    if config.mode != "overlay" || package_manager != PackageManager::Apt {
+        return Ok(());
+    }
+    println!("{}", "Configuring overlayroot...".yellow());
+
+    let path = rootfs.join(OVERLAYROOT_CONF);
+    fs::create_dir_all(rootfs.join("etc")).context("Failed to create /etc")?;
+    // recurse=0 leaves other mounts (/boot, /boot/efi, data partitions) writable
+    fs::write(&path, "# From [overlay_root] of the ulb profile\noverlayroot=\"tmpfs:recurse=0\"\n").context(format!("Failed to write {}", path.display()))?;
+    crate::run_in_chroot(profile, rootfs, &package_manager.install(&["overlayroot".to_string()]), "overlayroot installation")
+}
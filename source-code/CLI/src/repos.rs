@@ -0,0 +1,396 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::{PackageManager, Profile};
+
+// One [[repositories]] entry of the profile
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Repository {
+    pub name: String,
+    pub url: String,           // Archive/baseurl/Server URL, or "ppa:user/name" on ubuntu
+    pub suite: Option<String>, // apt suite, defaults to the base's suite
+    #[serde(default)]
+    pub components: Vec<String>, // apt components, defaults to main
+    pub key_url: Option<String>, // Signing key to fetch and trust for this repository
+    pub gpg_key: Option<String>, // Signing key as a URL or a path under files/, replaces key_url
+    pub keyring: Option<String>, // Keyring already present in the image (apt signed-by)
+    pub credentials: Option<String>, // Name of the build-time credentials for this repository
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+// Entry of the credentials file; never part of the profile itself
+#[derive(Deserialize, Debug, Clone)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// Looks up credentials by name: ULB_CRED_<NAME>_USERNAME/_PASSWORD environment variables
+/// first, then the [<name>] table of $ULB_CREDENTIALS_FILE or ~/.config/ulb/credentials.toml.
+fn resolve_credentials(name: &str) -> Result<Credentials> {
+    let env_name = name.to_uppercase().replace(['-', '.'], "_");
+    if let (Ok(username), Ok(password)) = (
+        std::env::var(format!("ULB_CRED_{}_USERNAME", env_name)),
+        std::env::var(format!("ULB_CRED_{}_PASSWORD", env_name)),
+    ) {
+        return Ok(Credentials { username, password });
+    }
+
+    let path = match std::env::var("ULB_CREDENTIALS_FILE") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".config/ulb/credentials.toml"),
+    };
+    let content = fs::read_to_string(&path).context(format!(
+        "Credentials '{}' not found in the environment and {} is not readable",
+        name,
+        path.display()
+    ))?;
+    let mut table: std::collections::BTreeMap<String, Credentials> =
+        toml::from_str(&content).context(format!("Failed to parse {}", path.display()))?;
+    table
+        .remove(name)
+        .ok_or_else(|| anyhow::anyhow!("Credentials '{}' not found in {}", name, path.display()))
+}
+
+// Percent-encodes the characters that would break the userinfo part of a URL
+fn encode_userinfo(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '@' | '/' | '%' | '?' | '#' | ' ' => format!("%{:02X}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn url_with_credentials(url: &str, credentials: &Credentials) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => format!(
+            "{}://{}:{}@{}",
+            scheme,
+            encode_userinfo(&credentials.username),
+            encode_userinfo(&credentials.password),
+            rest
+        ),
+        None => url.to_string(),
+    }
+}
+
+// Keys are staged here inside the rootfs before being moved into the right keyring
+const KEY_STAGING: &str = "/tmp/ulb-keys";
+
+enum KeySource {
+    Url(String),
+    File(PathBuf),
+}
+
+impl Repository {
+    fn signing_key(&self, files_dir: &Path) -> Option<KeySource> {
+        let key = self.gpg_key.as_ref().or(self.key_url.as_ref())?;
+        if key.starts_with("http://") || key.starts_with("https://") {
+            Some(KeySource::Url(key.clone()))
+        } else {
+            Some(KeySource::File(files_dir.join(key.trim_start_matches('/'))))
+        }
+    }
+
+    fn staged_key(&self) -> String {
+        format!("{}/{}.key", KEY_STAGING, self.name)
+    }
+
+    fn rpm_key_path(&self) -> String {
+        format!("/etc/pki/rpm-gpg/RPM-GPG-KEY-{}", self.name)
+    }
+}
+
+/// Writes the profile's extra repositories into the rootfs, trusts their signing keys and
+/// refreshes the package index, so the package install stage can see them.
+pub fn configure_repositories(profile: &Profile, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    if profile.repositories.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Configuring repositories...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut commands = Vec::new();
+    for repo in &profile.repositories {
+        if repo.name.is_empty() || repo.name.contains(['/', ' ']) {
+            return Err(anyhow::anyhow!("Invalid repository name '{}'", repo.name));
+        }
+        if let Some(key) = repo.signing_key(files_dir) {
+            commands.extend(import_key(profile, package_manager, rootfs, repo, key)?);
+        }
+        let credentials = repo.credentials.as_deref().map(resolve_credentials).transpose()?;
+        match package_manager {
+            PackageManager::Apt => {
+                commands.extend(apt_repository(profile, rootfs, repo)?);
+                if let Some(credentials) = &credentials {
+                    write_apt_auth(rootfs, repo, credentials)?;
+                }
+            }
+            PackageManager::Dnf => write_dnf_repository(rootfs, repo, credentials.as_ref())?,
+            PackageManager::Pacman => write_pacman_repository(rootfs, repo, credentials.as_ref())?,
+            PackageManager::Xbps => write_xbps_repository(rootfs, repo, credentials.as_ref())?,
+            PackageManager::Portage => {
+                return Err(anyhow::anyhow!("Extra repositories are not supported on the {} base", profile.base))
+            }
+        }
+    }
+
+    commands.push(format!("rm -rf {}", KEY_STAGING));
+    commands.push(match package_manager {
+        PackageManager::Apt => "apt-get update".to_string(),
+        PackageManager::Dnf => "dnf makecache".to_string(),
+        PackageManager::Pacman => "pacman -Sy".to_string(),
+        PackageManager::Xbps => "xbps-install -S".to_string(),
+        PackageManager::Portage => unreachable!(),
+    });
+    crate::run_in_chroot(profile, rootfs, &commands.join(" && "), "Repository setup")
+}
+
+/// Adds the ca_certificates (PEM blobs, or files under files/) to the system trust store and
+/// regenerates the bundles. Done before the repositories, so internal HTTPS mirrors are trusted.
+pub fn install_ca_certificates(profile: &Profile, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    if profile.ca_certificates.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Installing CA certificates...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let (anchors, update) = match package_manager {
+        PackageManager::Dnf => ("etc/pki/ca-trust/source/anchors", "update-ca-trust extract"),
+        PackageManager::Pacman => ("etc/ca-certificates/trust-source/anchors", "update-ca-trust extract"),
+        _ => ("usr/local/share/ca-certificates", "update-ca-certificates"),
+    };
+    for (index, certificate) in profile.ca_certificates.iter().enumerate() {
+        let pem = if certificate.trim_start().starts_with("-----BEGIN CERTIFICATE-----") {
+            certificate.clone()
+        } else {
+            let path = files_dir.join(certificate.trim_start_matches('/'));
+            fs::read_to_string(&path).context(format!("Failed to read CA certificate {}", path.display()))?
+        };
+        if !pem.contains("-----BEGIN CERTIFICATE-----") || !pem.contains("-----END CERTIFICATE-----") {
+            return Err(anyhow::anyhow!("ca_certificates entry {} is not a PEM certificate", index + 1));
+        }
+        // update-ca-certificates only picks up .crt files
+        crate::write_file(&rootfs.join(anchors).join(format!("ulb-{}.crt", index + 1)), &format!("{}\n", pem.trim()))?;
+    }
+
+    let ca_package = match package_manager {
+        PackageManager::Portage => "app-misc/ca-certificates",
+        _ => "ca-certificates",
+    };
+    let tool = update.split(' ').next().unwrap_or(update);
+    let ca_cmd = format!("{{ command -v {} >/dev/null || {}; }} && {}", tool, package_manager.install(&[ca_package.to_string()]), update);
+    crate::run_in_chroot(profile, rootfs, &ca_cmd, "CA certificate installation")
+}
+
+// Writes the sources.list.d entry and returns the chroot commands the repository still needs
+fn apt_repository(profile: &Profile, rootfs: &Path, repo: &Repository) -> Result<Vec<String>> {
+    let mut commands = Vec::new();
+
+    if let Some(ppa) = repo.url.strip_prefix("ppa:") {
+        if profile.base != "ubuntu" {
+            return Err(anyhow::anyhow!("PPA '{}' can only be used on the ubuntu base", repo.name));
+        }
+        if repo.enabled {
+            // add-apt-repository also fetches the PPA's signing key from Launchpad
+            commands.push("apt-get install -y --no-install-recommends software-properties-common".to_string());
+            commands.push(format!("add-apt-repository -y -n ppa:{}", ppa));
+        }
+        return Ok(commands);
+    }
+
+    let mut options = Vec::new();
+    if repo.gpg_key.is_some() || repo.key_url.is_some() {
+        options.push(format!("signed-by={}", apt_keyring(repo)));
+    } else if let Some(keyring) = &repo.keyring {
+        options.push(format!("signed-by={}", keyring));
+    }
+
+    let suite = repo.suite.clone().unwrap_or_else(|| crate::debootstrap_suite(profile));
+    let components = if repo.components.is_empty() {
+        "main".to_string()
+    } else {
+        repo.components.join(" ")
+    };
+    let options = if options.is_empty() {
+        String::new()
+    } else {
+        format!("[{}] ", options.join(" "))
+    };
+    let line = format!("deb {}{} {} {}", options, repo.url, suite, components);
+
+    let path = rootfs.join(format!("etc/apt/sources.list.d/{}.list", repo.name));
+    crate::write_file(&path, &format!("{}{}\n", if repo.enabled { "" } else { "# " }, line))?;
+    Ok(commands)
+}
+
+fn apt_keyring(repo: &Repository) -> String {
+    format!("/etc/apt/keyrings/{}.gpg", repo.name)
+}
+
+/// Stages the repository's signing key in the rootfs and returns the chroot commands that
+/// move it into the package manager's trust store.
+fn import_key(
+    profile: &Profile,
+    package_manager: PackageManager,
+    rootfs: &Path,
+    repo: &Repository,
+    key: KeySource,
+) -> Result<Vec<String>> {
+    let staged = repo.staged_key();
+    let mut commands = Vec::new();
+    match key {
+        KeySource::Url(url) => {
+            if package_manager == PackageManager::Apt {
+                commands.push("apt-get install -y --no-install-recommends ca-certificates curl gpg".to_string());
+            }
+            commands.push(format!("mkdir -p {} && curl -fsSL -o {} {}", KEY_STAGING, staged, url));
+        }
+        KeySource::File(path) => {
+            if !path.is_file() {
+                return Err(anyhow::anyhow!("GPG key for repository '{}' not found: {}", repo.name, path.display()));
+            }
+            let target = rootfs.join(staged.trim_start_matches('/'));
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).context("Failed to create key staging directory")?;
+            }
+            fs::copy(&path, &target).context(format!("Failed to copy {}", path.display()))?;
+            if package_manager == PackageManager::Apt {
+                commands.push("apt-get install -y --no-install-recommends gpg".to_string());
+            }
+        }
+    }
+
+    match package_manager {
+        // signed-by wants a binary keyring, but keys are usually published armored
+        PackageManager::Apt => commands.push(format!(
+            "mkdir -p /etc/apt/keyrings && if grep -q -- '-----BEGIN PGP' {key}; then gpg --dearmor --yes -o {ring} {key}; else cp {key} {ring}; fi",
+            key = staged,
+            ring = apt_keyring(repo)
+        )),
+        PackageManager::Dnf => commands.push(format!(
+            "mkdir -p /etc/pki/rpm-gpg && cp {} {path} && rpm --import {path}",
+            staged,
+            path = repo.rpm_key_path()
+        )),
+        PackageManager::Pacman => commands.push(format!(
+            "pacman-key --init && pacman-key --add {key} && pacman-key --lsign-key $(gpg --with-colons --show-keys {key} | awk -F: '/^fpr/ {{ print $10; exit }}')",
+            key = staged
+        )),
+        PackageManager::Xbps | PackageManager::Portage => {
+            return Err(anyhow::anyhow!("gpg_key is not supported on the {} base", profile.base))
+        }
+    }
+    Ok(commands)
+}
+
+/// Configures the repositories, runs `stages` and scrubs the credentials again, also when
+/// configuring or a stage fails, so a half-built rootfs left in /tmp/.ulb never keeps them.
+pub fn with_repositories(profile: &Profile, files_dir: &Path, rootfs: &Path, stages: impl FnOnce() -> Result<()>) -> Result<()> {
+    let result = configure_repositories(profile, files_dir, rootfs).and_then(|()| stages());
+    let scrubbed = scrub_credentials(profile, rootfs);
+    result.and(scrubbed)
+}
+
+/// Removes repository credentials from the rootfs once nothing else needs to download from
+/// the repositories, so the secrets never end up in the built image.
+fn scrub_credentials(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let authenticated: Vec<&Repository> = profile.repositories.iter().filter(|r| r.credentials.is_some()).collect();
+    if authenticated.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Scrubbing repository credentials...".yellow());
+
+    for repo in authenticated {
+        match crate::package_manager(profile)? {
+            PackageManager::Apt => {
+                let auth = rootfs.join(apt_auth_path(repo).trim_start_matches('/'));
+                if auth.exists() {
+                    fs::remove_file(&auth).context(format!("Failed to remove {}", auth.display()))?;
+                }
+            }
+            PackageManager::Dnf => write_dnf_repository(rootfs, repo, None)?,
+            PackageManager::Pacman => {
+                let credentials = resolve_credentials(repo.credentials.as_deref().unwrap_or_default())?;
+                let path = rootfs.join("etc/pacman.conf");
+                let content = fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+                let content = content.replace(&url_with_credentials(&repo.url, &credentials), &repo.url);
+                fs::write(&path, content).context(format!("Failed to write {}", path.display()))?;
+            }
+            PackageManager::Xbps => write_xbps_repository(rootfs, repo, None)?,
+            PackageManager::Portage => {}
+        }
+    }
+    Ok(())
+}
+
+fn apt_auth_path(repo: &Repository) -> String {
+    format!("/etc/apt/auth.conf.d/ulb-{}.conf", repo.name)
+}
+
+fn write_apt_auth(rootfs: &Path, repo: &Repository, credentials: &Credentials) -> Result<()> {
+    // auth.conf matches on the URL without its scheme
+    let machine = repo.url.split_once("://").map_or(repo.url.as_str(), |(_, rest)| rest);
+    let path = rootfs.join(apt_auth_path(repo).trim_start_matches('/'));
+    crate::write_file(
+        &path,
+        &format!("machine {} login {} password {}\n", machine, credentials.username, credentials.password),
+    )?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .context(format!("Failed to restrict permissions of {}", path.display()))
+}
+
+fn write_dnf_repository(rootfs: &Path, repo: &Repository, credentials: Option<&Credentials>) -> Result<()> {
+    let mut content = format!(
+        "[{name}]\nname={name}\nbaseurl={url}\nenabled={enabled}\n",
+        name = repo.name,
+        url = repo.url,
+        enabled = repo.enabled as u8
+    );
+    if repo.gpg_key.is_some() || repo.key_url.is_some() {
+        content.push_str(&format!("gpgcheck=1\ngpgkey=file://{}\n", repo.rpm_key_path()));
+    } else {
+        content.push_str("gpgcheck=0\n");
+    }
+    if let Some(credentials) = credentials {
+        content.push_str(&format!("username={}\npassword={}\n", credentials.username, credentials.password));
+    }
+    crate::write_file(&rootfs.join(format!("etc/yum.repos.d/{}.repo", repo.name)), &content)
+}
+
+fn write_pacman_repository(rootfs: &Path, repo: &Repository, credentials: Option<&Credentials>) -> Result<()> {
+    // Unsigned unless a key was imported into the pacman keyring
+    let siglevel = if repo.gpg_key.is_some() || repo.key_url.is_some() { "Required" } else { "Optional TrustAll" };
+    let prefix = if repo.enabled { "" } else { "#" };
+    let section = format!(
+        "\n{p}[{}]\n{p}SigLevel = {}\n{p}Server = {}\n",
+        repo.name,
+        siglevel,
+        credentials.map_or(repo.url.clone(), |c| url_with_credentials(&repo.url, c)),
+        p = prefix
+    );
+    append_config(&rootfs.join("etc/pacman.conf"), &section)
+}
+
+fn write_xbps_repository(rootfs: &Path, repo: &Repository, credentials: Option<&Credentials>) -> Result<()> {
+    let url = credentials.map_or(repo.url.clone(), |c| url_with_credentials(&repo.url, c));
+    let line = format!("{}repository={}\n", if repo.enabled { "" } else { "#" }, url);
+    crate::write_file(&rootfs.join(format!("etc/xbps.d/10-{}.conf", repo.name)), &line)
+}
+
+fn append_config(path: &Path, content: &str) -> Result<()> {
+    let mut existing = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    existing.push_str(content);
+    fs::write(path, existing).context(format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{boot, PackageManager, Profile};
+
+// Where the signing key and certificate are mounted in the build containers
+const KEY: &str = "/secureboot/db.key";
+const CERT: &str = "/secureboot/db.crt";
+// File name shim's MokManager offers for enrollment from the ESP
+const ENROLL_CERT: &str = "ENROLL_THIS_KEY_IN_MOKMANAGER.cer";
+
+// Optional [secure_boot] section: signs the kernel and every EFI binary of ISOs and disk images
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SecureBootConfig {
+    pub key: Option<String>, // PEM private key, defaults to db.key in the key store
+    pub cert: Option<String>, // PEM certificate, defaults to db.crt in the key store
+    pub shim: Option<bool>, // Boot through the distribution's Microsoft-signed shim, defaults to true
+}
+
+/// The signing key and certificate, once found.
+#[derive(Debug, Clone)]
+pub struct SigningKeys {
+    pub key: PathBuf,
+    pub cert: PathBuf,
+    pub shim: bool,
+}
+
+impl SigningKeys {
+    /// Read-only volumes putting the key and certificate where the signing commands expect them.
+    pub fn volumes(&self) -> Vec<String> {
+        vec![
+            format!("{}:{}:ro,z", self.key.display(), KEY),
+            format!("{}:{}:ro,z", self.cert.display(), CERT),
+        ]
+    }
+}
+
+/// The [secure_boot] keys, or None when nothing is signed. Paths not given in the profile come
+/// from the key store: $ULB_SECUREBOOT_DIR or ~/.config/ulb/secureboot.
+pub fn keys(profile: &Profile) -> Result<Option<SigningKeys>> {
+    let Some(config) = profile.secure_boot.as_ref() else {
+        return Ok(None);
+    };
+    if !profile.uefi_support || profile.bootloader == "extlinux" {
+        return Err(anyhow::anyhow!("[secure_boot] needs uefi_support = true and an EFI bootloader"));
+    }
+    let store = match std::env::var("ULB_SECUREBOOT_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".config/ulb/secureboot"),
+    };
+    let resolve = |path: &Option<String>, default: &str| {
+        let path = path.as_ref().map_or_else(|| store.join(default), PathBuf::from);
+        fs::canonicalize(&path).context(format!("Secure Boot key file {} not found", path.display()))
+    };
+    let shim = config.shim.unwrap_or(true);
+    if shim {
+        shim_suffix(crate::target_arch(profile)?)?;
+    }
+    Ok(Some(SigningKeys { key: resolve(&config.key, "db.key")?, cert: resolve(&config.cert, "db.crt")?, shim }))
+}
+
+// shim's file name suffix for `arch`
+fn shim_suffix(arch: &str) -> Result<&'static str> {
+    match arch {
+        "x86_64" => Ok("x64"),
+        "aarch64" => Ok("aa64"),
+        _ => Err(anyhow::anyhow!("shim is not available for {}, set shim = false in [secure_boot]", arch)),
+    }
+}
+
+/// Builder packages for signing, plus the signed shim and MokManager when booting through shim.
+pub fn tools(profile: &Profile, keys: &SigningKeys) -> Result<Vec<&'static str>> {
+    let package_manager = crate::package_manager(profile)?;
+    let mut tools = vec![match package_manager {
+        PackageManager::Apt => "sbsigntool",
+        PackageManager::Portage => "app-crypt/sbsigntools",
+        _ => "sbsigntools",
+    }];
+    if keys.shim {
+        tools.push("openssl");
+        tools.push(match (package_manager, crate::target_arch(profile)?) {
+            (PackageManager::Apt, _) => "shim-signed",
+            (PackageManager::Dnf, "aarch64") => "shim-aa64",
+            (PackageManager::Dnf, _) => "shim-x64",
+            _ => return Err(anyhow::anyhow!("No signed shim is packaged for the {} base, set shim = false", profile.base)),
+        });
+    }
+    Ok(tools)
+}
+
+/// Signs every kernel in the rootfs, so the ISO, disk image and netboot copies made from it
+/// all pass the shim's or the firmware's verification.
+pub fn sign_kernels(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(keys) = keys(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Signing kernels for Secure Boot...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let sign_cmd = format!(
+        r#"set -e
+{tools}
+for KERNEL in /rootfs/boot/vmlinuz-* /rootfs/lib/modules/*/vmlinuz; do
+  [ -f $KERNEL ] || continue
+  sbsign --key {key} --cert {cert} --output $KERNEL $KERNEL
+done"#,
+        tools = package_manager.refresh_and_install(&tools(profile, &keys)?),
+        key = KEY,
+        cert = CERT,
+    );
+    let mut volumes = vec![crate::rootfs_volume(rootfs)];
+    volumes.extend(keys.volumes());
+    crate::run_in_builder(profile, volumes, &sign_cmd, "Kernel signing")
+}
+
+/// Shell snippet signing every EFI binary under the ESP tree `esp_dir`. With shim, the
+/// removable-media loader moves to where shim chains to, and shim, MokManager and the
+/// certificate to enroll take its place.
+pub fn sign_esp_command(profile: &Profile, keys: &SigningKeys, esp_dir: &str) -> Result<String> {
+    let mut cmd = format!(
+        "for EFI in $(find {esp_dir} -iname '*.efi'); do sbsign --key {KEY} --cert {CERT} --output $EFI $EFI; done"
+    );
+    if keys.shim {
+        let arch = crate::target_arch(profile)?;
+        let suffix = shim_suffix(arch)?;
+        cmd.push_str(&format!(
+            r#"
+SHIM=$(ls /usr/lib/shim/shim{suffix}.efi.signed /boot/efi/EFI/*/shim{suffix}.efi 2>/dev/null | head -n1)
+MOK=$(ls /usr/lib/shim/mm{suffix}.efi.signed /usr/lib/shim/mm{suffix}.efi /boot/efi/EFI/*/mm{suffix}.efi 2>/dev/null | head -n1)
+[ -n "$SHIM" ] && [ -n "$MOK" ] || {{ echo "Signed shim not found in the builder" >&2; exit 1; }}
+mv {esp_dir}/EFI/BOOT/{fallback} {esp_dir}/EFI/BOOT/grub{suffix}.efi
+cp $SHIM {esp_dir}/EFI/BOOT/{fallback} && cp $MOK {esp_dir}/EFI/BOOT/mm{suffix}.efi
+openssl x509 -in {CERT} -outform DER -out {esp_dir}/{ENROLL_CERT}"#,
+            fallback = boot::efi_fallback_binary(arch),
+        ));
+    }
+    Ok(cmd)
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use colored::*;
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+const FILE_CONTEXTS: &str = "/etc/selinux/targeted/contexts/files/file_contexts";
+
+/// The selinux mode, once checked. Only the dnf bases ship a targeted policy to enforce.
+pub fn mode(profile: &Profile) -> Result<Option<&str>> {
+    let Some(mode) = profile.selinux.as_deref() else {
+        return Ok(None);
+    };
+    if !matches!(mode, "enforcing" | "permissive" | "disabled") {
+        return Err(anyhow::anyhow!("Unsupported selinux: {}. Supported: enforcing, permissive, disabled", mode));
+    }
+    if crate::package_manager(profile)? != PackageManager::Dnf {
+        return Err(anyhow::anyhow!("selinux needs a fedora or EL base"));
+    }
+    Ok(Some(mode))
+}
+
+/// Installs the targeted policy for selinux = "enforcing"/"permissive", writes the mode to
+/// /etc/selinux/config and labels the whole rootfs with setfiles. Files written in a chroot
+/// carry no labels, which an enforcing boot refuses; dnf bases are labelled even without the
+/// option whenever a policy is installed. Disk-only builds also relabel on first boot, for
+/// what the image stages add later.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if crate::package_manager(profile)? != PackageManager::Dnf {
+        return Ok(());
+    }
+    let mode = mode(profile)?;
+    let mut lines = vec!["set -e".to_string()];
+    match mode {
+        Some("disabled") => {
+            println!("{}", "Disabling SELinux...".yellow());
+            lines.push("[ -f /etc/selinux/config ] && sed -i 's/^SELINUX=.*/SELINUX=disabled/' /etc/selinux/config; true".to_string());
+            return crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "SELinux configuration");
+        }
+        Some(mode) => {
+            println!("{}", "Configuring SELinux...".yellow());
+            lines.push(crate::package_manager(profile)?.install(&["selinux-policy-targeted".to_string(), "policycoreutils".to_string()]));
+            lines.push(format!("printf '%s\\n' 'SELINUX={}' 'SELINUXTYPE=targeted' > /etc/selinux/config", mode));
+        }
+        None => {
+            println!("{}", "Labelling files for SELinux...".yellow());
+            lines.push(format!("[ -f {} ] && command -v setfiles >/dev/null || exit 0", FILE_CONTEXTS));
+        }
+    }
+    lines.push(format!("setfiles -F -e /proc -e /sys -e /dev -e /run {} /", FILE_CONTEXTS));
+    if !profile.format.iter().any(|format| format == "iso") {
+        lines.push("touch /.autorelabel".to_string());
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "SELinux labelling")
+}
@@ -0,0 +1,125 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{disk, PackageManager, Profile};
+
+const ZRAM_CONF: &str = "etc/systemd/zram-generator.conf";
+const SWAPFILE_SCRIPT: &str = "/usr/libexec/ulb-swapfile";
+
+// Optional [swap] section: compressed swap in RAM, a swapfile on disk images, or none
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SwapConfig {
+    pub kind: String, // "zram", "file" or "none"
+    pub size: Option<String>, // zram: a zram-generator expression (default "min(ram / 2, 4096)"); file: e.g. "2G" (default)
+    pub compression: Option<String>, // zram compression algorithm, e.g. "zstd" (default) or "lz4"
+    pub priority: Option<i32>, // Swap priority, defaults to 100 for zram
+}
+
+/// The [swap] settings, once checked. zram goes through zram-generator, so needs systemd; the
+/// swapfile is created on the first boot of a writable disk image.
+pub fn config(profile: &Profile) -> Result<Option<SwapConfig>> {
+    let Some(config) = profile.swap.clone() else {
+        return Ok(None);
+    };
+    match config.kind.as_str() {
+        "zram" | "none" if profile.init_system != "systemd" => {
+            return Err(anyhow::anyhow!("[swap] kind = \"{}\" needs init_system = \"systemd\" (zram-generator)", config.kind))
+        }
+        "zram" | "none" => {}
+        "file" => {
+            if !profile.format.iter().any(|f| disk::is_vm_format(f) || f == "rpi") {
+                return Err(anyhow::anyhow!("[swap] kind = \"file\" needs a disk image format"));
+            }
+            if disk::is_verity(profile)? {
+                return Err(anyhow::anyhow!("[swap] kind = \"file\" can't go on a read-only verity root"));
+            }
+            if let Some(size) = &config.size {
+                let number = size.strip_suffix(['M', 'G']).unwrap_or_default();
+                if number.parse::<u32>().map_or(true, |n| n == 0) {
+                    return Err(anyhow::anyhow!("Invalid [swap] size: {:?}, expected e.g. \"512M\" or \"2G\"", size));
+                }
+            }
+        }
+        kind => return Err(anyhow::anyhow!("Unsupported [swap] kind: {}. Supported: zram, file, none", kind)),
+    }
+    let texts = config.size.iter().chain(&config.compression);
+    if let Some(text) = texts.into_iter().find(|text| text.is_empty() || text.contains(['\n', '\'', '"'])) {
+        return Err(anyhow::anyhow!("Invalid [swap] value: {:?}", text));
+    }
+    Ok(Some(config))
+}
+
+// Creates and enables the swapfile at boot, never on live media; btrfs needs its own command
+// for a NOCOW file
+fn swapfile_script(size: &str) -> String {
+    format!(
+        r#"#!/bin/sh
+grep -qwE 'boot=live|rd\.live\.image' /proc/cmdline && exit 0
+SWAPFILE=/swapfile
+if [ ! -f $SWAPFILE ]; then
+    if [ "$(stat -f -c %T /)" = btrfs ]; then
+        btrfs filesystem mkswapfile --size {size} $SWAPFILE || exit 1
+    else
+        fallocate -l {size} $SWAPFILE && chmod 600 $SWAPFILE && mkswap $SWAPFILE >/dev/null || {{ rm -f $SWAPFILE; exit 1; }}
+    fi
+fi
+swapon $SWAPFILE
+"#
+    )
+}
+
+/// Sets up [swap]: zram-generator with its zram0 device, a first boot swapfile, or an empty
+/// zram-generator config that turns off the zram some bases (Fedora) enable by default.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    let Some(config) = config(profile)? else {
+        return Ok(());
+    };
+    println!("{}", "Configuring swap...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let swap_cmd = match config.kind.as_str() {
+        "zram" => {
+            crate::write_file(
+                &rootfs.join(ZRAM_CONF),
+                &format!(
+                    "[zram0]\nzram-size = {}\ncompression-algorithm = {}\nswap-priority = {}\n",
+                    config.size.as_deref().unwrap_or("min(ram / 2, 4096)"),
+                    config.compression.as_deref().unwrap_or("zstd"),
+                    config.priority.unwrap_or(100)
+                ),
+            )?;
+            package_manager.install(&[match package_manager {
+                PackageManager::Apt => "systemd-zram-generator".to_string(),
+                PackageManager::Portage => "sys-apps/zram-generator".to_string(),
+                _ => "zram-generator".to_string(),
+            }])
+        }
+        "file" => {
+            crate::write_file(&rootfs.join(SWAPFILE_SCRIPT.trim_start_matches('/')), &swapfile_script(config.size.as_deref().unwrap_or("2G")))?;
+            let enable = match profile.init_system.as_str() {
+                "openrc" => format!("mkdir -p /etc/local.d && ln -sf {} /etc/local.d/ulb-swapfile.start && rc-update add local default", SWAPFILE_SCRIPT),
+                // Void's core services run rc.local once the filesystems are up
+                "runit" | "s6" => format!("echo {} >> /etc/rc.local", SWAPFILE_SCRIPT),
+                _ => {
+                    crate::write_file(
+                        &rootfs.join("etc/systemd/system/ulb-swapfile.service"),
+                        &format!(
+                            "[Unit]\nDescription=Swapfile\nDefaultDependencies=no\nAfter=local-fs.target systemd-growfs-root.service\nBefore=swap.target\n\n\
+                             [Service]\nType=oneshot\nRemainAfterExit=yes\nExecStart={}\nExecStop=/sbin/swapoff /swapfile\n\n[Install]\nWantedBy=swap.target\n",
+                            SWAPFILE_SCRIPT
+                        ),
+                    )?;
+                    "systemctl enable ulb-swapfile.service".to_string()
+                }
+            };
+            format!("chmod 755 {} && {}", SWAPFILE_SCRIPT, enable)
+        }
+        _ => {
+            crate::write_file(&rootfs.join(ZRAM_CONF), "# No zram swap, from [swap] kind = \"none\"\n")?;
+            return Ok(());
+        }
+    };
+    crate::run_in_chroot(profile, rootfs, &swap_cmd, "Swap configuration")
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Profile;
+
+// Marker touched after the base install; anything changed later belongs to the extension
+const STAMP: &str = ".ulb-extension-stamp";
+
+// Optional [extension] section of the profile
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ExtensionConfig {
+    pub name: Option<String>,       // Image name, defaults to distro_name
+    pub os_id: Option<String>,      // ID= the host must match, "_any" to skip the check
+    pub version_id: Option<String>, // VERSION_ID= the host must match
+    pub level: Option<String>,      // SYSEXT_LEVEL= / CONFEXT_LEVEL=
+    pub filesystem: Option<String>, // "erofs" (default) or "squashfs"
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionKind {
+    Sysext,  // Merged into /usr and /opt by systemd-sysext
+    Confext, // Merged into /etc by systemd-confext
+}
+
+impl ExtensionKind {
+    pub fn from_format(format: &str) -> Option<Self> {
+        match format {
+            "sysext" => Some(ExtensionKind::Sysext),
+            "confext" => Some(ExtensionKind::Confext),
+            _ => None,
+        }
+    }
+
+    // Top-level directories systemd picks up from this kind of image
+    fn trees(self) -> &'static [&'static str] {
+        match self {
+            ExtensionKind::Sysext => &["usr", "opt"],
+            ExtensionKind::Confext => &["etc"],
+        }
+    }
+
+    fn release_dir(self) -> &'static str {
+        match self {
+            ExtensionKind::Sysext => "usr/lib/extension-release.d",
+            ExtensionKind::Confext => "etc/extension-release.d",
+        }
+    }
+
+    fn level_key(self) -> &'static str {
+        match self {
+            ExtensionKind::Sysext => "SYSEXT_LEVEL",
+            ExtensionKind::Confext => "CONFEXT_LEVEL",
+        }
+    }
+}
+
+/// Builds the profile's packages, files and scripts into a systemd-sysext/confext image
+/// instead of a bootable system. The base is installed first so that only the files added
+/// on top of it end up in the extension.
+pub fn build_extension(
+    profile: &Profile,
+    kind: ExtensionKind,
+    files_dir: &Path,
+    scripts_dir: &Path,
+    packages_dir: &Path,
+    build_dir: &Path,
+) -> Result<()> {
+    let config = profile.extension.clone().unwrap_or_default();
+    let name = config.name.clone().unwrap_or_else(|| profile.distro_name.clone());
+    let filesystem = config.filesystem.as_deref().unwrap_or("erofs");
+    if filesystem != "erofs" && filesystem != "squashfs" {
+        return Err(anyhow::anyhow!("Unsupported extension filesystem: {}. Supported: erofs, squashfs", filesystem));
+    }
+
+    let rootfs = PathBuf::from("/tmp/.ulb/rootfs");
+    fs::create_dir_all(&rootfs).context("Failed to create rootfs directory")?;
+    let base_image = crate::base_image(profile)?;
+
+    crate::install_base_system(profile, &rootfs)?;
+    crate::repos::with_repositories(profile, files_dir, &rootfs, || {
+        crate::run_in_container(&base_image, &rootfs, &format!("touch /rootfs/{}", STAMP), "Extension stamp")?;
+
+        crate::install_packages(profile, &rootfs)?;
+        crate::install_local_packages(profile, packages_dir, &rootfs)?;
+        crate::apps::install_appimages(profile, files_dir, &rootfs)?;
+        crate::copy_files(files_dir, &rootfs)?;
+        crate::run_scripts(scripts_dir, &rootfs)
+    })?;
+
+    println!("{}", "Collecting extension contents...".yellow());
+    let staging = PathBuf::from("/tmp/.ulb/extension");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).context("Failed to clear extension staging directory")?;
+    }
+    fs::create_dir_all(&staging).context("Failed to create extension staging directory")?;
+
+    // ctime rather than mtime: package managers preserve the archive mtimes
+    let collect_cmd = format!(
+        "cd /rootfs && for tree in {}; do [ -d \"$tree\" ] && find \"$tree\" -cnewer {} \\( -type f -o -type l \\) -print0; done | xargs -0 -r cp -a --parents -t /staging",
+        kind.trees().join(" "),
+        STAMP
+    );
+    crate::podman_run(
+        &base_image,
+        &[crate::rootfs_volume(&rootfs), format!("{}:/staging:z", staging.display())],
+        &["bash", "-c", &collect_cmd],
+        "Extension collection",
+    )?;
+
+    write_extension_release(&staging, kind, &name, profile, &config)?;
+
+    println!("{}", "Packing extension image...".yellow());
+    let image_name = format!("{}.raw", name);
+    let (tool, pack) = if filesystem == "erofs" {
+        ("erofs-utils", format!("mkfs.erofs --all-root /out/{} /staging", image_name))
+    } else {
+        ("squashfs-tools", format!("mksquashfs /staging /out/{} -comp xz -all-root -noappend", image_name))
+    };
+    // Tools don't persist between --rm containers
+    let package_manager = crate::package_manager(profile)?;
+    let tool = match package_manager {
+        crate::PackageManager::Portage => format!("sys-fs/{}", tool),
+        _ => tool.to_string(),
+    };
+    let pack_cmd = format!("{} && {}", package_manager.refresh_and_install(&[tool.as_str()]), pack);
+    crate::podman_run(
+        &base_image,
+        &[format!("{}:/staging:z", staging.display()), format!("{}:/out:z", build_dir.display())],
+        &["bash", "-c", &pack_cmd],
+        "Extension packing",
+    )?;
+
+    info!("Extension image built at {}", build_dir.join(&image_name).display());
+    Ok(())
+}
+
+fn write_extension_release(
+    staging: &Path,
+    kind: ExtensionKind,
+    name: &str,
+    profile: &Profile,
+    config: &ExtensionConfig,
+) -> Result<()> {
+    let release_dir = staging.join(kind.release_dir());
+    fs::create_dir_all(&release_dir).context("Failed to create extension-release directory")?;
+
+    let os_id = config.os_id.clone().unwrap_or_else(|| "_any".to_string());
+    let mut release = format!("ID={}\n", os_id);
+    if let Some(version_id) = &config.version_id {
+        release.push_str(&format!("VERSION_ID={}\n", version_id));
+    }
+    if let Some(level) = &config.level {
+        release.push_str(&format!("{}={}\n", kind.level_key(), level));
+    }
+    release.push_str(&format!("IMAGE_ID={}\nIMAGE_VERSION={}\n", name, profile.version));
+
+    let release_path = release_dir.join(format!("extension-release.{}", name));
+    fs::write(&release_path, release).context(format!("Failed to write {}", release_path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::{PackageManager, Profile};
+
+// One [[timers]] entry: a systemd timer and the oneshot service it starts
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Timer {
+    pub name: String, // Units are named ulb-<name>.timer/.service
+    pub command: String, // Command line run by the service
+    pub on_calendar: String, // systemd calendar expression, e.g. "daily" or "Mon *-*-* 03:00"
+    pub user: Option<String>, // Runs as root unless set
+    #[serde(default = "crate::default_true")]
+    pub persistent: bool, // Catch up on runs missed while powered off
+    pub randomized_delay: Option<String>, // Spread the start, e.g. "30min"
+}
+
+// One [[cron]] entry: a line of /etc/cron.d/ulb-<name>
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CronJob {
+    pub name: String,
+    pub schedule: String, // Five cron fields or a keyword, e.g. "0 3 * * *" or "@daily"
+    pub command: String,
+    pub user: Option<String>, // Runs as root unless set
+}
+
+/// Checks the [[timers]] and [[cron]] entries; timers need systemd.
+pub fn check(profile: &Profile) -> Result<()> {
+    if !profile.timers.is_empty() && profile.init_system != "systemd" {
+        return Err(anyhow::anyhow!("[[timers]] need init_system = \"systemd\", use [[cron]] instead"));
+    }
+    let names = profile.timers.iter().map(|t| (&t.name, &t.user)).chain(profile.cron.iter().map(|c| (&c.name, &c.user)));
+    for (name, user) in names {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) {
+            return Err(anyhow::anyhow!("Invalid timer or cron job name: {:?}", name));
+        }
+        if user.as_ref().is_some_and(|user| user.is_empty() || !user.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))) {
+            return Err(anyhow::anyhow!("Invalid user of {}: {:?}", name, user));
+        }
+    }
+    let texts = profile
+        .timers
+        .iter()
+        .flat_map(|t| [&t.command, &t.on_calendar].into_iter().chain(&t.randomized_delay))
+        .chain(profile.cron.iter().flat_map(|c| [&c.command, &c.schedule]));
+    if let Some(text) = texts.into_iter().find(|text| text.trim().is_empty() || text.contains('\n')) {
+        return Err(anyhow::anyhow!("Timer and cron job commands and schedules must be single non-empty lines: {:?}", text));
+    }
+    for job in &profile.cron {
+        let fields = job.schedule.split_whitespace().count();
+        if !(fields == 5 || (fields == 1 && job.schedule.starts_with('@'))) {
+            return Err(anyhow::anyhow!("Invalid [[cron]] schedule of {}: {:?}, expected e.g. \"0 3 * * *\" or \"@daily\"", job.name, job.schedule));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the [[timers]] units and enables the timers, and the [[cron]] jobs to /etc/cron.d
+/// with a cron daemon installed and enabled to run them.
+pub fn configure(profile: &Profile, rootfs: &Path) -> Result<()> {
+    check(profile)?;
+    if profile.timers.is_empty() && profile.cron.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Configuring timers and cron jobs...".yellow());
+
+    let mut lines = vec!["set -e".to_string()];
+    for timer in &profile.timers {
+        let unit_dir = rootfs.join("etc/systemd/system");
+        let user = timer.user.as_ref().map(|user| format!("User={}\n", user)).unwrap_or_default();
+        crate::write_file(
+            &unit_dir.join(format!("ulb-{}.service", timer.name)),
+            &format!("[Unit]\nDescription={} (from the ulb profile)\n\n[Service]\nType=oneshot\n{}ExecStart=/bin/sh -c '{}'\n", timer.name, user, timer.command.replace('%', "%%").replace('\'', "'\\''")),
+        )?;
+        let delay = timer.randomized_delay.as_ref().map(|delay| format!("RandomizedDelaySec={}\n", delay)).unwrap_or_default();
+        crate::write_file(
+            &unit_dir.join(format!("ulb-{}.timer", timer.name)),
+            &format!(
+                "[Unit]\nDescription={} timer\n\n[Timer]\nOnCalendar={}\nPersistent={}\n{}\n[Install]\nWantedBy=timers.target\n",
+                timer.name, timer.on_calendar, timer.persistent, delay
+            ),
+        )?;
+        lines.push(format!("systemctl enable ulb-{}.timer", timer.name));
+    }
+
+    if !profile.cron.is_empty() {
+        for job in &profile.cron {
+            crate::write_file(
+                &rootfs.join(format!("etc/cron.d/ulb-{}", job.name)),
+                &format!("# {} (from the ulb profile)\nSHELL=/bin/sh\nPATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\n{} {} {}\n", job.name, job.schedule, job.user.as_deref().unwrap_or("root"), job.command),
+            )?;
+        }
+        let package_manager = crate::package_manager(profile)?;
+        let (package, service) = match package_manager {
+            PackageManager::Apt => ("cron", "cron"),
+            PackageManager::Dnf => ("cronie", "crond"),
+            PackageManager::Portage => ("sys-process/cronie", "cronie"),
+            _ => ("cronie", "cronie"),
+        };
+        lines.push(package_manager.install(&[package.to_string()]));
+        lines.push(match profile.init_system.as_str() {
+            "openrc" => format!("rc-update add {} default", service),
+            "runit" => format!("ln -sf /etc/sv/{} /etc/runit/runsvdir/default/", service),
+            // The s6-rc database was compiled with the other services already
+            "s6" => format!("ln -sf /etc/sv/{} /etc/runit/runsvdir/default/\n{}", service, crate::s6_rc_command()),
+            _ => format!("systemctl enable {}", service),
+        });
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "Timer configuration")
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::Profile;
+
+const RULES_DIR: &str = "etc/udev/rules.d";
+
+// One [[udev_rules]] entry: a rules file for /etc/udev/rules.d
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UdevRule {
+    pub name: String, // File name with its priority, e.g. "70-scanner"; ".rules" is added
+    pub rules: Option<String>, // The rules themselves
+    pub file: Option<String>, // Or a rules file in files/
+}
+
+/// Checks the [[udev_rules]] names and that each has either rules or a file.
+pub fn check(profile: &Profile) -> Result<()> {
+    for rule in &profile.udev_rules {
+        let name = rule.name.trim_end_matches(".rules");
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+            return Err(anyhow::anyhow!("Invalid [[udev_rules]] name: {:?}", rule.name));
+        }
+        if rule.rules.is_some() == rule.file.is_some() {
+            return Err(anyhow::anyhow!("[[udev_rules]] {} needs exactly one of rules and file", rule.name));
+        }
+    }
+    Ok(())
+}
+
+/// Installs the [[udev_rules]] and checks them with `udevadm verify` in the chroot, so a typo
+/// fails the build rather than being skipped silently at boot. eudev and systemd before 254
+/// have no verify and the rules go in unchecked.
+pub fn install(profile: &Profile, files_dir: &Path, rootfs: &Path) -> Result<()> {
+    check(profile)?;
+    if profile.udev_rules.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "Installing udev rules...".yellow());
+
+    let rules_dir = rootfs.join(RULES_DIR);
+    fs::create_dir_all(&rules_dir).context("Failed to create the udev rules directory")?;
+    let mut paths = Vec::new();
+    for rule in &profile.udev_rules {
+        let content = match (&rule.rules, &rule.file) {
+            (Some(rules), _) => rules.clone(),
+            (_, Some(file)) => {
+                let source = files_dir.join(file.trim_start_matches('/'));
+                fs::read_to_string(&source).context(format!("Failed to read udev rules {}", source.display()))?
+            }
+            _ => unreachable!(),
+        };
+        let file_name = format!("{}.rules", rule.name.trim_end_matches(".rules"));
+        fs::write(rules_dir.join(&file_name), format!("{}\n", content.trim_end())).context(format!("Failed to write udev rules {}", file_name))?;
+        paths.push(format!("/{}/{}", RULES_DIR, file_name));
+    }
+
+    let verify_cmd = format!(
+        "if udevadm verify --help >/dev/null 2>&1; then udevadm verify --resolve-names=never {}; else echo 'udevadm verify unavailable, udev rules not checked'; fi",
+        paths.join(" ")
+    );
+    crate::run_in_chroot(profile, rootfs, &verify_cmd, "udev rules verification")
+}
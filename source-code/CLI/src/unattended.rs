@@ -0,0 +1,175 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{netinstall, Profile};
+
+// Optional [unattended] section: answers for a hands-free install from netinstall media
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UnattendedConfig {
+    pub user: String, // First user, with sudo
+    pub full_name: Option<String>,
+    pub password_hash: String, // crypt(3) hash, e.g. from `openssl passwd -6`
+    #[serde(default)]
+    pub ssh_keys: Vec<String>, // Authorized keys of the user; also installs the SSH server
+    pub disk: Option<String>, // Disk wiped and installed to, e.g. "/dev/sda"; defaults to the only/first one
+    #[serde(default)]
+    pub lvm: bool, // Partition with LVM instead of plain partitions
+    #[serde(default = "crate::default_true")]
+    pub reboot: bool, // Reboot into the installed system when done, otherwise power off
+}
+
+/// The [unattended] settings, once checked. They drive the netinstall media's installer, so only
+/// debian-installer (preseed) and Ubuntu's Subiquity (autoinstall) take them.
+pub fn config(profile: &Profile) -> Result<Option<UnattendedConfig>> {
+    let Some(config) = profile.unattended.clone() else {
+        return Ok(None);
+    };
+    if !netinstall::is_netinstall(profile)? || !matches!(profile.base.as_str(), "debian" | "ubuntu") {
+        return Err(anyhow::anyhow!("[unattended] needs variant = \"netinstall\" on the debian or ubuntu base"));
+    }
+    let mut chars = config.user.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        || !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+        || config.user == "root"
+    {
+        return Err(anyhow::anyhow!("Invalid [unattended] user: {:?}", config.user));
+    }
+    if !config.password_hash.starts_with('$') || config.password_hash.contains(['\'', '"', ' ', '\n']) {
+        return Err(anyhow::anyhow!("[unattended] password_hash must be a crypt(3) hash, e.g. from `openssl passwd -6`"));
+    }
+    let texts = config.full_name.iter().chain(&config.ssh_keys).chain(&config.disk);
+    if let Some(text) = texts.into_iter().find(|text| text.contains(['\'', '"', '\\', '\n'])) {
+        return Err(anyhow::anyhow!("[unattended] values can't contain quotes, backslashes or newlines: {}", text));
+    }
+    if config.disk.as_deref().is_some_and(|disk| !disk.starts_with("/dev/")) {
+        return Err(anyhow::anyhow!("[unattended] disk must be a device path, e.g. \"/dev/sda\""));
+    }
+    Ok(Some(config))
+}
+
+/// Kernel arguments making debian-installer ask nothing the preseed answers.
+pub fn preseed_boot_args(profile: &Profile) -> Result<&'static str> {
+    Ok(if config(profile)?.is_some() { "auto=true priority=critical" } else { "" })
+}
+
+/// Preseed answers for everything debian-installer asks: locale, keyboard, network, the user,
+/// the clock, partitioning the whole disk, GRUB and the reboot.
+pub fn preseed(profile: &Profile) -> Result<Vec<String>> {
+    let Some(config) = config(profile)? else {
+        return Ok(Vec::new());
+    };
+    let hostname = profile.hostname.clone().unwrap_or_else(|| profile.distro_name.to_lowercase());
+    let mut preseed = vec![
+        format!("d-i debian-installer/locale string {}", profile.locales.first().map_or("en_US.UTF-8", String::as_str)),
+        format!("d-i keyboard-configuration/xkb-keymap select {}", profile.x11_layout.as_ref().or(profile.keymap.as_ref()).map_or("us", String::as_str)),
+        "d-i netcfg/choose_interface select auto".to_string(),
+        format!("d-i netcfg/get_hostname string {}", hostname),
+        "d-i netcfg/get_domain string".to_string(),
+        format!("d-i netcfg/hostname string {}", hostname),
+        "d-i passwd/root-login boolean false".to_string(),
+        format!("d-i passwd/user-fullname string {}", config.full_name.as_deref().unwrap_or(&config.user)),
+        format!("d-i passwd/username string {}", config.user),
+        format!("d-i passwd/user-password-crypted password {}", config.password_hash),
+        "d-i clock-setup/utc boolean true".to_string(),
+        format!("d-i time/zone string {}", profile.timezone.as_deref().unwrap_or("UTC")),
+    ];
+    if let Some(disk) = &config.disk {
+        preseed.push(format!("d-i partman-auto/disk string {}", disk));
+    }
+    preseed.extend(
+        [
+            if config.lvm { "d-i partman-auto/method string lvm" } else { "d-i partman-auto/method string regular" },
+            "d-i partman-auto-lvm/guided_size string max",
+            "d-i partman-lvm/device_remove_lvm boolean true",
+            "d-i partman-md/device_remove_md boolean true",
+            "d-i partman-lvm/confirm boolean true",
+            "d-i partman-lvm/confirm_nooverwrite boolean true",
+            "d-i partman-auto/choose_recipe select atomic",
+            "d-i partman-partitioning/confirm_write_new_label boolean true",
+            "d-i partman/choose_partition select finish",
+            "d-i partman/confirm boolean true",
+            "d-i partman/confirm_nooverwrite boolean true",
+            "d-i partman-efi/non_efi_system boolean true",
+            if config.ssh_keys.is_empty() { "tasksel tasksel/first multiselect standard" } else { "tasksel tasksel/first multiselect standard, ssh-server" },
+            "popularity-contest popularity-contest/participate boolean false",
+            "d-i grub-installer/only_debian boolean true",
+            "d-i grub-installer/bootdev string default",
+            "d-i finish-install/reboot_in_progress note",
+        ]
+        .map(String::from),
+    );
+    if !config.reboot {
+        preseed.push("d-i debian-installer/exit/poweroff boolean true".to_string());
+    }
+    if !config.ssh_keys.is_empty() {
+        let ssh_dir = format!("/target/home/{}/.ssh", config.user);
+        let mut late = vec![format!("mkdir -p {}", ssh_dir)];
+        late.extend(config.ssh_keys.iter().map(|key| format!("echo '{}' >> {}/authorized_keys", key, ssh_dir)));
+        late.push(format!("in-target chown -R {0}: /home/{0}/.ssh", config.user));
+        preseed.push(format!("d-i preseed/late_command string {}", late.join("; ")));
+    }
+    Ok(preseed)
+}
+
+/// Subiquity's autoinstall user-data: the mirror, repositories and packages always, and with
+/// [unattended] the identity, locale, keyboard, storage and SSH answers. Without it every
+/// section stays interactive with the profile's values as defaults.
+pub fn autoinstall(profile: &Profile) -> Result<String> {
+    let config = config(profile)?;
+    let mut yaml = vec!["#cloud-config".to_string(), "autoinstall:".to_string(), "  version: 1".to_string()];
+    if config.is_none() {
+        yaml.push("  interactive-sections: [ \"*\" ]".to_string());
+    }
+    yaml.push(format!("  locale: {}", crate::quoted(profile.locales.first().map_or("en_US.UTF-8", String::as_str))));
+    if let Some(layout) = profile.x11_layout.as_ref().or(profile.keymap.as_ref()) {
+        yaml.push(format!("  keyboard:\n    layout: {}", crate::quoted(layout)));
+    }
+    if let Some(timezone) = &profile.timezone {
+        yaml.push(format!("  timezone: {}", crate::quoted(timezone)));
+    }
+
+    yaml.push(format!("  apt:\n    primary:\n      - arches: [ default ]\n        uri: {}", crate::quoted(&crate::mirror_list(profile)[0])));
+    let repositories: Vec<_> = profile.repositories.iter().filter(|r| r.enabled).collect();
+    if let Some(repo) = repositories.iter().find(|r| (r.gpg_key.is_some() || r.key_url.is_some()) && !r.url.starts_with("ppa:")) {
+        return Err(anyhow::anyhow!("Repository {} has a signing key, which Ubuntu netinstall media can't fetch; use a PPA", repo.name));
+    }
+    if !repositories.is_empty() {
+        yaml.push("    sources:".to_string());
+        let suite = crate::debootstrap_suite(profile);
+        for repo in repositories {
+            let source = if repo.url.starts_with("ppa:") {
+                repo.url.clone()
+            } else {
+                let components = if repo.components.is_empty() { "main".to_string() } else { repo.components.join(" ") };
+                format!("deb {} {} {}", repo.url, repo.suite.clone().unwrap_or_else(|| suite.clone()), components)
+            };
+            yaml.push(format!("      {}:\n        source: {}", repo.name, crate::quoted(&source)));
+        }
+    }
+    if !profile.packages.is_empty() {
+        let packages: Vec<String> = profile.packages.iter().map(|p| crate::quoted(crate::package_name(p))).collect();
+        yaml.push(format!("  packages: [ {} ]", packages.join(", ")));
+    }
+
+    if let Some(config) = config {
+        let hostname = profile.hostname.clone().unwrap_or_else(|| profile.distro_name.to_lowercase());
+        yaml.push(format!(
+            "  identity:\n    hostname: {}\n    realname: {}\n    username: {}\n    password: {}",
+            crate::quoted(&hostname),
+            crate::quoted(config.full_name.as_deref().unwrap_or(&config.user)),
+            crate::quoted(&config.user),
+            crate::quoted(&config.password_hash)
+        ));
+        let mut storage = format!("  storage:\n    layout:\n      name: {}", if config.lvm { "lvm" } else { "direct" });
+        if let Some(disk) = &config.disk {
+            storage.push_str(&format!("\n      match:\n        path: {}", crate::quoted(disk)));
+        }
+        yaml.push(storage);
+        if !config.ssh_keys.is_empty() {
+            let keys: Vec<String> = config.ssh_keys.iter().map(|key| format!("      - {}", crate::quoted(key))).collect();
+            yaml.push(format!("  ssh:\n    install-server: true\n    allow-pw: false\n    authorized-keys:\n{}", keys.join("\n")));
+        }
+        yaml.push(format!("  shutdown: {}", if config.reboot { "reboot" } else { "poweroff" }));
+    }
+    Ok(yaml.join("\n") + "\n")
+}
@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use colored::*;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{PackageManager, Profile};
+
+// The key `vagrant ssh` tries first and replaces on first boot
+const INSECURE_KEY_URL: &str = "https://raw.githubusercontent.com/hashicorp/vagrant/main/keys/vagrant.pub";
+// NAT interface MAC, shared between the OVF and the Vagrantfile so the guest sees one NIC
+const VIRTUALBOX_MAC: &str = "080027A1B2C3";
+
+// Optional [vagrant] section for the vagrant format
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct VagrantConfig {
+    #[serde(default)]
+    pub providers: Vec<String>, // "libvirt" and/or "virtualbox", defaults to both
+}
+
+fn providers(profile: &Profile) -> Result<Vec<String>> {
+    let config = profile.vagrant.clone().unwrap_or_default();
+    let providers = if config.providers.is_empty() {
+        vec!["libvirt".to_string(), "virtualbox".to_string()]
+    } else {
+        config.providers
+    };
+    if let Some(provider) = providers.iter().find(|p| *p != "libvirt" && *p != "virtualbox") {
+        return Err(anyhow::anyhow!("Unsupported Vagrant provider: {}. Supported: libvirt, virtualbox", provider));
+    }
+    Ok(providers)
+}
+
+/// Sets the rootfs up the way Vagrant expects a base box: a vagrant/vagrant user with
+/// passwordless sudo, the insecure public key, and sshd enabled on boot.
+pub fn prepare_rootfs(profile: &Profile, rootfs: &Path) -> Result<()> {
+    println!("{}", "Preparing Vagrant user...".yellow());
+    providers(profile)?;
+    if !profile.bios_support {
+        warn!("Vagrant providers boot boxes with BIOS by default; set bios_support = true");
+    }
+
+    let package_manager = crate::package_manager(profile)?;
+    let packages: &[&str] = match package_manager {
+        PackageManager::Apt | PackageManager::Dnf => &["sudo", "openssh-server"],
+        PackageManager::Portage => &["app-admin/sudo", "net-misc/openssh"],
+        _ => &["sudo", "openssh"],
+    };
+    let packages: Vec<String> = packages.iter().map(|p| p.to_string()).collect();
+    let enable_sshd = match profile.init_system.as_str() {
+        "openrc" => "rc-update add sshd default".to_string(),
+        "runit" => "ln -sf /etc/sv/sshd /etc/runit/runsvdir/default/".to_string(),
+        // The s6-rc database was compiled with the other services already
+        "s6" => format!("ln -sf /etc/sv/sshd /etc/runit/runsvdir/default/\n{}", crate::s6_rc_command()),
+        // Debian names the unit ssh
+        _ => "systemctl enable ssh 2>/dev/null || systemctl enable sshd".to_string(),
+    };
+    let user_cmd = format!(
+        "{} && (id vagrant >/dev/null 2>&1 || useradd -m -s /bin/bash vagrant) && \
+         echo vagrant:vagrant | chpasswd && \
+         mkdir -p /etc/sudoers.d && echo 'vagrant ALL=(ALL) NOPASSWD: ALL' > /etc/sudoers.d/vagrant && \
+         chmod 440 /etc/sudoers.d/vagrant && {}",
+        package_manager.install(&packages),
+        enable_sshd
+    );
+    crate::run_in_chroot(profile, rootfs, &user_cmd, "Vagrant user setup")?;
+
+    let ssh_dir = rootfs.join("home/vagrant/.ssh");
+    fs::create_dir_all(&ssh_dir).context("Failed to create vagrant .ssh directory")?;
+    let output = Command::new("curl")
+        .args(["-fsSL", "-o"])
+        .arg(ssh_dir.join("authorized_keys"))
+        .arg(INSECURE_KEY_URL)
+        .output()
+        .context("Failed to run curl")?;
+    if !output.status.success() {
+        error!("Key download failed: {}", String::from_utf8_lossy(&output.stderr));
+        return Err(anyhow::anyhow!("Failed to download the Vagrant insecure key"));
+    }
+    crate::run_in_chroot(
+        profile,
+        rootfs,
+        "chown -R vagrant:vagrant /home/vagrant/.ssh && chmod 700 /home/vagrant/.ssh && chmod 600 /home/vagrant/.ssh/authorized_keys",
+        "Vagrant key setup",
+    )
+}
+
+/// Packages the raw disk image into one .box per provider and removes the raw image.
+pub fn package_boxes(profile: &Profile, raw: &Path, build_dir: &Path) -> Result<Vec<PathBuf>> {
+    println!("{}", "Packaging Vagrant boxes...".yellow());
+
+    let raw_name = raw
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Disk image has no valid file name")?;
+    let disk_size = fs::metadata(raw).context("Failed to stat disk image")?.len();
+    let staging = PathBuf::from("/tmp/.ulb/vagrant");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).context("Failed to clear Vagrant staging directory")?;
+    }
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut box_cmd = vec![
+        "set -e".to_string(),
+        package_manager.refresh_and_install(&[crate::disk::qemu_img_package(package_manager)]),
+    ];
+    let mut boxes = Vec::new();
+    for provider in providers(profile)? {
+        let provider_dir = staging.join(&provider);
+        fs::create_dir_all(&provider_dir).context("Failed to create Vagrant staging directory")?;
+        let (metadata, vagrantfile, convert) = match provider.as_str() {
+            "libvirt" => (
+                format!(
+                    "{{\"provider\": \"libvirt\", \"format\": \"qcow2\", \"virtual_size\": {}}}\n",
+                    disk_size.div_ceil(1 << 30)
+                ),
+                "Vagrant.configure(\"2\") do |config|\n  config.vm.provider :libvirt do |libvirt|\n    libvirt.driver = \"kvm\"\n  end\nend\n".to_string(),
+                format!("qemu-img convert -f raw -O qcow2 /out/{} /staging/libvirt/box.img", raw_name),
+            ),
+            _ => {
+                fs::write(provider_dir.join("box.ovf"), virtualbox_ovf(profile, disk_size))
+                    .context("Failed to write box.ovf")?;
+                (
+                    "{\"provider\": \"virtualbox\"}\n".to_string(),
+                    format!("Vagrant.configure(\"2\") do |config|\n  config.vm.base_mac = \"{}\"\nend\n", VIRTUALBOX_MAC),
+                    format!(
+                        "qemu-img convert -f raw -O vmdk -o subformat=streamOptimized /out/{} /staging/virtualbox/box-disk001.vmdk",
+                        raw_name
+                    ),
+                )
+            }
+        };
+        fs::write(provider_dir.join("metadata.json"), metadata).context("Failed to write metadata.json")?;
+        fs::write(provider_dir.join("Vagrantfile"), vagrantfile).context("Failed to write Vagrantfile")?;
+
+        let box_name = format!("{}-{}-{}.box", profile.distro_name, profile.version, provider);
+        box_cmd.push(convert);
+        box_cmd.push(format!("tar -czf /out/{} -C /staging/{} .", box_name, provider));
+        boxes.push(build_dir.join(box_name));
+    }
+    box_cmd.push(format!("rm -f /out/{}", raw_name));
+
+    let volumes = vec![
+        format!("{}:/out:z", build_dir.display()),
+        format!("{}:/staging:z", staging.display()),
+    ];
+    crate::run_in_builder(profile, volumes, &box_cmd.join("\n"), "Vagrant box packaging")?;
+
+    for path in &boxes {
+        info!("Vagrant box built at {}", path.display());
+    }
+    Ok(boxes)
+}
+
+// Minimal OVF VirtualBox imports: one CPU, 1 GiB of memory, a SATA disk and a NAT interface
+fn virtualbox_ovf(profile: &Profile, disk_size: u64) -> String {
+    format!(
+        r#"<?xml version="1.0"?>
+<Envelope ovf:version="1.0" xml:lang="en-US" xmlns="http://schemas.dmtf.org/ovf/envelope/1" xmlns:ovf="http://schemas.dmtf.org/ovf/envelope/1" xmlns:rasd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_ResourceAllocationSettingData" xmlns:vssd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_VirtualSystemSettingData" xmlns:vbox="http://www.virtualbox.org/ovf/machine">
+  <References>
+    <File ovf:id="file1" ovf:href="box-disk001.vmdk"/>
+  </References>
+  <DiskSection>
+    <Info>Virtual disks</Info>
+    <Disk ovf:capacity="{size}" ovf:diskId="vmdisk1" ovf:fileRef="file1" ovf:format="http://www.vmware.com/interfaces/specifications/vmdk.html#streamOptimized"/>
+  </DiskSection>
+  <NetworkSection>
+    <Info>Logical networks</Info>
+    <Network ovf:name="NAT">
+      <Description>NAT network</Description>
+    </Network>
+  </NetworkSection>
+  <VirtualSystem ovf:id="{name}">
+    <Info>{name} {version}</Info>
+    <OperatingSystemSection ovf:id="101">
+      <Info>Guest operating system</Info>
+      <vbox:OSType ovf:required="false">Linux_64</vbox:OSType>
+    </OperatingSystemSection>
+    <VirtualHardwareSection>
+      <Info>Virtual hardware requirements</Info>
+      <System>
+        <vssd:ElementName>Virtual Hardware Family</vssd:ElementName>
+        <vssd:InstanceID>0</vssd:InstanceID>
+        <vssd:VirtualSystemIdentifier>{name}</vssd:VirtualSystemIdentifier>
+        <vssd:VirtualSystemType>virtualbox-2.2</vssd:VirtualSystemType>
+      </System>
+      <Item>
+        <rasd:Caption>1 virtual CPU</rasd:Caption>
+        <rasd:ElementName>1 virtual CPU</rasd:ElementName>
+        <rasd:InstanceID>1</rasd:InstanceID>
+        <rasd:ResourceType>3</rasd:ResourceType>
+        <rasd:VirtualQuantity>1</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:AllocationUnits>MegaBytes</rasd:AllocationUnits>
+        <rasd:Caption>1024 MB of memory</rasd:Caption>
+        <rasd:ElementName>1024 MB of memory</rasd:ElementName>
+        <rasd:InstanceID>2</rasd:InstanceID>
+        <rasd:ResourceType>4</rasd:ResourceType>
+        <rasd:VirtualQuantity>1024</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:Address>0</rasd:Address>
+        <rasd:Caption>sataController0</rasd:Caption>
+        <rasd:ElementName>sataController0</rasd:ElementName>
+        <rasd:InstanceID>3</rasd:InstanceID>
+        <rasd:ResourceSubType>AHCI</rasd:ResourceSubType>
+        <rasd:ResourceType>20</rasd:ResourceType>
+      </Item>
+      <Item>
+        <rasd:AddressOnParent>0</rasd:AddressOnParent>
+        <rasd:Caption>disk1</rasd:Caption>
+        <rasd:ElementName>disk1</rasd:ElementName>
+        <rasd:HostResource>/disk/vmdisk1</rasd:HostResource>
+        <rasd:InstanceID>4</rasd:InstanceID>
+        <rasd:Parent>3</rasd:Parent>
+        <rasd:ResourceType>17</rasd:ResourceType>
+      </Item>
+      <Item>
+        <rasd:AutomaticAllocation>true</rasd:AutomaticAllocation>
+        <rasd:Caption>Ethernet adapter on 'NAT'</rasd:Caption>
+        <rasd:Connection>NAT</rasd:Connection>
+        <rasd:ElementName>Ethernet adapter on 'NAT'</rasd:ElementName>
+        <rasd:InstanceID>5</rasd:InstanceID>
+        <rasd:ResourceSubType>E1000</rasd:ResourceSubType>
+        <rasd:ResourceType>10</rasd:ResourceType>
+      </Item>
+    </VirtualHardwareSection>
+  </VirtualSystem>
+</Envelope>
+"#,
+        size = disk_size,
+        name = profile.distro_name,
+        version = profile.version
+    )
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use colored::*;
+use std::path::Path;
+
+use crate::{kernel, PackageManager, Profile};
+
+// OpenZFS release package carrying the repository definitions for Fedora ("fedora") or
+// Enterprise Linux ("epel")
+fn zfs_release(dist: &str) -> String {
+    format!("https://zfsonlinux.org/{}/zfs-release-2-8$(rpm --eval '%{{dist}}').noarch.rpm", dist)
+}
+
+/// Checks that zfs = true can be honoured on this base before anything is built.
+pub fn check(profile: &Profile) -> Result<()> {
+    if !profile.zfs {
+        return Ok(());
+    }
+    if kernel::custom(profile)?.is_some() {
+        return Err(anyhow::anyhow!("zfs = true needs a packaged kernel, build ZFS into the [kernel.custom] one instead"));
+    }
+    match crate::package_manager(profile)? {
+        PackageManager::Dnf if profile.base != "fedora" && !profile.epel => {
+            Err(anyhow::anyhow!("zfs = true on Enterprise Linux needs epel = true"))
+        }
+        // Arch doesn't package ZFS, archzfs does
+        PackageManager::Pacman if !profile.repositories.iter().any(|r| r.name == "archzfs") => Err(anyhow::anyhow!(
+            "zfs = true on the arch base needs the archzfs repository in [[repositories]] (name = \"archzfs\")"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the ZFS module is built through DKMS. Ubuntu's kernels ship it, Enterprise Linux
+/// uses OpenZFS's kmod packages and Gentoo builds sys-fs/zfs-kmod itself.
+pub fn uses_dkms(profile: &Profile) -> Result<bool> {
+    Ok(profile.zfs
+        && match crate::package_manager(profile)? {
+            PackageManager::Apt => profile.base != "ubuntu",
+            PackageManager::Dnf => profile.base == "fedora",
+            PackageManager::Portage => false,
+            _ => true,
+        })
+}
+
+/// Installs the ZFS module and userland for zfs = true, with the initramfs hooks that import
+/// pools at boot. The DKMS module is built for the image's kernel with the other DKMS modules.
+pub fn install(profile: &Profile, rootfs: &Path) -> Result<()> {
+    if !profile.zfs {
+        return Ok(());
+    }
+    check(profile)?;
+    println!("{}", "Installing ZFS...".yellow());
+
+    let package_manager = crate::package_manager(profile)?;
+    let mut lines = vec!["set -e".to_string()];
+    let packages: &[&str] = match (package_manager, profile.base.as_str()) {
+        (PackageManager::Apt, "ubuntu") => &["zfsutils-linux", "zfs-initramfs"],
+        (PackageManager::Apt, _) => {
+            // zfs-dkms lives in contrib
+            lines.extend(crate::drivers::nonfree_repositories_command(profile, package_manager)?);
+            &["zfs-dkms", "zfsutils-linux", "zfs-initramfs"]
+        }
+        (PackageManager::Dnf, "fedora") => {
+            lines.push(format!("dnf install -y {}", zfs_release("fedora")));
+            &["zfs", "zfs-dracut"]
+        }
+        (PackageManager::Dnf, _) => {
+            lines.push(format!("dnf install -y {}", zfs_release("epel")));
+            // The prebuilt kmod packages follow the EL kernel ABI, DKMS would need a compiler in the image
+            lines.push("dnf config-manager --disable zfs && dnf config-manager --enable zfs-kmod".to_string());
+            &["zfs", "zfs-dracut"]
+        }
+        (PackageManager::Pacman, _) => &["zfs-dkms", "zfs-utils"],
+        (PackageManager::Xbps, _) => &["zfs"],
+        (PackageManager::Portage, _) => {
+            lines.push("mkdir -p /etc/portage/package.use && echo 'sys-fs/zfs rootfs' > /etc/portage/package.use/ulb-zfs".to_string());
+            &["sys-fs/zfs"]
+        }
+    };
+    lines.push(package_manager.install(&packages.iter().map(|p| p.to_string()).collect::<Vec<_>>()));
+    match package_manager {
+        // initramfs-tools picks up zfs-initramfs' hooks on its own
+        PackageManager::Apt => {}
+        PackageManager::Pacman => lines.push("sed -i '/^HOOKS=/s/ filesystems/ zfs filesystems/' /etc/mkinitcpio.conf".to_string()),
+        _ => lines.push(
+            "mkdir -p /etc/dracut.conf.d && echo 'add_dracutmodules+=\" zfs \"' > /etc/dracut.conf.d/ulb-zfs.conf".to_string(),
+        ),
+    }
+    crate::run_in_chroot(profile, rootfs, &lines.join("\n"), "ZFS installation")
+}